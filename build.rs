@@ -8,8 +8,6 @@ fn main() {
     println!("cargo:rustc-libdir={}/src/structure", project_dir);
     println!("cargo:rustc-link-search=native={}/src/bluefox", project_dir);
     println!("cargo:rustc-libdir={}/src/bluefox", project_dir);
-    println!("cargo:rustc-link-search=native={}/src/optoforce", project_dir);
-    println!("cargo:rustc-libdir={}/src/optoforce", project_dir);
     println!("cargo:rustc-link-search=native={}/src/biotac/wrapper", project_dir);
     println!("cargo:rustc-libdir={}/src/biotac/wrapper", project_dir);
 }
@@ -0,0 +1,221 @@
+//! Calibrate the cameras from checkerboard snapshots
+//!
+//! Uses the service's own calibration modules on the snapshots listed in a session's
+//! `structure_snapshots.csv` and `bluefox_snapshots.csv` (see the camera calibration flow). The
+//! Structure Sensor's IR camera and the Bluefox are calibrated separately, the transform between
+//! them is solved from the snapshots in which both found the board, and the depth camera is
+//! derived from the IR camera (scaled to the depth resolution, since depth is computed from the IR
+//! image).
+//!
+//! Usage:
+//!
+//! <pre>
+//! $ cargo run --example calibrate -- <session dir> <COLSxROWS> <square mm> [<output file>]
+//! </pre>
+//!
+//! The board size counts inner corners (where four squares meet), e.g. 9x6 for a board of 10 by 7
+//! squares. The calibration is written to `calibration.csv` in the session unless another output
+//! file is given; write it to `data/calibration.csv` (or point the `calibration` CLI command at
+//! it) to attach it to new sessions.
+
+#[macro_use] extern crate lazy_static;
+extern crate image;
+
+#[macro_use] mod common;
+
+#[allow(dead_code)]
+#[path = "../src/calibration/model.rs"]
+mod model;
+
+#[allow(dead_code)]
+#[path = "../src/calibration/checkerboard.rs"]
+mod checkerboard;
+
+#[allow(dead_code)]
+#[path = "../src/calibration/solve.rs"]
+mod solve;
+
+#[allow(dead_code)]
+#[path = "../src/bluefox/bayer.rs"]
+mod bayer;
+
+use std::{env, process};
+use std::io::{BufRead, BufReader, BufWriter, Read};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+fn usage(why: &str) -> ! {
+    errorln!("{}", why);
+    errorln!("Usage: calibrate <session dir> <COLSxROWS> <square mm> [<output file>]");
+    process::exit(1);
+}
+
+/// One line of a snapshot list
+struct Snapshot {
+    n: usize,
+    stream: String,
+    file: String,
+    width: usize,
+    height: usize,
+    settings: String,
+}
+
+/// Read a snapshot list (missing lists are empty)
+fn snapshots(path: &Path) -> Vec<Snapshot> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            errorln!("{}: {}, skipping", path.display(), e);
+            return vec![];
+        }
+    };
+    let mut snaps = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line.unwrap();
+        // snapshot, stream, file, host timestamp, device timestamp, width, height, settings (which may have commas)
+        let fields = line.splitn(8, ',').collect::<Vec<_>>();
+        if fields.len() < 8 {
+            continue;
+        }
+        match (fields[0].parse(), fields[5].parse(), fields[6].parse()) {
+            (Ok(n), Ok(w), Ok(h)) => snaps.push(Snapshot {
+                n        : n,
+                stream   : fields[1].to_owned(),
+                file     : fields[2].to_owned(),
+                width    : w,
+                height   : h,
+                settings : fields[7].to_owned(),
+            }),
+            _ => continue, // header
+        }
+    }
+    snaps
+}
+
+/// Load a snapshot as 8-bit grayscale
+///
+/// PNG snapshots are converted by the image crate, and raw Bluefox snapshots are demosaiced with
+/// the pattern and bit depth recorded in their settings.
+fn gray(dir: &Path, snap: &Snapshot) -> Result<Vec<u8>, String> {
+    let path = dir.join(&snap.file);
+    if snap.file.ends_with(".png") {
+        let img = try!(image::open(&path).map_err(|e| format!("could not read {}: {:?}", path.display(), e))).to_luma();
+        if (img.width() as usize, img.height() as usize) != (snap.width, snap.height) {
+            return Err(format!("{} is {}x{}, expected {}x{}", path.display(), img.width(), img.height(), snap.width, snap.height));
+        }
+        return Ok(img.into_raw());
+    }
+
+    let mut pattern = None;
+    let mut bits = None;
+    for setting in snap.settings.split(';').map(str::trim) {
+        let mut kv = setting.splitn(2, ' ');
+        match (kv.next(), kv.next()) {
+            (Some("pattern"), Some(p)) => pattern = bayer::Pattern::parse(p).ok(),
+            (Some("bits"), Some(b))    => bits = b.parse().ok(),
+            _ => {},
+        }
+    }
+    let layout = match (pattern, bits) {
+        (Some(pattern), Some(bits)) => bayer::Layout { width: snap.width, height: snap.height, pattern: pattern, bits: bits },
+        _ => return Err(format!("{}: not a PNG or a raw Bayer frame", path.display())),
+    };
+    let mut bytes = vec![];
+    try!(File::open(&path).and_then(|mut f| f.read_to_end(&mut bytes)).map_err(|e| format!("could not read {}: {}", path.display(), e)));
+    let raw = try!(bayer::unpack(&bytes, &layout).map_err(|e| format!("{}: {}", path.display(), e)));
+    let rgb = bayer::to_8bit(&bayer::demosaic(&raw, &layout, bayer::Method::Bilinear), layout.bits);
+    Ok(rgb.chunks(3).map(|p| ((p[0] as u16 + p[1] as u16 + p[2] as u16) / 3) as u8).collect())
+}
+
+/// Board views found in a camera's snapshots, with their snapshot numbers and the image size
+fn detect(dir: &Path, snaps: &[&Snapshot], board: &checkerboard::Board) -> (Vec<usize>, Vec<solve::View>, (usize, usize)) {
+    let (mut numbers, mut views) = (vec![], vec![]);
+    let size = snaps.first().map_or((0, 0), |s| (s.width, s.height));
+    for snap in snaps {
+        if (snap.width, snap.height) != size {
+            indentln!("snapshot {}: {}x{}, not {}x{} like the first one, skipping", snap.n, snap.width, snap.height, size.0, size.1);
+            continue;
+        }
+        let found = gray(dir, snap).and_then(|g| checkerboard::find_corners(&g, snap.width, snap.height, board));
+        match found {
+            Ok(corners) => {
+                indentln!("snapshot {}: found the board", snap.n);
+                numbers.push(snap.n);
+                views.push(solve::View { board: board.points(), image: corners });
+            },
+            Err(e) => indentln!("snapshot {}: no board ({})", snap.n, e),
+        }
+    }
+    (numbers, views, size)
+}
+
+/// Reprojection errors by snapshot number, for spotting bad snapshots
+fn per_view(numbers: &[usize], errors: &[f64]) -> String {
+    numbers.iter().zip(errors).map(|(n, e)| format!("{}: {:.3} px", n, e)).collect::<Vec<_>>().join(", ")
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 3 || args.len() > 4 {
+        usage("wrong number of arguments");
+    }
+    let dir = PathBuf::from(&args[0]);
+    let board = checkerboard::Board::parse(&args[1], &args[2]).unwrap_or_else(|e| usage(&e));
+    let output = args.get(3).map_or(dir.join("calibration.csv"), PathBuf::from);
+
+    let structure = snapshots(&dir.join("structure_snapshots.csv"));
+    let bluefox = snapshots(&dir.join("bluefox_snapshots.csv"));
+    if structure.iter().any(|s| s.settings.contains("mirroring on")) {
+        errorln!("warning: the Structure snapshots were taken with mirroring on, so the transform to the Bluefox will be wrong");
+    }
+
+    let mut cameras = vec![];
+
+    let (ir_numbers, ir_views, (w, h)) = {
+        indentln!(> "Structure IR:");
+        detect(&dir, &structure.iter().filter(|s| s.stream == "structure_ir").collect::<Vec<_>>(), &board)
+    };
+    let ir = solve::intrinsics("structure_ir", w, h, &ir_views).unwrap_or_else(|e| usage(&e));
+    indentln!("{}", ir.camera);
+    indentln!("RMS per snapshot: {}", per_view(&ir_numbers, &ir.errors));
+    cameras.push(ir.camera.clone());
+    match structure.iter().find(|s| s.stream == "structure") {
+        Some(depth) => {
+            let camera = ir.camera.scaled("structure", depth.width, depth.height);
+            indentln!("{}", camera);
+            cameras.push(camera);
+        },
+        None => errorln!("no depth snapshots, so no depth camera"),
+    }
+
+    let (bf_numbers, bf_views, (w, h)) = {
+        indentln!(> "Bluefox:");
+        detect(&dir, &bluefox.iter().collect::<Vec<_>>(), &board)
+    };
+    match solve::intrinsics("bluefox", w, h, &bf_views) {
+        Ok(bf) => {
+            indentln!("{}", bf.camera);
+            indentln!("RMS per snapshot: {}", per_view(&bf_numbers, &bf.errors));
+
+            let pairs = ir_numbers.iter().zip(&ir_views).zip(&ir.poses)
+                                  .filter_map(|((n, va), pose)| bf_numbers.iter().position(|m| m == n)
+                                                                                 .map(|i| (va, &bf_views[i], *pose)))
+                                  .collect::<Vec<_>>();
+            let mut camera = bf.camera;
+            match solve::extrinsics(&ir.camera, &camera, &pairs) {
+                Ok(e) => {
+                    camera.pose = e.pose;
+                    indentln!("{}", camera);
+                    indentln!("transform from Structure to Bluefox: {} snapshots, RMS {:.3} px", pairs.len(), e.rms);
+                },
+                Err(e) => errorln!("{} (the Bluefox will have no pose)", e),
+            }
+            cameras.push(camera);
+        },
+        Err(e) => errorln!("{} (the calibration will only have the Structure Sensor)", e),
+    }
+
+    let mut file = BufWriter::new(File::create(&output).unwrap_or_else(|e| usage(&format!("could not create {}: {}", output.display(), e))));
+    model::write(&mut file, &cameras).unwrap();
+    indentln!("wrote {} cameras to {}", cameras.len(), output.display());
+}
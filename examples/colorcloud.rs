@@ -0,0 +1,211 @@
+//! Colour recorded Structure Sensor depth frames with the Bluefox images
+//!
+//! Uses the service's own calibration and geometry modules. Each depth frame in the session is
+//! paired with the Bluefox frame nearest to it in host time (if that is close enough), and the
+//! Bluefox image is reprojected into the depth frame using the session's calibration (see
+//! examples/calibrate.rs). Raw Bluefox frames are demosaiced with the layouts in `bluefox_raw.csv`.
+//!
+//! Usage:
+//!
+//! <pre>
+//! $ cargo run --example colorcloud -- <session dir> ply|rgbd [--step N] [--every K] [--max-gap MS] [--calibration FILE]
+//! </pre>
+//!
+//! With `ply`, each depth frame is written as a coloured point cloud, `structure<N>_color.ply`
+//! (points the Bluefox did not see are left out). With `rgbd`, it is written as a pair of images
+//! the size of the depth frame: `structure<N>_rgb.png`, the registered colour (black where there is
+//! none), and `structure<N>_depth.png`, the depth as 16-bit grayscale in units of 100 µm.
+//!
+//! `--step N` only uses every Nth pixel in each direction for the point clouds, `--every K` only
+//! converts every Kth pair of frames, `--max-gap MS` is the largest time difference allowed between
+//! paired frames (default 50 ms), and `--calibration` overrides the session's `calibration.csv`.
+
+#[macro_use] extern crate lazy_static;
+extern crate lodepng;
+
+#[macro_use] mod common;
+
+#[allow(dead_code)]
+#[path = "../src/calibration/model.rs"]
+mod model;
+
+#[allow(dead_code)]
+#[path = "../src/calibration/register.rs"]
+mod register;
+
+#[allow(dead_code)]
+#[path = "../src/structure/geometry.rs"]
+mod geometry;
+
+#[allow(dead_code)]
+#[path = "../src/bluefox/bayer.rs"]
+mod bayer;
+
+use std::{env, process};
+use std::io::{BufRead, BufReader, BufWriter, Read};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use lodepng::ColorType;
+
+fn usage(why: &str) -> ! {
+    errorln!("{}", why);
+    errorln!("Usage: colorcloud <session dir> ply|rgbd [--step N] [--every K] [--max-gap MS] [--calibration FILE]");
+    process::exit(1);
+}
+
+/// One line of a times file
+struct Frame {
+    i: usize,
+    file: String,
+    /// Host time (s)
+    stamp: f64,
+}
+
+/// Read the frame number, file name and host time from each line of a times file
+fn times(path: &Path) -> Vec<Frame> {
+    let file = File::open(path).unwrap_or_else(|e| usage(&format!("could not open {}: {}", path.display(), e)));
+    BufReader::new(file).lines().filter_map(|line| {
+        let line = line.unwrap();
+        let fields = line.split(',').collect::<Vec<_>>();
+        if fields.len() < 3 {
+            return None;
+        }
+        match (fields[0].parse(), fields[2].parse()) {
+            (Ok(i), Ok(stamp)) => Some(Frame { i: i, file: fields[1].to_owned(), stamp: stamp }),
+            _ => None, // header
+        }
+    }).collect()
+}
+
+fn read(path: &Path) -> Vec<u8> {
+    let mut bytes = vec![];
+    File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)).unwrap_or_else(|e| usage(&format!("could not read {}: {}", path.display(), e)));
+    bytes
+}
+
+/// Load a Bluefox frame as 8-bit RGB
+fn bluefox_rgb(path: &Path, frame: usize, camera: &model::Camera, layouts: &[(usize, Option<bayer::Layout>)]) -> Result<Vec<u8>, String> {
+    let bytes = read(path);
+    let rgb = match bayer::Layout::at(layouts, frame) {
+        Some(layout) => {
+            let raw = try!(bayer::unpack(&bytes, &layout));
+            if (layout.width, layout.height) != (camera.width, camera.height) {
+                return Err(format!("frame is {}x{}, but the Bluefox was calibrated at {}x{}",
+                                   layout.width, layout.height, camera.width, camera.height));
+            }
+            bayer::to_8bit(&bayer::demosaic(&raw, &layout, bayer::Method::Bilinear), layout.bits)
+        },
+        None => bytes,
+    };
+    if rgb.len() != camera.width * camera.height * 3 {
+        return Err(format!("expected a {}x{} RGB frame, found {} bytes", camera.width, camera.height, rgb.len()));
+    }
+    Ok(rgb)
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 2 {
+        usage("not enough arguments");
+    }
+    let dir = PathBuf::from(&args[0]);
+    let format = args[1].clone();
+    if format != "ply" && format != "rgbd" {
+        usage(&format!("unknown format {:?}", format));
+    }
+    let (mut step, mut every, mut max_gap, mut calibration) = (1, 1, 0.05, dir.join("calibration.csv"));
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().unwrap_or_else(|| usage(&format!("no value for {}", option)));
+        let count = || match value.parse() {
+            Ok(n) if n > 0 => n,
+            _ => usage(&format!("bad {} {:?}", option, value)),
+        };
+        match &option[..] {
+            "--step"        => step = count(),
+            "--every"       => every = count(),
+            "--max-gap"     => max_gap = count() as f64 / 1000.0,
+            "--calibration" => calibration = PathBuf::from(value),
+            _               => usage(&format!("unknown option {:?}", option)),
+        }
+    }
+
+    let cameras = model::load(calibration.to_str().unwrap()).unwrap_or_else(|e| usage(&e));
+    let depth_camera = model::find(&cameras, "structure").unwrap_or_else(|| usage("no depth camera in the calibration")).clone();
+    let color_camera = model::find(&cameras, "bluefox").unwrap_or_else(|| usage("no Bluefox in the calibration")).clone();
+    indentln!("{}", depth_camera);
+    indentln!("{}", color_camera);
+
+    let depths = times(&dir.join("structure_times.csv"));
+    let colors = times(&dir.join("bluefox_times.csv"));
+    let layouts = match bayer::Layout::load(dir.join("bluefox_raw.csv").to_str().unwrap()) {
+        Ok(layouts) => layouts,
+        Err(_) => vec![], // no raw frames
+    };
+    indentln!("{} depth frames, {} Bluefox frames", depths.len(), colors.len());
+
+    let (mut converted, mut unpaired, mut failed) = (0, 0, 0);
+    let mut nearest = 0;
+    for depth in depths.iter().enumerate().filter(|&(n, _)| n % every == 0).map(|(_, d)| d) {
+        // both lists are in time order, so the nearest Bluefox frame only moves forward
+        while nearest + 1 < colors.len() && (colors[nearest + 1].stamp - depth.stamp).abs() <= (colors[nearest].stamp - depth.stamp).abs() {
+            nearest += 1;
+        }
+        let color = match colors.get(nearest) {
+            Some(c) if (c.stamp - depth.stamp).abs() <= max_gap => c,
+            _ => {
+                unpaired += 1;
+                continue;
+            }
+        };
+
+        let name = dir.join(&depth.file);
+        let bytes = read(&name);
+        if bytes.len() != 2 * depth_camera.width * depth_camera.height {
+            errorln!("{}: expected a {}x{} depth frame, skipping", name.display(), depth_camera.width, depth_camera.height);
+            failed += 1;
+            continue;
+        }
+        // depth frames are written big-endian
+        let depth_data = bytes.chunks(2).map(|b| ((b[0] as u16) << 8) | (b[1] as u16)).collect::<Vec<u16>>();
+        let rgb = match bluefox_rgb(&dir.join(&color.file), color.i, &color_camera, &layouts) {
+            Ok(rgb) => rgb,
+            Err(e) => {
+                errorln!("{}: {}, skipping", color.file, e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        let registered = register::register(&depth_data, &depth_camera, geometry::DEPTH_SCALE, &rgb, &color_camera);
+        let stem = name.file_stem().unwrap().to_str().unwrap().to_owned();
+        if format == "ply" {
+            let (mut points, mut point_colors) = (vec![], vec![]);
+            for v in (0..registered.height).filter(|v| v % step == 0) {
+                for u in (0..registered.width).filter(|u| u % step == 0) {
+                    let k = v * registered.width + u;
+                    if let (Some(p), Some(c)) = (registered.points[k], registered.colors[k]) {
+                        points.push(geometry::Point { x: p[0] as f32, y: p[1] as f32, z: p[2] as f32 });
+                        point_colors.push(c);
+                    }
+                }
+            }
+            let outname = name.with_file_name(format!("{}_color.ply", stem));
+            let mut outfile = BufWriter::new(File::create(&outname).unwrap());
+            geometry::write_color_ply(&mut outfile, &points, &point_colors).unwrap();
+            indentln!("{} + {} ({:.1} ms apart) -> {} ({} points)",
+                      depth.file, color.file, (color.stamp - depth.stamp) * 1000.0, outname.display(), points.len());
+        } else {
+            let rgb_name = name.with_file_name(format!("{}_rgb.png", stem));
+            let depth_name = name.with_file_name(format!("{}_depth.png", stem));
+            lodepng::encode_file(&rgb_name, &registered.image(), registered.width, registered.height, ColorType::LCT_RGB, 8).unwrap();
+            // PNG samples are big-endian
+            let be = depth_data.iter().flat_map(|&d| vec![(d >> 8) as u8, d as u8]).collect::<Vec<u8>>();
+            lodepng::encode_file(&depth_name, &be, registered.width, registered.height, ColorType::LCT_GREY, 16).unwrap();
+            indentln!("{} + {} ({:.1} ms apart) -> {}, {}",
+                      depth.file, color.file, (color.stamp - depth.stamp) * 1000.0, rgb_name.display(), depth_name.display());
+        }
+        converted += 1;
+    }
+    indentln!("converted {} frames ({} without a Bluefox frame within {} ms, {} failed)", converted, unpaired, max_gap * 1000.0, failed);
+}
@@ -6,7 +6,7 @@ use std::{env, process, mem, ptr, thread};
 use std::io::{Read, Write};
 use std::fs::File;
 use std::fmt::Debug;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::path::{Path, PathBuf};
 use self::lodepng::{encode_file, ColorType};
 
@@ -64,6 +64,101 @@ use self::lodepng::{encode_file, ColorType};
     }
 }
 
+/// Pseudo-terminals for emulating serial devices
+///
+/// The master end is held by the emulator; the slave end has a path under /dev/pts that can be
+/// handed to a service in place of the real device node.
+#[allow(dead_code)]
+pub mod pty {
+    use std::{io, mem};
+    use std::ffi::CStr;
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use super::libc;
+
+    pub struct Pty {
+        /// Master end (what the emulator reads and writes)
+        pub master: File,
+        /// Path to the slave end (what the service opens)
+        pub path: String,
+        /// Our own handle to the slave end, so the master does not get EIO while nobody else has
+        /// the port open
+        _slave: File,
+    }
+
+    impl Pty {
+        /// Open a new pseudo-terminal in raw mode
+        pub fn open() -> io::Result<Pty> {
+            unsafe {
+                let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let master = File::from_raw_fd(fd);
+                if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let name = libc::ptsname(fd);
+                if name.is_null() {
+                    return Err(io::Error::last_os_error());
+                }
+                let path = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+                // the line discipline must not echo, translate newlines or eat control characters
+                let mut tio: libc::termios = mem::zeroed();
+                if libc::tcgetattr(fd, &mut tio) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                libc::cfmakeraw(&mut tio);
+                if libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let slave = try!(OpenOptions::new().read(true).write(true).open(&path));
+                Ok(Pty { master: master, path: path, _slave: slave })
+            }
+        }
+
+        /// Wait up to `ms` milliseconds for the master end to become readable
+        pub fn readable(&self, ms: i32) -> io::Result<bool> {
+            let mut pfd = libc::pollfd { fd: self.master.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            match unsafe { libc::poll(&mut pfd, 1, ms) } {
+                n if n < 0 => Err(io::Error::last_os_error()),
+                0          => Ok(false),
+                _          => Ok(pfd.revents & libc::POLLIN != 0),
+            }
+        }
+    }
+}
+
+/// Tiny xorshift PRNG, for fault injection in the emulators
+#[allow(dead_code)]
+pub struct XorShift(u64);
+
+#[allow(dead_code)]
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        XorShift(if seed == 0 { 0x2545_F491_4F6C_DD1D } else { seed })
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns true with probability p
+    pub fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && (self.next() % 1_000_000) as f64 / 1_000_000.0 < p
+    }
+
+    /// Uniformly distributed in [0, n)
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
 /// Just like println!, but prints to stderr
 macro_rules! errorln {
     ($($arg:tt)*) => {{
@@ -164,14 +259,31 @@ pub trait Pixels<T> {
     fn pixel(&self, i: usize) -> T;
 }
 
-pub fn do_camera<T, Data: Debug + Pixels<T>>(width: usize, height: usize, channels: usize, color: ColorType, depth: libc::c_uint) {
+pub fn do_camera<T: 'static, Data: 'static + Debug + Pixels<T>>(width: usize, height: usize, channels: usize, color: ColorType, depth: libc::c_uint) {
     let inname = parse_in_arg(&mut env::args().skip(1));
+    do_frames(&inname, move |dat_path| {
+        let dat = dat_path.to_str().unwrap().to_string();
+        let png = dat_path.with_extension("png").to_str().unwrap().to_string();
+        let rows = do_binary::<Data>("", (dat, None));
+        let mut pixels = Vec::with_capacity(height*channels*rows.len());
+        for i in 0..rows.len() {
+            for j in 0..width {
+                pixels.push(rows[i].pixel(j));
+            }
+        }
+        attempt!(encode_file(png, &pixels, width, rows.len(), color, depth));
+    });
+}
 
-    let mut csvfile = attempt!(File::open(&inname));
+/// Run `convert` on the raw file of every frame listed in a camera's CSV file (spread over a few
+/// threads), then point the CSV file at the PNGs instead
+pub fn do_frames<F: Fn(PathBuf) + Send + Sync + 'static>(inname: &str, convert: F) {
+    let mut csvfile = attempt!(File::open(inname));
     let mut csvrdr = csv::Reader::from_reader(csvfile).has_headers(false);
-    let mut csvwtr = csv::Writer::from_memory();
-    csvwtr.encode(("Frame number", "Filename", "Unix timestamp"));
+    let mut csvwtr = csv::Writer::from_memory().flexible(true);
+    csvwtr.encode(("Frame number", "Filename", "Unix timestamp", "Device timestamp"));
 
+    let convert = Arc::new(convert);
     const N_THREADS: usize = 4;
     print!("Creating {} threads...", N_THREADS);
     let mut threads = Vec::with_capacity(N_THREADS);
@@ -179,19 +291,11 @@ pub fn do_camera<T, Data: Debug + Pixels<T>>(width: usize, height: usize, channe
     for i in 0..N_THREADS {
         print!("{}...", i);
         let (tx, rx) = mpsc::channel::<PathBuf>();
+        let convert = convert.clone();
         threads[i] = Some((
             thread::spawn(move || {
                 for dat_path in rx {
-                    let dat = dat_path.to_str().unwrap().to_string();
-                    let png = dat_path.with_extension("png").to_str().unwrap().to_string();
-                    let rows = do_binary::<Data>("", (dat, None));
-                    let mut pixels = Vec::with_capacity(height*channels*rows.len());
-                    for i in 0..rows.len() {
-                        for j in 0..width {
-                            pixels.push(rows[i].pixel(j));
-                        }
-                    }
-                    attempt!(encode_file(png, &pixels, width, rows.len(), color, depth));
+                    (*convert)(dat_path);
                 }
             }),
             tx
@@ -201,12 +305,15 @@ pub fn do_camera<T, Data: Debug + Pixels<T>>(width: usize, height: usize, channe
 
     let mut i = 0;
     let mut t = 0;
-    for row in csvrdr.decode() {
+    for row in csvrdr.records() {
         println!("reading frame {}...", i);
-        let (num, fname, stamp): (usize, String, f64) = row.ok().expect(&format!("failed to parse row {} of {}", i, inname));
-        csvwtr.encode((num, Path::new(&fname).with_extension("png").to_str().unwrap().to_string(), stamp));
+        // frame number, file name, host timestamp and (for some cameras) device timestamp
+        let mut row = row.ok().expect(&format!("failed to parse row {} of {}", i, inname));
+        let fname = row[1].clone();
+        row[1] = Path::new(&fname).with_extension("png").to_str().unwrap().to_string();
+        csvwtr.encode(row);
         i += 1;
-        let dat_path = Path::new(&inname).with_file_name(fname);
+        let dat_path = Path::new(inname).with_file_name(fname);
         threads[t].as_ref().unwrap().1.send(dat_path);
         t = (t + 1) % 4;
     }
@@ -218,7 +325,7 @@ pub fn do_camera<T, Data: Debug + Pixels<T>>(width: usize, height: usize, channe
         attempt!(present.0.join()); // now safe to join the thread
     }
 
-    attempt!(File::create(&inname)).write_all(csvwtr.as_bytes());
+    attempt!(File::create(inname)).write_all(csvwtr.as_bytes());
 }
 
 
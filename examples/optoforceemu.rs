@@ -0,0 +1,189 @@
+//! OptoForce emulator for exercising the native serial driver without the real sensor
+//!
+//! Opens a pseudo-terminal and speaks the OptoForce protocol on it (see src/optoforce/wrapper.rs):
+//! it streams checksummed sample frames at the configured speed, obeys configuration frames from
+//! the host, and can inject faults.
+//!
+//! Usage:
+//!
+//! <pre>
+//! $ cargo run --example optoforceemu -- [--bad-checksum P] [--garbage P] [--drop P]
+//!                                       [--state ok|x|y|z|failure] [--seed N]
+//! </pre>
+//!
+//! The pty path is printed on startup. Point the supervisor at it with
+//! `NRI_OPTOFORCE_PORT=/dev/pts/N ./run.sh` or the CLI command `optoforceport /dev/pts/N`.
+
+#[macro_use] extern crate lazy_static;
+extern crate libc;
+extern crate time;
+
+#[macro_use] mod common;
+
+use std::{env, process};
+use std::io::{Read, Write};
+use common::XorShift;
+use common::pty::Pty;
+
+const SAMPLE_HEADER: [u8; 4] = [0xAA, 0x07, 0x08, 0x0A];
+const CONFIG_HEADER: [u8; 4] = [0xAA, 0x00, 0x32, 0x01];
+
+/// Emulator configuration (from the command line)
+struct Options {
+    bad_checksum : f64,
+    garbage      : f64,
+    drop         : f64,
+    state        : u8,
+    seed         : u64,
+}
+
+impl Options {
+    fn parse() -> Options {
+        let mut opts = Options { bad_checksum: 0.0, garbage: 0.0, drop: 0.0, state: 5, seed: 1 };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().unwrap_or_else(|| usage(&format!("{} needs a value", arg)));
+            match &*arg {
+                "--bad-checksum" => opts.bad_checksum = value().parse().unwrap_or_else(|_| usage("bad probability")),
+                "--garbage"      => opts.garbage = value().parse().unwrap_or_else(|_| usage("bad probability")),
+                "--drop"         => opts.drop = value().parse().unwrap_or_else(|_| usage("bad probability")),
+                "--seed"         => opts.seed = value().parse().unwrap_or_else(|_| usage("bad seed")),
+                "--state"        => opts.state = match &*value() {
+                    "x"       => 1,
+                    "y"       => 2,
+                    "z"       => 3,
+                    "failure" => 4,
+                    "ok"      => 5,
+                    _         => usage("bad state"),
+                },
+                _ => usage(&format!("unknown argument {}", arg)),
+            }
+        }
+        opts
+    }
+}
+
+fn usage(why: &str) -> ! {
+    errorln!("{}", why);
+    errorln!("Usage: optoforceemu [--bad-checksum P] [--garbage P] [--drop P] [--state ok|x|y|z|failure] [--seed N]");
+    process::exit(1);
+}
+
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |acc, &b| acc.wrapping_add(b as u16))
+}
+
+/// Sample period (ns) for the speed bits of a config byte
+fn period(config: u8) -> u64 {
+    let hz = match (config >> 3) & 0b11 {
+        0 => 1000,
+        1 => 333,
+        2 => 100,
+        _ => 30,
+    };
+    1_000_000_000 / hz
+}
+
+/// State of the emulated sensor
+struct Sensor {
+    opts   : Options,
+    rng    : XorShift,
+    config : u8,
+    seq    : u16,
+    input  : Vec<u8>,
+}
+
+impl Sensor {
+    fn new(opts: Options) -> Sensor {
+        let seed = opts.seed;
+        Sensor { opts: opts, rng: XorShift::new(seed), config: 0b000_10_11_1, seq: 0, input: vec![] }
+    }
+
+    /// Look for configuration frames in the bytes received so far
+    fn receive(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+        while let Some(i) = self.input.windows(4).position(|w| w == CONFIG_HEADER) {
+            if self.input.len() < i + 7 {
+                break;
+            }
+            let frame = self.input[i..i + 7].to_vec();
+            self.input = self.input[i + 7..].to_vec();
+            let sum = (frame[5] as u16) << 8 | frame[6] as u16;
+            if sum == checksum(&frame[..5]) {
+                // the host does not get to set the state bits
+                self.config = (frame[4] & 0b0001_1111) | (self.config & 0b1110_0000);
+                println!("config {:#010b} (period {} us)", self.config, period(self.config) / 1000);
+            } else {
+                println!("config frame with bad checksum");
+            }
+        }
+    }
+
+    /// Build the next sample frame, injecting faults as configured
+    fn sample(&mut self, t: f64) -> Vec<u8> {
+        let mut out = vec![];
+
+        if self.rng.chance(self.opts.garbage) {
+            for _ in 0..(1 + self.rng.below(16)) {
+                out.push(self.rng.next() as u8);
+            }
+        }
+
+        self.seq = self.seq.wrapping_add(1);
+        if self.rng.chance(self.opts.drop) {
+            self.seq = self.seq.wrapping_add(1);
+        }
+
+        let config = (self.config & 0b0001_1111) | (self.opts.state << 5);
+        let forces = [(1000.0 * (t * 2.0).sin()) as i16, (1000.0 * (t * 3.0).cos()) as i16, (5000.0 + 500.0 * t.sin()) as i16];
+
+        let mut frame = SAMPLE_HEADER.to_vec();
+        frame.push((self.seq >> 8) as u8);
+        frame.push((self.seq & 0xFF) as u8);
+        frame.push(0);
+        frame.push(config);
+        for &f in &forces {
+            frame.push((f >> 8) as u8);
+            frame.push((f & 0xFF) as u8);
+        }
+        let mut sum = checksum(&frame);
+        if self.rng.chance(self.opts.bad_checksum) {
+            sum = sum.wrapping_add(1);
+        }
+        frame.push((sum >> 8) as u8);
+        frame.push((sum & 0xFF) as u8);
+
+        out.extend(&frame);
+        out
+    }
+}
+
+fn main() {
+    let opts = Options::parse();
+    let mut pty = Pty::open().unwrap();
+    println!("OptoForce emulator listening on {}", pty.path);
+
+    let mut sensor = Sensor::new(opts);
+    let begin = time::precise_time_ns();
+    let mut last = begin;
+    loop {
+        let elapsed = time::precise_time_ns() - last;
+        let timeout = match period(sensor.config).checked_sub(elapsed) {
+            Some(left) => (left / 1_000_000) as i32,
+            None       => 0,
+        };
+        if pty.readable(timeout).unwrap() {
+            let mut buf = [0u8; 64];
+            if let Ok(n) = pty.master.read(&mut buf) {
+                sensor.receive(&buf[..n]);
+            }
+        }
+
+        let now = time::precise_time_ns();
+        if now - last >= period(sensor.config) {
+            last = now;
+            let frame = sensor.sample((now - begin) as f64 / 1e9);
+            pty.master.write_all(&frame).unwrap();
+        }
+    }
+}
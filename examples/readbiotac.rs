@@ -14,6 +14,7 @@ struct Packet {
     tdc: u32,
     tac: u32,
     electrode: [u32; 19],
+    valid: u64,
 }
 
 impl fmt::Debug for Packet {
@@ -34,8 +35,10 @@ impl fmt::Debug for Packet {
             try!(write!(f, "{}, ",
                         self.electrode[i]));
         }
-        try!(write!(f, "{}",
+        try!(write!(f, "{}, ",
                     self.electrode[18]));
+        try!(write!(f, "{:#013x}",
+                    self.valid));
         Ok(())
     }
 }
@@ -48,6 +51,7 @@ fn main() {
         .chain(once(String::from("TDC")))
         .chain(once(String::from("TAC")))
         .chain((0..19).map(|i| format!("Electrode #{}", i)))
+        .chain(once(String::from("Valid")))
         .collect::<Vec<String>>()
         .join(", ");
     common::read_binary::<Packet>(&s);
@@ -0,0 +1,46 @@
+//! Convert a BioTac PAC stream (`biotac<N>_pac.dat`) to CSV, one row per sample
+//!
+//! Each sample gets its own timestamp, reconstructed by the service from the frame structure and
+//! sample rate (see src/biotac/pac.rs). Missing batches are reported on stderr, and show up in the
+//! output as a jump in the batch number.
+
+#[macro_use] extern crate lazy_static;
+extern crate time;
+
+#[macro_use] mod common;
+
+use std::fmt;
+
+#[repr(packed)]
+struct PacBatch {
+    start: time::Timespec,
+    batch: u64,
+    missed: u32,
+    offset: [u32; 22],
+    pac: [u32; 22],
+    valid: u32,
+}
+
+impl fmt::Debug for PacBatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if self.missed > 0 {
+            errorln!("gap: {} batches missing before batch {}", self.missed, self.batch);
+        }
+
+        let start = self.start.sec as f64 + self.start.nsec as f64 / 1_000_000_000f64;
+        for i in 0..22 {
+            if i > 0 {
+                try!(write!(f, "\n"));
+            }
+            try!(write!(f, "{:.9}, {}, {}, {}, {}",
+                        start + self.offset[i] as f64 / 1_000_000_000f64,
+                        self.batch, i,
+                        self.pac[i], (self.valid >> i) & 1));
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    common::read_binary::<PacBatch>("Timestamp, Batch, Sample, PAC, Valid");
+}
@@ -0,0 +1,58 @@
+//! Convert a processed BioTac stream (`biotac<N>_processed.dat`) to CSV
+//!
+//! Units are Pa (PDC, PAC), °C (TDC), °C/s (TAC) and kΩ (electrodes). See src/biotac/process.rs.
+
+#[macro_use] extern crate lazy_static;
+extern crate time;
+
+#[macro_use] mod common;
+
+use std::fmt;
+use std::iter::once;
+
+#[repr(packed)]
+struct Processed {
+    stamp: time::Timespec,
+    pdc: f64,
+    pac: [f64; 22],
+    tdc: f64,
+    tac: f64,
+    electrode: [f64; 19],
+}
+
+impl fmt::Debug for Processed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        try!(write!(f, "{:.9}, ",
+                    self.stamp.sec as f64 + self.stamp.nsec as f64 / 1_000_000_000f64));
+        try!(write!(f, "{}, ",
+                    self.pdc));
+        for i in 0..22 {
+            try!(write!(f, "{}, ",
+                        self.pac[i]));
+        }
+        try!(write!(f, "{}, ",
+                    self.tdc));
+        try!(write!(f, "{}, ",
+                    self.tac));
+        for i in 0..18 {
+            try!(write!(f, "{}, ",
+                        self.electrode[i]));
+        }
+        try!(write!(f, "{}",
+                    self.electrode[18]));
+        Ok(())
+    }
+}
+
+fn main() {
+    let s: String =
+        once(String::from("Timestamp"))
+        .chain(once(String::from("PDC (Pa)")))
+        .chain((0..22).map(|i| format!("PAC #{} (Pa)", i)))
+        .chain(once(String::from("TDC (C)")))
+        .chain(once(String::from("TAC (C/s)")))
+        .chain((0..19).map(|i| format!("Electrode #{} (kOhm)", i)))
+        .collect::<Vec<String>>()
+        .join(", ");
+    common::read_binary::<Processed>(&s);
+}
@@ -0,0 +1,129 @@
+//! Decoder for raw BioTac SPI captures
+//!
+//! Re-decodes the SPI batches saved by the BioTac service's "capture start" command, using the
+//! service's own decode module, into the normal packet stream for each finger slot (the same format
+//! as biotac<N>.dat, so the output can be fed to readbiotac). Slots with nothing plugged in decode
+//! as all parity errors, so only the slots given on the command line are written.
+//!
+//! Usage:
+//!
+//! <pre>
+//! $ cargo run --example readbiotacspi -- <capture file> <output prefix> [<slot>...]
+//! </pre>
+//!
+//! For each slot N (default: all of them), `<output prefix><N>.dat` is written, and the per-channel
+//! error counts are printed. Since the decoding is deterministic, a capture and its expected output
+//! can be kept around as a regression test for the decoder.
+
+#[macro_use] extern crate lazy_static;
+extern crate time;
+
+#[macro_use] mod common;
+
+#[allow(dead_code)]
+#[path = "../src/biotac/decode.rs"]
+mod decode;
+
+use std::{env, mem, process, slice};
+use std::io::{self, Read, Write};
+use std::fs::File;
+
+const CAPTURE_MAGIC: &'static [u8; 8] = b"NRIBSPI1";
+
+fn usage(why: &str) -> ! {
+    errorln!("{}", why);
+    errorln!("Usage: readbiotacspi <capture file> <output prefix> [<slot>...]");
+    process::exit(1);
+}
+
+fn le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64)
+}
+
+/// Read exactly `buf.len()` bytes, or return false at end of file
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match try!(reader.read(&mut buf[pos..])) {
+            0 => return Ok(false),
+            n => pos += n,
+        }
+    }
+    Ok(true)
+}
+
+/// Capture header: sample rate and frame structure
+fn header<R: Read>(reader: &mut R) -> io::Result<(u32, Vec<u8>)> {
+    let mut magic = [0u8; 8];
+    if !try!(fill(reader, &mut magic)) || &magic != CAPTURE_MAGIC {
+        usage("not a BioTac SPI capture (bad magic)");
+    }
+    let mut ints = [0u8; 8];
+    if !try!(fill(reader, &mut ints)) {
+        usage("truncated capture header");
+    }
+    let sample_rate = le(&ints[0..4]) as u32;
+    let frame_len = le(&ints[4..8]) as usize;
+    if frame_len == 0 {
+        usage("corrupt capture header (empty frame structure)");
+    }
+    let mut frame = vec![0u8; frame_len];
+    if !try!(fill(reader, &mut frame)) {
+        usage("truncated capture header");
+    }
+    Ok((sample_rate, frame))
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 2 {
+        usage("not enough arguments");
+    }
+    let slots = if args.len() > 2 {
+        args[2..].iter().map(|s| match s.parse() {
+            Ok(n) if n >= 1 && n as usize <= decode::SLOTS => n,
+            _ => usage(&format!("bad slot {:?}", s)),
+        }).collect::<Vec<u8>>()
+    } else {
+        (1..(decode::SLOTS as u8 + 1)).collect()
+    };
+
+    let mut infile = File::open(&args[0]).unwrap();
+    let (sample_rate, frame) = header(&mut infile).unwrap();
+    indentln!("sample rate {} Hz, frame of {} samples", sample_rate, frame.len());
+
+    let mut outputs = slots.iter().map(|&slot| {
+        let name = format!("{}{}.dat", args[1], slot);
+        indentln!("finger #{} -> {}", slot, name);
+        (slot, File::create(name).unwrap(), decode::Stats::new())
+    }).collect::<Vec<_>>();
+
+    let mut batches = 0;
+    loop {
+        let mut record = [0u8; 16];
+        if !fill(&mut infile, &mut record).unwrap() {
+            break;
+        }
+        let stamp = time::Timespec::new(le(&record[0..8]) as i64, le(&record[8..12]) as i32);
+        let mut raw = vec![0u8; le(&record[12..16]) as usize];
+        if !fill(&mut infile, &mut raw).unwrap() {
+            errorln!("truncated batch at the end of the capture");
+            break;
+        }
+        batches += 1;
+
+        for &mut (slot, ref mut file, ref mut stats) in &mut outputs {
+            match decode::decode(&raw, &frame, slot, stamp, stats) {
+                Ok(packet) => file.write_all(unsafe {
+                    slice::from_raw_parts(&packet as *const _ as *const u8, mem::size_of::<decode::Packet>())
+                }).unwrap(),
+                Err(e) => errorln!("batch {}, finger #{}: {}", batches, slot, e),
+            }
+        }
+    }
+
+    indentln!("decoded {} batches", batches);
+    for &(slot, _, ref stats) in &outputs {
+        indentln!("finger #{}: {}", slot, stats);
+    }
+}
@@ -0,0 +1,98 @@
+//! Demosaic raw Bayer frames recorded by the Bluefox service
+//!
+//! Uses the service's own bayer module and the layouts it recorded in `bluefox_raw.csv` (which must
+//! be next to the times file). Frames that were not recorded raw are skipped.
+//!
+//! Usage:
+//!
+//! <pre>
+//! $ cargo run --example readbluefoxraw -- <bluefox_times.csv> [bilinear|edge] [8|16]
+//! </pre>
+//!
+//! Each frame is written next to its .dat file as an RGB PNG with 8 (default) or 16 bits per
+//! channel, demosaiced with the bilinear (default) or edge-aware method.
+
+#[macro_use] extern crate lazy_static;
+extern crate lodepng;
+
+#[macro_use] mod common;
+
+#[allow(dead_code)]
+#[path = "../src/bluefox/bayer.rs"]
+mod bayer;
+
+use std::{env, process};
+use std::io::{BufRead, BufReader, Read};
+use std::fs::File;
+use std::path::Path;
+use lodepng::ColorType;
+
+fn usage(why: &str) -> ! {
+    errorln!("{}", why);
+    errorln!("Usage: readbluefoxraw <times file> [bilinear|edge] [8|16]");
+    process::exit(1);
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 1 || args.len() > 3 {
+        usage("wrong number of arguments");
+    }
+    let method = match args.get(1) {
+        Some(m) => bayer::Method::parse(m).unwrap_or_else(|e| usage(&e)),
+        None    => bayer::Method::Bilinear,
+    };
+    let depth = match args.get(2).map(|d| &d[..]) {
+        Some("8") | None => 8,
+        Some("16")       => 16,
+        Some(d)          => usage(&format!("bad bit depth {:?} (expected 8 or 16)", d)),
+    };
+
+    let times = Path::new(&args[0]);
+    let layout_path = times.with_file_name("bluefox_raw.csv");
+    let layouts = bayer::Layout::load(layout_path.to_str().unwrap()).unwrap_or_else(|e| usage(&e));
+    indentln!("{} raw layouts, demosaicing with {:?} to {}-bit PNGs", layouts.len(), method, depth);
+
+    let (mut converted, mut skipped) = (0, 0);
+    for line in BufReader::new(File::open(times).unwrap()).lines() {
+        let line = line.unwrap();
+        // frame number, file name, host timestamp, ...
+        let fields = line.split(',').collect::<Vec<_>>();
+        if fields.len() < 2 {
+            continue;
+        }
+        let frame = match fields[0].parse() {
+            Ok(i) => i,
+            Err(_) => continue, // header
+        };
+        let layout = match bayer::Layout::at(&layouts, frame) {
+            Some(l) => l,
+            None    => { skipped += 1; continue; }
+        };
+
+        let name = times.with_file_name(fields[1]);
+        let mut bytes = vec![];
+        File::open(&name).unwrap().read_to_end(&mut bytes).unwrap();
+        let raw = match bayer::unpack(&bytes, &layout) {
+            Ok(raw) => raw,
+            Err(e)  => {
+                errorln!("{}: {}, skipping", name.display(), e);
+                skipped += 1;
+                continue;
+            }
+        };
+        let rgb = bayer::demosaic(&raw, &layout, method);
+
+        let png = name.with_extension("png");
+        if depth == 8 {
+            lodepng::encode_file(&png, &bayer::to_8bit(&rgb, layout.bits), layout.width, layout.height, ColorType::LCT_RGB, 8).unwrap();
+        } else {
+            // PNG samples are big-endian
+            let be = bayer::to_16bit(&rgb, layout.bits).iter().flat_map(|&s| vec![(s >> 8) as u8, s as u8]).collect::<Vec<u8>>();
+            lodepng::encode_file(&png, &be, layout.width, layout.height, ColorType::LCT_RGB, 16).unwrap();
+        }
+        indentln!("{} -> {} ({}x{} {}, {} bits)", name.display(), png.display(), layout.width, layout.height, layout.pattern, layout.bits);
+        converted += 1;
+    }
+    indentln!("converted {} frames, skipped {}", converted, skipped);
+}
@@ -5,21 +5,26 @@ extern crate time;
 
 use std::fmt;
 
+#[repr(packed)]
 struct Data {
     stamp: time::Timespec,
-    xyz: [f64; 3]
+    seq: u16,
+    config: u8,
+    xyz: [f64; 3],
+    offset: [f64; 3],
 }
 
 impl fmt::Debug for Data {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        try!(write!(f, "{:.9}, {:.9}, {:.9}, {:.9}",
+        try!(write!(f, "{:.9}, {}, {}, {:.9}, {:.9}, {:.9}, {:.9}, {:.9}, {:.9}",
                     self.stamp.sec as f64 + self.stamp.nsec as f64 / 1_000_000_000f64,
-                    self.xyz[0], self.xyz[1], self.xyz[2]));
+                    self.seq, self.config >> 5,
+                    self.xyz[0], self.xyz[1], self.xyz[2],
+                    self.offset[0], self.offset[1], self.offset[2]));
         Ok(())
     }
 }
 
 fn main() {
-    common::read_binary::<Data>("Timestamp, X, Y, Z");
+    common::read_binary::<Data>("Timestamp, Sample number, State, X, Y, Z, X offset, Y offset, Z offset");
 }
-
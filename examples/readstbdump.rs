@@ -1,10 +1,20 @@
+//! Decoder for raw Teensy serial dumps
+//!
+//! Re-parses the bytes that came off the wire into the normal packet stream (the same format as
+//! teensy.dat, so the output can be fed to readteensy). Two input formats are understood:
+//!
+//! - captures made with the Teensy service's "capture start" command (they begin with the magic
+//!   bytes "NRITRAW1" and record a timestamp for every chunk read from the port). Each packet gets
+//!   the timestamp of the chunk that contained its last byte.
+//! - legacy raw dumps (just the bytes), for which the timestamps are meaningless.
+
 #[macro_use] extern crate lazy_static;
 extern crate time;
 
 #[macro_use] mod common;
 
 use std::{fmt, env, mem, ptr, slice};
-use std::io::{self, Read, Write};
+use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::fs::File;
 use std::path::Path;
 
@@ -33,6 +43,76 @@ trait RFC980: Read {
 
 impl<T: Read> RFC980 for T {}
 
+const CAPTURE_MAGIC: &'static [u8; 8] = b"NRITRAW1";
+
+/// A byte stream that knows when its bytes were received
+trait Stamped: Read {
+    fn stamp(&self) -> time::Timespec;
+}
+
+/// Reader for captures made by the Teensy service
+struct Capture<R: Read> {
+    inner: R,
+    chunk: Vec<u8>,
+    pos: usize,
+    stamp: time::Timespec,
+}
+
+impl<R: Read> Capture<R> {
+    fn new(inner: R) -> Capture<R> {
+        Capture { inner: inner, chunk: vec![], pos: 0, stamp: time::Timespec::new(0, 0) }
+    }
+
+    /// Load the next record from the capture file
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        let mut header = [0u8; 16];
+        match self.inner.read_exact(&mut header) {
+            Ok(()) => {},
+            Err(_) => return Ok(false), // end of file (possibly a truncated record)
+        }
+        let le = |bytes: &[u8]| bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        self.stamp = time::Timespec::new(le(&header[0..8]) as i64, le(&header[8..12]) as i32);
+        self.chunk = vec![0u8; le(&header[12..16]) as usize];
+        self.pos = 0;
+        try!(self.inner.read_exact(&mut self.chunk));
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Capture<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if !try!(self.next_chunk()) {
+                return Ok(0);
+            }
+        }
+        let n = try!((&self.chunk[self.pos..]).read(buf));
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R: Read> Stamped for Capture<R> {
+    fn stamp(&self) -> time::Timespec {
+        self.stamp
+    }
+}
+
+/// Reader for legacy dumps without timestamps
+struct Unstamped<R: Read>(R);
+
+impl<R: Read> Read for Unstamped<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read> Stamped for Unstamped<R> {
+    fn stamp(&self) -> time::Timespec {
+        time::get_time()
+    }
+}
+
 #[repr(packed)]
 struct XYZ<T> {
     x: T,
@@ -112,7 +192,7 @@ impl RawPacket {
     }
 }
 
-fn go<R: io::Read, W: io::Write>(mut reader: R, mut writer: W) -> Result<(),io::Error> {
+fn go<R: Stamped, W: io::Write>(mut reader: R, mut writer: W) -> Result<(),io::Error> {
     loop {
         let mut size_buf = [0u8; 4];
         let packet_size = match reader.read_exact(&mut size_buf) {
@@ -124,14 +204,14 @@ fn go<R: io::Read, W: io::Write>(mut reader: R, mut writer: W) -> Result<(),io::
                     let mut scanning = [0u8; 1];
                     let mut count = 0;
                     while count < 3 {
-                        reader.read_exact(&mut scanning).unwrap();
+                        try!(reader.read_exact(&mut scanning));
                         if scanning[0] == 'a' as u8 {
                             count += 1;
                         } else {
                             count = 0;
                         }
                     }
-                    reader.read_exact(&mut scanning).unwrap();
+                    try!(reader.read_exact(&mut scanning));
                     scanning[0] as usize
                 }
             },
@@ -144,8 +224,8 @@ fn go<R: io::Read, W: io::Write>(mut reader: R, mut writer: W) -> Result<(),io::
             Ok(()) => {
                 match unsafe { RawPacket::new(&buf) } {
                     Ok(p) => {
-                        writer.write_all(unsafe { slice::from_raw_parts(&time::get_time() as *const _ as *const _, mem::size_of::<time::Timespec>()) });
-                        writer.write_all(unsafe { slice::from_raw_parts(&p as *const _ as *const _, mem::size_of_val(&p)) });
+                        try!(writer.write_all(unsafe { slice::from_raw_parts(&reader.stamp() as *const _ as *const _, mem::size_of::<time::Timespec>()) }));
+                        try!(writer.write_all(unsafe { slice::from_raw_parts(&p as *const _ as *const _, mem::size_of_val(&p)) }));
                     },
                     Err(s) => errorln!("{:?}", s)
                 }
@@ -158,7 +238,18 @@ fn go<R: io::Read, W: io::Write>(mut reader: R, mut writer: W) -> Result<(),io::
 
 fn main() {
     let (inname, outname) = common::parse_inout_args(&mut env::args());
-    println!("{:?}", go(File::open(&inname).unwrap(), File::create(&outname).unwrap()));
+    let mut infile = File::open(&inname).unwrap();
+    let outfile = File::create(&outname).unwrap();
+
+    let mut magic = [0u8; 8];
+    let is_capture = infile.read_exact(&mut magic).is_ok() && &magic == CAPTURE_MAGIC;
+    if is_capture {
+        println!("{:?}", go(Capture::new(infile), outfile));
+    } else {
+        indentln!("no capture header, assuming legacy raw dump (timestamps will be bogus)");
+        infile.seek(SeekFrom::Start(0)).unwrap();
+        println!("{:?}", go(Unstamped(infile), outfile));
+    }
 }
 
 
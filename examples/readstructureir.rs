@@ -0,0 +1,103 @@
+//! Converter for recorded Structure IR frames
+//!
+//! Turns every `structure_ir<N>.dat` listed in `structure_ir_times.csv` into a PNG, and points the
+//! CSV file at the PNGs instead.
+//!
+//! Usage:
+//!
+//! <pre>
+//! $ cargo run --example readstructureir -- <structure_ir_times.csv> [WxH/format]
+//! </pre>
+//!
+//! The IR mode can be chosen at runtime (see src/structure/mod.rs), so by default the size and
+//! pixel format are taken from the `ir` column of the `structure_settings.csv` recorded next to
+//! the input. Give the mode on the command line (e.g. `640x480/gray16`) to override it. Only the
+//! `rgb888`, `gray8` and `gray16` formats can be converted.
+
+#[macro_use] extern crate lazy_static;
+#[macro_use] mod common;
+extern crate lodepng;
+extern crate libc;
+
+use std::{env, mem, process};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use lodepng::ColorType;
+
+fn usage(why: &str) -> ! {
+    errorln!("{}", why);
+    errorln!("Usage: readstructureir <structure_ir_times.csv> [WxH/format]");
+    process::exit(1);
+}
+
+/// Width, height, bytes per pixel, PNG color type and bit depth for a `WxH@FPS/format` video mode
+/// (the frame rate is optional, since it does not matter here)
+fn parse_mode(mode: &str) -> Result<(usize, usize, usize, ColorType, libc::c_uint), String> {
+    let mut parts = mode.splitn(2, '/');
+    let size = parts.next().unwrap().split('@').next().unwrap();
+    let format = try!(parts.next().ok_or(format!("no pixel format in IR mode {:?}", mode)));
+
+    let mut dims = size.splitn(2, 'x').map(|d| d.trim().parse::<usize>());
+    let (width, height) = match (dims.next(), dims.next()) {
+        (Some(Ok(w)), Some(Ok(h))) if w > 0 && h > 0 => (w, h),
+        _ => return Err(format!("bad resolution in IR mode {:?}", mode)),
+    };
+
+    let (bytes, color, depth) = match format.trim() {
+        "rgb888" => (3, ColorType::LCT_RGB, 8),
+        "gray8"  => (1, ColorType::LCT_GREY, 8),
+        "gray16" => (2, ColorType::LCT_GREY, 16),
+        other    => return Err(format!("IR format {} cannot be converted to PNG", other)),
+    };
+    Ok((width, height, bytes, color, depth))
+}
+
+/// The `ir` column of structure_settings.csv
+fn recorded_mode(settings: &Path) -> Result<String, String> {
+    let mut contents = String::new();
+    try!(File::open(settings).and_then(|mut f| f.read_to_string(&mut contents))
+                             .map_err(|e| format!("could not read {}: {}", settings.display(), e)));
+    let mut lines = contents.lines();
+    match (lines.next(), lines.next()) {
+        (Some(header), Some(values)) => {
+            header.split(',').position(|h| h == "ir")
+                             .and_then(|col| values.split(',').nth(col))
+                             .map(|mode| mode.to_owned())
+                             .ok_or(format!("no IR mode in {}", settings.display()))
+        },
+        _ => Err(format!("no settings in {}", settings.display())),
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let inname = args.next().unwrap_or_else(|| usage("no input file"));
+    let mode = args.next().map_or_else(|| recorded_mode(&Path::new(&inname).with_file_name("structure_settings.csv")),
+                                       Ok);
+    let (width, height, bytes, color, depth) = match mode.and_then(|m| parse_mode(&m)) {
+        Ok(mode) => mode,
+        Err(e)   => usage(&e),
+    };
+    println!("IR frames are {}x{}, {} bytes per pixel", width, height, bytes);
+
+    common::do_frames(&inname, move |dat_path| {
+        let mut raw = vec![];
+        attempt!(attempt!(File::open(&dat_path)).read_to_end(&mut raw));
+        if raw.len() != width * height * bytes {
+            errorln!("{}: {} bytes is not one {}x{} frame, skipping", dat_path.display(), raw.len(), width, height);
+            return;
+        }
+        if depth == 16 {
+            // the samples were recorded in host order, but PNG wants them big-endian
+            raw = raw.chunks(2).fold(Vec::with_capacity(raw.len()), |mut v, b| {
+                let x: u16 = unsafe { mem::transmute([b[0], b[1]]) };
+                v.push((x >> 8) as u8);
+                v.push(x as u8);
+                v
+            });
+        }
+        let png = dat_path.with_extension("png").to_str().unwrap().to_string();
+        attempt!(lodepng::encode_file(png, &raw, width, height, color, depth));
+    });
+}
@@ -0,0 +1,80 @@
+//! Convert recorded Structure Sensor depth frames to point clouds
+//!
+//! Uses the service's own geometry module and the intrinsics it recorded in the session.
+//!
+//! Usage:
+//!
+//! <pre>
+//! $ cargo run --example structurecloud -- <structure_intrinsics.csv> ply|pcd [--step N] <structureN.dat>...
+//! </pre>
+//!
+//! Each frame is written next to the input, with the extension changed to .ply or .pcd. With
+//! `--step N`, only every Nth pixel in each direction is used.
+
+#[macro_use] extern crate lazy_static;
+
+#[macro_use] mod common;
+
+#[allow(dead_code)]
+#[path = "../src/structure/geometry.rs"]
+mod geometry;
+
+use std::{env, process};
+use std::io::{BufWriter, Read};
+use std::fs::File;
+use std::path::Path;
+
+fn usage(why: &str) -> ! {
+    errorln!("{}", why);
+    errorln!("Usage: structurecloud <intrinsics file> ply|pcd [--step N] <depth frame>...");
+    process::exit(1);
+}
+
+fn main() {
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 3 {
+        usage("not enough arguments");
+    }
+    let intrinsics = match geometry::Intrinsics::load(&args[0], "depth") {
+        Ok(i) => i,
+        Err(e) => usage(&e),
+    };
+    let format = args[1].clone();
+    if format != "ply" && format != "pcd" {
+        usage(&format!("unknown format {:?}", format));
+    }
+    let mut frames = args.split_off(2);
+    let mut step = 1;
+    if frames[0] == "--step" {
+        if frames.len() < 3 {
+            usage("not enough arguments");
+        }
+        step = match frames[1].parse() {
+            Ok(n) if n > 0 => n,
+            _ => usage(&format!("bad step {:?}", frames[1])),
+        };
+        frames = frames.split_off(2);
+    }
+    indentln!("depth intrinsics: {}", intrinsics);
+
+    for name in &frames {
+        let mut bytes = vec![];
+        File::open(name).unwrap().read_to_end(&mut bytes).unwrap();
+        if bytes.len() != 2 * intrinsics.width * intrinsics.height {
+            errorln!("{}: expected a {}x{} depth frame, skipping", name, intrinsics.width, intrinsics.height);
+            continue;
+        }
+        // depth frames are written big-endian
+        let depth = bytes.chunks(2).map(|b| ((b[0] as u16) << 8) | (b[1] as u16)).collect::<Vec<u16>>();
+        let points = intrinsics.cloud(&depth, step);
+
+        let outname = Path::new(name).with_extension(&format);
+        let mut outfile = BufWriter::new(File::create(&outname).unwrap());
+        if format == "ply" {
+            geometry::write_ply(&mut outfile, &points).unwrap();
+        } else {
+            geometry::write_pcd(&mut outfile, &points).unwrap();
+        }
+        indentln!("{} -> {} ({} points)", name, outname.display(), points.len());
+    }
+}
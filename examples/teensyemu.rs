@@ -0,0 +1,279 @@
+//! Teensy emulator for exercising the serial code path without the real board
+//!
+//! Opens a pseudo-terminal and speaks the Teensy protocol on it: the handshake, ACKed stream
+//! start/stop and IMU rate commands, park state queries, and "aaa"+length framed data packets with
+//! checksums and IMU FIFOs. Faults can be injected to test the driver's resynchronization.
+//!
+//! Usage:
+//!
+//! <pre>
+//! $ cargo run --example teensyemu -- [--rate HZ] [--park none|stick|biotac|optoforce]
+//!                                    [--bad-checksum P] [--truncate P] [--garbage P]
+//!                                    [--old-firmware] [--seed N]
+//! </pre>
+//!
+//! The pty path is printed on startup. Point the supervisor at it with
+//! `NRI_TEENSY_PORT=/dev/pts/N ./run.sh` or the CLI command `teensyport /dev/pts/N`.
+
+#[macro_use] extern crate lazy_static;
+extern crate libc;
+extern crate time;
+
+#[macro_use] mod common;
+
+use std::{env, process};
+use std::io::{Read, Write};
+use common::XorShift;
+use common::pty::Pty;
+
+const ACK: u8 = 0x06;
+const PROTOCOL_VERSION: (u8, u8) = (1, 0);
+const CAPS: u8 = 0b0000_0011; // checksum, IMU rates
+
+/// Emulator configuration (from the command line)
+struct Options {
+    rate         : f64,
+    park         : u8,
+    bad_checksum : f64,
+    truncate     : f64,
+    garbage      : f64,
+    old_firmware : bool,
+    seed         : u64,
+}
+
+impl Options {
+    fn parse() -> Options {
+        let mut opts = Options { rate: 3000.0, park: 0, bad_checksum: 0.0, truncate: 0.0, garbage: 0.0, old_firmware: false, seed: 1 };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().unwrap_or_else(|| usage(&format!("{} needs a value", arg)));
+            match &*arg {
+                "--rate"         => opts.rate = value().parse().unwrap_or_else(|_| usage("bad rate")),
+                "--bad-checksum" => opts.bad_checksum = value().parse().unwrap_or_else(|_| usage("bad probability")),
+                "--truncate"     => opts.truncate = value().parse().unwrap_or_else(|_| usage("bad probability")),
+                "--garbage"      => opts.garbage = value().parse().unwrap_or_else(|_| usage("bad probability")),
+                "--seed"         => opts.seed = value().parse().unwrap_or_else(|_| usage("bad seed")),
+                "--old-firmware" => opts.old_firmware = true,
+                "--park"         => opts.park = match &*value() {
+                    "none"      => 0,
+                    "optoforce" => 1,
+                    "stick"     => 2,
+                    "biotac"    => 4,
+                    _           => usage("bad park state"),
+                },
+                _ => usage(&format!("unknown argument {}", arg)),
+            }
+        }
+        opts
+    }
+}
+
+fn usage(why: &str) -> ! {
+    errorln!("{}", why);
+    errorln!("Usage: teensyemu [--rate HZ] [--park none|stick|biotac|optoforce] [--bad-checksum P] [--truncate P] [--garbage P] [--old-firmware] [--seed N]");
+    process::exit(1);
+}
+
+/// Sample FIFO for one IMU sensor
+struct Fifo {
+    rate  : u16,
+    due   : f64,
+    count : usize,
+}
+
+impl Fifo {
+    fn new(rate: u16) -> Fifo {
+        Fifo { rate: rate, due: 0.0, count: 0 }
+    }
+
+    /// Accumulate samples that became due during `dt` seconds
+    fn tick(&mut self, dt: f64) {
+        self.due += self.rate as f64 * dt;
+        while self.due >= 1.0 {
+            self.due -= 1.0;
+            self.count += 1;
+        }
+    }
+
+    /// Take up to `max` samples out of the FIFO
+    fn take(&mut self, max: usize) -> usize {
+        let n = if self.count < max { self.count } else { max };
+        self.count -= n;
+        n
+    }
+}
+
+/// State of the emulated firmware
+struct Teensy {
+    opts      : Options,
+    rng       : XorShift,
+    streaming : bool,
+    acc       : Fifo,
+    gyro      : Fifo,
+    mag       : Fifo,
+    counter   : u8,
+    sample    : i16,
+}
+
+impl Teensy {
+    fn new(opts: Options) -> Teensy {
+        let seed = opts.seed;
+        Teensy {
+            opts      : opts,
+            rng       : XorShift::new(seed),
+            streaming : false,
+            acc       : Fifo::new(1600),
+            gyro      : Fifo::new(760),
+            mag       : Fifo::new(100),
+            counter   : 0,
+            sample    : 0,
+        }
+    }
+
+    /// React to one command byte (plus payload, read from `port` if necessary)
+    fn command<P: Read + Write>(&mut self, byte: u8, port: &mut P) {
+        let ack = |port: &mut P, b: u8| {
+            port.write_all(&[ACK, b]).unwrap();
+        };
+
+        match byte {
+            b'v' if !self.opts.old_firmware => {
+                println!("handshake");
+                port.write_all(b"NRI").unwrap();
+                port.write_all(&[PROTOCOL_VERSION.0, PROTOCOL_VERSION.1, CAPS]).unwrap();
+            },
+            b'1' => {
+                println!("stream start");
+                self.streaming = true;
+                if !self.opts.old_firmware { ack(port, byte); }
+            },
+            b'2' => {
+                println!("stream stop");
+                self.streaming = false;
+                if !self.opts.old_firmware { ack(port, byte); }
+            },
+            b'4' => {
+                println!("park query (state {})", self.opts.park);
+                port.write_all(&[!self.opts.park]).unwrap();
+            },
+            b'r' if !self.opts.old_firmware => {
+                let mut payload = [0u8; 6];
+                port.read_exact(&mut payload).unwrap();
+                let rate = |i: usize| payload[i] as u16 | (payload[i + 1] as u16) << 8;
+                self.acc = Fifo::new(rate(0));
+                self.gyro = Fifo::new(rate(2));
+                self.mag = Fifo::new(rate(4));
+                println!("IMU rates acc={} gyro={} mag={}", self.acc.rate, self.gyro.rate, self.mag.rate);
+                ack(port, byte);
+            },
+            other => println!("ignoring command byte {:#04x}", other),
+        }
+    }
+
+    /// Build the next data packet (without the "aaa"+length header)
+    fn packet(&mut self, dt: f64) -> Vec<u8> {
+        self.acc.tick(dt);
+        self.gyro.tick(dt);
+        self.mag.tick(dt);
+        self.counter = self.counter.wrapping_add(1);
+        self.sample = self.sample.wrapping_add(1);
+
+        let mut buf = Vec::with_capacity(255);
+        let imu = self.acc.rate as u32 + self.gyro.rate as u32 + self.mag.rate as u32 > 0;
+        if imu {
+            // the whole packet has to fit in 255 bytes, so at most 36 IMU triplets
+            let a = self.acc.take(24);
+            let g = self.gyro.take(35 - a);
+            self.mag.take(1);
+            buf.push(a as u8);
+            buf.push(g as u8);
+            for i in 0..(a + g) {
+                let v = self.sample.wrapping_add(i as i16);
+                for &x in &[v, v.wrapping_neg(), v.wrapping_mul(2)] {
+                    buf.push((x & 0xFF) as u8);
+                    buf.push((x >> 8) as u8);
+                }
+            }
+            // magnetometer is big-endian
+            for &x in &[self.sample, self.sample.wrapping_neg(), 0] {
+                buf.push((x >> 8) as u8);
+                buf.push((x & 0xFF) as u8);
+            }
+        }
+        for i in 0..30 {
+            buf.push(self.counter.wrapping_add(i));
+        }
+        buf.push(self.counter);
+
+        let sum = buf.iter().fold(0u8, |a, &b| a.wrapping_add(b));
+        buf.push(sum);
+        buf
+    }
+
+    /// Frame a packet, injecting faults as configured
+    fn frame(&mut self, mut packet: Vec<u8>) -> Vec<u8> {
+        let mut out = vec![];
+
+        if self.rng.chance(self.opts.garbage) {
+            for _ in 0..(1 + self.rng.below(16)) {
+                out.push(self.rng.next() as u8);
+            }
+        }
+
+        if self.rng.chance(self.opts.bad_checksum) {
+            let last = packet.len() - 1;
+            packet[last] = packet[last].wrapping_add(1);
+        }
+
+        out.extend(b"aaa");
+        out.push(packet.len() as u8);
+
+        if self.rng.chance(self.opts.truncate) {
+            let keep = self.rng.below(packet.len());
+            packet.truncate(keep);
+        }
+
+        out.extend(&packet);
+        out
+    }
+}
+
+fn main() {
+    let opts = Options::parse();
+    let period = (1e9 / opts.rate) as u64; // ns
+    let mut pty = Pty::open().unwrap();
+    println!("Teensy emulator listening on {}", pty.path);
+
+    let mut teensy = Teensy::new(opts);
+    let mut last = time::precise_time_ns();
+    loop {
+        // sleep until the next command byte, or until the next packet is due
+        let timeout = if teensy.streaming {
+            match period.checked_sub(time::precise_time_ns() - last) {
+                Some(left) => (left / 1_000_000) as i32,
+                None       => 0,
+            }
+        } else {
+            10
+        };
+        if pty.readable(timeout).unwrap() {
+            let mut byte = [0u8; 1];
+            if let Ok(1) = pty.master.read(&mut byte) {
+                let mut master = &pty.master;
+                teensy.command(byte[0], &mut master);
+            }
+        }
+
+        let now = time::precise_time_ns();
+        let elapsed = now - last;
+        if !teensy.streaming {
+            last = now;
+        } else if elapsed >= period {
+            last = now;
+            let dt = elapsed as f64 / 1e9;
+            let packet = teensy.packet(dt);
+            let framed = teensy.frame(packet);
+            pty.master.write_all(&framed).unwrap();
+        }
+    }
+}
@@ -1,6 +1,5 @@
 #!/bin/bash
 
-export LD_LIBRARY_PATH=src/optoforce
 export RUST_BACKTRACE=1
 
 if [ "$#" -ne 0 ]; then 
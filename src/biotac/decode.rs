@@ -0,0 +1,427 @@
+//! Decoding of raw SPI batches from the Cheetah into BioTac packets
+//!
+//! Each sample in a batch is two command bytes (the channel that was sampled) followed by one
+//! 16-bit word from each finger slot. A word carries 12 bits of data and an odd parity bit in each
+//! byte. Words that fail the parity check are not stored; instead, the corresponding bit in the
+//! packet's validity mask is left clear, and the error is counted for that finger and channel.
+//!
+//! Nothing in here touches the hardware, and the module only depends on std and the time crate,
+//! so that the offline tools can include it as-is (see examples/readbiotacspi.rs) and decode raw
+//! SPI captures exactly the same way as the service.
+
+extern crate time;
+
+use std::fmt;
+use std::mem;
+
+/// Number of samples in one batch (one full frame)
+pub const BATCH_SAMPLES: usize = 44;
+/// Number of finger slots on the Cheetah (MAX_BIOTACS_PER_CHEETAH in biotac.h)
+pub const SLOTS: usize = 3;
+/// Bytes per sample: command word plus one word per finger slot
+pub const SAMPLE_BYTES: usize = 2 + 2*SLOTS;
+/// Number of distinct channels (PDC, PAC, TDC, TAC and 19 electrodes)
+pub const N_CHANNELS: usize = 23;
+/// Number of values in a packet (and bits in the validity mask)
+pub const N_VALUES: usize = 44;
+
+static PARITY: [u8; 128] = [0x01, 0x02, 0x04, 0x07, 0x08, 0x0B, 0x0D, 0x0E,
+                            0x10, 0x13, 0x15, 0x16, 0x19, 0x1A, 0x1C, 0x1F,
+                            0x20, 0x23, 0x25, 0x26, 0x29, 0x2A, 0x2C, 0x2F,
+                            0x31, 0x32, 0x34, 0x37, 0x38, 0x3B, 0x3D, 0x3E,
+                            0x40, 0x43, 0x45, 0x46, 0x49, 0x4A, 0x4C, 0x4F,
+                            0x51, 0x52, 0x54, 0x57, 0x58, 0x5B, 0x5D, 0x5E,
+                            0x61, 0x62, 0x64, 0x67, 0x68, 0x6B, 0x6D, 0x6E,
+                            0x70, 0x73, 0x75, 0x76, 0x79, 0x7A, 0x7C, 0x7F,
+                            0x80, 0x83, 0x85, 0x86, 0x89, 0x8A, 0x8C, 0x8F,
+                            0x91, 0x92, 0x94, 0x97, 0x98, 0x9B, 0x9D, 0x9E,
+                            0xA1, 0xA2, 0xA4, 0xA7, 0xA8, 0xAB, 0xAD, 0xAE,
+                            0xB0, 0xB3, 0xB5, 0xB6, 0xB9, 0xBA, 0xBC, 0xBF,
+                            0xC1, 0xC2, 0xC4, 0xC7, 0xC8, 0xCB, 0xCD, 0xCE,
+                            0xD0, 0xD3, 0xD5, 0xD6, 0xD9, 0xDA, 0xDC, 0xDF,
+                            0xE0, 0xE3, 0xE5, 0xE6, 0xE9, 0xEA, 0xEC, 0xEF,
+                            0xF1, 0xF2, 0xF4, 0xF7, 0xF8, 0xFB, 0xFD, 0xFE];
+
+/// A channel sampled by the BioTac
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Channel {
+    Pdc,
+    Pac,
+    Tdc,
+    Tac,
+    /// Electrode number (0-18)
+    Electrode(u8),
+}
+
+impl Channel {
+    /// Channel selected by a sampling command byte
+    pub fn from_command(cmd: u8) -> Option<Channel> {
+        match (cmd & 0x7E) >> 1 {
+            0           => Some(Channel::Pac),
+            1           => Some(Channel::Pdc),
+            2           => Some(Channel::Tac),
+            3           => Some(Channel::Tdc),
+            c @ 17...35 => Some(Channel::Electrode(c - 17)),
+            _           => None,
+        }
+    }
+
+    /// Index into per-channel arrays (same order as the packet fields)
+    pub fn index(self) -> usize {
+        match self {
+            Channel::Pdc          => 0,
+            Channel::Pac          => 1,
+            Channel::Tdc          => 2,
+            Channel::Tac          => 3,
+            Channel::Electrode(e) => 4 + e as usize,
+        }
+    }
+
+    pub fn from_index(i: usize) -> Option<Channel> {
+        match i {
+            0              => Some(Channel::Pdc),
+            1              => Some(Channel::Pac),
+            2              => Some(Channel::Tdc),
+            3              => Some(Channel::Tac),
+            4...22         => Some(Channel::Electrode((i - 4) as u8)),
+            _              => None,
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Channel::Pdc          => write!(f, "PDC"),
+            Channel::Pac          => write!(f, "PAC"),
+            Channel::Tdc          => write!(f, "TDC"),
+            Channel::Tac          => write!(f, "TAC"),
+            Channel::Electrode(e) => write!(f, "E{:02}", e + 1),
+        }
+    }
+}
+
+/// One frame of data from one finger
+///
+/// Bits of the validity mask, in field order: PDC is bit 0, PAC samples are bits 1-22, TDC is
+/// bit 23, TAC is bit 24 and the electrodes are bits 25-43. A clear bit means the value was not
+/// received intact (and is zero).
+#[repr(packed)]
+pub struct Packet {
+    pub stamp: time::Timespec,
+    pub pdc: u32,
+    pub pac: [u32; 22],
+    pub tdc: u32,
+    pub tac: u32,
+    pub electrode: [u32; 19],
+    pub valid: u64,
+}
+
+impl Packet {
+    pub fn new(stamp: time::Timespec) -> Packet {
+        let mut packet = unsafe { mem::zeroed::<Packet>() };
+        packet.stamp = stamp;
+        packet
+    }
+
+    /// All the values in field order (the same order as the validity mask bits)
+    pub fn values(&self) -> [u32; N_VALUES] {
+        // copy the arrays out first, since references into a packed struct may be unaligned
+        let (pac, electrode) = (self.pac, self.electrode);
+        let mut values = [0; N_VALUES];
+        values[0] = self.pdc;
+        for (v, &x) in values[1..23].iter_mut().zip(&pac) {
+            *v = x;
+        }
+        values[23] = self.tdc;
+        values[24] = self.tac;
+        for (v, &x) in values[25..].iter_mut().zip(&electrode) {
+            *v = x;
+        }
+        values
+    }
+
+    pub fn is_valid(&self, i: usize) -> bool {
+        self.valid & (1 << i) != 0
+    }
+
+    /// Store a value and mark it valid. Returns false if there is no room for it.
+    fn set(&mut self, channel: Channel, pac_index: usize, value: u32) -> bool {
+        let bit = match channel {
+            Channel::Pdc => { self.pdc = value; 0 },
+            Channel::Tdc => { self.tdc = value; 23 },
+            Channel::Tac => { self.tac = value; 24 },
+            Channel::Pac => {
+                if pac_index >= 22 {
+                    return false;
+                }
+                self.pac[pac_index] = value;
+                1 + pac_index
+            },
+            Channel::Electrode(e) => { self.electrode[e as usize] = value; 25 + e as usize },
+        };
+        self.valid |= 1 << bit;
+        true
+    }
+}
+
+/// Error counters for one finger
+pub struct Stats {
+    /// Words received
+    pub words: usize,
+    /// Words with bad parity, per channel (see Channel::index)
+    pub parity: [usize; N_CHANNELS],
+    /// Samples with an unexpected channel in the frame structure
+    pub bad_channel: usize,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats { words: 0, parity: [0; N_CHANNELS], bad_channel: 0 }
+    }
+
+    pub fn parity_errors(&self) -> usize {
+        self.parity.iter().fold(0, |a, &b| a + b)
+    }
+
+    /// Fraction of words that failed the parity check
+    pub fn error_rate(&self) -> f64 {
+        if self.words == 0 {
+            0.0
+        } else {
+            self.parity_errors() as f64 / self.words as f64
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} parity errors in {} words ({:.3}%)",
+                    self.parity_errors(), self.words, 100.0 * self.error_rate()));
+        for (i, &n) in self.parity.iter().enumerate() {
+            if n > 0 {
+                try!(write!(f, ", {} {}", Channel::from_index(i).unwrap(), n));
+            }
+        }
+        if self.bad_channel > 0 {
+            try!(write!(f, ", {} bad channel IDs", self.bad_channel));
+        }
+        Ok(())
+    }
+}
+
+/// Things that can go wrong while talking to the BioTacs
+#[derive(Debug)]
+pub enum Error {
+    /// A BioTac SDK call returned an error code
+    BioTac { call: &'static str, code: i32 },
+    /// A Cheetah driver call returned an error status
+    Cheetah { call: &'static str, code: i32, status: String },
+    /// The Cheetah returned a batch of the wrong size
+    BatchLength { expected: usize, actual: usize },
+    /// The frame structure is empty
+    EmptyFrame,
+    /// There is no such finger slot on the Cheetah
+    BadSlot(u8),
+    /// No BioTacs are plugged in
+    NoFingers,
+    /// A selected finger is not plugged in
+    NotConnected(u8),
+    /// A different BioTac appeared in a slot after reconnecting
+    Replaced { slot: u8, was: String, now: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BioTac { call, code } => {
+                let what = match code {
+                    -1 => "wrong finger number",
+                    -2 => "no BioTac detected",
+                    -3 => "wrong maximum BioTac number",
+                    -4 => "batch too small",
+                    -5 => "no Cheetah detected",
+                    -6 => "unable to open Cheetah",
+                    -7 => "unable to open file",
+                    _  => "unknown error",
+                };
+                write!(f, "{} failed: {} ({})", call, what, code)
+            },
+            Error::Cheetah { call, code, ref status } => write!(f, "{} failed: {} ({})", call, status, code),
+            Error::BatchLength { expected, actual } => write!(f, "expected a {}-byte batch, got {} bytes", expected, actual),
+            Error::EmptyFrame => write!(f, "empty frame structure"),
+            Error::BadSlot(slot) => write!(f, "no finger slot #{} (expected 1-{})", slot, SLOTS),
+            Error::NoFingers => write!(f, "no BioTac connected to the Cheetah"),
+            Error::NotConnected(slot) => write!(f, "BioTac finger #{} selected, but it is not connected", slot),
+            Error::Replaced { slot, ref was, ref now } => write!(f, "finger #{} was {}, but now it is {}", slot, was, now),
+        }
+    }
+}
+
+/// Decode one finger's data out of a raw SPI batch
+///
+/// `frame` is the frame structure (the sampling command for each sample, repeated as needed to
+/// fill the batch) and `slot` is the finger slot (1-3).
+pub fn decode(raw: &[u8], frame: &[u8], slot: u8, stamp: time::Timespec, stats: &mut Stats) -> Result<Packet, Error> {
+    if raw.len() != BATCH_SAMPLES * SAMPLE_BYTES {
+        return Err(Error::BatchLength { expected: BATCH_SAMPLES * SAMPLE_BYTES, actual: raw.len() });
+    }
+    if frame.is_empty() {
+        return Err(Error::EmptyFrame);
+    }
+    if slot < 1 || slot as usize > SLOTS {
+        return Err(Error::BadSlot(slot));
+    }
+
+    let mut packet = Packet::new(stamp);
+    let mut pac_index = 0;
+    let offset = 2 + 2*(slot as usize - 1);
+    for (i, sample) in raw.chunks(SAMPLE_BYTES).enumerate() {
+        let channel = Channel::from_command(frame[i % frame.len()]);
+        let high = sample[offset];
+        let low  = sample[offset + 1];
+        stats.words += 1;
+
+        match channel {
+            Some(channel) => {
+                if PARITY[(low >> 1) as usize] == low && PARITY[(high >> 1) as usize] == high {
+                    let value = (high as u32 >> 1) * 32 + (low as u32 >> 3);
+                    if !packet.set(channel, pac_index, value) {
+                        stats.bad_channel += 1;
+                    }
+                } else {
+                    stats.parity[channel.index()] += 1;
+                }
+                if channel == Channel::Pac {
+                    pac_index += 1;
+                }
+            },
+            None => stats.bad_channel += 1,
+        }
+    }
+
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The default frame structure: each electrode then PDC, TAC and TDC, interleaved with PAC
+    fn default_frame() -> Vec<u8> {
+        let mut frame = vec![];
+        for c in (17..36).chain(vec![1, 2, 3]) {
+            frame.push(0x80 | (c << 1)); // parity bit is ignored when decoding
+            frame.push(0x80);
+        }
+        frame
+    }
+
+    /// Encode a 12-bit value the way the BioTac sends it (odd parity in each byte)
+    fn encode(value: u32) -> [u8; 2] {
+        [PARITY[(value >> 5) as usize], PARITY[((value & 31) << 2) as usize]]
+    }
+
+    /// A batch in which slot 1 sends `values[i]` for sample i, and the other slots send garbage
+    fn batch(frame: &[u8], values: &[u32]) -> Vec<u8> {
+        let mut raw = vec![];
+        for (i, &value) in values.iter().enumerate() {
+            raw.push(frame[i]);
+            raw.push(0);
+            raw.extend(&encode(value));
+            for _ in 1..SLOTS {
+                raw.extend(&[0xFF, 0xFF]);
+            }
+        }
+        raw
+    }
+
+    fn stamp() -> time::Timespec {
+        time::Timespec::new(1, 2)
+    }
+
+    #[test]
+    fn known_batch() {
+        let frame = default_frame();
+        let values: Vec<u32> = (0..BATCH_SAMPLES as u32).map(|i| (i * 93 + 7) % 4096).collect();
+        let raw = batch(&frame, &values);
+
+        let mut stats = Stats::new();
+        let packet = decode(&raw, &frame, 1, stamp(), &mut stats).unwrap();
+
+        // frame is E01,PAC,E02,PAC,...,E19,PAC,PDC,PAC,TAC,PAC,TDC,PAC
+        let decoded = packet.values();
+        for e in 0..19 {
+            assert_eq!(decoded[25 + e], values[2*e]);
+        }
+        assert_eq!(decoded[0], values[38]);
+        assert_eq!(decoded[24], values[40]);
+        assert_eq!(decoded[23], values[42]);
+        for p in 0..22 {
+            assert_eq!(decoded[1 + p], values[2*p + 1]);
+        }
+        assert!((0..N_VALUES).all(|i| packet.is_valid(i)));
+        assert_eq!(stats.words, BATCH_SAMPLES);
+        assert_eq!(stats.parity_errors(), 0);
+        assert_eq!(stats.bad_channel, 0);
+
+        // the other slots are all parity errors
+        let mut stats = Stats::new();
+        let packet = decode(&raw, &frame, 2, stamp(), &mut stats).unwrap();
+        assert!((0..N_VALUES).all(|i| !packet.is_valid(i)));
+        assert_eq!(stats.parity_errors(), BATCH_SAMPLES);
+        assert_eq!(stats.parity[Channel::Pac.index()], 22);
+    }
+
+    #[test]
+    fn parity_error() {
+        let frame = default_frame();
+        let values = vec![0x5A5; BATCH_SAMPLES];
+        let mut raw = batch(&frame, &values);
+        // flip one data bit of the E03 word (sample 4)
+        raw[4*SAMPLE_BYTES + 3] ^= 0x10;
+
+        let mut stats = Stats::new();
+        let packet = decode(&raw, &frame, 1, stamp(), &mut stats).unwrap();
+        assert!(!packet.is_valid(25 + 2));
+        assert_eq!(packet.values()[25 + 2], 0);
+        assert_eq!((0..N_VALUES).filter(|&i| packet.is_valid(i)).count(), N_VALUES - 1);
+        assert_eq!(stats.parity[Channel::Electrode(2).index()], 1);
+        assert_eq!(stats.parity_errors(), 1);
+        assert_eq!(stats.words, BATCH_SAMPLES);
+    }
+
+    #[test]
+    fn empty_frame() {
+        let raw = vec![0; BATCH_SAMPLES * SAMPLE_BYTES];
+        match decode(&raw, &[], 1, stamp(), &mut Stats::new()) {
+            Err(Error::EmptyFrame) => {},
+            _ => panic!("empty frame was accepted"),
+        }
+    }
+
+    #[test]
+    fn bad_slot() {
+        let frame = default_frame();
+        let raw = batch(&frame, &vec![0; BATCH_SAMPLES]);
+        for &slot in &[0, SLOTS as u8 + 1, 255] {
+            match decode(&raw, &frame, slot, stamp(), &mut Stats::new()) {
+                Err(Error::BadSlot(s)) => assert_eq!(s, slot),
+                _ => panic!("slot {} was accepted", slot),
+            }
+        }
+    }
+
+    #[test]
+    fn batch_length() {
+        let frame = default_frame();
+        let raw = vec![0; BATCH_SAMPLES * SAMPLE_BYTES - 1];
+        match decode(&raw, &frame, 1, stamp(), &mut Stats::new()) {
+            Err(Error::BatchLength { expected, actual }) => {
+                assert_eq!(expected, BATCH_SAMPLES * SAMPLE_BYTES);
+                assert_eq!(actual, BATCH_SAMPLES * SAMPLE_BYTES - 1);
+            },
+            _ => panic!("short batch was accepted"),
+        }
+    }
+}
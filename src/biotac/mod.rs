@@ -1,162 +1,492 @@
 //! Service to read data from the BioTac sensor
+//!
+//! Up to three BioTacs can be plugged into the Cheetah adapter at once. The SPI bus clocks in a
+//! word from every finger slot for each sample, so all fingers are sampled simultaneously, and each
+//! recorded finger gets its own output file (`biotac<N>.dat`, where N is the slot number 1-3). The
+//! serial numbers of the recorded fingers are written to `biotac_fingers.csv`.
+//!
+//! Words that fail the parity check are counted per finger and channel, and flagged in the
+//! validity mask at the end of each packet (see the decode module). If the Cheetah stops
+//! responding, the service raises an alarm and keeps trying to reconnect (the same BioTacs must
+//! be in the same slots) instead of crashing.
+//!
+//! By default every connected finger is recorded. The selection can be narrowed with the
+//! `NRI_BIOTAC_FINGERS` environment variable (e.g. `1,3`), or at runtime with `set_fingers`
+//! (takes effect the next time the service starts). Selecting a finger that is not connected is
+//! an error.
+//!
+//! The PAC samples are also written to their own stream (`biotac<N>_pac.dat`), with per-sample
+//! timing reconstructed from the frame structure and sample rate (see the pac module).
+//!
+//! For debugging the decoder, the raw SPI batches can be saved as they come off the Cheetah with
+//! the `capture start` and `capture stop` commands, and decoded again offline with
+//! `examples/readbiotacspi.rs` (which uses the same decode module as the service).
+//!
+//! Next to each raw stream, a processed stream in physical units is written (see the process
+//! module). The per-finger conversion factors are read from `calibration.csv` in this directory,
+//! or the file named by the `NRI_BIOTAC_CALIBRATION` environment variable.
+
+use std::env;
+use std::sync::RwLock;
+
+/// Number of finger slots on the Cheetah adapter (the same as decode::SLOTS)
+pub const MAX_FINGERS: u8 = 3;
+
+/// Default location of the per-finger calibration file
+pub const DEFAULT_CALIBRATION: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/biotac/calibration.csv");
+
+/// Path of the calibration file in use
+pub fn calibration_path() -> String {
+    env::var("NRI_BIOTAC_CALIBRATION").unwrap_or(DEFAULT_CALIBRATION.to_owned())
+}
+
+lazy_static! {
+    /// Finger slots to record (None means all connected fingers)
+    static ref FINGERS: RwLock<Option<Vec<u8>>> = RwLock::new(
+        env::var("NRI_BIOTAC_FINGERS").ok().and_then(|s| parse_fingers(&s).expect("bad NRI_BIOTAC_FINGERS")));
+}
+
+/// Parse a finger selection: "all", or a comma-separated list of slot numbers
+fn parse_fingers(s: &str) -> Result<Option<Vec<u8>>, String> {
+    if s == "all" {
+        return Ok(None);
+    }
+
+    let mut fingers = vec![];
+    for word in s.split(',') {
+        match word.trim().parse() {
+            Ok(i) if i >= 1 && i <= MAX_FINGERS => if !fingers.contains(&i) { fingers.push(i) },
+            _ => return Err(format!("invalid finger {:?} (expected 1-{})", word, MAX_FINGERS)),
+        }
+    }
+    if fingers.is_empty() {
+        return Err("no fingers selected".to_owned());
+    }
+    fingers.sort();
+    Ok(Some(fingers))
+}
+
+/// Change the fingers recorded by the BioTac service ("all" or e.g. "1,3")
+///
+/// Takes effect the next time the service is started.
+pub fn set_fingers(s: &str) -> Result<(), String> {
+    *FINGERS.write().unwrap() = try!(parse_fingers(s));
+    Ok(())
+}
+
+/// Get the current finger selection (None means all connected fingers)
+pub fn fingers() -> Option<Vec<u8>> {
+    FINGERS.read().unwrap().clone()
+}
 
 group_attr! {
     #[cfg(target_os = "linux")]
 
     extern crate time;
 
-    use ::comms::{Controllable, CmdFrom, Block};
+    use ::comms::{Controllable, CmdFrom, Block, Overrun};
     use ::scribe::{Writer, Writable};
     use std::sync::mpsc::Sender;
     use std::default::Default;
-    use std::{mem, str};
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::io::Write;
+    use std::mem;
+    use super::MAX_FINGERS;
 
     mod wrapper;
+    mod decode;
+    mod process;
+    mod pac;
 
-    pub struct Biotac {
-        cheetah: wrapper::biotac::Cheetah,
-        info: wrapper::biotac::bt_info,
-        finger: u8,
+    use self::decode::{Packet, Stats, Error, BATCH_SAMPLES, SAMPLE_BYTES};
+    use self::process::{Calibration, Processor, Processed};
+    use self::pac::{Clock, PacBatch, Placed};
+
+    unsafe impl Writable for Packet {}
+
+    /// How often to try to reconnect to a lost Cheetah (seconds)
+    const RECONNECT_INTERVAL: i64 = 1;
+    /// How often to report error rates to the web interface (in batches)
+    const STATUS_INTERVAL: usize = 100;
+    /// How many batches to average for a baseline
+    const BASELINE_BATCHES: usize = 50;
+
+    /// Magic bytes at the beginning of a raw SPI capture file
+    ///
+    /// Then comes the header: `sample_rate: u32, frame_len: u32` followed by the frame structure
+    /// (one sampling command byte per sample). The rest of the file is a sequence of records, one
+    /// per batch: `sec: i64, nsec: i32, len: u32` followed by `len` bytes of SPI data. All integers
+    /// are little-endian.
+    const CAPTURE_MAGIC: &'static [u8; 8] = b"NRIBSPI1";
+
+    /// One BioTac plugged into the Cheetah
+    struct Finger {
+        /// Slot number on the adapter (1-3)
+        slot: u8,
+        serial: String,
         file: Writer<Packet>,
+        stats: Stats,
+        processor: Processor,
+        processed: Writer<Processed>,
+        pac: Writer<PacBatch>,
+    }
+
+    /// An open Cheetah with the sampling batch configured
+    struct Connection {
+        cheetah: wrapper::biotac::Cheetah,
+        /// Sampling command for each sample in the batch
+        frame: Vec<u8>,
+        /// Connected fingers (slot, serial number)
+        fingers: Vec<(u8, String)>,
+        sample_rate: u32,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            unsafe { wrapper::biotac::bt_cheetah_close(self.cheetah) };
+        }
+    }
+
+    pub struct Biotac {
+        tx: Sender<CmdFrom>,
+        conn: Option<Connection>,
+        retry_at: time::Timespec,
+        fingers: Vec<Finger>,
+        /// PAC batches waiting for their timing to be worked out
+        clock: Clock<Vec<PacBatch>>,
+        /// Raw SPI capture file (if capturing)
+        capture: Option<Writer<[u8]>>,
         i: usize,
         start: time::Tm,
     }
 
-    #[repr(packed)]
-    struct Packet {
-        stamp: time::Timespec,
-        pdc: u32,
-        pac: [u32; 22],
-        tdc: u32,
-        tac: u32,
-        electrode: [u32; 19],
+    /// Turn a BioTac SDK return code into a Result
+    fn check_bt(call: &'static str, code: wrapper::biotac::BioTac) -> Result<(), Error> {
+        if code == 0 {
+            Ok(())
+        } else {
+            Err(Error::BioTac { call: call, code: code })
+        }
     }
 
-    unsafe impl Writable for Packet {}
+    /// Turn a Cheetah driver return value (a count, or a negative status) into a Result
+    fn check_ch(call: &'static str, ret: i32) -> Result<usize, Error> {
+        if ret >= 0 {
+            Ok(ret as usize)
+        } else {
+            let status = unsafe { CStr::from_ptr(wrapper::cheetah::ch_status_string(ret)) };
+            Err(Error::Cheetah { call: call, code: ret, status: status.to_string_lossy().into_owned() })
+        }
+    }
+
+    impl Connection {
+        /// Open the Cheetah, find the fingers, and start sampling
+        ///
+        /// If `wanted` is given, those finger slots must be connected (others are ignored).
+        fn open(wanted: Option<&[u8]>) -> Result<Connection, Error> {
+            let mut info = wrapper::biotac::bt_info {
+                spi_clock_speed: 4400,
+                number_of_biotacs: 0,
+                sample_rate_Hz: 4400,
+                frame: Default::default(),
+                batch: wrapper::biotac::bt_info_batch {
+                    batch_frame_count: 1,
+                    batch_ms: 10,
+                },
+            };
+
+            let mut cheetah: wrapper::biotac::Cheetah = 0;
+            try!(check_bt("bt_cheetah_initialize", unsafe { wrapper::biotac::bt_cheetah_initialize(&info, &mut cheetah) }));
+            let mut conn = Connection { cheetah: cheetah, frame: vec![], fingers: vec![], sample_rate: info.sample_rate_Hz as u32 };
+
+            // get properties and choose fingers
+            let connected = try!(conn.probe());
+            if connected.is_empty() {
+                return Err(Error::NoFingers);
+            }
+            conn.fingers = match wanted {
+                Some(wanted) => {
+                    for &slot in wanted {
+                        if !connected.iter().any(|&(i, _)| i == slot) {
+                            return Err(Error::NotConnected(slot));
+                        }
+                    }
+                    connected.into_iter().filter(|&(i, _)| wanted.contains(&i)).collect()
+                },
+                None => connected,
+            };
+            info.number_of_biotacs = conn.fingers.len() as i32;
+
+            // configure batch
+            try!(check_bt("bt_cheetah_configure_batch",
+                          unsafe { wrapper::biotac::bt_cheetah_configure_batch(cheetah, &mut info, BATCH_SAMPLES as i32) }));
+            conn.frame = info.frame.frame_structure[..info.frame.frame_size as usize].iter().map(|&c| c as u8).collect();
+
+            let len = try!(check_ch("ch_spi_batch_length", unsafe { wrapper::cheetah::ch_spi_batch_length(cheetah) }));
+            if len != BATCH_SAMPLES * SAMPLE_BYTES {
+                return Err(Error::BatchLength { expected: BATCH_SAMPLES * SAMPLE_BYTES, actual: len });
+            }
+
+            Ok(conn)
+        }
+
+        /// Find out which finger slots have a BioTac plugged in, and get their serial numbers
+        fn probe(&self) -> Result<Vec<(u8, String)>, Error> {
+            let mut connected = vec![];
+            for i in 1..(MAX_FINGERS+1) {
+                let mut props: wrapper::biotac::bt_property = unsafe { mem::zeroed::<wrapper::biotac::bt_property>() };
+                try!(check_bt("bt_cheetah_get_properties",
+                              unsafe { wrapper::biotac::bt_cheetah_get_properties(self.cheetah, i as i32, &mut props) }));
+                if props.bt_connected == 1 {
+                    let len = props.serial_number.iter().position(|&c| c == 0).unwrap_or(props.serial_number.len());
+                    let serial = String::from_utf8_lossy(&props.serial_number[..len]).into_owned();
+                    println!("finger #{} serial number = {}", i, serial);
+                    connected.push((i, serial));
+                }
+            }
+            Ok(connected)
+        }
+
+        /// Collect the next batch and queue up another one
+        fn collect(&mut self) -> Result<Vec<u8>, Error> {
+            let len = BATCH_SAMPLES * SAMPLE_BYTES;
+            let mut raw = vec![0u8; len];
+            let got = try!(check_ch("ch_spi_async_collect",
+                                    unsafe { wrapper::cheetah::ch_spi_async_collect(self.cheetah, len as i32, raw.as_mut_ptr()) }));
+            if got != len {
+                return Err(Error::BatchLength { expected: len, actual: got });
+            }
+            try!(check_ch("ch_spi_async_submit", unsafe { wrapper::cheetah::ch_spi_async_submit(self.cheetah) }));
+            Ok(raw)
+        }
+    }
+
+    /// Write out PAC batches once their timing is known
+    fn write_pac(fingers: &mut [Finger], placed: Vec<Placed<Vec<PacBatch>>>) {
+        for p in placed {
+            if p.missed > 0 {
+                errorln!("BioTac: {} batches missing before #{}", p.missed, p.batch);
+            }
+            let (start, batch, missed) = (p.start, p.batch, p.missed);
+            for (finger, mut pac) in fingers.iter_mut().zip(p.payload.into_iter()) {
+                pac.place(start, batch, missed);
+                finger.pac.write(pac);
+            }
+        }
+    }
+
+    impl Biotac {
+        /// Tell the web interface about an alarm (or clear it)
+        fn alarm(&self, msg: &str) {
+            self.tx.send(CmdFrom::Data(format!("send alarm biotac {}", msg))).unwrap();
+        }
+
+        /// Try to get the Cheetah back, with the same fingers in the same slots
+        fn reconnect(&mut self) {
+            let now = time::get_time();
+            if now < self.retry_at {
+                return;
+            }
+            self.retry_at = now + time::Duration::seconds(RECONNECT_INTERVAL);
+
+            let slots = self.fingers.iter().map(|f| f.slot).collect::<Vec<_>>();
+            let result = Connection::open(Some(&slots)).and_then(|conn| {
+                for finger in &self.fingers {
+                    let serial = &conn.fingers.iter().find(|&&(i, _)| i == finger.slot).unwrap().1;
+                    if *serial != finger.serial {
+                        return Err(Error::Replaced { slot: finger.slot, was: finger.serial.clone(), now: serial.clone() });
+                    }
+                }
+                Ok(conn)
+            });
+            match result {
+                Ok(conn) => {
+                    println!("BioTac: reconnected");
+                    self.conn = Some(conn);
+                    self.alarm("clear");
+                },
+                Err(e) => errorln!("BioTac: reconnect failed: {}", e),
+            }
+        }
+
+        /// Start saving raw SPI batches into a new file (in the current directory, i.e. the session
+        /// directory if a flow is running)
+        fn start_capture(&mut self) -> Option<String> {
+            let (sample_rate, frame) = match self.conn {
+                Some(ref conn) => (conn.sample_rate, conn.frame.clone()),
+                None           => return None,
+            };
+
+            let name = format!("biotac_spi.{}.dat", time::get_time().sec);
+            let mut writer = Writer::with_file(&*name);
+            let mut header = CAPTURE_MAGIC.to_vec();
+            for i in 0..4 { header.push((sample_rate        >> (8*i)) as u8); }
+            for i in 0..4 { header.push((frame.len() as u32 >> (8*i)) as u8); }
+            header.extend(&frame);
+            writer.write(&header);
+            self.capture = Some(writer);
+            Some(name)
+        }
+
+        /// Save a raw batch (if capturing)
+        fn capture(&mut self, stamp: time::Timespec, raw: &[u8]) {
+            if let Some(ref mut writer) = self.capture {
+                let mut record = Vec::with_capacity(16 + raw.len());
+                for i in 0..8 { record.push((stamp.sec  as u64 >> (8*i)) as u8); }
+                for i in 0..4 { record.push((stamp.nsec as u32 >> (8*i)) as u8); }
+                for i in 0..4 { record.push((raw.len()  as u32 >> (8*i)) as u8); }
+                record.extend(raw);
+                writer.write(&record);
+            }
+        }
+
+        /// Send the error rates to the web interface
+        fn report(&self) {
+            let status = self.fingers.iter()
+                                     .map(|f| format!("#{} {:.3}% parity errors", f.slot, 100.0 * f.stats.error_rate()))
+                                     .collect::<Vec<_>>()
+                                     .join(", ");
+            self.tx.send(CmdFrom::Data(format!("send status biotac {}", status))).unwrap();
+        }
+    }
 
     guilty! {
         impl Controllable for Biotac {
             const NAME: &'static str = "biotac",
-            const BLOCK: Block = Block::Period(10_000_000),
-
-            fn setup(_: Sender<CmdFrom>, _: Option<String>) -> Biotac {
-                // initialize Cheetah
-                let mut info = wrapper::biotac::bt_info {
-                    spi_clock_speed: 4400,
-                    number_of_biotacs: 1,
-                    sample_rate_Hz: 4400,
-                    frame: Default::default(),
-                    batch: wrapper::biotac::bt_info_batch {
-                        batch_frame_count: 1,
-                        batch_ms: 10,
-                    },
-                };
+            // every step reads one batch, so missed steps have to be made up
+            const BLOCK: Block = Block::Period(10_000_000, Overrun::CatchUp(5)),
 
-                let cheetah = unsafe {
-                    let mut cheetah: wrapper::biotac::Cheetah = mem::zeroed::<wrapper::biotac::Cheetah>();
-                    assert!(0 == wrapper::biotac::bt_cheetah_initialize(&info, &mut cheetah));
-                    cheetah
-                };
+            fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> Biotac {
+                let wanted = super::fingers();
+                let conn = Connection::open(wanted.as_ref().map(|w| &w[..]))
+                                      .unwrap_or_else(|e| panic!("Could not start BioTac: {}", e));
+
+                let cal_path = super::calibration_path();
+                let mut fingers_csv = File::create("biotac_fingers.csv").unwrap();
+                writeln!(fingers_csv, "Finger, Serial number").unwrap();
+                let fingers = conn.fingers.iter().map(|&(slot, ref serial)| {
+                    writeln!(fingers_csv, "{}, {}", slot, serial).unwrap();
 
-                // get properties
-                let mut finger = None;
-                for i in 1..(3+1) {
-                    let props = unsafe {
-                        let mut props: wrapper::biotac::bt_property = mem::zeroed::<wrapper::biotac::bt_property>();
-                        assert!(0 == wrapper::biotac::bt_cheetah_get_properties(cheetah, i, &mut props));
-                        props
+                    let cal = match Calibration::load(&cal_path, serial) {
+                        Ok(Some(cal)) => cal,
+                        Ok(None) => {
+                            errorln!("WARNING: no calibration for BioTac {} in {}, using nominal values", serial, cal_path);
+                            Calibration::default()
+                        },
+                        Err(e) => {
+                            errorln!("WARNING: {}, using nominal BioTac calibration", e);
+                            Calibration::default()
+                        },
                     };
-                    if props.bt_connected == 1 {
-                        assert!(finger.is_none());
-                        finger = Some(i);
-                        println!("finger #{} serial number = {}",
-                                 i,
-                                 str::from_utf8(&props.serial_number[..props.serial_number
-                                                                       .iter()
-                                                                       .position(|&c| c == 0)
-                                                                       .unwrap()])
-                                 .unwrap());
+                    let mut processor = Processor::new(cal);
+                    processor.capture_baseline(BASELINE_BATCHES);
+
+                    Finger {
+                        slot: slot,
+                        serial: serial.clone(),
+                        file: Writer::with_file(format!("biotac{}.dat", slot)),
+                        stats: Stats::new(),
+                        processor: processor,
+                        processed: Writer::with_file(format!("biotac{}_processed.dat", slot)),
+                        pac: Writer::with_file(format!("biotac{}_pac.dat", slot)),
                     }
-                }
-                let finger = finger.unwrap() as u8;
+                }).collect();
 
-                // configure batch
-                unsafe {
-                    assert!(0 == wrapper::biotac::bt_cheetah_configure_batch(cheetah, &mut info, 44));
+                Biotac {
+                    tx: tx,
+                    clock: Clock::new(conn.sample_rate, BATCH_SAMPLES),
+                    conn: Some(conn),
+                    retry_at: time::get_time(),
+                    fingers: fingers,
+                    capture: None,
+                    i: 0,
+                    start: time::now()
                 }
-
-                Biotac { cheetah: cheetah, info: info, finger: finger, file: Writer::with_file("biotac.dat"), i: 0, start: time::now() }
             }
 
-            fn step(&mut self, _: Option<String>) {
-                static PARITY: [u8; 128] = [0x01, 0x02, 0x04, 0x07, 0x08, 0x0B, 0x0D, 0x0E,
-                                            0x10, 0x13, 0x15, 0x16, 0x19, 0x1A, 0x1C, 0x1F,
-                                            0x20, 0x23, 0x25, 0x26, 0x29, 0x2A, 0x2C, 0x2F,
-                                            0x31, 0x32, 0x34, 0x37, 0x38, 0x3B, 0x3D, 0x3E,
-                                            0x40, 0x43, 0x45, 0x46, 0x49, 0x4A, 0x4C, 0x4F,
-                                            0x51, 0x52, 0x54, 0x57, 0x58, 0x5B, 0x5D, 0x5E,
-                                            0x61, 0x62, 0x64, 0x67, 0x68, 0x6B, 0x6D, 0x6E,
-                                            0x70, 0x73, 0x75, 0x76, 0x79, 0x7A, 0x7C, 0x7F,
-                                            0x80, 0x83, 0x85, 0x86, 0x89, 0x8A, 0x8C, 0x8F,
-                                            0x91, 0x92, 0x94, 0x97, 0x98, 0x9B, 0x9D, 0x9E,
-                                            0xA1, 0xA2, 0xA4, 0xA7, 0xA8, 0xAB, 0xAD, 0xAE,
-                                            0xB0, 0xB3, 0xB5, 0xB6, 0xB9, 0xBA, 0xBC, 0xBF,
-                                            0xC1, 0xC2, 0xC4, 0xC7, 0xC8, 0xCB, 0xCD, 0xCE,
-                                            0xD0, 0xD3, 0xD5, 0xD6, 0xD9, 0xDA, 0xDC, 0xDF,
-                                            0xE0, 0xE3, 0xE5, 0xE6, 0xE9, 0xEA, 0xEC, 0xEF,
-                                            0xF1, 0xF2, 0xF4, 0xF7, 0xF8, 0xFB, 0xFD, 0xFE];
-
+            fn step(&mut self, cmd: Option<String>) {
+                match cmd.as_ref().map(|s| &s[..]) {
+                    Some("capture start") => match self.start_capture() {
+                        Some(name) => println!("Started raw BioTac SPI capture into {}", name),
+                        None       => errorln!("Cannot capture while the Cheetah is disconnected"),
+                    },
+                    Some("capture stop") => {
+                        if self.capture.take().is_some() {
+                            println!("Stopped raw BioTac SPI capture.");
+                        } else {
+                            errorln!("No raw BioTac SPI capture running");
+                        }
+                    },
+                    Some("baseline") => {
+                        println!("BioTac: capturing baseline over the next {} batches", BASELINE_BATCHES);
+                        for finger in &mut self.fingers {
+                            finger.processor.capture_baseline(BASELINE_BATCHES);
+                        }
+                    },
+                    Some(cmd) => errorln!("BioTac: unknown command {:?}", cmd),
+                    None => {},
+                }
 
+                let result = self.conn.as_mut().map(|conn| conn.collect());
+                let raw = match result {
+                    Some(Ok(raw)) => raw,
+                    Some(Err(e)) => {
+                        errorln!("BioTac: {}", e);
+                        self.conn = None; // closes the Cheetah
+                        write_pac(&mut self.fingers, self.clock.flush());
+                        self.alarm(&format!("Cheetah lost ({}), reconnecting", e));
+                        return;
+                    },
+                    None => {
+                        self.reconnect();
+                        return;
+                    },
+                };
                 self.i += 1;
 
-                let packet = unsafe {
-                    let mut packet: Packet = mem::zeroed::<Packet>();
-                    packet.stamp = time::get_time();
-
-                    let spi_data_len: i32 = wrapper::cheetah::ch_spi_batch_length(self.cheetah);
-                    assert!(spi_data_len == 352);
-                    let mut bt_raw_data: Vec<u8> = vec![0u8; spi_data_len as usize];
-                    assert!(spi_data_len == wrapper::cheetah::ch_spi_async_collect(self.cheetah, spi_data_len, bt_raw_data.as_mut_ptr()));
-                    assert!(spi_data_len == wrapper::cheetah::ch_spi_async_submit(self.cheetah));
-
-                    let byte_shift: i32 = 8;
-                    let n_samples: i32 = spi_data_len / byte_shift;
-                    let mut pac_index: u32 = 0;
-                    for i in 0..n_samples {
-                        let channel_id: i8 = (self.info.frame.frame_structure[(i % (self.info.frame.frame_size)) as usize] & 0x7E) >> 1;
-                        for j in 0..3 {
-                            let high = bt_raw_data[(i*byte_shift + j*2 + 2) as usize];
-                            let low  = bt_raw_data[(i*byte_shift + j*2 + 3) as usize];
-                            let spi_data: u32 = (high as u32 >> 1) * 32 + (low as u32 >> 3);
-                            if (PARITY[(low >> 1) as usize] == low) && (PARITY[(high >> 1) as usize] == high) {
-                                match channel_id {
-                                    3 => packet.tdc = spi_data,
-                                    2 => packet.tac = spi_data,
-                                    1 => packet.pdc = spi_data,
-                                    0 => packet.pac[pac_index as usize] = spi_data,
-                                    c @ 17...35 => packet.electrode[(c - 17) as usize] = spi_data,
-                                    _ => println!("bad channel ID at ({}, {})", i, j),
+                let stamp = time::get_time();
+                self.capture(stamp, &raw);
+                let frame = &self.conn.as_ref().unwrap().frame;
+                let mut pacs = Vec::with_capacity(self.fingers.len());
+                for finger in &mut self.fingers {
+                    match decode::decode(&raw, frame, finger.slot, stamp, &mut finger.stats) {
+                        Ok(packet) => {
+                            pacs.push(PacBatch::new(&packet, frame, self.clock.sample_ns));
+                            let had_baseline = finger.processor.baseline().is_some();
+                            if let Some(processed) = finger.processor.process(&packet) {
+                                if !had_baseline {
+                                    println!("BioTac finger #{}: baseline {}", finger.slot, finger.processor.baseline().unwrap());
                                 }
-                            } else if (j+1) as u8 == self.finger {
-                                println!("bad parity at ({}, {})", i, j);
+                                finger.processed.write(processed);
                             }
-                        }
-                        if channel_id == 0 {
-                            pac_index += 1;
-                        }
+                            finger.file.write(packet);
+                        },
+                        Err(e) => {
+                            errorln!("BioTac finger #{}: {}", finger.slot, e);
+                            pacs.push(PacBatch::new(&Packet::new(stamp), frame, self.clock.sample_ns));
+                        },
                     }
+                }
+                let placed = self.clock.push(stamp, pacs);
+                write_pac(&mut self.fingers, placed);
 
-                    packet
-                };
-
-                self.file.write(packet);
+                if self.i % STATUS_INTERVAL == 0 {
+                    self.report();
+                }
             }
 
             fn teardown(&mut self) {
-                unsafe { wrapper::biotac::bt_cheetah_close(self.cheetah) };
+                self.capture = None;
+                self.conn = None;
+                write_pac(&mut self.fingers, self.clock.flush());
                 let end = time::now();
                 let millis = (end - self.start).num_milliseconds() as f64;
                 println!("{} Biotac packets grabbed in {} s ({} FPS)!", self.i, millis/1000.0, 1000.0*(self.i as f64)/millis);
+                for finger in &self.fingers {
+                    println!("finger #{} ({}): {}", finger.slot, finger.serial, finger.stats);
+                }
+                println!("{} gaps in the BioTac stream ({} batches missing)", self.clock.gaps, self.clock.missed);
             }
         }
     }
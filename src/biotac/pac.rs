@@ -0,0 +1,248 @@
+//! PAC (dynamic pressure) as a high-rate time series
+//!
+//! The BioTac samples one channel every 1/`sample_rate_Hz` seconds, following the frame structure,
+//! and every other sample in the default frame is PAC. So within a batch the PAC samples are
+//! evenly spaced at 2.2 kHz, but the raw packets stamp the whole batch with a single time.
+//!
+//! This module reconstructs when each batch started (keeping a continuous clock across batches,
+//! and noticing when batches go missing) and the offset of each PAC sample within the batch. The
+//! result is written to its own stream, `biotac<N>_pac.dat`, which `examples/readbiotacpac.rs`
+//! expands to one row per sample.
+
+use std::collections::VecDeque;
+use std::mem;
+use super::time::{Timespec, Duration};
+use super::decode::{Channel, Packet, BATCH_SAMPLES};
+use ::scribe::Writable;
+
+/// Number of PAC samples in a batch
+pub const PAC_SAMPLES: usize = 22;
+
+/// How much of the difference between the reconstructed and observed batch times to correct per
+/// batch (so the clock follows slow drift, but not jitter)
+const SLEW: i64 = 16;
+
+/// How many batches to look ahead when placing one (more than the 16 the Cheetah queues up)
+const WINDOW: usize = 32;
+
+/// The PAC samples from one batch of one finger
+///
+/// Sample i was taken at `start + offset[i]`.
+#[repr(packed)]
+pub struct PacBatch {
+    /// Time of the first sample in the batch
+    pub start: Timespec,
+    /// Batch number since the service started (missing batches are counted)
+    pub batch: u64,
+    /// Number of batches missing right before this one
+    pub missed: u32,
+    /// Offset of each sample from the start of the batch (ns)
+    pub offset: [u32; PAC_SAMPLES],
+    pub pac: [u32; PAC_SAMPLES],
+    /// Bit i is set if sample i was received intact
+    pub valid: u32,
+}
+
+unsafe impl Writable for PacBatch {}
+
+impl PacBatch {
+    /// Pull the PAC samples out of a decoded packet
+    ///
+    /// `frame` is the frame structure the packet was decoded with (repeated over the batch, as in
+    /// decode). The batch timing is filled in later (see Clock).
+    pub fn new(packet: &Packet, frame: &[u8], sample_ns: i64) -> PacBatch {
+        let mut batch = unsafe { mem::zeroed::<PacBatch>() };
+        if frame.is_empty() {
+            return batch;
+        }
+
+        let mut j = 0;
+        for i in 0..BATCH_SAMPLES {
+            if j == PAC_SAMPLES {
+                break;
+            }
+            if Channel::from_command(frame[i % frame.len()]) == Some(Channel::Pac) {
+                batch.offset[j] = (i as i64 * sample_ns) as u32;
+                batch.pac[j] = packet.pac[j];
+                if packet.is_valid(1 + j) {
+                    batch.valid |= 1 << j;
+                }
+                j += 1;
+            }
+        }
+        batch
+    }
+
+    /// Fill in the batch timing
+    pub fn place(&mut self, start: Timespec, batch: u64, missed: u64) {
+        self.start = start;
+        self.batch = batch;
+        self.missed = missed as u32;
+    }
+}
+
+/// A batch with its place on the clock
+pub struct Placed<T> {
+    pub payload: T,
+    /// Time of the first sample
+    pub start: Timespec,
+    /// Batch number (counting missing batches)
+    pub batch: u64,
+    /// Number of batches missing right before this one
+    pub missed: u64,
+}
+
+/// Reconstructs batch start times from the times they were collected
+///
+/// A batch can only be collected after the Cheetah has finished it, but it may be collected much
+/// later (the host thread was busy, and the Cheetah kept going on the batches queued ahead). So
+/// the collection times are an upper bound, and the true clock is their lower envelope. Batches are
+/// held back until enough later ones have arrived to see that envelope: if even the earliest of
+/// them is late by more than half a batch, the Cheetah really stopped (its queue ran dry) and
+/// batches are missing.
+///
+/// Each batch carries a payload (the PAC samples of every finger), which is handed back along with
+/// the batch's place on the clock.
+pub struct Clock<T> {
+    pub sample_ns: i64,
+    batch_ns: i64,
+    /// Batches waiting to be placed (payload, collection time minus batch length)
+    pending: VecDeque<(T, Timespec)>,
+    /// Expected start of the next batch
+    next: Option<Timespec>,
+    batch: u64,
+    /// Number of gaps seen
+    pub gaps: usize,
+    /// Total number of batches missed
+    pub missed: u64,
+}
+
+impl<T> Clock<T> {
+    pub fn new(sample_rate_hz: u32, batch_samples: usize) -> Clock<T> {
+        let sample_ns = 1_000_000_000 / sample_rate_hz as i64;
+        Clock {
+            sample_ns: sample_ns,
+            batch_ns: sample_ns * batch_samples as i64,
+            pending: VecDeque::with_capacity(WINDOW + 1),
+            next: None,
+            batch: 0,
+            gaps: 0,
+            missed: 0
+        }
+    }
+
+    /// Add a batch that was collected at `stamp`, and get back any batches that are now placed
+    pub fn push(&mut self, stamp: Timespec, payload: T) -> Vec<Placed<T>> {
+        // the batch finished (at the earliest) just before it was collected
+        self.pending.push_back((payload, stamp - Duration::nanoseconds(self.batch_ns)));
+
+        let mut placed = vec![];
+        while self.pending.len() > WINDOW {
+            placed.push(self.place());
+        }
+        placed
+    }
+
+    /// Place all the batches that are waiting (e.g. before stopping or after losing the Cheetah)
+    ///
+    /// The next batch pushed starts a new clock and counts as a gap.
+    pub fn flush(&mut self) -> Vec<Placed<T>> {
+        let mut placed = vec![];
+        while !self.pending.is_empty() {
+            placed.push(self.place());
+        }
+        if self.next.is_some() {
+            self.next = None;
+            self.gaps += 1;
+        }
+        placed
+    }
+
+    /// Place the oldest pending batch
+    fn place(&mut self) -> Placed<T> {
+        let (start, missed) = match self.next {
+            Some(next) => {
+                // how late is the lower envelope of the pending batches?
+                let drift = self.pending.iter().enumerate().map(|(k, &(_, observed))| {
+                    let predicted = next + Duration::nanoseconds(k as i64 * self.batch_ns);
+                    (observed - predicted).num_nanoseconds().unwrap_or(i64::max_value())
+                }).min().unwrap();
+
+                if drift > self.batch_ns / 2 {
+                    let missed = ((drift + self.batch_ns / 2) / self.batch_ns) as u64;
+                    self.gaps += 1;
+                    self.missed += missed;
+                    (next + Duration::nanoseconds(drift), missed)
+                } else if drift < 0 {
+                    // we were running ahead
+                    (next + Duration::nanoseconds(drift), 0)
+                } else {
+                    (next + Duration::nanoseconds(drift / SLEW), 0)
+                }
+            },
+            None => {
+                let earliest = self.pending.iter().enumerate().map(|(k, &(_, observed))| {
+                    observed - Duration::nanoseconds(k as i64 * self.batch_ns)
+                }).min().unwrap();
+                (earliest, 0)
+            },
+        };
+
+        let (payload, _) = self.pending.pop_front().unwrap();
+        self.batch += missed;
+        let batch = self.batch;
+        self.batch += 1;
+        self.next = Some(start + Duration::nanoseconds(self.batch_ns));
+        Placed { payload: payload, start: start, batch: batch, missed: missed }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::decode::{self, Stats, SAMPLE_BYTES, SLOTS};
+
+    const SAMPLE_NS: i64 = 1000;
+
+    /// A batch in which every word from slot 1 is a valid zero
+    fn zeros() -> Vec<u8> {
+        let mut raw = vec![];
+        for _ in 0..BATCH_SAMPLES {
+            raw.extend(&[0, 0, 0x01, 0x01]); // zero, with odd parity in each byte
+            for _ in 1..SLOTS {
+                raw.extend(&[0, 0]);
+            }
+        }
+        assert_eq!(raw.len(), BATCH_SAMPLES * SAMPLE_BYTES);
+        raw
+    }
+
+    fn pac_batch(frame: &[u8]) -> PacBatch {
+        let packet = decode::decode(&zeros(), frame, 1, Timespec::new(0, 0), &mut Stats::new()).unwrap();
+        PacBatch::new(&packet, frame, SAMPLE_NS)
+    }
+
+    #[test]
+    fn default_frame() {
+        // E01, PAC, E02, PAC, ...
+        let frame = (0..BATCH_SAMPLES).map(|i| if i % 2 == 0 { 0xA2 } else { 0x80 }).collect::<Vec<u8>>();
+        let batch = pac_batch(&frame);
+        let offset = batch.offset;
+        for j in 0..PAC_SAMPLES {
+            assert_eq!(offset[j], ((2*j + 1) as i64 * SAMPLE_NS) as u32);
+        }
+        assert_eq!({ batch.valid }, (1 << PAC_SAMPLES) - 1);
+    }
+
+    #[test]
+    fn short_frame() {
+        // the frame is repeated to fill the batch, so PAC samples after the first pass count too
+        let frame = [0x80, 0x83, 0x85, 0x86]; // PAC, PDC, TAC, TDC
+        let batch = pac_batch(&frame);
+        let offset = batch.offset;
+        for j in 0..BATCH_SAMPLES / 4 {
+            assert_eq!(offset[j], ((4*j) as i64 * SAMPLE_NS) as u32);
+        }
+        assert_eq!({ batch.valid }, (1 << (BATCH_SAMPLES / 4)) - 1);
+    }
+}
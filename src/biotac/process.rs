@@ -0,0 +1,263 @@
+//! Conversion of raw BioTac readings to physical units, relative to a baseline
+//!
+//! The raw stream holds 12-bit counts. The processed stream (`biotac<N>_processed.dat`) holds:
+//!
+//! - PDC: static pressure above the baseline (Pa)
+//! - PAC: dynamic pressure around the baseline mean (Pa)
+//! - TDC: absolute temperature (°C)
+//! - TAC: heat flux relative to the baseline (°C/s)
+//! - electrodes: impedance change from the baseline (kΩ)
+//!
+//! The baseline is the average of a number of batches taken while the finger is unloaded. One is
+//! captured automatically when the service starts, and it can be recaptured at any time with the
+//! `baseline` command (e.g. `: biotac baseline` in a flow). Flows also send that command whenever
+//! a park-triggered state begins with the BioTac parked (see web/flow.rs). Nothing is written to
+//! the processed stream until the first baseline is complete.
+//!
+//! The conversion factors come from the calibration file (one line per finger, looked up by serial
+//! number). For a finger without an entry, rough nominal sensitivities are used and a warning is
+//! printed.
+
+use std::f64;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::mem;
+use super::time;
+use super::decode::{Packet, N_VALUES};
+use ::scribe::Writable;
+
+/// Ranges of the value indices (as in Packet::values)
+const PDC: usize = 0;
+const PAC: (usize, usize) = (1, 23);
+const TDC: usize = 23;
+const TAC: usize = 24;
+const ELECTRODE: (usize, usize) = (25, 44);
+
+/// Conversion factors for one finger
+#[derive(Debug, Copy, Clone)]
+pub struct Calibration {
+    /// Pa per PDC count
+    pub pdc_gain: f64,
+    /// Pa per PAC count
+    pub pac_gain: f64,
+    /// °C per TDC count
+    pub tdc_gain: f64,
+    /// °C at zero TDC counts
+    pub tdc_offset: f64,
+    /// °C/s per TAC count
+    pub tac_gain: f64,
+    /// kΩ per electrode count
+    pub electrode_gain: f64,
+}
+
+impl Default for Calibration {
+    /// Rough nominal sensitivities (good enough to look at, but calibrate fingers for real data)
+    fn default() -> Calibration {
+        Calibration {
+            pdc_gain       : 36.5,
+            pac_gain       : 0.37,
+            tdc_gain       : 0.01,
+            tdc_offset     : 0.0,
+            tac_gain       : 0.001,
+            electrode_gain : 0.01,
+        }
+    }
+}
+
+impl Calibration {
+    /// Look up a finger in the calibration file
+    ///
+    /// The file is CSV with the columns serial, pdc_gain, pac_gain, tdc_gain, tdc_offset,
+    /// tac_gain, electrode_gain. Blank lines and lines starting with '#' are ignored.
+    pub fn load(path: &str, serial: &str) -> Result<Option<Calibration>, String> {
+        let file = try!(File::open(path).map_err(|e| format!("could not open {}: {}", path, e)));
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = try!(line.map_err(|e| format!("could not read {}: {}", path, e)));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+            if fields[0] != serial {
+                continue;
+            }
+            if fields.len() != 7 {
+                return Err(format!("{}:{}: expected 7 fields, found {}", path, n + 1, fields.len()));
+            }
+            let mut numbers = [0.0; 6];
+            for i in 0..6 {
+                numbers[i] = try!(fields[i + 1].parse()
+                                               .map_err(|_| format!("{}:{}: bad number {:?}", path, n + 1, fields[i + 1])));
+            }
+            return Ok(Some(Calibration {
+                pdc_gain       : numbers[0],
+                pac_gain       : numbers[1],
+                tdc_gain       : numbers[2],
+                tdc_offset     : numbers[3],
+                tac_gain       : numbers[4],
+                electrode_gain : numbers[5],
+            }));
+        }
+        Ok(None)
+    }
+}
+
+/// One frame of processed data from one finger
+///
+/// Values that were not received intact are NaN.
+#[repr(packed)]
+pub struct Processed {
+    pub stamp: time::Timespec,
+    pub pdc: f64,
+    pub pac: [f64; 22],
+    pub tdc: f64,
+    pub tac: f64,
+    pub electrode: [f64; 19],
+}
+
+unsafe impl Writable for Processed {}
+
+impl Processed {
+    fn new(stamp: time::Timespec, values: &[f64; N_VALUES]) -> Processed {
+        // fill the arrays first, since references into a packed struct may be unaligned
+        let (mut pac, mut electrode) = ([0.0; 22], [0.0; 19]);
+        for (p, &v) in pac.iter_mut().zip(&values[PAC.0..PAC.1]) {
+            *p = v;
+        }
+        for (e, &v) in electrode.iter_mut().zip(&values[ELECTRODE.0..ELECTRODE.1]) {
+            *e = v;
+        }
+        let mut processed = unsafe { mem::zeroed::<Processed>() };
+        processed.stamp = stamp;
+        processed.pdc = values[PDC];
+        processed.pac = pac;
+        processed.tdc = values[TDC];
+        processed.tac = values[TAC];
+        processed.electrode = electrode;
+        processed
+    }
+}
+
+/// Mean raw value of each channel while unloaded
+#[derive(Copy, Clone)]
+pub struct Baseline {
+    values: [f64; N_VALUES],
+}
+
+impl Baseline {
+    /// Mean PAC level (all the PAC samples share it)
+    fn pac(&self) -> f64 {
+        self.values[PAC.0]
+    }
+}
+
+impl fmt::Display for Baseline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PDC {:.1}, PAC {:.1}, TDC {:.1}, TAC {:.1}",
+               self.values[PDC], self.pac(), self.values[TDC], self.values[TAC])
+    }
+}
+
+/// Baseline capture in progress
+struct Collector {
+    left: usize,
+    sums: [f64; N_VALUES],
+    counts: [usize; N_VALUES],
+}
+
+impl Collector {
+    fn new(batches: usize) -> Collector {
+        Collector { left: batches, sums: [0.0; N_VALUES], counts: [0; N_VALUES] }
+    }
+
+    /// Add a packet. Returns the baseline once enough packets have been seen.
+    fn add(&mut self, packet: &Packet) -> Option<Baseline> {
+        let raw = packet.values();
+        for i in 0..N_VALUES {
+            if packet.is_valid(i) {
+                self.sums[i] += raw[i] as f64;
+                self.counts[i] += 1;
+            }
+        }
+
+        self.left -= 1;
+        if self.left > 0 {
+            return None;
+        }
+
+        // all PAC samples are the same channel, so pool them
+        let pac_sum = self.sums[PAC.0..PAC.1].iter().fold(0.0, |a, &b| a + b);
+        let pac_count = self.counts[PAC.0..PAC.1].iter().fold(0, |a, &b| a + b);
+        for i in PAC.0..PAC.1 {
+            self.sums[i] = pac_sum;
+            self.counts[i] = pac_count;
+        }
+
+        let mut baseline = Baseline { values: [f64::NAN; N_VALUES] };
+        for i in 0..N_VALUES {
+            if self.counts[i] > 0 {
+                baseline.values[i] = self.sums[i] / self.counts[i] as f64;
+            }
+        }
+        Some(baseline)
+    }
+}
+
+/// Converts one finger's packets
+pub struct Processor {
+    cal: Calibration,
+    baseline: Option<Baseline>,
+    collector: Option<Collector>,
+}
+
+impl Processor {
+    pub fn new(cal: Calibration) -> Processor {
+        Processor { cal: cal, baseline: None, collector: None }
+    }
+
+    /// Start capturing a new baseline from the next `batches` packets
+    ///
+    /// The old baseline (if any) stays in use until the new one is complete.
+    pub fn capture_baseline(&mut self, batches: usize) {
+        self.collector = Some(Collector::new(batches));
+    }
+
+    pub fn baseline(&self) -> Option<&Baseline> {
+        self.baseline.as_ref()
+    }
+
+    /// Convert a packet (returns None if there is no baseline yet)
+    pub fn process(&mut self, packet: &Packet) -> Option<Processed> {
+        let done = self.collector.as_mut().and_then(|c| c.add(packet));
+        if let Some(baseline) = done {
+            self.collector = None;
+            self.baseline = Some(baseline);
+        }
+
+        let baseline = match self.baseline {
+            Some(ref b) => b,
+            None        => return None,
+        };
+
+        let raw = packet.values();
+        let mut values = [f64::NAN; N_VALUES];
+        for i in 0..N_VALUES {
+            if !packet.is_valid(i) {
+                continue;
+            }
+            let counts = raw[i] as f64;
+            let delta = counts - baseline.values[i];
+            values[i] = match i {
+                PDC => self.cal.pdc_gain * delta,
+                TDC => self.cal.tdc_gain * counts + self.cal.tdc_offset,
+                TAC => self.cal.tac_gain * delta,
+                _ if i < PAC.1 => self.cal.pac_gain * delta,
+                _ => self.cal.electrode_gain * delta,
+            };
+        }
+
+        Some(Processed::new(packet.stamp, &values))
+    }
+}
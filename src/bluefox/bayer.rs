@@ -0,0 +1,454 @@
+//! Raw Bayer frames and demosaicing
+//!
+//! In raw mode (`colorproc raw` with one of the `bayergr*` formats) the driver hands over the sensor
+//! data untouched: one color sample per pixel, at the sensor's bit depth. 8-bit frames are one
+//! byte per pixel, and deeper ones are one little-endian 16-bit word per pixel with the value in
+//! the low bits. Which color each pixel saw is given by the color filter array (CFA) pattern,
+//! named after the top-left 2x2 block read row by row (GRBG for the `bayergr*` formats).
+//!
+//! The service writes the layout of the raw frames (size, pattern and bit depth) to
+//! `bluefox_raw.csv` whenever it changes (with a `none` pattern from the frame where it goes back
+//! to RGB), and examples/readbluefoxraw.rs turns recorded frames
+//! into RGB PNGs using this module (which only depends on std, so the example can include it
+//! as-is). The live preview uses the much cheaper half-resolution `preview`.
+
+use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// Column names of the raw layout file
+pub const LAYOUT_HEADER: &'static str = "frame,width,height,pattern,bits";
+
+/// Color filter array pattern
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    Rggb,
+    Grbg,
+    Gbrg,
+    Bggr,
+}
+
+/// Channel indices in RGB pixels
+const R: usize = 0;
+const G: usize = 1;
+const B: usize = 2;
+
+impl Pattern {
+    pub fn parse(s: &str) -> Result<Pattern, String> {
+        match &*s.to_lowercase() {
+            "rggb" => Ok(Pattern::Rggb),
+            "grbg" => Ok(Pattern::Grbg),
+            "gbrg" => Ok(Pattern::Gbrg),
+            "bggr" => Ok(Pattern::Bggr),
+            _      => Err(format!("bad CFA pattern {:?} (expected rggb, grbg, gbrg or bggr)", s)),
+        }
+    }
+
+    /// Pattern of a sensor pixel format (by its configuration name)
+    pub fn of_format(format: &str) -> Option<Pattern> {
+        if format.starts_with("bayergr") {
+            Some(Pattern::Grbg)
+        } else {
+            None
+        }
+    }
+
+    /// Channels of the top-left 2x2 block, row by row
+    fn cells(self) -> [usize; 4] {
+        match self {
+            Pattern::Rggb => [R, G, G, B],
+            Pattern::Grbg => [G, R, B, G],
+            Pattern::Gbrg => [G, B, R, G],
+            Pattern::Bggr => [B, G, G, R],
+        }
+    }
+
+    /// Channel sampled at a pixel
+    pub fn channel(self, x: usize, y: usize) -> usize {
+        self.cells()[(y % 2) * 2 + x % 2]
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Pattern::Rggb => "rggb",
+            Pattern::Grbg => "grbg",
+            Pattern::Gbrg => "gbrg",
+            Pattern::Bggr => "bggr",
+        })
+    }
+}
+
+/// Everything needed to interpret a raw frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub width: usize,
+    pub height: usize,
+    pub pattern: Pattern,
+    /// Significant bits per sample (8-16)
+    pub bits: u8,
+}
+
+impl Layout {
+    /// Size of one frame in bytes
+    pub fn frame_bytes(&self) -> usize {
+        self.width * self.height * if self.bits > 8 { 2 } else { 1 }
+    }
+
+    /// Largest sample value
+    pub fn max(&self) -> u16 {
+        ((1u32 << self.bits) - 1) as u16
+    }
+
+    /// One line of the layout file (without the newline)
+    pub fn to_csv(&self, frame: usize) -> String {
+        format!("{},{},{},{},{}", frame, self.width, self.height, self.pattern, self.bits)
+    }
+
+    /// The line of the layout file saying that frames are not raw from `frame` on
+    pub fn none_csv(frame: usize) -> String {
+        format!("{},,,none,", frame)
+    }
+
+    /// Read a layout file: the layouts in effect from each frame number on (None where the frames
+    /// are not raw)
+    pub fn load(path: &str) -> Result<Vec<(usize, Option<Layout>)>, String> {
+        let file = try!(File::open(path).map_err(|e| format!("could not open {}: {}", path, e)));
+        let mut layouts = vec![];
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = try!(line.map_err(|e| format!("could not read {}: {}", path, e)));
+            if n == 0 && line.trim() == LAYOUT_HEADER {
+                continue;
+            }
+            let fields = line.trim().split(',').map(str::trim).collect::<Vec<_>>();
+            if fields.len() != 5 {
+                return Err(format!("{}:{}: expected 5 fields, found {}", path, n + 1, fields.len()));
+            }
+            let number = |i: usize| fields[i].parse::<usize>()
+                                             .map_err(|_| format!("{}:{}: bad number {:?}", path, n + 1, fields[i]));
+            if fields[3] == "none" {
+                layouts.push((try!(number(0)), None));
+                continue;
+            }
+            let bits = try!(number(4));
+            if bits < 8 || bits > 16 {
+                return Err(format!("{}:{}: bad bit depth {}", path, n + 1, bits));
+            }
+            layouts.push((try!(number(0)), Some(Layout {
+                width   : try!(number(1)),
+                height  : try!(number(2)),
+                pattern : try!(Pattern::parse(fields[3]).map_err(|e| format!("{}:{}: {}", path, n + 1, e))),
+                bits    : bits as u8,
+            })));
+        }
+        Ok(layouts)
+    }
+
+    /// The layout of a frame, given the contents of a layout file (None if it is not raw)
+    pub fn at(layouts: &[(usize, Option<Layout>)], frame: usize) -> Option<Layout> {
+        layouts.iter().rev().find(|&&(from, _)| from <= frame).and_then(|&(_, layout)| layout)
+    }
+}
+
+/// Demosaicing algorithm
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Method {
+    /// Average the nearest samples of each color
+    Bilinear,
+    /// Interpolate green along the edge (Hamilton-Adams), then red and blue from color differences
+    EdgeAware,
+}
+
+impl Method {
+    pub fn parse(s: &str) -> Result<Method, String> {
+        match s {
+            "bilinear" => Ok(Method::Bilinear),
+            "edge"     => Ok(Method::EdgeAware),
+            _          => Err(format!("bad demosaicing method {:?} (expected bilinear or edge)", s)),
+        }
+    }
+}
+
+/// Samples of a raw frame
+pub fn unpack(data: &[u8], layout: &Layout) -> Result<Vec<u16>, String> {
+    if data.len() != layout.frame_bytes() {
+        return Err(format!("expected {} bytes for a {}x{} {}-bit frame, found {}",
+                           layout.frame_bytes(), layout.width, layout.height, layout.bits, data.len()));
+    }
+    let max = layout.max();
+    Ok(if layout.bits > 8 {
+        data.chunks(2).map(|b| ((b[0] as u16) | ((b[1] as u16) << 8)) & max).collect()
+    } else {
+        data.iter().map(|&b| b as u16).collect()
+    })
+}
+
+/// Mirror a coordinate back into the image, keeping its parity (so the CFA color stays the same)
+///
+/// In frames only one or two pixels across, the mirror image can land outside the frame too, so
+/// the result is clamped (and the parity is lost).
+fn reflect(i: isize, n: usize) -> usize {
+    let n = n as isize;
+    let i = if i < 0 { -i } else if i >= n { 2 * (n - 1) - i } else { i };
+    cmp::min(cmp::max(i, 0), n - 1) as usize
+}
+
+/// Full-resolution RGB (interleaved, at the raw bit depth)
+pub fn demosaic(raw: &[u16], layout: &Layout, method: Method) -> Vec<u16> {
+    assert_eq!(raw.len(), layout.width * layout.height);
+    match method {
+        Method::Bilinear  => bilinear(raw, layout),
+        Method::EdgeAware => edge_aware(raw, layout),
+    }
+}
+
+fn bilinear(raw: &[u16], layout: &Layout) -> Vec<u16> {
+    let (w, h) = (layout.width, layout.height);
+    let mut rgb = vec![0; w * h * 3];
+    for y in 0..h {
+        for x in 0..w {
+            let (mut sum, mut count) = ([0u32; 3], [0u32; 3]);
+            for dy in -1..2 {
+                for dx in -1..2 {
+                    let (xx, yy) = (reflect(x as isize + dx, w), reflect(y as isize + dy, h));
+                    let c = layout.pattern.channel(xx, yy);
+                    sum[c] += raw[yy * w + xx] as u32;
+                    count[c] += 1;
+                }
+            }
+            let own = layout.pattern.channel(x, y);
+            for c in 0..3 {
+                rgb[(y * w + x) * 3 + c] = if c == own {
+                    raw[y * w + x]
+                } else if count[c] == 0 {
+                    0 // a frame too narrow to have this color at all
+                } else {
+                    ((sum[c] + count[c] / 2) / count[c]) as u16
+                };
+            }
+        }
+    }
+    rgb
+}
+
+fn edge_aware(raw: &[u16], layout: &Layout) -> Vec<u16> {
+    let (w, h) = (layout.width, layout.height);
+    let max = layout.max() as i32;
+    let at = |x: isize, y: isize| raw[reflect(y, h) * w + reflect(x, w)] as i32;
+
+    // green everywhere, following whichever direction has the smaller gradient
+    let mut green = vec![0i32; w * h];
+    for y in 0..h {
+        for x in 0..w {
+            let (xi, yi) = (x as isize, y as isize);
+            let own = at(xi, yi);
+            green[y * w + x] = if layout.pattern.channel(x, y) == G {
+                own
+            } else {
+                let (l, r, u, d) = (at(xi - 1, yi), at(xi + 1, yi), at(xi, yi - 1), at(xi, yi + 1));
+                let lap_h = 2 * own - at(xi - 2, yi) - at(xi + 2, yi);
+                let lap_v = 2 * own - at(xi, yi - 2) - at(xi, yi + 2);
+                let grad_h = (l - r).abs() + lap_h.abs();
+                let grad_v = (u - d).abs() + lap_v.abs();
+                let est_h = (l + r) * 2 + lap_h;
+                let est_v = (u + d) * 2 + lap_v;
+                let est = if grad_h < grad_v {
+                    est_h / 4
+                } else if grad_v < grad_h {
+                    est_v / 4
+                } else {
+                    (est_h + est_v) / 8
+                };
+                clamp(est, max)
+            };
+        }
+    }
+
+    // red and blue from the average color difference of their nearest samples
+    let mut rgb = vec![0; w * h * 3];
+    for y in 0..h {
+        for x in 0..w {
+            let own = layout.pattern.channel(x, y);
+            let g = green[y * w + x];
+            for c in 0..3 {
+                rgb[(y * w + x) * 3 + c] = if c == own {
+                    raw[y * w + x]
+                } else if c == G {
+                    g as u16
+                } else {
+                    let (mut diff, mut count) = (0, 0);
+                    for dy in -1..2 {
+                        for dx in -1..2 {
+                            let (xx, yy) = (reflect(x as isize + dx, w), reflect(y as isize + dy, h));
+                            if layout.pattern.channel(xx, yy) == c {
+                                diff += raw[yy * w + xx] as i32 - green[yy * w + xx];
+                                count += 1;
+                            }
+                        }
+                    }
+                    if count == 0 { g as u16 } else { clamp(g + diff / count, max) as u16 }
+                };
+            }
+        }
+    }
+    rgb
+}
+
+fn clamp(x: i32, max: i32) -> i32 {
+    if x < 0 { 0 } else if x > max { max } else { x }
+}
+
+/// Scale samples to 8 bits
+pub fn to_8bit(samples: &[u16], bits: u8) -> Vec<u8> {
+    samples.iter().map(|&s| (s >> (bits - 8)) as u8).collect()
+}
+
+/// Scale samples to the full 16-bit range
+pub fn to_16bit(samples: &[u16], bits: u8) -> Vec<u16> {
+    let max = ((1u32 << bits) - 1) as u32;
+    samples.iter().map(|&s| (s as u32 * 65535 / max) as u16).collect()
+}
+
+/// Quick half-resolution 8-bit RGB, one pixel per 2x2 block (for the live preview)
+///
+/// Returns the pixels and their (height, width).
+pub fn preview(data: &[u8], layout: &Layout) -> Result<(Vec<u8>, (usize, usize)), String> {
+    let raw = try!(unpack(data, layout));
+    let (w, h) = (layout.width / 2, layout.height / 2);
+    let shift = layout.bits - 8;
+    let mut rgb = Vec::with_capacity(w * h * 3);
+    for y in 0..h {
+        for x in 0..w {
+            let mut px = [0u32; 3];
+            for &(dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let (xx, yy) = (2 * x + dx, 2 * y + dy);
+                px[layout.pattern.channel(xx, yy)] += raw[yy * layout.width + xx] as u32;
+            }
+            px[G] /= 2; // there are two greens in every block
+            for c in 0..3 {
+                rgb.push((px[c] >> shift) as u8);
+            }
+        }
+    }
+    Ok((rgb, (h, w)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    fn layout(width: usize, height: usize, pattern: Pattern, bits: u8) -> Layout {
+        Layout { width: width, height: height, pattern: pattern, bits: bits }
+    }
+
+    /// The raw frame of a flat-coloured scene
+    fn flat(layout: &Layout, rgb: [u16; 3]) -> Vec<u16> {
+        let mut raw = vec![];
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                raw.push(rgb[layout.pattern.channel(x, y)]);
+            }
+        }
+        raw
+    }
+
+    #[test]
+    fn unpack_8bit() {
+        let l = layout(2, 2, Pattern::Grbg, 8);
+        assert_eq!(unpack(&[1, 2, 3, 255], &l).unwrap(), vec![1, 2, 3, 255]);
+    }
+
+    #[test]
+    fn unpack_16bit() {
+        // little-endian, and anything above the bit depth is masked off
+        let l = layout(2, 1, Pattern::Grbg, 12);
+        assert_eq!(unpack(&[0x34, 0x02, 0xFF, 0xFF], &l).unwrap(), vec![0x234, 0xFFF]);
+    }
+
+    #[test]
+    fn unpack_wrong_length() {
+        assert!(unpack(&[0; 3], &layout(2, 2, Pattern::Grbg, 8)).is_err());
+        assert!(unpack(&[0; 4], &layout(2, 2, Pattern::Grbg, 10)).is_err());
+        assert!(unpack(&[0; 9], &layout(2, 2, Pattern::Grbg, 16)).is_err());
+    }
+
+    #[test]
+    fn demosaic_flat() {
+        for &pattern in &[Pattern::Rggb, Pattern::Grbg, Pattern::Gbrg, Pattern::Bggr] {
+            let l = layout(8, 6, pattern, 12);
+            let raw = flat(&l, [3000, 1200, 400]);
+            for &method in &[Method::Bilinear, Method::EdgeAware] {
+                let rgb = demosaic(&raw, &l, method);
+                assert_eq!(rgb.len(), 8 * 6 * 3);
+                for (i, px) in rgb.chunks(3).enumerate() {
+                    assert!(px == &[3000, 1200, 400], "{} {:?} at {}", pattern, method, i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn demosaic_tiny() {
+        // a single green pixel: no red or blue anywhere to interpolate from
+        let l = layout(1, 1, Pattern::Grbg, 8);
+        assert_eq!(demosaic(&[77], &l, Method::Bilinear), vec![0, 77, 0]);
+        assert_eq!(demosaic(&[77], &l, Method::EdgeAware), vec![77, 77, 77]);
+
+        // reflecting two pixels out of a 2x2 frame has to be clamped
+        let l = layout(2, 2, Pattern::Grbg, 8);
+        let raw = flat(&l, [200, 100, 50]);
+        for &method in &[Method::Bilinear, Method::EdgeAware] {
+            let rgb = demosaic(&raw, &l, method);
+            assert_eq!(rgb.len(), 2 * 2 * 3);
+            for y in 0..2 {
+                for x in 0..2 {
+                    let own = l.pattern.channel(x, y);
+                    assert_eq!(rgb[(y * 2 + x) * 3 + own], raw[y * 2 + x]);
+                }
+            }
+        }
+        assert_eq!(demosaic(&raw, &l, Method::Bilinear), vec![200, 100, 50, 200, 100, 50, 200, 100, 50, 200, 100, 50]);
+    }
+
+    #[test]
+    fn load_layouts() {
+        let path = env::temp_dir().join("bayer_load_layouts.csv");
+        File::create(&path).unwrap()
+            .write_all(format!("{}\n5,640,480,grbg,12\n10,,,none,\n20, 320, 240, RGGB, 8\n", LAYOUT_HEADER).as_bytes())
+            .unwrap();
+        let layouts = Layout::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let layouts = layouts.unwrap();
+
+        let first = layout(640, 480, Pattern::Grbg, 12);
+        let last = layout(320, 240, Pattern::Rggb, 8);
+        assert_eq!(layouts, vec![(5, Some(first)), (10, None), (20, Some(last))]);
+        assert_eq!(Layout::at(&layouts, 0), None); // before the first line
+        assert_eq!(Layout::at(&layouts, 5), Some(first));
+        assert_eq!(Layout::at(&layouts, 9), Some(first));
+        assert_eq!(Layout::at(&layouts, 10), None);
+        assert_eq!(Layout::at(&layouts, 19), None);
+        assert_eq!(Layout::at(&layouts, 1000), Some(last));
+
+        // and back out
+        assert_eq!(first.to_csv(5), "5,640,480,grbg,12");
+        assert_eq!(Layout::none_csv(10), "10,,,none,");
+    }
+
+    #[test]
+    fn load_bad_layouts() {
+        for (i, contents) in ["0,640,480,grbg,20\n", "0,640,480,bgrg,12\n", "0,640,480\n", "x,640,480,grbg,12\n"].iter().enumerate() {
+            let path = env::temp_dir().join(format!("bayer_load_bad_layouts{}.csv", i));
+            File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+            let layouts = Layout::load(path.to_str().unwrap());
+            fs::remove_file(&path).unwrap();
+            assert!(layouts.is_err(), "{:?} loaded", contents);
+        }
+        assert!(Layout::load("/nonexistent/bluefox_raw.csv").is_err());
+    }
+}
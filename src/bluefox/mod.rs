@@ -1,4 +1,239 @@
 //! Service to capture frames from the mvBlueFOX3 camera
+//!
+//! # Commands
+//!
+//! - `disk start`/`disk stop`: start/stop recording
+//! - `kick`: send the current frame to the web interface
+//! - `set <setting> <value>`: change an acquisition setting (see below)
+//! - `preset <name>`: apply a preset (`preview` for aiming, `capture` for full-resolution frames,
+//!   `raw`/`color` to switch between raw Bayer and RGB frames)
+//! - `snapshot <N>`: save the next frame at full resolution as `snapshot<N>_bluefox.png` (or
+//!   `.dat` for raw frames), listed in `bluefox_snapshots.csv` with its timestamps and the
+//!   effective settings. The supervisor sends this to all the cameras at once (see the `snapshot`
+//!   CLI and flow commands).
+//!
+//! While someone is watching, a live preview is streamed to the web interface as `bluefox` (see
+//! the preview module).
+//!
+//! # Settings
+//!
+//! Acquisition settings are applied in order when the service starts, from the `NRI_BLUEFOX`
+//! environment variable (comma-separated `key=value` pairs, where `preset=<name>` expands to the
+//! preset's settings), which can be changed at runtime with `set` (e.g. the `bluefox` CLI
+//! command). Anything not mentioned is left as the camera has it.
+//!
+//! - `width`, `height`, `offsetx`, `offsety`: area of interest (pixels)
+//! - `binx`, `biny`, `decimatex`, `decimatey`: binning and decimation factors
+//! - `format`: sensor pixel format (`bayergr8`, `bayergr10`, `bayergr12`, `bayergr16`,
+//!   `rgb8packed`, `bgr8packed`, `bgra8packed`, `bgr10v2packed`, `rgb8`, `bgr8`, `bgra8`, `rgb10p32`)
+//! - `framerate`: acquisition frame rate in Hz, or `off` to run free
+//! - `colorproc`: color processing mode (`auto`, `raw`, `colorbayer`, `colorbayertomono`, `rawtoplanes`)
+//! - `scale`: driver-side scaling to `WxH`, or `off`; `scalemode`: `nearestneighbor`, `linear` or
+//!   `cubic`
+//!
+//! Every setting is read back after it is applied, and the service refuses to start (or, at
+//! runtime, reports an error) if the camera rejects or changes it. The effective settings are
+//! written to `bluefox_settings.csv` when the service starts, and every change made while running
+//! is appended with the number of the frame it took effect at.
+//!
+//! # Acquisition
+//!
+//! The camera runs continuously, with a few requests always queued in the driver, so frames are
+//! not lost while the previous one is being written. The frame rate is therefore set by the camera
+//! (`framerate`, 7.5 Hz unless configured otherwise; cameras that cannot limit it run free, with a
+//! warning at startup). If no frame arrives within a second, the queue is reset and an alarm is
+//! raised in the web interface until frames come back. Timeouts, failed requests (e.g. incomplete
+//! frames) and gaps in the camera's frame IDs are logged to `bluefox_drops.csv`
+//! (`frame,event,count`), and each line of `bluefox_times.csv` carries the driver's frame number
+//! and the camera's frame ID after the host timestamp.
+//!
+//! # Raw frames
+//!
+//! With `colorproc raw` and a `bayergr*` format (the `raw` preset), frames are recorded as the
+//! sensor's Bayer samples at its native bit depth instead of driver-processed RGB (see the bayer
+//! module for the layout). The size, CFA pattern and bit depth are written to `bluefox_raw.csv`
+//! whenever they change (with a `none` line when frames go back to RGB), and
+//! examples/readbluefoxraw.rs demosaics the recording offline. The live preview is a quick
+//! half-resolution debayer.
+
+use std::env;
+use std::fmt;
+use std::sync::RwLock;
+
+/// Names of the sensor pixel formats (as in wrapper::settings::PixelFormat)
+const FORMATS: [&'static str; 12] = ["bayergr8", "bayergr10", "bayergr12", "bayergr16",
+                                     "rgb8packed", "bgr8packed", "bgra8packed", "bgr10v2packed",
+                                     "rgb8", "bgr8", "bgra8", "rgb10p32"];
+
+/// Names of the color processing modes (as in wrapper::settings::ColorProc)
+const COLOR_PROCS: [&'static str; 5] = ["auto", "raw", "colorbayer", "colorbayertomono", "rawtoplanes"];
+
+/// Names of the scaling interpolation modes (as in wrapper::settings::InterpolationMode)
+const SCALE_MODES: [&'static str; 3] = ["nearestneighbor", "linear", "cubic"];
+
+/// An acquisition setting
+#[derive(Debug, Clone, PartialEq)]
+pub enum Setting {
+    Width(i64),
+    Height(i64),
+    OffsetX(i64),
+    OffsetY(i64),
+    BinX(i64),
+    BinY(i64),
+    DecimateX(i64),
+    DecimateY(i64),
+    Format(String),
+    /// Acquisition frame rate (None to run free)
+    FrameRate(Option<f64>),
+    ColorProc(String),
+    /// Scaled size (None for no scaling)
+    Scale(Option<(i32, i32)>),
+    ScaleMode(String),
+}
+
+impl Setting {
+    pub fn parse(key: &str, value: &str) -> Result<Setting, String> {
+        let int = || match value.parse::<i64>() {
+            Ok(i) if i >= 0 => Ok(i),
+            _               => Err(format!("bad {} {:?} (expected a non-negative integer)", key, value)),
+        };
+        let name = |names: &[&str]| if names.contains(&value) {
+            Ok(value.to_owned())
+        } else {
+            Err(format!("bad {} {:?} (expected one of {})", key, value, names.join(", ")))
+        };
+        Ok(match key {
+            "width"     => Setting::Width(try!(int())),
+            "height"    => Setting::Height(try!(int())),
+            "offsetx"   => Setting::OffsetX(try!(int())),
+            "offsety"   => Setting::OffsetY(try!(int())),
+            "binx"      => Setting::BinX(try!(int())),
+            "biny"      => Setting::BinY(try!(int())),
+            "decimatex" => Setting::DecimateX(try!(int())),
+            "decimatey" => Setting::DecimateY(try!(int())),
+            "format"    => Setting::Format(try!(name(&FORMATS[..]))),
+            "colorproc" => Setting::ColorProc(try!(name(&COLOR_PROCS[..]))),
+            "scalemode" => Setting::ScaleMode(try!(name(&SCALE_MODES[..]))),
+            "framerate" => Setting::FrameRate(if value == "off" {
+                None
+            } else {
+                match value.parse::<f64>() {
+                    Ok(hz) if hz > 0.0 => Some(hz),
+                    _                  => return Err(format!("bad framerate {:?} (expected Hz or off)", value)),
+                }
+            }),
+            "scale" => Setting::Scale(if value == "off" {
+                None
+            } else {
+                let mut dims = value.split('x').map(str::parse);
+                match (dims.next(), dims.next(), dims.next()) {
+                    (Some(Ok(w)), Some(Ok(h)), None) => Some((w, h)),
+                    _                                => return Err(format!("bad scale {:?} (expected WxH or off)", value)),
+                }
+            }),
+            _ => return Err(format!("unknown Bluefox setting {:?}", key)),
+        })
+    }
+
+    pub fn key(&self) -> &'static str {
+        match *self {
+            Setting::Width(_)     => "width",
+            Setting::Height(_)    => "height",
+            Setting::OffsetX(_)   => "offsetx",
+            Setting::OffsetY(_)   => "offsety",
+            Setting::BinX(_)      => "binx",
+            Setting::BinY(_)      => "biny",
+            Setting::DecimateX(_) => "decimatex",
+            Setting::DecimateY(_) => "decimatey",
+            Setting::Format(_)    => "format",
+            Setting::FrameRate(_) => "framerate",
+            Setting::ColorProc(_) => "colorproc",
+            Setting::Scale(_)     => "scale",
+            Setting::ScaleMode(_) => "scalemode",
+        }
+    }
+
+    /// The value as it would be written in the configuration
+    pub fn value(&self) -> String {
+        match *self {
+            Setting::Width(x) | Setting::Height(x) | Setting::OffsetX(x) | Setting::OffsetY(x)
+                | Setting::BinX(x) | Setting::BinY(x) | Setting::DecimateX(x) | Setting::DecimateY(x) => x.to_string(),
+            Setting::Format(ref s) | Setting::ColorProc(ref s) | Setting::ScaleMode(ref s) => s.clone(),
+            Setting::FrameRate(hz) => hz.map_or("off".to_owned(), |hz| hz.to_string()),
+            Setting::Scale(size)   => size.map_or("off".to_owned(), |(w, h)| format!("{}x{}", w, h)),
+        }
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.key(), self.value())
+    }
+}
+
+/// Settings that make up a preset
+pub fn preset(name: &str) -> Result<Vec<Setting>, String> {
+    match name {
+        // bin the sensor down for a quick, light stream while aiming the camera
+        "preview" => Ok(vec![Setting::BinX(2), Setting::BinY(2), Setting::Scale(None)]),
+        // every pixel the sensor has
+        "capture" => Ok(vec![Setting::BinX(1), Setting::BinY(1), Setting::DecimateX(1), Setting::DecimateY(1), Setting::Scale(None)]),
+        // unprocessed Bayer samples (12 bits is the most the mvBlueFOX3 sensors deliver)
+        "raw"     => Ok(vec![Setting::Format("bayergr12".to_owned()), Setting::ColorProc("raw".to_owned())]),
+        // let the driver debayer to RGB again
+        "color"   => Ok(vec![Setting::Format("bayergr8".to_owned()), Setting::ColorProc("auto".to_owned())]),
+        _         => Err(format!("unknown Bluefox preset {:?} (expected preview, capture, raw or color)", name)),
+    }
+}
+
+/// Parse a `set`/`preset` pair into the settings to apply
+pub fn parse(key: &str, value: &str) -> Result<Vec<Setting>, String> {
+    if key == "preset" {
+        preset(value)
+    } else {
+        Setting::parse(key, value).map(|s| vec![s])
+    }
+}
+
+/// Add settings to a list, replacing any earlier values of the same settings
+fn merge(list: &mut Vec<Setting>, new: Vec<Setting>) {
+    for setting in new {
+        list.retain(|s| s.key() != setting.key());
+        list.push(setting);
+    }
+}
+
+lazy_static! {
+    /// Settings applied the next time the service starts
+    static ref SETTINGS: RwLock<Vec<Setting>> = RwLock::new({
+        // the camera free-runs now, so pace it like the old polling loop unless told otherwise
+        let mut settings = vec![Setting::FrameRate(Some(7.5))];
+        if let Ok(s) = env::var("NRI_BLUEFOX") {
+            for pair in s.split(',').filter(|p| !p.trim().is_empty()) {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) => merge(&mut settings, parse(k.trim(), v.trim()).expect("bad NRI_BLUEFOX")),
+                    _                  => panic!("bad NRI_BLUEFOX: expected key=value, found {:?}", pair),
+                }
+            }
+        }
+        settings
+    });
+}
+
+/// Change a setting of the Bluefox service (or apply a preset, with key "preset")
+///
+/// Takes effect the next time the service is started.
+pub fn set(key: &str, value: &str) -> Result<(), String> {
+    let new = try!(parse(key, value));
+    merge(&mut SETTINGS.write().unwrap(), new);
+    Ok(())
+}
+
+/// Get the settings currently in use
+pub fn settings() -> Vec<Setting> {
+    SETTINGS.read().unwrap().clone()
+}
 
 group_attr!{
     #[cfg(target_os = "linux")]
@@ -13,12 +248,162 @@ group_attr!{
     use self::serialize::base64::ToBase64;
     use std::sync::Mutex;
     use std::sync::mpsc::Sender;
-    use ::comms::{Controllable, CmdFrom, Block, RestartableThread};
+    use ::comms::{Controllable, CmdFrom, Block, RestartableThread, Mailbox};
     use ::scribe::Writer;
+    use ::pool::{Pool, Buffer};
+    use ::preview::Preview;
+    use std::fmt::Debug;
+    use super::Setting;
 
-    type PngStuff = (usize, Vec<u8>, (usize, usize), ColorType);
+    type PngStuff = (usize, Buffer, (usize, usize), ColorType);
 
     mod wrapper;
+    #[allow(dead_code)] mod bayer;
+
+    /// Number of requests to keep queued in the driver
+    const QUEUE_DEPTH: usize = 4;
+
+    /// How long to wait for a frame before giving up on the queue (ms)
+    const TIMEOUT_MS: i32 = 1000;
+
+    /// Frame buffers kept for reuse (enough for scribe to fall a second behind)
+    const POOL_FRAMES: usize = 32;
+
+    /// Every setting, in the order they are read back
+    const KEYS: [&'static str; 13] = ["width", "height", "offsetx", "offsety", "binx", "biny", "decimatex", "decimatey",
+                                      "format", "framerate", "colorproc", "scale", "scalemode"];
+
+    /// Look up a driver enum by its configuration name
+    fn from_name<T: Copy + Debug>(all: &[T], name: &str) -> T {
+        *all.iter().find(|x| name_of(*x) == name).unwrap() // names are checked by Setting::parse
+    }
+
+    /// Log a timeout, failed request or dropped frames
+    ///
+    /// (A free function, so it can be called while a frame still borrows the device.)
+    fn log_drop(file: &mut Writer<[u8]>, i: usize, event: &str, count: usize) {
+        file.write(format!("{},{},{}\n", i, event, count).as_bytes());
+    }
+
+    fn name_of<T: Debug>(x: &T) -> String {
+        format!("{:?}", x).to_lowercase()
+    }
+
+    /// Read a setting back from the camera
+    fn read(device: &wrapper::Device, key: &str) -> Result<Setting, String> {
+        let failed = |e: wrapper::TPROPHANDLING_ERROR| format!("could not read {}: {:?}", key, e);
+        Ok(match key {
+            "width"     => Setting::Width(try!(device.get_width().map_err(&failed))),
+            "height"    => Setting::Height(try!(device.get_height().map_err(&failed))),
+            "offsetx"   => Setting::OffsetX(try!(device.get_offset_x().map_err(&failed))),
+            "offsety"   => Setting::OffsetY(try!(device.get_offset_y().map_err(&failed))),
+            "binx"      => Setting::BinX(try!(device.get_bin_x().map_err(&failed))),
+            "biny"      => Setting::BinY(try!(device.get_bin_y().map_err(&failed))),
+            "decimatex" => Setting::DecimateX(try!(device.get_decimate_x().map_err(&failed))),
+            "decimatey" => Setting::DecimateY(try!(device.get_decimate_y().map_err(&failed))),
+            "format"    => Setting::Format(name_of(&try!(device.get_pixel_format().map_err(&failed)))),
+            "colorproc" => Setting::ColorProc(name_of(&try!(device.get_color_proc().map_err(&failed)))),
+            "scalemode" => Setting::ScaleMode(name_of(&try!(device.get_scale_mode().map_err(&failed)))),
+            "framerate" => Setting::FrameRate(if try!(device.get_afr_enabled().map_err(&failed)) {
+                Some(try!(device.get_afr().map_err(&failed)))
+            } else {
+                None
+            }),
+            "scale" => Setting::Scale(if try!(device.get_scale_enabled().map_err(&failed)) {
+                Some((try!(device.get_scale_width().map_err(&failed)), try!(device.get_scale_height().map_err(&failed))))
+            } else {
+                None
+            }),
+            _ => unreachable!(),
+        })
+    }
+
+    /// Apply a setting, and check that the camera took it
+    fn apply(device: &wrapper::Device, setting: &Setting) -> Result<(), String> {
+        use self::wrapper::settings::{PIXEL_FORMATS, COLOR_PROCS, INTERPOLATION_MODES};
+
+        let failed = |e: wrapper::TPROPHANDLING_ERROR| format!("could not set {}: {:?}", setting, e);
+        match *setting {
+            Setting::Width(x)      => try!(device.set_width(x).map_err(&failed)),
+            Setting::Height(x)     => try!(device.set_height(x).map_err(&failed)),
+            Setting::OffsetX(x)    => try!(device.set_offset_x(x).map_err(&failed)),
+            Setting::OffsetY(x)    => try!(device.set_offset_y(x).map_err(&failed)),
+            Setting::BinX(x)       => try!(device.set_bin_x(x).map_err(&failed)),
+            Setting::BinY(x)       => try!(device.set_bin_y(x).map_err(&failed)),
+            Setting::DecimateX(x)  => try!(device.set_decimate_x(x).map_err(&failed)),
+            Setting::DecimateY(x)  => try!(device.set_decimate_y(x).map_err(&failed)),
+            Setting::Format(ref s)    => try!(device.set_pixel_format(from_name(&PIXEL_FORMATS, s)).map_err(&failed)),
+            Setting::ColorProc(ref s) => try!(device.set_color_proc(from_name(&COLOR_PROCS, s)).map_err(&failed)),
+            Setting::ScaleMode(ref s) => try!(device.set_scale_mode(from_name(&INTERPOLATION_MODES, s)).map_err(&failed)),
+            Setting::FrameRate(None)  => try!(device.set_afr_enabled(false).map_err(&failed)),
+            Setting::FrameRate(Some(hz)) => {
+                try!(device.set_afr_enabled(true).map_err(&failed));
+                try!(device.set_afr(hz).map_err(&failed));
+            },
+            Setting::Scale(None) => try!(device.set_scale_enabled(false).map_err(&failed)),
+            Setting::Scale(Some((w, h))) => {
+                try!(device.set_scale_enabled(true).map_err(&failed));
+                try!(device.set_scale_width(w).map_err(&failed));
+                try!(device.set_scale_height(h).map_err(&failed));
+            },
+        }
+
+        let actual = try!(read(device, setting.key()));
+        let took = match (setting, &actual) {
+            // the camera rounds the frame rate to what the sensor timing allows
+            (&Setting::FrameRate(Some(want)), &Setting::FrameRate(Some(got))) => (want - got).abs() <= 0.01 * want,
+            _ => *setting == actual,
+        };
+        if took {
+            Ok(())
+        } else {
+            Err(format!("asked for {}, but the camera set {}", setting, actual.value()))
+        }
+    }
+
+    /// All the settings as the camera has them now
+    fn effective(device: &wrapper::Device) -> Vec<Setting> {
+        KEYS.iter().filter_map(|key| read(device, key).ok()).collect()
+    }
+
+    /// CFA pattern of the frames, if the camera is set to deliver raw Bayer data
+    fn raw_pattern(device: &wrapper::Device) -> Option<bayer::Pattern> {
+        match (read(device, "colorproc"), read(device, "format")) {
+            (Ok(Setting::ColorProc(ref cp)), Ok(Setting::Format(ref format))) if cp == "raw" => bayer::Pattern::of_format(format),
+            _ => None,
+        }
+    }
+
+    /// Layout of a raw frame (from the buffer the driver filled in)
+    fn raw_layout(image: &wrapper::Image, pattern: bayer::Pattern) -> Option<bayer::Layout> {
+        use self::wrapper::PixelFormat::*;
+
+        let bits = match image.format() {
+            Mono8  => 8,
+            Mono10 => 10,
+            Mono12 => 12,
+            Mono16 => 16,
+            _      => return None,
+        };
+        let (h, w) = image.size();
+        Some(bayer::Layout { width: w, height: h, pattern: pattern, bits: bits })
+    }
+
+    /// RGB pixels and (height, width) to show for a frame (a quick debayer for raw frames)
+    fn rgb_frame(image: &wrapper::Image, layout: Option<bayer::Layout>, frames: &Pool) -> Option<(Buffer, (usize, usize))> {
+        let (h, w) = image.size();
+        if let Some(layout) = layout {
+            match bayer::preview(image.data(), &layout) {
+                Ok((rgb, size)) => Some((Buffer::from(rgb), size)),
+                Err(e)          => { println!("Bluefox: no preview: {}", e); None },
+            }
+        } else if image.data().len() == h*w*3 {
+            Some((frames.copy(image.data()), (h, w)))
+        } else {
+            println!("Bluefox: no preview for {:?} frames", image.format());
+            None
+        }
+    }
 
     /// Controllable struct for the camera
     pub struct Bluefox {
@@ -33,53 +418,128 @@ group_attr!{
         writing: bool,
 
         /// PNG writer rebootable thread
-        png: RestartableThread<PngStuff>,
+        png: RestartableThread<PngStuff, Result<(), String>>,
+
+        /// Live preview stream
+        preview: Preview,
 
         /// Timestamp file handle
         stampfile: Writer<[u8]>,
 
+        /// Settings log handle
+        settings_file: Writer<[u8]>,
+
+        /// Log of timeouts, failed requests and dropped frames
+        dropfile: Writer<[u8]>,
+
+        /// Frame ID of the last good frame (to notice dropped frames)
+        last_id: Option<i32>,
+        /// Number of timeouts (each one resets the request queue)
+        timeouts: usize,
+        /// Number of requests that came back without a complete frame
+        failed: usize,
+        /// Number of frames the camera sent that never arrived
+        dropped: usize,
+        /// Whether the timeout alarm is raised
+        alarm: bool,
+
+        /// Send the next frame to the web interface
+        kick: bool,
+
+        /// CFA pattern, when recording raw Bayer frames
+        pattern: Option<bayer::Pattern>,
+        /// Raw frame layout last written to the layout file
+        layout: Option<bayer::Layout>,
+        /// Raw frame layout file handle
+        layout_file: Writer<[u8]>,
+
+        /// Number of the snapshot to take from the next frame
+        snapshot: Option<usize>,
+        /// Snapshot list handle
+        snapfile: Writer<[u8]>,
+
+        tx: Sender<CmdFrom>,
+
+        /// Buffers for frames on their way to disk or the previews
+        frames: Pool,
+
         writer: Writer<[u8]>
     }
 
+    impl Bluefox {
+        /// Log settings to the session, as of the given frame
+        fn log_settings(&mut self, frame: usize, settings: &[Setting]) {
+            for setting in settings {
+                self.settings_file.write(format!("{},{},{}\n", frame, setting.key(), setting.value()).as_bytes());
+            }
+        }
+
+        /// Apply settings at runtime (and log the ones that worked)
+        fn change(&mut self, settings: Result<Vec<Setting>, String>) {
+            let settings = match settings {
+                Ok(s)  => s,
+                Err(e) => { println!("Bluefox: {}", e); return; }
+            };
+            // the queued requests were set up for the old image format
+            self.device.reset_queue().unwrap();
+            for setting in settings {
+                match apply(&self.device, &setting) {
+                    Ok(()) => {
+                        println!("Bluefox: {}", setting);
+                        let i = self.i;
+                        self.log_settings(i, &[setting]);
+                    },
+                    Err(e) => println!("Bluefox: {}", e),
+                }
+            }
+            self.pattern = raw_pattern(&self.device);
+        }
+    }
+
     guilty!{
         impl Controllable for Bluefox {
             const NAME: &'static str = "bluefox",
-            const BLOCK: Block = Block::Period(133_333_333),
+            const BLOCK: Block = Block::Immediate,
 
             fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> Bluefox {
                 let device = wrapper::Device::new().unwrap();
                 //device.request_reset();
-                
-                // TODO set desired properties (height, width, pixel format, frame rate)
 
-                println!("height = {}\nwidth = {}\npixel format = {:?}",
-                         device.get_height().unwrap(),
-                         device.get_width().unwrap(),
-                         device.get_pixel_format().unwrap());
+                for setting in &super::settings() {
+                    match (apply(&device, setting), setting) {
+                        (Ok(()), _) => {},
+                        // not every camera can limit its frame rate (AFR), and it still works without
+                        (Err(e), &Setting::FrameRate(_)) => errorln!("Bluefox: {} (running free instead)", e),
+                        (Err(e), _) => panic!("Bluefox: {}", e),
+                    }
+                }
+                let settings = effective(&device);
+                println!("Bluefox settings: {}", settings.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", "));
 
-                let mtx = Mutex::new(tx);
-                Bluefox {
+                let mtx = Mutex::new(tx.clone());
+                let mut bluefox = Bluefox {
                     device: device,
                     i: 0,
                     writing: false,
                     start: time::now(),
 
-                    png: RestartableThread::new("Bluefox PNG thread",
-                                                move |(i, unencoded, (h, w), bd)| {
+                    png: RestartableThread::with_mailbox("Bluefox PNG thread", Mailbox::Latest, 1,
+                                                         move |(i, unencoded, (h, w), bd)| {
                         let mut encoded = Vec::with_capacity(w*h);
-                        let to_resize = prof!("imagebuffer",
-                                              ImageBuffer::<image::Rgb<u8>, _>::from_raw(w as u32,
-                                                                                         h as u32,
-                                                                                         unencoded)
-                                              .unwrap());
+                        let to_resize = try!(prof!("imagebuffer",
+                                                   ImageBuffer::<image::Rgb<u8>, _>::from_raw(w as u32,
+                                                                                              h as u32,
+                                                                                              unencoded))
+                                             .ok_or(format!("frame {} is not {}x{} RGB", i, w, h)));
                         let (ww, hh) = ((w as u32)/4, (h as u32)/4);
                         let resized = prof!("resize",
                                             imageops::resize(&to_resize,
                                                              ww,
                                                              hh,
                                                              FilterType::Nearest));
-                        prof!("encode",
-                              PNGEncoder::new(&mut encoded).encode(&resized, ww, hh, bd).unwrap());
+                        try!(prof!("encode",
+                                   PNGEncoder::new(&mut encoded).encode(&resized, ww, hh, bd))
+                             .map_err(|e| format!("could not encode frame {}: {}", i, e)));
                         prof!("send",
                               mtx
                                 .lock()
@@ -90,43 +550,49 @@ group_attr!{
                                                 i,
                                                 prof!("base64",
                                                       encoded.to_base64(base64::STANDARD)))))
-                                .unwrap());
+                                .map_err(|e| format!("could not send frame {}: {}", i, e)))
                     }),
 
+                    preview: Preview::new("bluefox"),
                     stampfile: Writer::with_file("bluefox_times.csv"),
+                    settings_file: Writer::with_file("bluefox_settings.csv"),
+                    dropfile: Writer::with_file("bluefox_drops.csv"),
+                    last_id: None,
+                    timeouts: 0,
+                    failed: 0,
+                    dropped: 0,
+                    alarm: false,
+                    kick: false,
+                    pattern: None,
+                    layout: None,
+                    layout_file: Writer::with_file("bluefox_raw.csv"),
+                    snapshot: None,
+                    snapfile: Writer::with_file("bluefox_snapshots.csv"),
+                    tx: tx,
+                    frames: Pool::new("bluefox", POOL_FRAMES),
                     writer: Writer::with_files("bluefox{}.dat"),
-                }
+                };
+                bluefox.settings_file.write(b"frame,setting,value\n");
+                bluefox.dropfile.write(b"frame,event,count\n");
+                bluefox.layout_file.write(format!("{}\n", bayer::LAYOUT_HEADER).as_bytes());
+                bluefox.snapfile.write(b"snapshot,stream,file,host_stamp,frame_id,width,height,settings\n");
+                bluefox.pattern = raw_pattern(&bluefox.device);
+                bluefox.log_settings(0, &settings);
+                bluefox
             }
 
             fn step(&mut self, data: Option<String>) {
-                self.i += 1;
-
-                let image = self.device.request().unwrap();
-
-                if self.writing {
-                    let stamp = time::get_time();
-                    self.writer.write(image.data());
-                    self.stampfile.write(format!("{},bluefox{}.dat,{:.9}\n",
-                                                 self.i,
-                                                 self.i,
-                                                 (stamp.sec as f64
-                                                  + stamp.nsec as f64
-                                                  / 1_000_000_000f64))
-                                         .as_bytes());
-                } else {
-                    self.writer.decoy();
+                match data.as_ref().map(|s| s.split_whitespace().collect::<Vec<_>>()) {
+                    // settings change between frames
+                    Some(ref words) if words.len() == 3 && words[0] == "set" => self.change(super::parse(words[1], words[2])),
+                    Some(ref words) if words.len() == 2 && words[0] == "preset" => self.change(super::preset(words[1])),
+                    _ => {},
                 }
                 match data.as_ref().map(|s| s as &str) {
-                    Some("kick") => {
-                        //self.device.set_reverse_x(!self.device.get_reverse_x().unwrap());
-                        //self.device.set_reverse_y(!self.device.get_reverse_y().unwrap());
-                        println!("buf = {:?}", image.buf);
-                        prof!("send to thread",
-                              self.png.send((self.i,
-                                             image.data().into(),
-                                             image.size(),
-                                             ColorType::RGB(8)))
-                              .unwrap())
+                    Some("kick") => self.kick = true,
+                    Some(s) if s.starts_with("snapshot") => match s[8..].trim().parse() {
+                        Ok(n)  => self.snapshot = Some(n),
+                        Err(_) => println!("Bluefox: bad command {:?} (expected snapshot <N>)", s),
                     },
                     Some("disk start") => {
                         println!("Started Bluefox recording.");
@@ -138,6 +604,114 @@ group_attr!{
                     },
                     Some(_) | None => ()
                 }
+                for result in self.png.results() {
+                    if let Err(e) = result {
+                        errorln!("Bluefox PNG thread: {}", e);
+                    }
+                }
+
+                // keep the driver busy while we deal with the last frame
+                prof!("queue", self.device.queue(QUEUE_DEPTH).unwrap());
+                let image = match self.device.wait(TIMEOUT_MS) {
+                    Ok(image) => image,
+                    Err(wrapper::TDMR_ERROR::DEV_WAIT_FOR_REQUEST_FAILED) | Err(wrapper::TDMR_ERROR::DMR_TIMEOUT) => {
+                        println!("Bluefox: no frame in {} ms, resetting the request queue", TIMEOUT_MS);
+                        self.timeouts += 1;
+                        log_drop(&mut self.dropfile, self.i, "timeout", 1);
+                        if !self.alarm {
+                            self.alarm = true;
+                            self.tx.send(CmdFrom::Data("send alarm bluefox no frames from the camera".to_owned())).unwrap();
+                        }
+                        self.device.reset_queue().unwrap();
+                        self.last_id = None; // the frame IDs may jump after a reset
+                        return;
+                    },
+                    Err(e) => panic!("Bluefox: waiting for a frame failed: {:?}", e),
+                };
+                if self.alarm {
+                    self.alarm = false;
+                    self.tx.send(CmdFrom::Data("send alarm bluefox clear".to_owned())).unwrap();
+                }
+
+                if image.result != wrapper::RequestResult::Ok {
+                    self.failed += 1;
+                    log_drop(&mut self.dropfile, self.i, &format!("{:?}", image.result), 1);
+                    return;
+                }
+                if let Some(last) = self.last_id {
+                    let gap = image.info.frame_id.wrapping_sub(last).wrapping_sub(1);
+                    if gap > 0 {
+                        self.dropped += gap as usize;
+                        log_drop(&mut self.dropfile, self.i, "dropped", gap as usize);
+                    }
+                }
+                self.last_id = Some(image.info.frame_id);
+
+                self.i += 1;
+                let layout = self.pattern.and_then(|p| raw_layout(&image, p));
+                if layout != self.layout {
+                    let line = match layout {
+                        Some(l) => l.to_csv(self.i),
+                        None    => bayer::Layout::none_csv(self.i),
+                    };
+                    self.layout_file.write(format!("{}\n", line).as_bytes());
+                }
+                self.layout = layout;
+                if self.writing {
+                    let stamp = time::get_time();
+                    let data = prof!("copy", self.frames.copy(image.data()));
+                    self.writer.write_buffer(data);
+                    self.stampfile.write(format!("{},bluefox{}.dat,{:.9},{},{}\n",
+                                                 self.i,
+                                                 self.i,
+                                                 (stamp.sec as f64
+                                                  + stamp.nsec as f64
+                                                  / 1_000_000_000f64),
+                                                 image.info.frame_nr,
+                                                 image.info.frame_id)
+                                         .as_bytes());
+                } else {
+                    self.writer.decoy();
+                }
+
+                if let Some(n) = self.snapshot.take() {
+                    let stamp = time::get_time();
+                    let (h, w) = image.size();
+                    let mut settings = effective(&self.device).iter().map(|s| s.to_string()).collect::<Vec<_>>();
+                    let (file, bytes) = if let Some(layout) = layout {
+                        settings.push(format!("pattern {}", layout.pattern));
+                        settings.push(format!("bits {}", layout.bits));
+                        (format!("snapshot{}_bluefox.dat", n), image.data().to_vec())
+                    } else if image.data().len() == h*w*3 {
+                        let mut encoded = Vec::with_capacity(h*w);
+                        prof!("encode", PNGEncoder::new(&mut encoded).encode(image.data(), w as u32, h as u32, ColorType::RGB(8)).unwrap());
+                        (format!("snapshot{}_bluefox.png", n), encoded)
+                    } else {
+                        (format!("snapshot{}_bluefox.dat", n), image.data().to_vec())
+                    };
+                    Writer::<[u8]>::with_file(file.clone()).write(&bytes);
+                    self.snapfile.write(format!("{},bluefox,{},{:.9},{},{},{},{}\n",
+                                                n, file,
+                                                stamp.sec as f64 + stamp.nsec as f64 / 1_000_000_000f64,
+                                                image.info.frame_id, w, h, settings.join("; ")).as_bytes());
+                    println!("Bluefox: saved snapshot {} (frame {})", n, self.i);
+                    self.tx.send(CmdFrom::Data(format!("send status bluefox snapshot {} saved", n))).unwrap();
+                }
+
+                if self.kick {
+                    self.kick = false;
+                    //self.device.set_reverse_x(!self.device.get_reverse_x().unwrap());
+                    //self.device.set_reverse_y(!self.device.get_reverse_y().unwrap());
+                    println!("buf = {:?}", image.buf);
+                    if let Some((rgb, size)) = rgb_frame(&image, layout, &self.frames) {
+                        prof!("send to thread", self.png.send((self.i, rgb, size, ColorType::RGB(8))).unwrap());
+                    }
+                }
+                if self.preview.due() {
+                    if let Some((rgb, size)) = rgb_frame(&image, layout, &self.frames) {
+                        self.preview.send(rgb, size, ColorType::RGB(8));
+                    }
+                }
                 /*
                 PNGEncoder::new(&mut f).encode(image.data(),
                                                image.size().1 as u32,
@@ -149,13 +723,15 @@ group_attr!{
             fn teardown(&mut self) {
                 self.png.join();
                 let end = time::now();
-                //device.request_reset();
+                self.device.reset_queue().unwrap();
                 self.device.close().unwrap();
                 let millis = (end - self.start).num_milliseconds() as f64;
                 println!("{} bluefox frames grabbed in {} s ({} FPS)!",
                          self.i,
                          millis/1000.0,
                          1000.0*(self.i as f64)/millis);
+                println!("Bluefox: {} timeouts, {} failed requests, {} dropped frames (see bluefox_drops.csv)",
+                         self.timeouts, self.failed, self.dropped);
             }
         }
     }
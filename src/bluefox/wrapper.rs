@@ -7,6 +7,7 @@ use self::conv::TryFrom;
 use std::slice;
 use std::mem;
 use std::ptr;
+use std::cell::Cell;
 
 macro_rules! dmr_status2result {
     ($code:expr) => { dmr_status2result!($code, ()) };
@@ -228,6 +229,11 @@ pub mod settings {
         }
     }
 
+    /// All the pixel formats (the configuration names are these in lowercase)
+    pub static PIXEL_FORMATS: [PixelFormat; 12] = [PixelFormat::BayerGR8, PixelFormat::BayerGR10, PixelFormat::BayerGR12, PixelFormat::BayerGR16,
+                                                   PixelFormat::RGB8Packed, PixelFormat::BGR8Packed, PixelFormat::BGRA8Packed, PixelFormat::BGR10V2Packed,
+                                                   PixelFormat::RGB8, PixelFormat::BGR8, PixelFormat::BGRa8, PixelFormat::RGB10p32];
+
     custom_derive! {
         #[repr(C)]
         #[derive(Copy, Clone, Debug, TryFrom(i32))]
@@ -240,6 +246,8 @@ pub mod settings {
         }
     }
 
+    pub static COLOR_PROCS: [ColorProc; 5] = [ColorProc::Auto, ColorProc::Raw, ColorProc::ColorBayer, ColorProc::ColorBayerToMono, ColorProc::RawToPlanes];
+
     custom_derive! {
         #[repr(C)]
         #[derive(Copy, Clone, Debug, TryFrom(i32))]
@@ -249,6 +257,8 @@ pub mod settings {
             Cubic           = 2,
         }
     }
+
+    pub static INTERPOLATION_MODES: [InterpolationMode; 3] = [InterpolationMode::NearestNeighbor, InterpolationMode::Linear, InterpolationMode::Cubic];
 }
 
 #[repr(C)]
@@ -312,7 +322,7 @@ struct ChannelData {
 #[repr(C, packed)]
 #[derive(Debug)]
 #[allow(raw_pointer_derive)]
-struct ImageBuffer {
+pub struct ImageBuffer {
     pub bytes_per_pixel : c_int,
     pub height          : c_int,
     pub width           : c_int,
@@ -323,8 +333,64 @@ struct ImageBuffer {
     pub channels        : *mut ChannelData,
 }
 
-struct Image<'a> {
+/// Outcome of an image request (TRequestResult)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RequestResult {
+    Ok,
+    Timeout,
+    Error,
+    RequestAborted,
+    FrameIncomplete,
+    DeviceAccessLost,
+    InconsistentBufferContent,
+    FrameCorrupt,
+    /// One of the "unprocessible request" codes (0x80000000 and up)
+    Other(u32),
+}
+
+impl RequestResult {
+    fn from_raw(r: c_int) -> RequestResult {
+        match r {
+            0     => RequestResult::Ok,
+            1     => RequestResult::Timeout,
+            2     => RequestResult::Error,
+            3     => RequestResult::RequestAborted,
+            4     => RequestResult::FrameIncomplete,
+            5     => RequestResult::DeviceAccessLost,
+            6     => RequestResult::InconsistentBufferContent,
+            7     => RequestResult::FrameCorrupt,
+            other => RequestResult::Other(other as u32),
+        }
+    }
+}
+
+/// RequestResult struct from the C API (result and TRequestState)
+#[repr(C)]
+struct RequestResultStruct {
+    result : c_int,
+    state  : c_int,
+}
+
+/// Frame information for a request (the start of RequestInfo: the driver only fills in as much as
+/// we ask for)
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RequestInfo {
+    /// Frame ID reported by the camera
+    pub frame_id         : c_int,
+    pub expose_start_us  : c_int,
+    pub expose_time_us   : c_int,
+    pub transfer_delay_us: c_int,
+    /// Percentage of the frame that did not arrive
+    pub missing_data_pc  : c_double,
+    /// Frame number counted by the driver
+    pub frame_nr         : c_uint,
+}
+
+pub struct Image<'a> {
     pub buf: ImageBuffer,
+    pub result: RequestResult,
+    pub info: RequestInfo,
     reqnr: c_int,
     parent: &'a Device,
 }
@@ -345,6 +411,9 @@ extern "C" {
     fn DMR_ImageRequestWaitFor(hDrv: HDRV, timeout_ms: c_int, queueNr: c_int, pRequestNr: *mut c_int) -> TDMR_ERROR;
     fn DMR_ImageRequestUnlock(hDrv: HDRV, requestNr: c_int) -> TDMR_ERROR;
     fn DMR_GetImageRequestBuffer(hDrv: HDRV, requestNr: c_int, ppBuffer: *mut *mut ImageBuffer) -> TDMR_ERROR;
+    fn DMR_GetImageRequestResult(hDrv: HDRV, requestNr: c_int, pResult: *mut RequestResultStruct) -> TDMR_ERROR;
+    fn DMR_GetImageRequestInfoEx(hDrv: HDRV, requestNr: c_int, pInfo: *mut RequestInfo, infoSize: libc::size_t, queueNr: c_int, timeout_ms: c_int) -> TDMR_ERROR;
+    fn DMR_ImageRequestReset(hDrv: HDRV, requestCtrl: c_int, mode: c_int) -> TDMR_ERROR;
 
     fn DMR_FindList(hDrv: HDRV, pName: *const c_char, typ: ListType, flags: c_uint, pHList: *mut HLIST) -> TDMR_ERROR;
 
@@ -362,6 +431,8 @@ pub struct Device {
     dmr: HDMR,
     dev: HDEV,
     drv: HDRV,
+    /// Number of requests queued in the driver (see queue())
+    queued: Cell<usize>,
 }
 
 trait ObjProp {
@@ -422,7 +493,7 @@ macro_rules! getset {
 
 impl Device {
     pub fn new() -> Result<Device, TDMR_ERROR> {
-        let mut this = Device { dmr: HDMR(0), dev: HDEV(0), drv: HDRV(0) };
+        let mut this = Device { dmr: HDMR(0), dev: HDEV(0), drv: HDRV(0), queued: Cell::new(0) };
         try!(dmr_status2result!(unsafe { DMR_Init(&mut this.dmr) }));
         let mut n: u32 = 0;
         try!(dmr_status2result!(unsafe { DMR_GetDeviceCount(&mut n as *mut _) }));
@@ -463,12 +534,51 @@ impl Device {
         }
     }
 
-    pub fn request(&self) -> Result<Image, TDMR_ERROR> {
-        try!(dmr_status2result!(unsafe { DMR_ImageRequestSingle(self.drv, 0, ptr::null_mut()) }));
+    /// Queue requests in the driver until `depth` are waiting (or it runs out of request objects)
+    ///
+    /// Returns the number of requests now queued.
+    pub fn queue(&self, depth: usize) -> Result<usize, TDMR_ERROR> {
+        while self.queued.get() < depth {
+            match unsafe { DMR_ImageRequestSingle(self.drv, 0, ptr::null_mut()) } {
+                TDMR_ERROR::DMR_NO_ERROR => self.queued.set(self.queued.get() + 1),
+                TDMR_ERROR::DEV_NO_FREE_REQUEST_AVAILABLE => break, // the rest are still locked by us
+                other => return dmr_status2result!(other, self.queued.get()),
+            }
+        }
+        Ok(self.queued.get())
+    }
+
+    /// Wait up to `timeout_ms` for the next queued request to finish
+    ///
+    /// The request is handed back even if it failed (check Image::result). A timeout comes back as
+    /// Err(DEV_WAIT_FOR_REQUEST_FAILED).
+    pub fn wait(&self, timeout_ms: i32) -> Result<Image, TDMR_ERROR> {
         let mut reqnr: c_int = 0;
-        try!(dmr_status2result!(unsafe { DMR_ImageRequestWaitFor(self.drv, -1, 0, &mut reqnr) }));
-        let mut image_buf = ImageBuffer { bytes_per_pixel: 0, channel_count: 0, height: 0, size: 0, width: 0, channels: ptr::null_mut(), pixel_format: PixelFormat::Mono8, data: ptr::null_mut() };
-        dmr_status2result!(unsafe { DMR_GetImageRequestBuffer(self.drv, reqnr, &mut &mut image_buf as *mut &mut ImageBuffer as *mut *mut ImageBuffer) }, Image { buf: image_buf, reqnr: reqnr, parent: self })
+        try!(dmr_status2result!(unsafe { DMR_ImageRequestWaitFor(self.drv, timeout_ms, 0, &mut reqnr) }));
+        self.queued.set(self.queued.get().saturating_sub(1));
+
+        let mut image = Image {
+            buf: ImageBuffer { bytes_per_pixel: 0, channel_count: 0, height: 0, size: 0, width: 0, channels: ptr::null_mut(), pixel_format: PixelFormat::Mono8, data: ptr::null_mut() },
+            result: RequestResult::Error,
+            info: RequestInfo::default(),
+            reqnr: reqnr,
+            parent: self
+        };
+        let mut result = RequestResultStruct { result: 2, state: 0 };
+        try!(dmr_status2result!(unsafe { DMR_GetImageRequestResult(self.drv, reqnr, &mut result) }));
+        image.result = RequestResult::from_raw(result.result);
+        try!(dmr_status2result!(unsafe { DMR_GetImageRequestInfoEx(self.drv, reqnr, &mut image.info, mem::size_of::<RequestInfo>(), 0, 0) }));
+        if image.result == RequestResult::Ok {
+            try!(dmr_status2result!(unsafe { DMR_GetImageRequestBuffer(self.drv, reqnr, &mut &mut image.buf as *mut &mut ImageBuffer as *mut *mut ImageBuffer) }));
+        }
+        Ok(image)
+    }
+
+    /// Cancel all the queued requests (e.g. after a timeout, or before changing the image format)
+    pub fn reset_queue(&self) -> Result<(), TDMR_ERROR> {
+        try!(dmr_status2result!(unsafe { DMR_ImageRequestReset(self.drv, 0, 0) }));
+        self.queued.set(0);
+        Ok(())
     }
 
     pub fn close(&self) -> Result<(), TDMR_ERROR> {
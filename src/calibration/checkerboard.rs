@@ -0,0 +1,480 @@
+//! Finding checkerboard corners in grayscale frames
+//!
+//! The board is described by its number of inner corners (where four squares meet) in each
+//! direction and the size of its squares. Detection works in three steps:
+//!
+//! 1. Candidate corners are found with the ChESS detector (Bennett and Lasenby, 2014) on a copy of
+//!    the frame shrunk to at most MAX_DETECT pixels across, so that the detector's fixed ring size
+//!    fits the squares of a board that fills a fair part of the image.
+//! 2. The candidates are linked into a grid by growing it outwards from a strong candidate, each
+//!    step predicting the next corner from the ones already found. The grid has to come out as a
+//!    complete `cols`x`rows` block.
+//! 3. The corners are refined to subpixel accuracy in the full-resolution frame, where the image
+//!    gradients around a saddle point are perpendicular to the direction towards it.
+//!
+//! Corners are returned in row-major order, matching Board::points. A checkerboard looks the same
+//! turned around, so the order is fixed by the image: columns run left to right and rows top to
+//! bottom, which is consistent between cameras looking at the board from roughly the same side as
+//! long as the board is held with its columns not too close to vertical.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::f64;
+
+/// Frames wider or taller than this are shrunk (by halves) for detection
+const MAX_DETECT: usize = 800;
+
+/// Radius of the ChESS sampling ring (pixels, in the shrunk frame)
+const RING: f64 = 5.0;
+
+/// Weakest candidate kept, as a fraction of the strongest response
+const MIN_RESPONSE: f32 = 0.1;
+
+/// Strongest candidates tried as the seed of the grid
+const SEEDS: usize = 30;
+
+/// How far a corner may be from its predicted position, as a fraction of the grid spacing
+const TOLERANCE: f64 = 0.35;
+
+/// A checkerboard calibration target
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Board {
+    /// Inner corners along each row
+    pub cols: usize,
+    /// Inner corners along each column
+    pub rows: usize,
+    /// Side of a square (metres)
+    pub square: f64,
+}
+
+impl Board {
+    /// Parse the corner counts (`COLSxROWS`) and the square size (mm)
+    pub fn parse(corners: &str, square_mm: &str) -> Result<Board, String> {
+        let mut dims = corners.splitn(2, 'x').map(str::parse::<usize>);
+        let (cols, rows) = match (dims.next(), dims.next()) {
+            (Some(Ok(c)), Some(Ok(r))) if c >= 2 && r >= 2 => (c, r),
+            _ => return Err(format!("bad board size {:?} (expected COLSxROWS inner corners, e.g. 9x6)", corners)),
+        };
+        match square_mm.parse::<f64>() {
+            Ok(s) if s > 0.0 => Ok(Board { cols: cols, rows: rows, square: s / 1000.0 }),
+            _ => Err(format!("bad square size {:?} (expected millimetres)", square_mm)),
+        }
+    }
+
+    /// Number of corners
+    pub fn len(&self) -> usize {
+        self.cols * self.rows
+    }
+
+    /// Corner positions on the board (metres, z = 0), in row-major order
+    pub fn points(&self) -> Vec<[f64; 3]> {
+        let mut points = Vec::with_capacity(self.len());
+        for j in 0..self.rows {
+            for i in 0..self.cols {
+                points.push([i as f64 * self.square, j as f64 * self.square, 0.0]);
+            }
+        }
+        points
+    }
+}
+
+/// Grayscale image as floats
+struct Gray {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Gray {
+    fn at(&self, x: isize, y: isize) -> f32 {
+        let x = cmp::min(cmp::max(x, 0), self.width as isize - 1) as usize;
+        let y = cmp::min(cmp::max(y, 0), self.height as isize - 1) as usize;
+        self.data[y * self.width + x]
+    }
+
+    /// 3x3 binomial blur
+    fn blur(&self) -> Gray {
+        let mut data = Vec::with_capacity(self.data.len());
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                let row = |y| self.at(x - 1, y) + 2.0 * self.at(x, y) + self.at(x + 1, y);
+                data.push((row(y - 1) + 2.0 * row(y) + row(y + 1)) / 16.0);
+            }
+        }
+        Gray { width: self.width, height: self.height, data: data }
+    }
+
+    /// Half size (averaging 2x2 blocks)
+    fn shrink(&self) -> Gray {
+        let (w, h) = (self.width / 2, self.height / 2);
+        let mut data = Vec::with_capacity(w * h);
+        for y in 0..h as isize {
+            for x in 0..w as isize {
+                data.push((self.at(2*x, 2*y) + self.at(2*x + 1, 2*y) + self.at(2*x, 2*y + 1) + self.at(2*x + 1, 2*y + 1)) / 4.0);
+            }
+        }
+        Gray { width: w, height: h, data: data }
+    }
+}
+
+/// ChESS response at every pixel (zero near the edges)
+fn chess(img: &Gray) -> Vec<f32> {
+    let ring = (0..16).map(|n| {
+        let a = n as f64 * f64::consts::PI / 8.0;
+        ((RING * a.cos()).round() as isize, (RING * a.sin()).round() as isize)
+    }).collect::<Vec<_>>();
+    let r = RING as usize + 1;
+
+    let mut response = vec![0.0; img.data.len()];
+    if img.width <= 2 * r || img.height <= 2 * r {
+        return response;
+    }
+    let mut s = [0.0f32; 16];
+    for y in r..img.height - r {
+        for x in r..img.width - r {
+            let (xi, yi) = (x as isize, y as isize);
+            for (n, &(dx, dy)) in ring.iter().enumerate() {
+                s[n] = img.at(xi + dx, yi + dy);
+            }
+            let mut sum = 0.0;
+            for n in 0..4 {
+                sum += (s[n] + s[n + 8] - s[n + 4] - s[n + 12]).abs();
+            }
+            let mut diff = 0.0;
+            for n in 0..8 {
+                diff += (s[n] - s[n + 8]).abs();
+            }
+            let ring_mean = s.iter().fold(0.0, |a, &b| a + b) / 16.0;
+            let local_mean = (img.at(xi, yi) + img.at(xi - 1, yi) + img.at(xi + 1, yi) + img.at(xi, yi - 1) + img.at(xi, yi + 1)) / 5.0;
+            response[y * img.width + x] = sum - diff - 16.0 * (ring_mean - local_mean).abs();
+        }
+    }
+    response
+}
+
+/// Local maxima of the response, strongest first
+fn candidates(img: &Gray, response: &[f32]) -> Vec<(f64, f64)> {
+    let best = response.iter().fold(0.0f32, |a, &b| a.max(b));
+    if best <= 0.0 {
+        return vec![];
+    }
+    let r = RING as isize;
+    let mut found = vec![];
+    for y in r..img.height as isize - r {
+        for x in r..img.width as isize - r {
+            let v = response[y as usize * img.width + x as usize];
+            if v < MIN_RESPONSE * best {
+                continue;
+            }
+            let mut peak = true;
+            'nms: for dy in -r..r + 1 {
+                for dx in -r..r + 1 {
+                    let w = response[(y + dy) as usize * img.width + (x + dx) as usize];
+                    // ties go to the first pixel in scan order
+                    if w > v || (w == v && (dy, dx) < (0, 0)) {
+                        peak = false;
+                        break 'nms;
+                    }
+                }
+            }
+            if peak {
+                found.push((v, x as f64, y as f64));
+            }
+        }
+    }
+    found.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    found.into_iter().map(|(_, x, y)| (x, y)).collect()
+}
+
+fn dist((ax, ay): (f64, f64), (bx, by): (f64, f64)) -> f64 {
+    ((ax - bx).powi(2) + (ay - by).powi(2)).sqrt()
+}
+
+/// Grow a grid of candidates out from one seed
+///
+/// Returns the candidate index at each grid position that was reached.
+fn grow(points: &[(f64, f64)], seed: usize) -> Option<HashMap<(isize, isize), usize>> {
+    let p = points[seed];
+    let mut near = (0..points.len()).filter(|&i| i != seed).collect::<Vec<_>>();
+    near.sort_by(|&a, &b| dist(p, points[a]).partial_cmp(&dist(p, points[b])).unwrap());
+    near.truncate(8);
+    if near.len() < 2 {
+        return None;
+    }
+
+    // the nearest neighbour is along one axis, and the nearest one roughly perpendicular to it is
+    // along the other
+    let u = (points[near[0]].0 - p.0, points[near[0]].1 - p.1);
+    let lu = dist(p, points[near[0]]);
+    let v = match near[1..].iter()
+                           .map(|&i| (points[i].0 - p.0, points[i].1 - p.1))
+                           .find(|&(x, y)| {
+                               let lv = (x*x + y*y).sqrt();
+                               ((x*u.0 + y*u.1) / (lu * lv)).abs() < 0.5 && lv < 2.0 * lu
+                           }) {
+        Some(v) => v,
+        None => return None,
+    };
+
+    let mut grid = HashMap::new();
+    let mut used = vec![false; points.len()];
+    grid.insert((0, 0), seed);
+    used[seed] = true;
+    let mut queue = VecDeque::new();
+    queue.push_back((0isize, 0isize));
+    while let Some((i, j)) = queue.pop_front() {
+        let here = points[grid[&(i, j)]];
+        for &(di, dj) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            if grid.contains_key(&(i + di, j + dj)) {
+                continue;
+            }
+            // continue the line through the previous corner if there is one, so the prediction
+            // follows the perspective
+            let step = match grid.get(&(i - di, j - dj)) {
+                Some(&k) => (here.0 - points[k].0, here.1 - points[k].1),
+                None     => (di as f64 * u.0 + dj as f64 * v.0, di as f64 * u.1 + dj as f64 * v.1),
+            };
+            let predicted = (here.0 + step.0, here.1 + step.1);
+            let tolerance = TOLERANCE * (step.0 * step.0 + step.1 * step.1).sqrt();
+            let best = (0..points.len())
+                           .filter(|&k| !used[k])
+                           .map(|k| (k, dist(predicted, points[k])))
+                           .filter(|&(_, d)| d < tolerance)
+                           .fold(None, |best: Option<(usize, f64)>, (k, d)| match best {
+                               Some((_, bd)) if bd <= d => best,
+                               _ => Some((k, d)),
+                           });
+            if let Some((k, _)) = best {
+                used[k] = true;
+                grid.insert((i + di, j + dj), k);
+                queue.push_back((i + di, j + dj));
+            }
+        }
+    }
+    Some(grid)
+}
+
+/// Pick a complete `cols`x`rows` block out of a grid and put its corners in the canonical order
+fn arrange(points: &[(f64, f64)], grid: &HashMap<(isize, isize), usize>, board: &Board) -> Option<Vec<(f64, f64)>> {
+    let (i0, i1) = (grid.keys().map(|k| k.0).min().unwrap(), grid.keys().map(|k| k.0).max().unwrap());
+    let (j0, j1) = (grid.keys().map(|k| k.1).min().unwrap(), grid.keys().map(|k| k.1).max().unwrap());
+
+    // the board can be in the grid either way round, and it has to be the only complete block
+    let mut blocks = vec![];
+    for &(ni, nj, transposed) in &[(board.cols, board.rows, false), (board.rows, board.cols, true)] {
+        for a in i0..i1 + 2 - ni as isize {
+            for b in j0..j1 + 2 - nj as isize {
+                let complete = (0..ni as isize).all(|i| (0..nj as isize).all(|j| grid.contains_key(&(a + i, b + j))));
+                if complete {
+                    blocks.push((a, b, transposed));
+                }
+            }
+        }
+        if board.cols == board.rows {
+            break;
+        }
+    }
+    if blocks.len() != 1 {
+        return None;
+    }
+    let (a, b, transposed) = blocks[0];
+
+    // corner (col, row) of the block, before fixing the direction of the axes
+    let at = |c: usize, r: usize| -> (f64, f64) {
+        let key = if transposed { (a + r as isize, b + c as isize) } else { (a + c as isize, b + r as isize) };
+        points[grid[&key]]
+    };
+    let (last_c, last_r) = (board.cols - 1, board.rows - 1);
+    let along_row = (at(last_c, 0).0 - at(0, 0).0, at(last_c, 0).1 - at(0, 0).1);
+    let along_col = (at(0, last_r).0 - at(0, 0).0, at(0, last_r).1 - at(0, 0).1);
+
+    // square boards: rows should run closer to horizontal than columns
+    let swap = board.cols == board.rows && along_row.0.abs() < along_col.0.abs();
+    let (along_row, along_col) = if swap { (along_col, along_row) } else { (along_row, along_col) };
+    // columns left to right...
+    let flip_c = along_row.0 < 0.0;
+    // ...and then rows so that the board is not mirrored (x right, y down, as in the image)
+    let cross = along_row.0 * along_col.1 - along_row.1 * along_col.0;
+    let flip_r = (cross < 0.0) != flip_c;
+
+    let mut corners = Vec::with_capacity(board.len());
+    for r in 0..board.rows {
+        for c in 0..board.cols {
+            let c = if flip_c { last_c - c } else { c };
+            let r = if flip_r { last_r - r } else { r };
+            corners.push(if swap { at(r, c) } else { at(c, r) });
+        }
+    }
+    Some(corners)
+}
+
+/// Move a corner to the point where the surrounding gradients are perpendicular to the direction
+/// towards it (as in OpenCV's cornerSubPix)
+fn refine(img: &Gray, (x, y): (f64, f64), half: isize) -> (f64, f64) {
+    let (mut qx, mut qy) = (x, y);
+    for _ in 0..20 {
+        let (cx, cy) = (qx.round() as isize, qy.round() as isize);
+        let (mut a, mut b, mut c, mut bx, mut by) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for dy in -half..half + 1 {
+            for dx in -half..half + 1 {
+                let (px, py) = (cx + dx, cy + dy);
+                let gx = (img.at(px + 1, py) - img.at(px - 1, py)) as f64 / 2.0;
+                let gy = (img.at(px, py + 1) - img.at(px, py - 1)) as f64 / 2.0;
+                let w = (-((dx * dx + dy * dy) as f64) / (half * half) as f64).exp();
+                let (gxx, gxy, gyy) = (w * gx * gx, w * gx * gy, w * gy * gy);
+                a += gxx;
+                b += gxy;
+                c += gyy;
+                bx += gxx * px as f64 + gxy * py as f64;
+                by += gxy * px as f64 + gyy * py as f64;
+            }
+        }
+        let det = a * c - b * b;
+        if det.abs() < 1e-9 {
+            break;
+        }
+        let (nx, ny) = ((c * bx - b * by) / det, (a * by - b * bx) / det);
+        let moved = dist((nx, ny), (qx, qy));
+        if dist((nx, ny), (x, y)) > half as f64 {
+            // wandered off: keep the original estimate
+            return (x, y);
+        }
+        qx = nx;
+        qy = ny;
+        if moved < 0.01 {
+            break;
+        }
+    }
+    (qx, qy)
+}
+
+/// Find the board's corners in an 8-bit grayscale frame
+///
+/// Returns the subpixel corner positions in row-major order (see Board::points), or why the board
+/// was not found.
+pub fn find_corners(gray: &[u8], width: usize, height: usize, board: &Board) -> Result<Vec<(f64, f64)>, String> {
+    assert_eq!(gray.len(), width * height);
+    let full = Gray { width: width, height: height, data: gray.iter().map(|&g| g as f32).collect() }.blur();
+
+    let mut small = Gray { width: full.width, height: full.height, data: full.data.clone() };
+    let mut scale = 1.0;
+    while cmp::max(small.width, small.height) > MAX_DETECT {
+        small = small.shrink();
+        scale *= 2.0;
+    }
+    let points = candidates(&small, &chess(&small));
+    if points.len() < board.len() {
+        return Err(format!("only {} corner candidates for {} corners", points.len(), board.len()));
+    }
+
+    let mut largest = 0;
+    let mut corners = None;
+    for seed in 0..cmp::min(SEEDS, points.len()) {
+        if let Some(grid) = grow(&points, seed) {
+            largest = cmp::max(largest, grid.len());
+            if grid.len() >= board.len() {
+                if let Some(c) = arrange(&points, &grid, board) {
+                    corners = Some(c);
+                    break;
+                }
+            }
+        }
+    }
+    let corners = match corners {
+        Some(c) => c,
+        None    => return Err(format!("no {}x{} grid among {} candidates (largest grid {} corners)",
+                                      board.cols, board.rows, points.len(), largest)),
+    };
+
+    // refine in a window about a third of the smallest spacing between neighbouring corners
+    let mut spacing = f64::INFINITY;
+    for r in 0..board.rows {
+        for c in 0..board.cols {
+            let here = corners[r * board.cols + c];
+            if c + 1 < board.cols { spacing = spacing.min(dist(here, corners[r * board.cols + c + 1])); }
+            if r + 1 < board.rows { spacing = spacing.min(dist(here, corners[(r + 1) * board.cols + c])); }
+        }
+    }
+    let half = cmp::min(cmp::max((spacing * scale / 3.0).round() as isize, 2), 15);
+    Ok(corners.into_iter()
+              .map(|(x, y)| refine(&full, (x * scale + (scale - 1.0) / 2.0, y * scale + (scale - 1.0) / 2.0), half))
+              .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render a board (with a white margin) turned by `angle` radians, with its first inner corner
+    /// at `origin` and squares `square` pixels across, antialiased 4x4
+    ///
+    /// Returns the image and the true corner positions in row-major order.
+    fn render(board: &Board, (width, height): (usize, usize), origin: (f64, f64), square: f64, angle: f64) -> (Vec<u8>, Vec<(f64, f64)>) {
+        let (s, c) = angle.sin_cos();
+        let mut image = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut dark = 0;
+                for sy in 0..4 {
+                    for sx in 0..4 {
+                        let dx = x as f64 + (sx as f64 + 0.5) / 4.0 - 0.5 - origin.0;
+                        let dy = y as f64 + (sy as f64 + 0.5) / 4.0 - 0.5 - origin.1;
+                        let (u, v) = ((c * dx + s * dy) / square, (-s * dx + c * dy) / square);
+                        let inside = u >= -1.0 && u < board.cols as f64 && v >= -1.0 && v < board.rows as f64;
+                        if inside && (u.floor() as i64 + v.floor() as i64) % 2 == 0 {
+                            dark += 1;
+                        }
+                    }
+                }
+                image.push((230 - dark * 200 / 16) as u8);
+            }
+        }
+        let mut corners = vec![];
+        for r in 0..board.rows {
+            for i in 0..board.cols {
+                let (u, v) = (i as f64 * square, r as f64 * square);
+                corners.push((origin.0 + c * u - s * v, origin.1 + s * u + c * v));
+            }
+        }
+        (image, corners)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Board::parse("9x6", "25").unwrap(), Board { cols: 9, rows: 6, square: 0.025 });
+        assert!(Board::parse("9", "25").is_err());
+        assert!(Board::parse("1x6", "25").is_err());
+        assert!(Board::parse("9x6", "-1").is_err());
+    }
+
+    #[test]
+    fn finds_rendered_board() {
+        let board = Board { cols: 7, rows: 5, square: 0.03 };
+        for &angle in &[0.0, 0.1, -0.2] {
+            let (image, truth) = render(&board, (640, 480), (190.0, 150.0), 40.0, angle);
+            let corners = find_corners(&image, 640, 480, &board).unwrap();
+            assert_eq!(corners.len(), board.len());
+            for (found, truth) in corners.iter().zip(&truth) {
+                assert!(dist(*found, *truth) < 0.25, "corner at {:?} should be at {:?} (turned {})", found, truth, angle);
+            }
+        }
+    }
+
+    #[test]
+    fn finds_board_in_large_frame() {
+        // shrunk for detection and refined at full size
+        let board = Board { cols: 6, rows: 4, square: 0.03 };
+        let (image, truth) = render(&board, (1280, 960), (400.0, 300.0), 90.0, 0.05);
+        let corners = find_corners(&image, 1280, 960, &board).unwrap();
+        for (found, truth) in corners.iter().zip(&truth) {
+            assert!(dist(*found, *truth) < 0.3, "corner at {:?} should be at {:?}", found, truth);
+        }
+    }
+
+    #[test]
+    fn wrong_board_size() {
+        let (image, _) = render(&Board { cols: 7, rows: 5, square: 0.03 }, (640, 480), (190.0, 150.0), 40.0, 0.0);
+        assert!(find_corners(&image, 640, 480, &Board { cols: 8, rows: 5, square: 0.03 }).is_err());
+        assert!(find_corners(&vec![128; 640 * 480], 640, 480, &Board { cols: 7, rows: 5, square: 0.03 }).is_err());
+    }
+}
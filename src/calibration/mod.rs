@@ -0,0 +1,64 @@
+//! Camera calibration
+//!
+//! Intrinsics, distortion and the transform from the Structure Sensor to the Bluefox are solved
+//! offline from checkerboard snapshots (see the camera calibration flow, which takes them, and
+//! examples/calibrate.rs, which turns a session's snapshots into a calibration file). The model
+//! and file format are in the model module, corner detection in checkerboard and the solver in
+//! solve. The register module uses a calibration to colour depth frames with the Bluefox image
+//! (see examples/colorcloud.rs). Like structure/geometry.rs, all of them only depend on std, so the
+//! examples can include them by path. The service itself only needs the model (to check and attach
+//! calibration files); the rest is compiled here just for its tests.
+//!
+//! The calibration in use is read from `data/calibration.csv` by default, or from the path in the
+//! `NRI_CALIBRATION` environment variable, and can be changed at runtime with the `calibration` CLI
+//! command. Every flow copies it into its session directory as `calibration.csv` when it starts,
+//! so recordings carry the calibration they were taken with.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+#[allow(dead_code)] pub mod model;
+#[cfg(test)] #[allow(dead_code)] mod checkerboard;
+#[cfg(test)] #[allow(dead_code)] mod solve;
+#[cfg(test)] #[allow(dead_code)] mod register;
+
+const DEFAULT_FILE: &'static str = "data/calibration.csv";
+
+lazy_static! {
+    /// Path of the calibration file attached to new sessions
+    static ref FILE: RwLock<String> = RwLock::new(env::var("NRI_CALIBRATION").unwrap_or(DEFAULT_FILE.to_owned()));
+}
+
+/// Change the calibration file attached to new sessions
+///
+/// The file is checked (and its cameras printed) before it is accepted.
+pub fn set(path: &str) -> Result<(), String> {
+    let cameras = try!(model::load(path));
+    for camera in &cameras {
+        println!("{}", camera);
+    }
+    let absolute = try!(fs::canonicalize(path).map_err(|e| format!("could not find {}: {}", path, e)));
+    *FILE.write().unwrap() = absolute.to_string_lossy().into_owned();
+    Ok(())
+}
+
+/// Get the path of the calibration file currently in use
+pub fn file() -> String {
+    FILE.read().unwrap().clone()
+}
+
+/// Copy the calibration file into a session directory
+///
+/// A missing or unreadable calibration is reported but does not stop the session.
+pub fn attach<P: AsRef<Path>>(dir: P) {
+    let file = file();
+    if let Err(e) = model::load(&file) {
+        errorln!("No camera calibration attached to the session ({})", e);
+        return;
+    }
+    if let Err(e) = fs::copy(&file, dir.as_ref().join("calibration.csv")) {
+        errorln!("Could not copy camera calibration {} into the session: {}", file, e);
+    }
+}
@@ -0,0 +1,348 @@
+//! Calibrated camera models and the calibration file
+//!
+//! Each camera has pinhole intrinsics, Brown-Conrady distortion (k1, k2, p1, p2, k3, as in OpenCV)
+//! and a pose relative to the reference camera, which is the Structure Sensor's IR camera (the
+//! depth stream is computed from it, so depth pixels are in the same frame). Poses map reference
+//! coordinates into the camera's own: `p_cam = R * p_ref + t`, with R given as a rotation vector
+//! (axis times angle, in radians) and t in metres.
+//!
+//! The calibration file is a CSV with one line per camera (see HEADER), written by
+//! examples/calibrate.rs.
+
+use std::f64;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+
+/// Column names of the calibration file
+pub const HEADER: &'static str = "camera,width,height,fx,fy,cx,cy,k1,k2,p1,p2,k3,rx,ry,rz,tx,ty,tz,rms";
+
+pub type Vec3 = [f64; 3];
+pub type Mat3 = [[f64; 3]; 3];
+
+fn dot(a: Vec3, b: Vec3) -> f64 {
+    a[0]*b[0] + a[1]*b[1] + a[2]*b[2]
+}
+
+pub fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [a[1]*b[2] - a[2]*b[1], a[2]*b[0] - a[0]*b[2], a[0]*b[1] - a[1]*b[0]]
+}
+
+fn mat_vec(m: &Mat3, v: Vec3) -> Vec3 {
+    [dot(m[0], v), dot(m[1], v), dot(m[2], v)]
+}
+
+fn mat_mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut c = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            c[i][j] = a[i][0]*b[0][j] + a[i][1]*b[1][j] + a[i][2]*b[2][j];
+        }
+    }
+    c
+}
+
+fn transpose(m: &Mat3) -> Mat3 {
+    [[m[0][0], m[1][0], m[2][0]],
+     [m[0][1], m[1][1], m[2][1]],
+     [m[0][2], m[1][2], m[2][2]]]
+}
+
+/// Rotation matrix of a rotation vector (Rodrigues' formula)
+pub fn rotation_matrix(r: Vec3) -> Mat3 {
+    let theta = dot(r, r).sqrt();
+    if theta < 1e-12 {
+        // first order, so that small rotations still have a derivative
+        return [[1.0, -r[2], r[1]], [r[2], 1.0, -r[0]], [-r[1], r[0], 1.0]];
+    }
+    let k = [r[0] / theta, r[1] / theta, r[2] / theta];
+    let (s, c) = theta.sin_cos();
+    let v = 1.0 - c;
+    [[c + k[0]*k[0]*v,        k[0]*k[1]*v - k[2]*s,  k[0]*k[2]*v + k[1]*s],
+     [k[1]*k[0]*v + k[2]*s,   c + k[1]*k[1]*v,       k[1]*k[2]*v - k[0]*s],
+     [k[2]*k[0]*v - k[1]*s,   k[2]*k[1]*v + k[0]*s,  c + k[2]*k[2]*v]]
+}
+
+/// Rotation vector of a rotation matrix (inverse of rotation_matrix)
+pub fn rotation_vector(m: &Mat3) -> Vec3 {
+    let c = (m[0][0] + m[1][1] + m[2][2] - 1.0) / 2.0;
+    let axis = [m[2][1] - m[1][2], m[0][2] - m[2][0], m[1][0] - m[0][1]];
+    let s = dot(axis, axis).sqrt() / 2.0;
+    // (acos(c) loses precision for small angles)
+    let theta = s.atan2(c);
+    if theta < 1e-9 {
+        return [axis[0] / 2.0, axis[1] / 2.0, axis[2] / 2.0];
+    }
+    if s > 1e-6 || theta < f64::consts::FRAC_PI_2 {
+        let f = theta / (2.0 * s);
+        return [axis[0] * f, axis[1] * f, axis[2] * f];
+    }
+    // theta is close to pi: the axis is the column of (R + I)/2 with the largest diagonal entry
+    let i = (0..3).fold(0, |best, i| if m[i][i] > m[best][best] { i } else { best });
+    let mut k = [0.0; 3];
+    for j in 0..3 {
+        k[j] = (m[j][i] + m[i][j]) / 2.0 + if i == j { 1.0 } else { 0.0 };
+    }
+    let n = dot(k, k).sqrt();
+    [k[0] / n * theta, k[1] / n * theta, k[2] / n * theta]
+}
+
+/// Rigid transform (rotation vector and translation)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Pose {
+    pub r: Vec3,
+    pub t: Vec3,
+}
+
+impl Pose {
+    pub fn identity() -> Pose {
+        Pose { r: [0.0; 3], t: [0.0; 3] }
+    }
+
+    pub fn from_matrix(m: &Mat3, t: Vec3) -> Pose {
+        Pose { r: rotation_vector(m), t: t }
+    }
+
+    pub fn matrix(&self) -> Mat3 {
+        rotation_matrix(self.r)
+    }
+
+    pub fn apply(&self, p: Vec3) -> Vec3 {
+        let q = mat_vec(&self.matrix(), p);
+        [q[0] + self.t[0], q[1] + self.t[1], q[2] + self.t[2]]
+    }
+
+    /// The transform that applies `first` and then `self`
+    pub fn after(&self, first: &Pose) -> Pose {
+        let m = self.matrix();
+        let t = mat_vec(&m, first.t);
+        Pose::from_matrix(&mat_mul(&m, &first.matrix()), [t[0] + self.t[0], t[1] + self.t[1], t[2] + self.t[2]])
+    }
+
+    pub fn inverse(&self) -> Pose {
+        let mt = transpose(&self.matrix());
+        let t = mat_vec(&mt, self.t);
+        Pose::from_matrix(&mt, [-t[0], -t[1], -t[2]])
+    }
+}
+
+/// A calibrated camera
+#[derive(Debug, Clone, PartialEq)]
+pub struct Camera {
+    /// Stream name (`structure_ir`, `structure` or `bluefox`)
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    /// Focal length (pixels)
+    pub fx: f64,
+    pub fy: f64,
+    /// Principal point (pixels)
+    pub cx: f64,
+    pub cy: f64,
+    /// k1, k2, p1, p2, k3
+    pub distortion: [f64; 5],
+    /// Transform from the reference camera's frame to this one's
+    pub pose: Pose,
+    /// RMS reprojection error of the calibration (pixels)
+    pub rms: f64,
+}
+
+impl Camera {
+    /// Pixel coordinates of a point in this camera's frame (None if it is behind the camera)
+    pub fn project(&self, p: Vec3) -> Option<(f64, f64)> {
+        if p[2] <= 0.0 {
+            return None;
+        }
+        let (x, y) = (p[0] / p[2], p[1] / p[2]);
+        let d = self.distortion;
+        let (k1, k2, p1, p2, k3) = (d[0], d[1], d[2], d[3], d[4]);
+        let r2 = x*x + y*y;
+        let radial = 1.0 + r2*(k1 + r2*(k2 + r2*k3));
+        let xd = x*radial + 2.0*p1*x*y + p2*(r2 + 2.0*x*x);
+        let yd = y*radial + p1*(r2 + 2.0*y*y) + 2.0*p2*x*y;
+        Some((self.fx*xd + self.cx, self.fy*yd + self.cy))
+    }
+
+    /// Pixel coordinates of a point in the reference camera's frame
+    pub fn project_from_reference(&self, p: Vec3) -> Option<(f64, f64)> {
+        self.project(self.pose.apply(p))
+    }
+
+    /// Direction of the ray through a pixel, as a point at z = 1 in this camera's frame
+    ///
+    /// The distortion is undone by fixed-point iteration, which converges for the mild distortion
+    /// of these lenses.
+    pub fn unproject(&self, (u, v): (f64, f64)) -> Vec3 {
+        let (xd, yd) = ((u - self.cx) / self.fx, (v - self.cy) / self.fy);
+        let d = self.distortion;
+        let (k1, k2, p1, p2, k3) = (d[0], d[1], d[2], d[3], d[4]);
+        let (mut x, mut y) = (xd, yd);
+        for _ in 0..10 {
+            let r2 = x*x + y*y;
+            let radial = 1.0 + r2*(k1 + r2*(k2 + r2*k3));
+            x = (xd - 2.0*p1*x*y - p2*(r2 + 2.0*x*x)) / radial;
+            y = (yd - p1*(r2 + 2.0*y*y) - 2.0*p2*x*y) / radial;
+        }
+        [x, y, 1.0]
+    }
+
+    /// The same camera at another resolution, assuming the image is scaled by the width ratio
+    /// (and cropped or padded at the bottom to the new height)
+    pub fn scaled(&self, name: &str, width: usize, height: usize) -> Camera {
+        let s = width as f64 / self.width as f64;
+        Camera {
+            name   : name.to_owned(),
+            width  : width,
+            height : height,
+            fx     : self.fx * s,
+            fy     : self.fy * s,
+            cx     : (self.cx + 0.5) * s - 0.5,
+            cy     : (self.cy + 0.5) * s - 0.5,
+            .. self.clone()
+        }
+    }
+
+    /// One line of the calibration file (without the newline)
+    pub fn to_csv(&self) -> String {
+        let d = self.distortion;
+        let (r, t) = (self.pose.r, self.pose.t);
+        format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                self.name, self.width, self.height, self.fx, self.fy, self.cx, self.cy,
+                d[0], d[1], d[2], d[3], d[4], r[0], r[1], r[2], t[0], t[1], t[2], self.rms)
+    }
+}
+
+impl fmt::Display for Camera {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = self.distortion;
+        write!(f, "{} {}x{}, f = ({:.1}, {:.1}), c = ({:.1}, {:.1}), k = ({:.4}, {:.4}, {:.4}), p = ({:.4}, {:.4}), \
+                   t = ({:.1}, {:.1}, {:.1}) mm, R = {:.2}°, RMS {:.3} px",
+               self.name, self.width, self.height, self.fx, self.fy, self.cx, self.cy,
+               d[0], d[1], d[4], d[2], d[3],
+               self.pose.t[0] * 1000.0, self.pose.t[1] * 1000.0, self.pose.t[2] * 1000.0,
+               dot(self.pose.r, self.pose.r).sqrt().to_degrees(), self.rms)
+    }
+}
+
+/// Read a calibration file
+pub fn load(path: &str) -> Result<Vec<Camera>, String> {
+    let file = try!(File::open(path).map_err(|e| format!("could not open {}: {}", path, e)));
+    let mut cameras = vec![];
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = try!(line.map_err(|e| format!("could not read {}: {}", path, e)));
+        if line.trim().is_empty() || line.trim() == HEADER {
+            continue;
+        }
+        let fields = line.trim().split(',').map(str::trim).collect::<Vec<_>>();
+        if fields.len() != 19 {
+            return Err(format!("{}:{}: expected 19 fields, found {}", path, n + 1, fields.len()));
+        }
+        let mut numbers = [0.0; 18];
+        for i in 0..18 {
+            numbers[i] = try!(fields[i + 1].parse()
+                                           .map_err(|_| format!("{}:{}: bad number {:?}", path, n + 1, fields[i + 1])));
+        }
+        cameras.push(Camera {
+            name       : fields[0].to_owned(),
+            width      : numbers[0] as usize,
+            height     : numbers[1] as usize,
+            fx         : numbers[2],
+            fy         : numbers[3],
+            cx         : numbers[4],
+            cy         : numbers[5],
+            distortion : [numbers[6], numbers[7], numbers[8], numbers[9], numbers[10]],
+            pose       : Pose { r: [numbers[11], numbers[12], numbers[13]], t: [numbers[14], numbers[15], numbers[16]] },
+            rms        : numbers[17],
+        });
+    }
+    if cameras.is_empty() {
+        return Err(format!("{}: no cameras", path));
+    }
+    Ok(cameras)
+}
+
+/// Look up a camera by stream name
+pub fn find<'a>(cameras: &'a [Camera], name: &str) -> Option<&'a Camera> {
+    cameras.iter().find(|c| c.name == name)
+}
+
+/// Write a calibration file
+pub fn write<W: Write>(w: &mut W, cameras: &[Camera]) -> io::Result<()> {
+    try!(writeln!(w, "{}", HEADER));
+    for camera in cameras {
+        try!(writeln!(w, "{}", camera.to_csv()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3, tolerance: f64) -> bool {
+        (0..3).all(|i| (a[i] - b[i]).abs() < tolerance)
+    }
+
+    fn close_matrix(a: &Mat3, b: &Mat3, tolerance: f64) -> bool {
+        (0..3).all(|i| close(a[i], b[i], tolerance))
+    }
+
+    fn along(axis: Vec3, theta: f64) -> Vec3 {
+        let n = dot(axis, axis).sqrt();
+        [axis[0] / n * theta, axis[1] / n * theta, axis[2] / n * theta]
+    }
+
+    #[test]
+    fn rotation_round_trip() {
+        for &theta in &[0.0, 1e-14, 1e-10, 1e-6, 0.3, 1.0, 2.5, 3.0] {
+            for &axis in &[[1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [1.0, 2.0, 3.0], [-0.3, 0.1, -2.0]] {
+                let r = along(axis, theta);
+                let back = rotation_vector(&rotation_matrix(r));
+                assert!(close(r, back, 1e-9), "{:?} came back as {:?}", r, back);
+            }
+        }
+    }
+
+    #[test]
+    fn rotation_round_trip_near_pi() {
+        // the rotation vector is only defined up to sign at pi, so compare the matrices (and the
+        // angle)
+        for &theta in &[f64::consts::PI - 1e-3, f64::consts::PI - 1e-8, f64::consts::PI] {
+            for &axis in &[[1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [1.0, 2.0, 3.0], [-0.3, 0.1, -2.0]] {
+                let m = rotation_matrix(along(axis, theta));
+                let back = rotation_vector(&m);
+                assert!((dot(back, back).sqrt() - theta).abs() < 1e-6, "angle of {:?} is not {}", back, theta);
+                assert!(close_matrix(&rotation_matrix(back), &m, 1e-6), "{:?} does not give {:?}", back, m);
+            }
+        }
+    }
+
+    #[test]
+    fn rotation_matrix_is_orthonormal() {
+        let m = rotation_matrix([0.4, -1.2, 0.7]);
+        let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        assert!(close_matrix(&mat_mul(&m, &transpose(&m)), &identity, 1e-12));
+    }
+
+    #[test]
+    fn pose_inverse() {
+        let pose = Pose { r: [0.1, -0.4, 0.25], t: [0.05, -0.02, 0.3] };
+        for &composed in &[pose.after(&pose.inverse()), pose.inverse().after(&pose)] {
+            assert!(close(composed.r, [0.0; 3], 1e-12), "{:?} is not the identity", composed);
+            assert!(close(composed.t, [0.0; 3], 1e-12), "{:?} is not the identity", composed);
+        }
+        let p = [0.3, 0.2, 1.5];
+        assert!(close(pose.inverse().apply(pose.apply(p)), p, 1e-12));
+    }
+
+    #[test]
+    fn pose_after() {
+        let (a, b) = (Pose { r: [0.1, -0.4, 0.25], t: [0.05, -0.02, 0.3] }, Pose { r: [-1.0, 0.2, 0.0], t: [0.0, 0.1, -0.1] });
+        let p = [0.3, 0.2, 1.5];
+        assert!(close(a.after(&b).apply(p), a.apply(b.apply(p)), 1e-12));
+        for &composed in &[a.after(&Pose::identity()), Pose::identity().after(&a)] {
+            assert!(close(composed.r, a.r, 1e-12), "{:?} is not {:?}", composed, a);
+            assert!(close(composed.t, a.t, 1e-12), "{:?} is not {:?}", composed, a);
+        }
+    }
+}
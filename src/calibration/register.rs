@@ -0,0 +1,190 @@
+//! Colouring depth frames from another camera's image
+//!
+//! Every depth pixel is back-projected to a 3D point with the depth camera's model, moved into the
+//! colour camera's frame with the two cameras' poses, and projected into the colour image, where
+//! its colour is sampled bilinearly. The colour camera sees the scene from a few centimetres away,
+//! so some of the surface behind edges is hidden from it; a coarse depth buffer in the colour image
+//! catches those points, which get no colour rather than the colour of whatever is in front.
+
+use std::{cmp, f64};
+use super::model::{Camera, Vec3};
+
+/// Pixels of the colour image per depth buffer cell (in each direction)
+const ZBUFFER_CELL: usize = 4;
+
+/// How far behind the nearest point in its depth buffer cell a point can be and still be seen
+/// (metres)
+const OCCLUSION_MARGIN: f64 = 0.02;
+
+/// A depth frame with colours from another camera
+pub struct Registered {
+    pub width: usize,
+    pub height: usize,
+    /// Point seen by each depth pixel, in the depth camera's frame (metres), if it has a reading
+    pub points: Vec<Option<Vec3>>,
+    /// Colour of each depth pixel, if the colour camera saw its point
+    pub colors: Vec<Option<[u8; 3]>>,
+}
+
+impl Registered {
+    /// The colours as an RGB image the size of the depth frame (black where there is no colour)
+    pub fn image(&self) -> Vec<u8> {
+        self.colors.iter().flat_map(|c| c.unwrap_or([0, 0, 0]).to_vec()).collect()
+    }
+}
+
+/// Sample an RGB image at a subpixel position (None outside the image)
+fn sample(rgb: &[u8], width: usize, height: usize, (u, v): (f64, f64)) -> Option<[u8; 3]> {
+    if u < 0.0 || v < 0.0 || u > (width - 1) as f64 || v > (height - 1) as f64 {
+        return None;
+    }
+    let (x0, y0) = (u.floor() as usize, v.floor() as usize);
+    let (x1, y1) = (cmp::min(x0 + 1, width - 1), cmp::min(y0 + 1, height - 1));
+    let (fx, fy) = (u - x0 as f64, v - y0 as f64);
+    let mut color = [0; 3];
+    for c in 0..3 {
+        let at = |x: usize, y: usize| rgb[(y * width + x) * 3 + c] as f64;
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        color[c] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+    Some(color)
+}
+
+/// Colour a depth frame from an RGB image
+///
+/// `depth` is in units of `depth_scale` metres (zero for no reading), and `rgb` is interleaved
+/// 8-bit RGB. The sizes must match the cameras.
+pub fn register(depth: &[u16], depth_camera: &Camera, depth_scale: f64,
+                rgb: &[u8], color_camera: &Camera) -> Registered {
+    let (w, h) = (depth_camera.width, depth_camera.height);
+    let (cw, ch) = (color_camera.width, color_camera.height);
+    assert_eq!(depth.len(), w * h);
+    assert_eq!(rgb.len(), cw * ch * 3);
+
+    // depth camera frame -> reference frame -> colour camera frame
+    let to_color = color_camera.pose.after(&depth_camera.pose.inverse());
+
+    let mut points = Vec::with_capacity(w * h);
+    let mut seen = Vec::with_capacity(w * h);
+    for v in 0..h {
+        for u in 0..w {
+            let d = depth[v * w + u];
+            if d == 0 {
+                points.push(None);
+                seen.push(None);
+                continue;
+            }
+            let z = d as f64 * depth_scale;
+            let ray = depth_camera.unproject((u as f64, v as f64));
+            let p = [ray[0] * z, ray[1] * z, z];
+            let q = to_color.apply(p);
+            points.push(Some(p));
+            seen.push(color_camera.project(q).map(|uv| (uv, q[2])));
+        }
+    }
+
+    // nearest point in each cell of the colour image
+    let (zw, zh) = ((cw + ZBUFFER_CELL - 1) / ZBUFFER_CELL, (ch + ZBUFFER_CELL - 1) / ZBUFFER_CELL);
+    let cell = |(u, v): (f64, f64)| -> Option<usize> {
+        if u < 0.0 || v < 0.0 || u >= cw as f64 || v >= ch as f64 {
+            None
+        } else {
+            Some((v as usize / ZBUFFER_CELL) * zw + u as usize / ZBUFFER_CELL)
+        }
+    };
+    let mut zbuffer = vec![f64::INFINITY; zw * zh];
+    for &(uv, z) in seen.iter().filter_map(|s| s.as_ref()) {
+        if let Some(i) = cell(uv) {
+            zbuffer[i] = zbuffer[i].min(z);
+        }
+    }
+
+    let colors = seen.iter().map(|s| match *s {
+        Some((uv, z)) => match cell(uv) {
+            Some(i) if z <= zbuffer[i] + OCCLUSION_MARGIN => sample(rgb, cw, ch, uv),
+            _ => None,
+        },
+        None => None,
+    }).collect();
+
+    Registered { width: w, height: h, points: points, colors: colors }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::model::Pose;
+
+    /// A 40x30 pinhole camera, `t` metres from the reference camera
+    fn camera(name: &str, t: Vec3) -> Camera {
+        Camera {
+            name       : name.to_owned(),
+            width      : 40,
+            height     : 30,
+            fx         : 40.0,
+            fy         : 40.0,
+            cx         : 19.5,
+            cy         : 14.5,
+            distortion : [0.0; 5],
+            pose       : Pose { r: [0.0; 3], t: t },
+            rms        : 0.0,
+        }
+    }
+
+    /// Colour image with the column and row in the red and green channels
+    fn gradient() -> Vec<u8> {
+        let mut rgb = vec![];
+        for y in 0..30 {
+            for x in 0..40 {
+                rgb.push(x as u8 * 6);
+                rgb.push(y as u8 * 8);
+                rgb.push(100);
+            }
+        }
+        rgb
+    }
+
+    /// A plane 1 m away, with a strip in columns 20..24 at `near` metres (in mm)
+    fn scene(near: u16) -> Vec<u16> {
+        (0..30 * 40).map(|i| if i % 40 >= 20 && i % 40 < 24 { near } else { 1000 }).collect()
+    }
+
+    #[test]
+    fn plane() {
+        // the colour camera is 5 cm to the right, which moves the plane 2 pixels to the left
+        let (depth, color) = (camera("structure", [0.0; 3]), camera("bluefox", [-0.05, 0.0, 0.0]));
+        let reg = register(&[1000; 30 * 40], &depth, 0.001, &gradient(), &color);
+        assert_eq!((reg.width, reg.height), (40, 30));
+
+        let p = reg.points[5 * 40 + 10].unwrap();
+        assert!((p[0] + 0.2375).abs() < 1e-9 && (p[1] + 0.2375).abs() < 1e-9 && (p[2] - 1.0).abs() < 1e-9, "{:?}", p);
+        assert_eq!(reg.colors[5 * 40 + 10], Some([48, 40, 100]));
+        assert_eq!(reg.colors[29 * 40 + 39], Some([222, 232, 100]));
+        // off the left edge of the colour image
+        assert_eq!(reg.colors[5 * 40 + 1], None);
+
+        // no reading, no point or colour
+        let mut holes = [1000; 30 * 40];
+        holes[5 * 40 + 10] = 0;
+        let reg = register(&holes, &depth, 0.001, &gradient(), &color);
+        assert_eq!((reg.points[5 * 40 + 10], reg.colors[5 * 40 + 10]), (None, None));
+    }
+
+    #[test]
+    fn occlusion() {
+        let (depth, color) = (camera("structure", [0.0; 3]), camera("bluefox", [-0.05, 0.0, 0.0]));
+
+        // the strip at 50 cm lands 4 pixels to the left, in columns 16..20 of the colour image,
+        // where it hides the plane seen by depth columns 18 and 19
+        let reg = register(&scene(500), &depth, 0.001, &gradient(), &color);
+        assert_eq!(reg.colors[5 * 40 + 21], Some([102, 40, 100]));
+        assert_eq!(reg.colors[5 * 40 + 19], None);
+        assert_eq!(reg.colors[5 * 40 + 14], Some([72, 40, 100]));
+        assert_eq!(reg.colors[5 * 40 + 26], Some([144, 40, 100]));
+
+        // a strip just 1 cm in front is within the margin, so the plane behind it still shows
+        let reg = register(&scene(990), &depth, 0.001, &gradient(), &color);
+        assert_eq!(reg.colors[5 * 40 + 19], Some([102, 40, 100]));
+    }
+}
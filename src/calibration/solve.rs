@@ -0,0 +1,542 @@
+//! Solving for camera intrinsics, distortion and the transform between two cameras
+//!
+//! Each view is a set of board corners found in one frame. For a single camera:
+//!
+//! 1. A homography from the board plane to the image is fitted to each view (normalized DLT).
+//! 2. With the principal point at the centre of the image, each homography gives two linear
+//!    constraints on the focal lengths (Zhang's method with the principal point and skew fixed),
+//!    and then the pose of the board in that view.
+//! 3. Everything (focal lengths, principal point, k1, k2, p1, p2 and the board poses) is refined
+//!    by Levenberg-Marquardt on the reprojection error. k3 is left at zero: it is poorly
+//!    determined unless the views reach far into the corners.
+//!
+//! For a pair of calibrated cameras, the transform between them is first estimated from the board
+//! poses in views that both cameras saw, and then refined together with those poses on the
+//! reprojection error in both images (the intrinsics stay fixed).
+
+use std::f64;
+use super::model::{self, Camera, Pose, Vec3, Mat3};
+
+/// One view of the board: corner positions on the board (metres) and in the image (pixels)
+pub struct View {
+    pub board: Vec<Vec3>,
+    pub image: Vec<(f64, f64)>,
+}
+
+/// Result of calibrating one camera
+pub struct Intrinsic {
+    pub camera: Camera,
+    /// Pose of the board in each view (board coordinates to camera coordinates)
+    pub poses: Vec<Pose>,
+    /// RMS reprojection error of each view (pixels)
+    pub errors: Vec<f64>,
+}
+
+/// Result of calibrating a pair of cameras
+pub struct Extrinsic {
+    /// Transform from the first camera's frame to the second's
+    pub pose: Pose,
+    /// RMS reprojection error over both cameras (pixels)
+    pub rms: f64,
+}
+
+/// Solve a dense square system by Gaussian elimination with partial pivoting (None if singular)
+fn solve_linear(mut a: Vec<f64>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for k in 0..n {
+        let p = (k..n).fold(k, |p, i| if a[i*n + k].abs() > a[p*n + k].abs() { i } else { p });
+        if a[p*n + k].abs() < 1e-300 {
+            return None;
+        }
+        if p != k {
+            for j in 0..n {
+                a.swap(k*n + j, p*n + j);
+            }
+            b.swap(k, p);
+        }
+        for i in k + 1..n {
+            let f = a[i*n + k] / a[k*n + k];
+            if f != 0.0 {
+                for j in k..n {
+                    a[i*n + j] -= f * a[k*n + j];
+                }
+                b[i] -= f * b[k];
+            }
+        }
+    }
+    let mut x = vec![0.0; n];
+    for k in (0..n).rev() {
+        let s = (k + 1..n).fold(b[k], |s, j| s - a[k*n + j] * x[j]);
+        x[k] = s / a[k*n + k];
+    }
+    Some(x)
+}
+
+/// Eigenvector of the smallest eigenvalue of a symmetric matrix (cyclic Jacobi)
+fn smallest_eigenvector(mut a: Vec<f64>, n: usize) -> Vec<f64> {
+    let mut v = vec![0.0; n*n];
+    for i in 0..n {
+        v[i*n + i] = 1.0;
+    }
+    for _ in 0..100 {
+        let off = (0..n).flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+                        .fold(0.0, |s, (i, j)| s + a[i*n + j] * a[i*n + j]);
+        if off < 1e-30 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p*n + q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q*n + q] - a[p*n + p]) / (2.0 * a[p*n + q]);
+                let t = theta.signum() / (theta.abs() + (theta*theta + 1.0).sqrt());
+                let t = if theta == 0.0 { 1.0 } else { t };
+                let c = 1.0 / (t*t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[k*n + p], a[k*n + q]);
+                    a[k*n + p] = c*akp - s*akq;
+                    a[k*n + q] = s*akp + c*akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p*n + k], a[q*n + k]);
+                    a[p*n + k] = c*apk - s*aqk;
+                    a[q*n + k] = s*apk + c*aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[k*n + p], v[k*n + q]);
+                    v[k*n + p] = c*vkp - s*vkq;
+                    v[k*n + q] = s*vkp + c*vkq;
+                }
+            }
+        }
+    }
+    let min = (0..n).fold(0, |m, i| if a[i*n + i] < a[m*n + m] { i } else { m });
+    (0..n).map(|k| v[k*n + min]).collect()
+}
+
+/// Similarity transform that moves points to their centroid and scales them to an average
+/// distance of sqrt(2) (for a well-conditioned DLT)
+fn normalization(points: &[(f64, f64)]) -> Mat3 {
+    let n = points.len() as f64;
+    let (mx, my) = points.iter().fold((0.0, 0.0), |(x, y), p| (x + p.0 / n, y + p.1 / n));
+    let d = points.iter().fold(0.0, |d, p| d + ((p.0 - mx).powi(2) + (p.1 - my).powi(2)).sqrt() / n);
+    let s = if d > 0.0 { f64::consts::SQRT_2 / d } else { 1.0 };
+    [[s, 0.0, -s*mx], [0.0, s, -s*my], [0.0, 0.0, 1.0]]
+}
+
+fn mul3(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut c = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            c[i][j] = a[i][0]*b[0][j] + a[i][1]*b[1][j] + a[i][2]*b[2][j];
+        }
+    }
+    c
+}
+
+fn transform(m: &Mat3, (x, y): (f64, f64)) -> (f64, f64) {
+    let w = m[2][0]*x + m[2][1]*y + m[2][2];
+    ((m[0][0]*x + m[0][1]*y + m[0][2]) / w, (m[1][0]*x + m[1][1]*y + m[1][2]) / w)
+}
+
+/// Homography from the board plane (x, y in metres) to the image
+fn homography(view: &View) -> Mat3 {
+    let board = view.board.iter().map(|p| (p[0], p[1])).collect::<Vec<_>>();
+    let (nb, ni) = (normalization(&board), normalization(&view.image));
+
+    // accumulate A^T A for the DLT rows, and take its null vector
+    let mut ata = vec![0.0; 81];
+    for (&b, &i) in board.iter().zip(&view.image) {
+        let (x, y) = transform(&nb, b);
+        let (u, v) = transform(&ni, i);
+        let rows = [[-x, -y, -1.0, 0.0, 0.0, 0.0, u*x, u*y, u],
+                    [0.0, 0.0, 0.0, -x, -y, -1.0, v*x, v*y, v]];
+        for row in &rows {
+            for j in 0..9 {
+                for k in 0..9 {
+                    ata[j*9 + k] += row[j] * row[k];
+                }
+            }
+        }
+    }
+    let h = smallest_eigenvector(ata, 9);
+    let hn = [[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], h[8]]];
+
+    // undo the normalization: H = Ni^-1 Hn Nb
+    let s = ni[0][0];
+    let ni_inv = [[1.0/s, 0.0, -ni[0][2]/s], [0.0, 1.0/s, -ni[1][2]/s], [0.0, 0.0, 1.0]];
+    mul3(&mul3(&ni_inv, &hn), &nb)
+}
+
+/// Focal lengths from the homographies, with the principal point at (cx, cy)
+///
+/// With the image shifted so the principal point is at the origin, the image of the absolute
+/// conic is diag(1/fx², 1/fy², 1), and each homography's columns h1, h2 give h1ᵀBh2 = 0 and
+/// h1ᵀBh1 = h2ᵀBh2. That is linear in (1/fx², 1/fy²).
+fn focal_lengths(hs: &[Mat3], cx: f64, cy: f64) -> Result<(f64, f64), String> {
+    let mut ata = [[0.0; 2]; 2];
+    let mut atb = [0.0; 2];
+    for h in hs {
+        let shift = [[1.0, 0.0, -cx], [0.0, 1.0, -cy], [0.0, 0.0, 1.0]];
+        let h = mul3(&shift, h);
+        let (h1, h2) = ([h[0][0], h[1][0], h[2][0]], [h[0][1], h[1][1], h[2][1]]);
+        let rows = [([h1[0]*h2[0], h1[1]*h2[1]], -h1[2]*h2[2]),
+                    ([h1[0]*h1[0] - h2[0]*h2[0], h1[1]*h1[1] - h2[1]*h2[1]], -(h1[2]*h1[2] - h2[2]*h2[2]))];
+        for &(a, b) in &rows {
+            for j in 0..2 {
+                for k in 0..2 {
+                    ata[j][k] += a[j] * a[k];
+                }
+                atb[j] += a[j] * b;
+            }
+        }
+    }
+    let x = try!(solve_linear(vec![ata[0][0], ata[0][1], ata[1][0], ata[1][1]], atb.to_vec())
+                     .ok_or("cannot estimate focal lengths (are the views all parallel to the image?)".to_owned()));
+    if x[0] <= 0.0 || x[1] <= 0.0 {
+        return Err("cannot estimate focal lengths (got imaginary ones): try views with the board tilted more".to_owned());
+    }
+    Ok((1.0 / x[0].sqrt(), 1.0 / x[1].sqrt()))
+}
+
+/// Pose of the board from a homography and the camera matrix
+fn board_pose(h: &Mat3, fx: f64, fy: f64, cx: f64, cy: f64) -> Pose {
+    let k_inv = [[1.0/fx, 0.0, -cx/fx], [0.0, 1.0/fy, -cy/fy], [0.0, 0.0, 1.0]];
+    let m = mul3(&k_inv, h);
+    let col = |j: usize| [m[0][j], m[1][j], m[2][j]];
+    let norm = |v: Vec3| (v[0]*v[0] + v[1]*v[1] + v[2]*v[2]).sqrt();
+    let mut lambda = 1.0 / norm(col(0));
+    if m[2][2] * lambda < 0.0 {
+        // the board must be in front of the camera
+        lambda = -lambda;
+    }
+    let scale = |v: Vec3, s: f64| [v[0]*s, v[1]*s, v[2]*s];
+    let r1 = scale(col(0), lambda);
+    let r2 = scale(col(1), lambda);
+    let t = scale(col(2), lambda);
+
+    // nearest rotation (Gram-Schmidt is close enough for a starting point)
+    let r1 = scale(r1, 1.0 / norm(r1));
+    let d = r1[0]*r2[0] + r1[1]*r2[1] + r1[2]*r2[2];
+    let r2 = [r2[0] - d*r1[0], r2[1] - d*r1[1], r2[2] - d*r1[2]];
+    let r2 = scale(r2, 1.0 / norm(r2));
+    let r3 = model::cross(r1, r2);
+    let r = [[r1[0], r2[0], r3[0]], [r1[1], r2[1], r3[1]], [r1[2], r2[2], r3[2]]];
+    Pose::from_matrix(&r, t)
+}
+
+/// Minimize the sum of squared residuals by Levenberg-Marquardt, with a forward-difference
+/// Jacobian
+///
+/// `residuals(params, only)` fills in the residuals; `only` is a hint naming the one parameter that
+/// changed since the last call, so callers can skip residuals that do not depend on it (they must
+/// then return the previous values there). Returns the final sum of squares.
+fn levenberg_marquardt<F>(params: &mut Vec<f64>, mut residuals: F) -> f64
+    where F: FnMut(&[f64], Option<usize>, &mut Vec<f64>)
+{
+    let n = params.len();
+    let mut r = vec![];
+    residuals(params, None, &mut r);
+    let mut cost = r.iter().fold(0.0, |s, x| s + x*x);
+    let mut lambda = 1e-3;
+
+    for _ in 0..100 {
+        // Jacobian, column by column
+        let m = r.len();
+        let mut jac = vec![0.0; m * n];
+        let mut rp = r.clone();
+        for k in 0..n {
+            let h = 1e-6 * params[k].abs().max(1e-2);
+            let mut p = params.clone();
+            p[k] += h;
+            residuals(&p, Some(k), &mut rp);
+            for i in 0..m {
+                jac[i*n + k] = (rp[i] - r[i]) / h;
+            }
+            residuals(params, Some(k), &mut rp);
+        }
+
+        // normal equations (skipping the many zeros)
+        let mut jtj = vec![0.0; n * n];
+        let mut jtr = vec![0.0; n];
+        let mut nonzero = Vec::with_capacity(n);
+        for i in 0..m {
+            let row = &jac[i*n .. (i + 1)*n];
+            nonzero.clear();
+            nonzero.extend((0..n).filter(|&k| row[k] != 0.0));
+            for &a in &nonzero {
+                jtr[a] += row[a] * r[i];
+                for &b in &nonzero {
+                    jtj[a*n + b] += row[a] * row[b];
+                }
+            }
+        }
+
+        // try steps until one improves things (or give up)
+        let mut improved = false;
+        while lambda < 1e10 {
+            let mut a = jtj.clone();
+            for k in 0..n {
+                a[k*n + k] += lambda * jtj[k*n + k].max(1e-12);
+            }
+            let step = match solve_linear(a, jtr.iter().map(|x| -x).collect()) {
+                Some(step) => step,
+                None       => { lambda *= 10.0; continue; }
+            };
+            let candidate = params.iter().zip(&step).map(|(p, s)| p + s).collect::<Vec<_>>();
+            let mut rc = vec![];
+            residuals(&candidate, None, &mut rc);
+            let new_cost = rc.iter().fold(0.0, |s, x| s + x*x);
+            if new_cost < cost {
+                let gain = (cost - new_cost) / cost;
+                *params = candidate;
+                r = rc;
+                cost = new_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = gain > 1e-10;
+                break;
+            }
+            lambda *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+    cost
+}
+
+/// Residuals (x and y, in pixels) of one view
+fn reproject(camera: &Camera, pose: &Pose, view: &View, out: &mut [f64]) {
+    for (k, (p, i)) in view.board.iter().zip(&view.image).enumerate() {
+        let (u, v) = camera.project(pose.apply(*p)).unwrap_or((1e6, 1e6));
+        out[2*k] = u - i.0;
+        out[2*k + 1] = v - i.1;
+    }
+}
+
+fn pose_params(pose: &Pose) -> Vec<f64> {
+    vec![pose.r[0], pose.r[1], pose.r[2], pose.t[0], pose.t[1], pose.t[2]]
+}
+
+fn pose_from(p: &[f64]) -> Pose {
+    Pose { r: [p[0], p[1], p[2]], t: [p[3], p[4], p[5]] }
+}
+
+/// Number of camera parameters before the board poses
+const CAMERA_PARAMS: usize = 8;
+
+fn camera_from(name: &str, width: usize, height: usize, p: &[f64]) -> Camera {
+    Camera {
+        name       : name.to_owned(),
+        width      : width,
+        height     : height,
+        fx         : p[0],
+        fy         : p[1],
+        cx         : p[2],
+        cy         : p[3],
+        distortion : [p[4], p[5], p[6], p[7], 0.0],
+        pose       : Pose::identity(),
+        rms        : 0.0,
+    }
+}
+
+/// Calibrate one camera from views of the board
+///
+/// The camera's pose is left as the identity.
+pub fn intrinsics(name: &str, width: usize, height: usize, views: &[View]) -> Result<Intrinsic, String> {
+    if views.len() < 3 {
+        return Err(format!("{}: need at least 3 views of the board, found {}", name, views.len()));
+    }
+    let (cx, cy) = ((width as f64 - 1.0) / 2.0, (height as f64 - 1.0) / 2.0);
+    let hs = views.iter().map(homography).collect::<Vec<_>>();
+    let (fx, fy) = try!(focal_lengths(&hs, cx, cy).map_err(|e| format!("{}: {}", name, e)));
+
+    let mut params = vec![fx, fy, cx, cy, 0.0, 0.0, 0.0, 0.0];
+    for h in &hs {
+        params.extend(pose_params(&board_pose(h, fx, fy, cx, cy)));
+    }
+    let offsets = views.iter().scan(0, |o, v| { let here = *o; *o += 2 * v.board.len(); Some(here) }).collect::<Vec<_>>();
+    let total = views.iter().fold(0, |n, v| n + 2 * v.board.len());
+
+    let cost = levenberg_marquardt(&mut params, |p, only, out| {
+        out.resize(total, 0.0);
+        let camera = camera_from(name, width, height, p);
+        for (n, view) in views.iter().enumerate() {
+            let first = CAMERA_PARAMS + 6*n;
+            // a board pose parameter only moves its own view
+            if let Some(k) = only {
+                if k >= CAMERA_PARAMS && (k < first || k >= first + 6) {
+                    continue;
+                }
+            }
+            let len = 2 * view.board.len();
+            reproject(&camera, &pose_from(&p[first..first + 6]), view, &mut out[offsets[n]..offsets[n] + len]);
+        }
+    });
+
+    let mut camera = camera_from(name, width, height, &params);
+    camera.rms = (cost / (total / 2) as f64).sqrt();
+    let poses = (0..views.len()).map(|n| pose_from(&params[CAMERA_PARAMS + 6*n..])).collect::<Vec<_>>();
+    let errors = views.iter().zip(&poses).map(|(view, pose)| {
+        let mut out = vec![0.0; 2 * view.board.len()];
+        reproject(&camera, pose, view, &mut out);
+        (out.iter().fold(0.0, |s, x| s + x*x) / view.board.len() as f64).sqrt()
+    }).collect();
+    Ok(Intrinsic { camera: camera, poses: poses, errors: errors })
+}
+
+/// Find the transform from camera `a` to camera `b`
+///
+/// Each pair holds the same board view as seen by both cameras, with the board pose in camera `a`
+/// from its own calibration as a starting point. The intrinsics are not changed.
+pub fn extrinsics(a: &Camera, b: &Camera, pairs: &[(&View, &View, Pose)]) -> Result<Extrinsic, String> {
+    if pairs.is_empty() {
+        return Err(format!("no views of the board seen by both {} and {}", a.name, b.name));
+    }
+
+    // starting point: average over the views of (board to b) after (a to board)
+    let mut guesses = vec![];
+    for &(_, vb, ref in_a) in pairs {
+        let hb = homography(vb);
+        // undistorted enough for a starting point
+        let in_b = board_pose(&hb, b.fx, b.fy, b.cx, b.cy);
+        guesses.push(in_b.after(&in_a.inverse()));
+    }
+    let n = guesses.len() as f64;
+    let mean = guesses.iter().fold([0.0; 6], |mut m, g| {
+        for k in 0..3 {
+            m[k] += g.r[k] / n;
+            m[k + 3] += g.t[k] / n;
+        }
+        m
+    });
+
+    let mut params = mean.to_vec();
+    for &(_, _, ref in_a) in pairs {
+        params.extend(pose_params(in_a));
+    }
+    let total = pairs.iter().fold(0, |n, &(va, vb, _)| n + 2 * (va.board.len() + vb.board.len()));
+    let offsets = pairs.iter().scan(0, |o, &(va, vb, _)| {
+        let here = *o;
+        *o += 2 * (va.board.len() + vb.board.len());
+        Some(here)
+    }).collect::<Vec<_>>();
+
+    let cost = levenberg_marquardt(&mut params, |p, only, out| {
+        out.resize(total, 0.0);
+        let between = pose_from(&p[0..6]);
+        for (n, &(va, vb, _)) in pairs.iter().enumerate() {
+            let first = 6 + 6*n;
+            if let Some(k) = only {
+                if k >= 6 && (k < first || k >= first + 6) {
+                    continue;
+                }
+            }
+            let in_a = pose_from(&p[first..first + 6]);
+            let (la, lb) = (2 * va.board.len(), 2 * vb.board.len());
+            let o = offsets[n];
+            reproject(a, &in_a, va, &mut out[o..o + la]);
+            reproject(b, &between.after(&in_a), vb, &mut out[o + la..o + la + lb]);
+        }
+    });
+
+    Ok(Extrinsic { pose: pose_from(&params[0..6]), rms: (cost / (total / 2) as f64).sqrt() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::model::{Camera, Pose};
+
+    fn camera(name: &str, width: usize, height: usize, f: (f64, f64), distortion: [f64; 5]) -> Camera {
+        Camera {
+            name       : name.to_owned(),
+            width      : width,
+            height     : height,
+            fx         : f.0,
+            fy         : f.1,
+            cx         : (width as f64 - 1.0) / 2.0,
+            cy         : (height as f64 - 1.0) / 2.0,
+            distortion : distortion,
+            pose       : Pose::identity(),
+            rms        : 0.0,
+        }
+    }
+
+    /// A 9x6 board with 30 mm squares
+    fn board() -> Vec<Vec3> {
+        let mut points = vec![];
+        for j in 0..6 {
+            for i in 0..9 {
+                points.push([i as f64 * 0.03, j as f64 * 0.03, 0.0]);
+            }
+        }
+        points
+    }
+
+    /// Board poses (board to camera) tilted various ways, with the board about 60 cm away
+    fn poses() -> Vec<Pose> {
+        [[0.3, 0.0, 0.0], [0.0, 0.3, 0.0], [-0.2, 0.25, 0.1], [0.25, -0.2, -0.1], [0.1, 0.1, 0.5], [-0.3, -0.1, 0.0]]
+            .iter()
+            .map(|&r| Pose { r: r, t: [-0.12, -0.075, 0.6] })
+            .collect()
+    }
+
+    fn view(camera: &Camera, pose: &Pose) -> View {
+        let board = board();
+        let image = board.iter().map(|&p| camera.project(pose.apply(p)).unwrap()).collect();
+        View { board: board, image: image }
+    }
+
+    #[test]
+    fn intrinsics_of_synthetic_views() {
+        let truth = camera("test", 640, 480, (520.0, 515.0), [-0.05, 0.02, 0.001, -0.0005, 0.0]);
+        let views = poses().iter().map(|pose| view(&truth, pose)).collect::<Vec<_>>();
+
+        let found = intrinsics("test", 640, 480, &views).unwrap();
+        let c = &found.camera;
+        assert!(c.rms < 1e-3, "RMS error {}", c.rms);
+        assert!((c.fx - truth.fx).abs() < 0.05 && (c.fy - truth.fy).abs() < 0.05, "f = ({}, {})", c.fx, c.fy);
+        assert!((c.cx - truth.cx).abs() < 0.05 && (c.cy - truth.cy).abs() < 0.05, "c = ({}, {})", c.cx, c.cy);
+        for k in 0..4 {
+            assert!((c.distortion[k] - truth.distortion[k]).abs() < 1e-3, "distortion {:?}", c.distortion);
+        }
+        for (found, truth) in found.poses.iter().zip(&poses()) {
+            for k in 0..3 {
+                assert!((found.r[k] - truth.r[k]).abs() < 1e-4 && (found.t[k] - truth.t[k]).abs() < 1e-4,
+                        "board pose {:?} should be {:?}", found, truth);
+            }
+        }
+    }
+
+    #[test]
+    fn too_few_views() {
+        let truth = camera("test", 640, 480, (520.0, 515.0), [0.0; 5]);
+        let views = poses().iter().take(2).map(|pose| view(&truth, pose)).collect::<Vec<_>>();
+        assert!(intrinsics("test", 640, 480, &views).is_err());
+    }
+
+    #[test]
+    fn extrinsics_of_synthetic_views() {
+        let a = camera("a", 640, 480, (520.0, 515.0), [0.0; 5]);
+        let b = camera("b", 1280, 960, (1040.0, 1040.0), [-0.02, 0.0, 0.0, 0.0, 0.0]);
+        let between = Pose { r: [0.02, -0.05, 0.01], t: [0.04, 0.002, -0.003] };
+
+        let (va, vb) = poses().iter()
+                              .map(|pose| (view(&a, pose), view(&b, &between.after(pose))))
+                              .unzip::<_, _, Vec<_>, Vec<_>>();
+        // rough board poses in a, as the intrinsic calibration would give
+        let starts = poses().iter().map(|p| Pose { r: [p.r[0] + 0.01, p.r[1], p.r[2]], t: [p.t[0], p.t[1], p.t[2] + 0.01] }).collect::<Vec<_>>();
+        let pairs = (0..va.len()).map(|n| (&va[n], &vb[n], starts[n])).collect::<Vec<_>>();
+
+        let found = extrinsics(&a, &b, &pairs).unwrap();
+        assert!(found.rms < 1e-3, "RMS error {}", found.rms);
+        for k in 0..3 {
+            assert!((found.pose.r[k] - between.r[k]).abs() < 1e-5 && (found.pose.t[k] - between.t[k]).abs() < 1e-5,
+                    "{:?} should be {:?}", found.pose, between);
+        }
+        assert!(extrinsics(&a, &b, &[]).is_err());
+    }
+}
@@ -1,93 +1,219 @@
 //! CLI interface to view and control running services
+//!
+//! Commands are read from stdin, one line (of semicolon-separated commands) at a time. The CLI
+//! waits on stdin with Block::Fd, so it can be stopped like any other service.
 
-use super::comms::{Controllable, CmdFrom, Power, Block};
+extern crate libc;
+
+use super::comms::{self, Controllable, CmdFrom, Power, Block};
 use std::{env, thread};
-use std::io::{self, BufRead, Write};
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
 use std::process::Command;
 use std::sync::mpsc::Sender;
 use std::time::Duration;
+use self::libc::{c_void, size_t};
 
 /// Controllable struct for the CLI
 pub struct CLI {
     tx: Sender<CmdFrom>,
+    /// Input that does not make a whole line yet
+    input: Vec<u8>,
 }
 
-guilty!{
-    impl Controllable for CLI {
-        const NAME: &'static str = "cli",
-        const BLOCK: Block = Block::Immediate,
+/// Descriptor of standard input
+const STDIN: RawFd = 0;
 
-        fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> CLI {
-            CLI { tx: tx }
-        }
-
-        fn step(&mut self, _: Option<String>) {
-            print!("> ");
-            io::stdout().flush().unwrap();
-
-            let stdin = io::stdin();
-            let fat_line = stdin.lock().lines().next().unwrap().unwrap();
-            let line = fat_line.trim();
+fn prompt() {
+    print!("> ");
+    io::stdout().flush().unwrap();
+}
 
-            if line.starts_with("!") {
-                Command::new("sh").args(&["-c", &line[1..]]).status().unwrap();
-            } else {
-                for command in line.split(";") {
-                    let mut words = command.trim().split(" ");
-                    match words.next().unwrap_or("") {
-                        "" => {},
-                        "cd" => { env::set_current_dir(words.next().unwrap()).unwrap(); },
-                        "sleep" => {
-                            if let Some(ms_str) = words.next() {
-                                if let Ok(ms) = ms_str.parse::<u64>() {
-                                    thread::sleep(Duration::from_millis(ms));
-                                } else {
-                                    errorln!("Invalid millisecond value");
-                                }
+impl CLI {
+    /// Run one line of commands
+    fn command(&mut self, line: &str) {
+        if line.starts_with("!") {
+            Command::new("sh").args(&["-c", &line[1..]]).status().unwrap();
+        } else {
+            for command in line.split(";") {
+                let mut words = command.trim().split(" ");
+                match words.next().unwrap_or("") {
+                    "" => {},
+                    "cd" => { env::set_current_dir(words.next().unwrap()).unwrap(); },
+                    "sleep" => {
+                        if let Some(ms_str) = words.next() {
+                            if let Ok(ms) = ms_str.parse::<u64>() {
+                                thread::sleep(Duration::from_millis(ms));
                             } else {
-                                errorln!("No millisecond value");
+                                errorln!("Invalid millisecond value");
                             }
-                        },
-                        "start" => {
-                            while let Some(dev) = words.next() {
-                                if !rpc!(self.tx, CmdFrom::Start, dev.to_owned()).unwrap() {
-                                    errorln!("Failed to start {}", dev);
-                                }
+                        } else {
+                            errorln!("No millisecond value");
+                        }
+                    },
+                    "start" => {
+                        while let Some(dev) = words.next() {
+                            if !rpc!(self.tx, CmdFrom::Start, dev.to_owned()).unwrap() {
+                                errorln!("Failed to start {}", dev);
                             }
-                        },
-                        "stop" => {
-                            while let Some(dev) = words.next() {
-                                if !rpc!(self.tx, CmdFrom::Stop, dev.to_owned()).unwrap() {
-                                    errorln!("Failed to stop {}", dev);
-                                }
+                        }
+                    },
+                    "stop" => {
+                        while let Some(dev) = words.next() {
+                            if !rpc!(self.tx, CmdFrom::Stop, dev.to_owned()).unwrap() {
+                                errorln!("Failed to stop {}", dev);
                             }
-                        },
-                        "status" => {
-                            println!("{:?}", super::teensy::ParkState::metermaid());
-                        },
-                        "quit" => {
-                            self.tx.send(CmdFrom::Quit).unwrap();
-                        },
-                        "panic" => {
-                            self.tx.send(CmdFrom::Panic).unwrap();
-                        },
-                        "reboot" => {
-                            self.tx.send(CmdFrom::Power(Power::Reboot)).unwrap();
                         }
-                        "poweroff" => {
-                            self.tx.send(CmdFrom::Power(Power::PowerOff)).unwrap();
+                    },
+                    "status" => {
+                        println!("{:?}", super::teensy::ParkState::metermaid());
+                    },
+                    "teensyport" => {
+                        match words.next() {
+                            Some(path) => super::teensy::set_port(path),
+                            None       => println!("{}", super::teensy::port()),
+                        }
+                    },
+                    "optoforceport" => {
+                        match words.next() {
+                            Some(path) => super::optoforce::set_port(path),
+                            None       => println!("{}", super::optoforce::port()),
+                        }
+                    },
+                    "structure" => {
+                        match (words.next(), words.next()) {
+                            (Some(key), Some(value)) => if let Err(e) = super::structure::set(key, value) {
+                                errorln!("{}", e);
+                            },
+                            (None, _)    => println!("{}", super::structure::settings()),
+                            (Some(_), _) => errorln!("Usage: structure [<setting> <value>]"),
+                        }
+                    },
+                    "bluefox" => {
+                        match (words.next(), words.next()) {
+                            (Some(key), Some(value)) => if let Err(e) = super::bluefox::set(key, value) {
+                                errorln!("{}", e);
+                            },
+                            (None, _) => for setting in super::bluefox::settings() {
+                                println!("{}", setting);
+                            },
+                            (Some(_), _) => errorln!("Usage: bluefox [<setting> <value> | preset <name>]"),
+                        }
+                    },
+                    "preview" => {
+                        match (words.next(), words.next()) {
+                            (Some(camera), Some(value)) => if let Err(e) = super::preview::set(camera, value) {
+                                errorln!("{}", e);
+                            },
+                            (None, _) => for (camera, config) in super::preview::configs() {
+                                println!("{} {}", camera, config);
+                            },
+                            (Some(camera), None) => println!("{}", super::preview::config(camera)),
+                        }
+                    },
+                    "calibration" => {
+                        match words.next() {
+                            Some(path) => if let Err(e) = super::calibration::set(path) {
+                                errorln!("{}", e);
+                            },
+                            None => println!("{}", super::calibration::file()),
+                        }
+                    },
+                    "threads" => {
+                        for stats in super::comms::thread_stats() {
+                            println!("{}", stats);
+                        }
+                    },
+                    "timing" => {
+                        for timing in super::comms::timing_stats() {
+                            println!("{}", timing);
+                        }
+                    },
+                    "pools" => {
+                        for stats in super::pool::pool_stats() {
+                            println!("{}", stats);
+                        }
+                    },
+                    "biotacfingers" => {
+                        match words.next() {
+                            Some(fingers) => if let Err(e) = super::biotac::set_fingers(fingers) {
+                                errorln!("{}", e);
+                            },
+                            None => match super::biotac::fingers() {
+                                Some(fingers) => println!("{:?}", fingers),
+                                None          => println!("all"),
+                            },
                         }
-                        "data" => {
-                            self.tx.send(CmdFrom::Data(words.collect::<Vec<_>>().join(" "))).unwrap();
-                        },
-                        _ => println!("Unknown command!")
+                    },
+                    "quit" => {
+                        self.tx.send(CmdFrom::Quit).unwrap();
+                    },
+                    "panic" => {
+                        self.tx.send(CmdFrom::Panic).unwrap();
+                    },
+                    "reboot" => {
+                        self.tx.send(CmdFrom::Power(Power::Reboot)).unwrap();
                     }
+                    "poweroff" => {
+                        self.tx.send(CmdFrom::Power(Power::PowerOff)).unwrap();
+                    }
+                    "snapshot" => {
+                        self.tx.send(CmdFrom::Data("snapshot".to_owned())).unwrap();
+                    },
+                    "data" => {
+                        self.tx.send(CmdFrom::Data(words.collect::<Vec<_>>().join(" "))).unwrap();
+                    },
+                    _ => println!("Unknown command!")
+                }
+            }
+        }
+    }
+}
+
+guilty!{
+    impl Controllable for CLI {
+        const NAME: &'static str = "cli",
+        const BLOCK: Block = Block::Fd,
+
+        fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> CLI {
+            comms::watch(Some(STDIN));
+            prompt();
+            CLI { tx: tx, input: Vec::new() }
+        }
+
+        fn step(&mut self, _: Option<String>) {
+            if !comms::readable() {
+                return; // nothing sends commands to the CLI
+            }
+
+            // stdin is readable, so this does not block
+            let mut buf = [0u8; 1024];
+            let n = unsafe { libc::read(STDIN, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    errorln!("Could not read from stdin: {}", e);
+                    comms::watch(None);
                 }
+                return;
+            }
+            if n == 0 {
+                println!("stdin closed, CLI not listening any more");
+                comms::watch(None);
+                return;
+            }
+
+            self.input.extend(&buf[..n as usize]);
+            while let Some(end) = self.input.iter().position(|&b| b == b'\n') {
+                let line = String::from_utf8_lossy(&self.input[..end]).into_owned();
+                self.input = self.input[end + 1..].to_vec();
+                self.command(line.trim());
+                prompt();
             }
         }
 
         fn teardown(&mut self) {
-            // this will never be called
+            // nothing to clean up (stdin stays open for the next setup())
         }
     }
 }
@@ -3,11 +3,14 @@
 extern crate time;
 extern crate libc;
 
-use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError, SendError};
-use std::thread;
-use std::{mem, ptr};
+use std::sync::mpsc::{self, Sender, Receiver, TryRecvError, RecvError, SendError};
+use std::sync::{Arc, Weak, Mutex, Condvar};
+use std::collections::VecDeque;
+use std::cell::Cell;
+use std::os::unix::io::RawFd;
+use std::{cmp, fmt, io, mem, ptr, thread};
 use super::hprof;
-use self::libc::{nanosleep, timespec};
+use self::libc::{c_int, c_short, c_uint, c_ulong, c_void, time_t, c_long, timespec};
 
 /// Commands sent from the supervisor thread to services
 #[derive(Clone)]
@@ -84,11 +87,449 @@ pub enum Block {
     Immediate,
 
     /// Request a period N (in nanoseconds) managed by go(). Used by services that are not IO-bound
-    /// or CPU-bound.  Assuming each call to step() completes in less than N ns, step() will be
-    /// called approximately every N ns. A simple proportional controller is used to adjust the
-    /// sleep time, to account for unmeasured delays and get as close as possible to the desired
-    /// period.
-    Period(i64),
+    /// or CPU-bound. Steps are scheduled on absolute deadlines of the monotonic clock (every N ns
+    /// from the first step), so delays do not accumulate. A step that runs past the next deadline
+    /// is an overrun, handled according to the Overrun policy. Step times and wakeup jitter are
+    /// kept in the service's Timing (see timing_stats), which is also sent to the web interface
+    /// every few seconds. Commands are handled as soon as they arrive (so even a long period does
+    /// not hold up stopping), but data for step() waits for the next deadline.
+    Period(i64, Overrun),
+
+    /// Wait until the file descriptor given to watch() is readable, or a command arrives, and then
+    /// call step(). Used by services that read from a device or a pipe: step() runs exactly when
+    /// there is something to read (so reading does not block), and commands still get through
+    /// promptly. Until a file descriptor is set, this is the same as Infinite. When step() was
+    /// woken by a command instead, readable() returns false and step() must not read.
+    Fd,
+}
+
+/// What a Block::Period service does after a step overruns its period
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Overrun {
+    /// Drop the deadlines that have passed and wait for the next one, staying in phase with the
+    /// original schedule. Used by services that poll a device which keeps its own time.
+    Skip,
+
+    /// Run the missed steps back to back until caught up, as long as no more than N deadlines
+    /// have passed (beyond that, skip like Skip). Used by services where every step consumes a
+    /// fixed amount of data, so missed steps would otherwise fall behind the device.
+    CatchUp(u32),
+}
+
+/// Counts of durations in power-of-two buckets of microseconds (< 1 µs, < 2 µs, < 4 µs, ...)
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    pub buckets: [u64; 24],
+    /// Longest duration seen (ns)
+    pub max: u64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram { buckets: [0; 24], max: 0 }
+    }
+
+    fn add(&mut self, ns: u64) {
+        let us = ns / 1000;
+        let bucket = cmp::min(64 - us.leading_zeros() as usize, self.buckets.len() - 1);
+        self.buckets[bucket] += 1;
+        self.max = cmp::max(self.max, ns);
+    }
+
+    /// Number of durations counted
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().fold(0, |sum, &n| sum + n)
+    }
+
+    /// Upper bound of the bucket holding the given quantile (µs)
+    pub fn quantile(&self, q: f64) -> u64 {
+        let target = (q * self.count() as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return 1 << i;
+            }
+        }
+        1 << (self.buckets.len() - 1)
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.count() == 0 {
+            return write!(f, "none");
+        }
+        write!(f, "p50 < {} µs, p99 < {} µs, max {} µs",
+               self.quantile(0.5), self.quantile(0.99), self.max / 1000)
+    }
+}
+
+/// How well a Block::Period service is keeping to its schedule (see timing_stats)
+///
+/// Counted since the service was last started.
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub name: &'static str,
+    /// Requested period (ns)
+    pub period: u64,
+    pub overrun: Overrun,
+    pub steps: u64,
+    /// Steps that finished after the next deadline
+    pub overruns: u64,
+    /// Deadlines dropped without a step
+    pub skipped: u64,
+    /// Time spent in step()
+    pub latency: Histogram,
+    /// How late each step started, compared to its deadline
+    pub jitter: Histogram,
+}
+
+impl Timing {
+    fn new(name: &'static str, period: u64, overrun: Overrun) -> Timing {
+        Timing {
+            name     : name,
+            period   : period,
+            overrun  : overrun,
+            steps    : 0,
+            overruns : 0,
+            skipped  : 0,
+            latency  : Histogram::new(),
+            jitter   : Histogram::new(),
+        }
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (every {:.3} ms, {:?}): {} steps, {} overruns, {} skipped; step {}; jitter {}",
+               self.name, self.period as f64 / 1e6, self.overrun,
+               self.steps, self.overruns, self.skipped, self.latency, self.jitter)
+    }
+}
+
+lazy_static! {
+    /// Schedules of every Block::Period service that has been started
+    static ref TIMINGS: Mutex<Vec<Arc<Mutex<Timing>>>> = Mutex::new(Vec::new());
+}
+
+/// Get the timing of all the Block::Period services (e.g. for the `timing` CLI command)
+pub fn timing_stats() -> Vec<Timing> {
+    TIMINGS.lock().unwrap().iter().map(|t| t.lock().unwrap().clone()).collect()
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    // not in our version of libc
+    fn clock_nanosleep(clock: c_int, flags: c_int, request: *const timespec, remain: *mut timespec) -> c_int;
+}
+
+/// Sleep until the given time of the monotonic clock (ns, as from time::precise_time_ns)
+#[cfg(target_os = "linux")]
+fn sleep_until(deadline: u64) {
+    const CLOCK_MONOTONIC: c_int = 1;
+    const TIMER_ABSTIME: c_int = 1;
+
+    let ts = timespec { tv_sec: (deadline / 1_000_000_000) as time_t, tv_nsec: (deadline % 1_000_000_000) as c_long };
+    // the deadline is absolute, so an interrupted sleep can just be restarted
+    while unsafe { clock_nanosleep(CLOCK_MONOTONIC, TIMER_ABSTIME, &ts, ptr::null_mut()) } == libc::EINTR {}
+}
+
+/// Sleep until the given time of the monotonic clock (ns, as from time::precise_time_ns)
+#[cfg(not(target_os = "linux"))]
+fn sleep_until(deadline: u64) {
+    let now = time::precise_time_ns();
+    if deadline > now {
+        let ns = deadline - now;
+        let ts = timespec { tv_sec: (ns / 1_000_000_000) as time_t, tv_nsec: (ns % 1_000_000_000) as c_long };
+        unsafe { libc::nanosleep(&ts, ptr::null_mut()); }
+    }
+}
+
+/// How often a Block::Period service's timing is sent to the web interface (ns)
+const TIMING_REPORT_INTERVAL: u64 = 5_000_000_000;
+
+/// Absolute deadlines for a Block::Period service
+struct Schedule {
+    period: u64,
+    overrun: Overrun,
+    /// When the next step is due (ns, as from time::precise_time_ns)
+    next: u64,
+    timing: Arc<Mutex<Timing>>,
+    /// When the timing was last reported (ns, as from time::precise_time_ns)
+    reported: u64,
+}
+
+impl Schedule {
+    /// Start a schedule with the first step due now
+    fn new(timing: Arc<Mutex<Timing>>) -> Schedule {
+        let (period, overrun) = {
+            let mut t = timing.lock().unwrap();
+            let fresh = Timing::new(t.name, t.period, t.overrun);
+            *t = fresh;
+            (t.period, t.overrun)
+        };
+        let now = time::precise_time_ns();
+        Schedule { period: period, overrun: overrun, next: now, timing: timing, reported: now }
+    }
+
+    /// Send the timing to the web interface (as `timing <service> <summary>`) if it is time to
+    fn report(&mut self, tx: &Sender<CmdFrom>) {
+        let now = time::precise_time_ns();
+        if now - self.reported >= TIMING_REPORT_INTERVAL {
+            self.reported = now;
+            let timing = self.timing.lock().unwrap().clone();
+            tx.send(CmdFrom::Data(format!("send timing {} {}", timing.name, timing))).unwrap();
+        }
+    }
+
+    /// Wait for the next deadline (or, if a command arrives first, return false to let it be
+    /// handled, so that stopping a service with a long period does not take a whole period)
+    fn wait_for_commands(&self, name: &str, rx: &CmdReceiver) -> bool {
+        let mut fds = [PollFd { fd: rx.wakeup.read, events: POLLIN, revents: 0 }];
+        loop {
+            let now = time::precise_time_ns();
+            if now >= self.next {
+                return true;
+            }
+            // poll only has millisecond resolution, so sleep_until takes care of the rest
+            let ms = (self.next - now) / 1_000_000;
+            if ms == 0 {
+                return true;
+            }
+            let ms = cmp::min(ms, c_int::max_value() as u64) as c_int;
+            match unsafe { poll(fds.as_mut_ptr(), fds.len() as c_ulong, ms) } {
+                0 => {},
+                n if n > 0 => return false,
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        panic!("{}: poll failed: {}", name, e);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Wait for the next deadline, returning the time the step starts
+    fn wait(&mut self) -> u64 {
+        sleep_until(self.next);
+        let start = time::precise_time_ns();
+        self.timing.lock().unwrap().jitter.add(start.saturating_sub(self.next));
+        start
+    }
+
+    /// Count a step that started at the given time, and move on to the next deadline
+    fn done(&mut self, start: u64) {
+        self.finish(start, time::precise_time_ns());
+    }
+
+    /// Count a step that ran from `start` to `end`, and move on to the next deadline
+    fn finish(&mut self, start: u64, end: u64) {
+        let mut timing = self.timing.lock().unwrap();
+        timing.steps += 1;
+        timing.latency.add(end - start);
+
+        self.next += self.period;
+        if end > self.next {
+            timing.overruns += 1;
+            // deadlines that have passed, counting the one that is due now
+            let behind = (end - self.next) / self.period + 1;
+            let catch_up = match self.overrun {
+                Overrun::Skip        => false,
+                Overrun::CatchUp(n)  => behind <= n as u64,
+            };
+            if !catch_up {
+                timing.skipped += behind;
+                self.next += behind * self.period;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    // not in our version of libc
+    fn eventfd(initval: c_uint, flags: c_int) -> c_int;
+}
+
+extern "C" {
+    // not in our version of libc
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: c_short,
+    revents: c_short,
+}
+
+const POLLIN: c_short = 0x01;
+const POLLERR: c_short = 0x08;
+const POLLHUP: c_short = 0x10;
+const POLLNVAL: c_short = 0x20;
+
+/// Readable exactly while there are commands waiting for a service (see CmdSender)
+///
+/// An eventfd in semaphore mode on Linux (a pipe elsewhere): every command sent adds a token, and
+/// every command received takes one.
+struct Wakeup {
+    read: c_int,
+    write: c_int,
+}
+
+impl Wakeup {
+    #[cfg(target_os = "linux")]
+    fn new() -> Wakeup {
+        const EFD_SEMAPHORE: c_int = 1;
+        let fd = unsafe { eventfd(0, EFD_SEMAPHORE | libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if fd < 0 {
+            panic!("Could not create eventfd: {}", io::Error::last_os_error());
+        }
+        Wakeup { read: fd, write: fd }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new() -> Wakeup {
+        let mut fds = [0 as c_int; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            panic!("Could not create wakeup pipe: {}", io::Error::last_os_error());
+        }
+        for &fd in &fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK); }
+        }
+        Wakeup { read: fds[0], write: fds[1] }
+    }
+
+    /// Add a token
+    fn signal(&self) {
+        let one = 1u64; // an eventfd wants 8 bytes (a pipe only looks at the first)
+        let len = if self.read == self.write { 8 } else { 1 };
+        unsafe { libc::write(self.write, &one as *const u64 as *const c_void, len); }
+    }
+
+    /// Take a token (if there is one)
+    fn take(&self) {
+        let mut token = 0u64;
+        let len = if self.read == self.write { 8 } else { 1 };
+        unsafe { libc::read(self.read, &mut token as *mut u64 as *mut c_void, len); }
+    }
+}
+
+impl Drop for Wakeup {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            if self.write != self.read {
+                libc::close(self.write);
+            }
+        }
+    }
+}
+
+/// Sending end of a service's command channel
+///
+/// Like an mpsc::Sender, but it also wakes the service if it is waiting on a file descriptor
+/// (see Block::Fd).
+#[derive(Clone)]
+pub struct CmdSender {
+    tx: Sender<CmdTo>,
+    wakeup: Arc<Wakeup>,
+}
+
+impl CmdSender {
+    pub fn send(&self, cmd: CmdTo) -> Result<(), SendError<CmdTo>> {
+        // token first, so that the receiver always finds one for every command it gets (otherwise
+        // a leftover token would keep the wakeup readable with nothing to receive)
+        self.wakeup.signal();
+        self.tx.send(cmd)
+    }
+}
+
+/// Receiving end of a service's command channel (see CmdSender)
+pub struct CmdReceiver {
+    rx: Receiver<CmdTo>,
+    wakeup: Arc<Wakeup>,
+}
+
+impl CmdReceiver {
+    fn recv(&self) -> Result<CmdTo, RecvError> {
+        let cmd = try!(self.rx.recv());
+        self.wakeup.take();
+        Ok(cmd)
+    }
+
+    fn try_recv(&self) -> Result<CmdTo, TryRecvError> {
+        let cmd = try!(self.rx.try_recv());
+        self.wakeup.take();
+        Ok(cmd)
+    }
+}
+
+/// Create a command channel for a service
+pub fn service_channel() -> (CmdSender, CmdReceiver) {
+    let (tx, rx) = mpsc::channel();
+    let wakeup = Arc::new(Wakeup::new());
+    (CmdSender { tx: tx, wakeup: wakeup.clone() }, CmdReceiver { rx: rx, wakeup: wakeup })
+}
+
+thread_local! {
+    /// File descriptor that the service running on this thread waits on (see Block::Fd)
+    static WATCHED: Cell<Option<RawFd>> = Cell::new(None);
+
+    /// Whether the watched file descriptor was ready when step() was called (see readable)
+    static READY: Cell<bool> = Cell::new(false)
+}
+
+/// Set the file descriptor that a Block::Fd service waits on, or None to only wait for commands
+///
+/// Call this from setup() or step() (it applies to the service running on the current thread), and
+/// again whenever the service reopens its device. It is cleared before every setup().
+pub fn watch(fd: Option<RawFd>) {
+    WATCHED.with(|w| w.set(fd));
+}
+
+/// Whether a Block::Fd service's step() may read from its watched file descriptor
+///
+/// False when the step was only called to deliver a command: a read then would block until data
+/// arrives (or the read times out), holding up the next command.
+pub fn readable() -> bool {
+    READY.with(|r| r.get())
+}
+
+/// Wait until the watched file descriptor is readable or a command arrives
+///
+/// Returns whether the file descriptor is ready. A hangup or error (e.g. a device that was
+/// unplugged) would keep it "ready" forever, so it is reported and no longer watched, but step()
+/// still gets one call to see the error when it reads. The service has to watch() again after it
+/// reopens the device.
+fn wait_fd(name: &str, rx: &CmdReceiver) -> bool {
+    let watched = WATCHED.with(|w| w.get());
+    let mut fds = [PollFd { fd: rx.wakeup.read,         events: POLLIN, revents: 0 },
+                   PollFd { fd: watched.unwrap_or(-1), events: POLLIN, revents: 0 }]; // poll skips -1
+    loop {
+        if unsafe { poll(fds.as_mut_ptr(), fds.len() as c_ulong, -1) } >= 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            panic!("{}: poll failed: {}", name, e);
+        }
+    }
+
+    if fds[1].revents & POLLNVAL != 0 {
+        errorln!("{}: watched file descriptor {} is not open, waiting for commands only", name, fds[1].fd);
+        watch(None);
+        return false;
+    }
+    if fds[1].revents & (POLLHUP | POLLERR) != 0 {
+        errorln!("{}: watched file descriptor {} {}, waiting for commands only",
+                 name, fds[1].fd, if fds[1].revents & POLLHUP != 0 { "hung up" } else { "has an error" });
+        watch(None);
+        return true;
+    }
+    fds[1].revents & POLLIN != 0
 }
 
 guilty!{
@@ -229,7 +670,7 @@ fn handle_err<C: Controllable>(c: &mut C) -> Option<Break> {
 /// Helper function for go()
 ///
 /// Handles communications from the main thread
-fn handle<C: Controllable>(block: bool, c: &mut C, rx: &Receiver<CmdTo>, data: &mut Option<String>) -> Option<Break> {
+fn handle<C: Controllable>(block: bool, c: &mut C, rx: &CmdReceiver, data: &mut Option<String>) -> Option<Break> {
     if block {
         match rx.recv() {
             Ok(cmd) => handle_ok(cmd, c, data),
@@ -250,7 +691,10 @@ fn handle<C: Controllable>(block: bool, c: &mut C, rx: &Receiver<CmdTo>, data: &
 ///
 /// Runs in a loop receiving commands from the supervisor thread. Manages a Controllable instance,
 /// calling its setup()/step()/teardown() methods as necessary.
-pub fn go<C: Controllable>(rx: Receiver<CmdTo>, tx: Sender<CmdFrom>) {
+pub fn go<C: Controllable>(rx: CmdReceiver, tx: Sender<CmdFrom>) {
+    // kept across restarts, so timing_stats() still has the last run after the service stops
+    let mut timing = None;
+
     'alive: loop {
         let mut data = None;
 
@@ -267,27 +711,29 @@ pub fn go<C: Controllable>(rx: Receiver<CmdTo>, tx: Sender<CmdFrom>) {
         }
 
         tx.send(CmdFrom::Timeout { thread: guilty!(C::NAME), ms: 1000 }).unwrap();
+        watch(None);
         let mut c = C::setup(tx.clone(), data);
         tx.send(CmdFrom::Timein { thread: guilty!(C::NAME) }).unwrap();
 
-        let mut block = guilty!(C::BLOCK);
-        let actual_period = match block {
-            Block::Immediate      => 0,
-            Block::Infinite       => -1,
-            Block::Period(period) => period,
+        let block = guilty!(C::BLOCK);
+        let mut schedule = match block {
+            Block::Period(period, overrun) => {
+                if timing.is_none() {
+                    let t = Arc::new(Mutex::new(Timing::new(guilty!(C::NAME), cmp::max(period, 1) as u64, overrun)));
+                    TIMINGS.lock().unwrap().push(t.clone());
+                    timing = Some(t);
+                }
+                timing.clone().map(Schedule::new)
+            },
+            _ => None,
         };
 
         super::PROF.with(|wrapped_prof| {
             *wrapped_prof.borrow_mut() = Some(hprof::Profiler::new(guilty!(C::NAME)));
         });
 
-        let mut i = 0;
-        let mut life_start = time::now();
         'running: loop {
             data = None;
-            i += 1;
-
-            let start = time::now();
 
             match block {
                 Block::Immediate => {
@@ -298,24 +744,27 @@ pub fn go<C: Controllable>(rx: Receiver<CmdTo>, tx: Sender<CmdFrom>) {
                     maybe_break!(handle(true, &mut c, &rx, &mut data), 'running, 'alive);
                     prof!("step", c.step(data));
                 }
-                Block::Period(desired_period) => {
-                    maybe_break!(handle(false, &mut c, &rx, &mut data), 'running, 'alive);
-                    prof!("step", c.step(data));
-                    if let Some(nanos) = (time::now() - start).num_nanoseconds() {
-                        if nanos < desired_period {
-                            unsafe {
-                                nanosleep(&timespec { tv_sec: 0, tv_nsec: desired_period - nanos }, ptr::null_mut());
-                            }
-                        }
+                Block::Period(..) => {
+                    let schedule = schedule.as_mut().unwrap();
+                    // handle commands as they arrive, but hold one for step() until the deadline
+                    // (and leave any more in the queue until then, so it is not overwritten)
+                    while data.is_none() && !schedule.wait_for_commands(guilty!(C::NAME), &rx) {
+                        maybe_break!(handle(false, &mut c, &rx, &mut data), 'running, 'alive);
                     }
-
-                    if i == 100 {
-                        if let Some(nanos) = (time::now() - life_start).num_nanoseconds() {
-                            let duration = nanos as f64 / i as f64;
-                            block = Block::Period(desired_period + actual_period - duration as i64);
-                        }
-                        i = 0;
-                        life_start = time::now();
+                    let start = schedule.wait();
+                    if data.is_none() {
+                        maybe_break!(handle(false, &mut c, &rx, &mut data), 'running, 'alive);
+                    }
+                    prof!("step", c.step(data));
+                    schedule.done(start);
+                    schedule.report(&tx);
+                }
+                Block::Fd => {
+                    let ready = prof!("wait", wait_fd(guilty!(C::NAME), &rx));
+                    maybe_break!(handle(false, &mut c, &rx, &mut data), 'running, 'alive);
+                    if ready || data.is_some() {
+                        READY.with(|r| r.set(ready));
+                        prof!("step", c.step(data));
                     }
                 }
             }
@@ -332,18 +781,208 @@ pub fn go<C: Controllable>(rx: Receiver<CmdTo>, tx: Sender<CmdFrom>) {
     });
 }
 
-/// Container for a thread that repeatedly performs some action in response to input. Can be
-/// stopped and restarted.
-pub struct RestartableThread<Data: Send + 'static> {
-    /// Sending end of a channel used to send inputs to the thread.
-    ///
-    /// Wrapped in a Option so it can be dropped without moving self.
-    tx: Option<Sender<Data>>,
+/// Where the inputs of a RestartableThread wait for a worker
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mailbox {
+    /// Keep every input (the queue grows without bound if the workers fall behind)
+    Queue,
 
-    /// Handle to the running thread
-    ///
-    /// Wrapped in an option so it can be joined without moving self.
-    thread: Option<thread::JoinHandle<()>>,
+    /// Keep only the newest input: sending replaces one that no worker has picked up yet. Used for
+    /// previews, where a stale frame is worthless.
+    Latest,
+
+    /// Keep up to N inputs, dropping the oldest to make room
+    Bounded(usize),
+}
+
+impl Mailbox {
+    fn capacity(self) -> Option<usize> {
+        match self {
+            Mailbox::Queue      => None,
+            Mailbox::Latest     => Some(1),
+            Mailbox::Bounded(n) => Some(cmp::max(n, 1)),
+        }
+    }
+}
+
+/// What a RestartableThread has been up to (see thread_stats)
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub name: &'static str,
+    pub mailbox: Mailbox,
+    pub workers: usize,
+    /// Inputs handled
+    pub handled: u64,
+    /// Inputs dropped (replaced by newer ones) before a worker got to them
+    pub dropped: u64,
+    /// Inputs waiting right now
+    pub waiting: usize,
+    /// Total time spent handling inputs (ns)
+    pub busy: u64,
+    /// Longest time spent handling one input (ns)
+    pub longest: u64,
+    /// The workers' profiler timings, added up (updated about once a second)
+    pub profile: Vec<ProfLine>,
+}
+
+/// Time spent in one prof!() block (see Stats::profile)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfLine {
+    pub name: &'static str,
+    /// Number of enclosing prof!() blocks
+    pub depth: usize,
+    pub calls: u64,
+    /// Total time (ns)
+    pub total: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} ({:?} x{}): {} handled, {} dropped, {} waiting, {:.1} ms average, {:.1} ms longest",
+                    self.name, self.mailbox, self.workers, self.handled, self.dropped, self.waiting,
+                    if self.handled == 0 { 0.0 } else { self.busy as f64 / self.handled as f64 / 1e6 },
+                    self.longest as f64 / 1e6));
+        for line in &self.profile {
+            try!(write!(f, "\n    {:indent$}{}: {} calls, {:.1} ms total, {:.3} ms average",
+                        "", line.name, line.calls, line.total as f64 / 1e6,
+                        if line.calls == 0 { 0.0 } else { line.total as f64 / line.calls as f64 / 1e6 },
+                        indent = 2 * line.depth));
+        }
+        Ok(())
+    }
+}
+
+/// Flatten this thread's profiler tree (depth first)
+fn profile_lines() -> Vec<ProfLine> {
+    fn walk(node: &hprof::ProfileNode, depth: usize, out: &mut Vec<ProfLine>) {
+        for child in node.children.borrow().iter() {
+            out.push(ProfLine { name: child.name, depth: depth, calls: child.calls.get(), total: child.total_time.get() });
+            walk(child, depth + 1, out);
+        }
+    }
+
+    super::PROF.with(|wrapped_prof| {
+        let mut lines = vec![];
+        if let Some(ref prof) = *wrapped_prof.borrow() {
+            walk(&prof.root(), 0, &mut lines);
+        }
+        lines
+    })
+}
+
+/// Add up the profiles of several threads (blocks are matched by name and nesting)
+fn merge_profiles(profiles: &[Vec<ProfLine>]) -> Vec<ProfLine> {
+    let mut merged: Vec<(Vec<&'static str>, ProfLine)> = vec![];
+    for profile in profiles {
+        let mut path = vec![];
+        for line in profile {
+            path.truncate(line.depth);
+            path.push(line.name);
+            match merged.iter().position(|&(ref p, _)| *p == path) {
+                Some(i) => {
+                    merged[i].1.calls += line.calls;
+                    merged[i].1.total += line.total;
+                },
+                None => {
+                    // after the last block in the same parent (so it still shows up under it)
+                    let at = merged.iter()
+                                   .rposition(|&(ref p, _)| p.len() > line.depth && p[..line.depth] == path[..line.depth])
+                                   .map_or(merged.len(), |i| i + 1);
+                    merged.insert(at, (path.clone(), line.clone()));
+                },
+            }
+        }
+    }
+    merged.into_iter().map(|(_, line)| line).collect()
+}
+
+lazy_static! {
+    /// Stats of every RestartableThread that is still around
+    static ref STATS: Mutex<Vec<Weak<Mutex<Stats>>>> = Mutex::new(Vec::new());
+}
+
+/// Get the stats of all the RestartableThreads (e.g. for the `threads` CLI command)
+pub fn thread_stats() -> Vec<Stats> {
+    let mut all = STATS.lock().unwrap();
+    all.retain(|s| s.upgrade().is_some());
+    all.iter().filter_map(|s| s.upgrade()).map(|s| s.lock().unwrap().clone()).collect()
+}
+
+/// Largest number of results kept for the owner to collect (the oldest are dropped)
+const MAX_RESULTS: usize = 64;
+
+/// How often each worker copies its profiler timings into the stats (ns)
+const PROFILE_INTERVAL: u64 = 1_000_000_000;
+
+/// Inputs and outputs of a RestartableThread's workers
+struct Inbox<Data, Out> {
+    pending: VecDeque<Data>,
+    results: VecDeque<Out>,
+    /// False once the thread is being joined
+    open: bool,
+}
+
+/// State shared between a RestartableThread and its workers
+struct Shared<Data, Out> {
+    inbox: Mutex<Inbox<Data, Out>>,
+    /// Signalled when there is input (or the thread is being joined)
+    ready: Condvar,
+    stats: Arc<Mutex<Stats>>,
+    /// Latest profiler timings of each worker
+    profiles: Mutex<Vec<Vec<ProfLine>>>,
+}
+
+impl<Data, Out> Shared<Data, Out> {
+    /// Wait for an input (None once the thread is being joined and all the inputs are done)
+    fn take(&self) -> Option<Data> {
+        let mut inbox = self.inbox.lock().unwrap();
+        loop {
+            if let Some(x) = inbox.pending.pop_front() {
+                self.stats.lock().unwrap().waiting = inbox.pending.len();
+                return Some(x);
+            }
+            if !inbox.open {
+                return None;
+            }
+            inbox = self.ready.wait(inbox).unwrap();
+        }
+    }
+
+    /// Store a worker's result
+    fn finish(&self, out: Out, ns: u64) {
+        let mut inbox = self.inbox.lock().unwrap();
+        if inbox.results.len() >= MAX_RESULTS {
+            inbox.results.pop_front();
+        }
+        inbox.results.push_back(out);
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.handled += 1;
+        stats.busy += ns;
+        stats.longest = cmp::max(stats.longest, ns);
+    }
+
+    /// Store a worker's profiler timings (see profile_lines)
+    fn profile(&self, worker: usize, lines: Vec<ProfLine>) {
+        let mut profiles = self.profiles.lock().unwrap();
+        profiles[worker] = lines;
+        self.stats.lock().unwrap().profile = merge_profiles(&profiles);
+    }
+}
+
+/// Container for a thread (or a pool of threads) that repeatedly performs some action in response
+/// to input. Can be stopped and restarted.
+///
+/// Inputs wait in a mailbox (see Mailbox) until a worker is free. Whatever the action returns is
+/// kept for the owner to collect with RestartableThread::results(), so workers can report errors
+/// without panicking.
+pub struct RestartableThread<Data: Send + 'static, Out: Send + 'static = ()> {
+    shared: Arc<Shared<Data, Out>>,
+
+    mailbox: Mailbox,
+
+    /// Handles to the running workers
+    threads: Vec<thread::JoinHandle<()>>,
 }
 
 impl<Data: Send + 'static> RestartableThread<Data> {
@@ -351,57 +990,314 @@ impl<Data: Send + 'static> RestartableThread<Data> {
     /// The thread will run (and wait for input) until RestartableThread::join() is called or the
     /// RestartableThread instance is dropped.
     /// To pass input, use RestartableThread::send().
+    ///
+    /// This is a single worker that queues every input; see with_mailbox for the other modes.
     pub fn new<F>(n: &'static str, f: F) -> RestartableThread<Data>
-        where F: Send + 'static + Fn(Data)
+        where F: Send + Sync + 'static + Fn(Data)
     {
-        let (tx, rx) = channel();
-        RestartableThread {
-            tx: Some(tx),
-            thread: Some(thread::spawn(move || {
-                super::PROF.with(|wrapped_prof| {
-                    *wrapped_prof.borrow_mut() = Some(hprof::Profiler::new(n));
-                });
-
-                while let Ok(x) = rx.recv() {
-                    prof!("step", f(x));
-                }
+        RestartableThread::with_mailbox(n, Mailbox::Queue, 1, f)
+    }
+}
+
+impl<Data: Send + 'static, Out: Send + 'static> RestartableThread<Data, Out> {
+    /// Create a RestartableThread with the given mailbox and number of workers
+    ///
+    /// Each worker keeps its own profiler, which it prints when it exits. The totals for the pool,
+    /// including the profilers' timings, are also kept up to date for thread_stats().
+    pub fn with_mailbox<F>(n: &'static str, mailbox: Mailbox, workers: usize, f: F) -> RestartableThread<Data, Out>
+        where F: Send + Sync + 'static + Fn(Data) -> Out
+    {
+        let workers = cmp::max(workers, 1);
+        let stats = Arc::new(Mutex::new(Stats {
+            name    : n,
+            mailbox : mailbox,
+            workers : workers,
+            handled : 0,
+            dropped : 0,
+            waiting : 0,
+            busy    : 0,
+            longest : 0,
+            profile : vec![],
+        }));
+        STATS.lock().unwrap().push(Arc::downgrade(&stats));
+
+        let shared = Arc::new(Shared {
+            inbox: Mutex::new(Inbox { pending: VecDeque::new(), results: VecDeque::new(), open: true }),
+            ready: Condvar::new(),
+            stats: stats,
+            profiles: Mutex::new(vec![vec![]; workers]),
+        });
+        let f = Arc::new(f);
+
+        let threads = (0..workers).map(|i| {
+            let (shared, f) = (shared.clone(), f.clone());
+            thread::Builder::new()
+                .name(format!("{} (worker #{})", n, i + 1))
+                .spawn(move || {
+                    super::PROF.with(|wrapped_prof| {
+                        *wrapped_prof.borrow_mut() = Some(hprof::Profiler::new(n));
+                    });
 
-                super::PROF.with(|wrapped_prof| {
-                    if let Some(ref prof) = *wrapped_prof.borrow() {
-                        prof.print_timing();
+                    let mut profiled = 0;
+                    while let Some(x) = shared.take() {
+                        let start = time::precise_time_ns();
+                        let out = prof!("step", f(x));
+                        let end = time::precise_time_ns();
+                        shared.finish(out, end - start);
+                        if end - profiled >= PROFILE_INTERVAL {
+                            profiled = end;
+                            shared.profile(i, profile_lines());
+                        }
                     }
-                });
-            })),
-        }
+                    shared.profile(i, profile_lines());
+
+                    super::PROF.with(|wrapped_prof| {
+                        if let Some(ref prof) = *wrapped_prof.borrow() {
+                            prof.print_timing();
+                        }
+                    });
+                }).unwrap()
+        }).collect();
+
+        RestartableThread { shared: shared, mailbox: mailbox, threads: threads }
     }
 
-    /// Kill the thread. This shuts down the message queue, causing the thread to exit, and then
-    /// waits for it to finish up any outstanding work. No deadlocks here!
+    /// Kill the thread. This shuts down the mailbox, causing the workers to exit, and then waits
+    /// for them to finish up any outstanding work. No deadlocks here!
     pub fn join(&mut self) {
-        if self.thread.is_some() {
-            self.tx = None; // this causes the Sender to be dropped
+        if !self.threads.is_empty() {
+            self.shared.inbox.lock().unwrap().open = false;
+            self.shared.ready.notify_all();
 
-            let mut old_thread = None;
-            mem::swap(&mut old_thread, &mut self.thread);
-            old_thread.unwrap().join().unwrap(); // safe to join since we hung up the channel
+            for thread in mem::replace(&mut self.threads, Vec::new()) {
+                thread.join().unwrap(); // safe to join since we closed the mailbox
+            }
         }
     }
 
     /// Send some input to the thread. Nonblocking.
-    /// Returns a SendError if Sender::send() fails or if the private Sender has somehow
-    /// disappeared (which is impossible).
+    ///
+    /// If the mailbox is full, the oldest waiting input is dropped to make room. Returns a
+    /// SendError if the thread has been joined.
     pub fn send(&self, d: Data) -> Result<(), SendError<Data>> {
-        if let Some(ref s) = self.tx {
-            s.send(d)
-        } else {
-            Err(SendError(d))
+        let mut inbox = self.shared.inbox.lock().unwrap();
+        if !inbox.open {
+            return Err(SendError(d));
+        }
+        let mut dropped = 0;
+        if let Some(capacity) = self.mailbox.capacity() {
+            while inbox.pending.len() >= capacity {
+                inbox.pending.pop_front();
+                dropped += 1;
+            }
         }
+        inbox.pending.push_back(d);
+
+        {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.dropped += dropped;
+            stats.waiting = inbox.pending.len();
+        }
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Collect the results of the inputs handled since the last call (oldest first)
+    pub fn results(&self) -> Vec<Out> {
+        mem::replace(&mut self.shared.inbox.lock().unwrap().results, VecDeque::new()).into_iter().collect()
+    }
+
+    /// Get the stats of this thread
+    pub fn stats(&self) -> Stats {
+        self.shared.stats.lock().unwrap().clone()
     }
 }
 
-impl<Data: Send + 'static> Drop for RestartableThread<Data> {
+impl<Data: Send + 'static, Out: Send + 'static> Drop for RestartableThread<Data, Out> {
     /// When the RestartableThread goes out of scope, kill the thread.
     fn drop(&mut self) {
         self.join();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicIsize, Ordering, ATOMIC_ISIZE_INIT};
+    use std::sync::mpsc::{self, Sender, Receiver};
+    use std::thread;
+    use std::time::Duration;
+    use super::libc::{self, c_void};
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn histogram() {
+        let mut h = Histogram::new();
+        assert_eq!(h.count(), 0);
+        assert_eq!(format!("{}", h), "none");
+
+        for &ns in &[500, 1_500, 1_999, 3_000, 700_000] {
+            h.add(ns);
+        }
+        assert_eq!(h.count(), 5);
+        assert_eq!(&h.buckets[..4], &[1, 2, 1, 0]); // < 1 µs, < 2 µs, < 4 µs, < 8 µs
+        assert_eq!(h.buckets[10], 1); // 700 µs is < 1024 µs
+        assert_eq!(h.max, 700_000);
+        assert_eq!(h.quantile(0.5), 2);
+        assert_eq!(h.quantile(0.99), 1024);
+
+        // longer than the last bucket
+        h.add(u64::max_value());
+        assert_eq!(h.buckets[23], 1);
+        assert_eq!(h.max, u64::max_value());
+    }
+
+    fn schedule(overrun: Overrun) -> Schedule {
+        let mut schedule = Schedule::new(Arc::new(Mutex::new(Timing::new("test", 10 * MS, overrun))));
+        schedule.next = 0;
+        schedule
+    }
+
+    #[test]
+    fn schedule_on_time() {
+        let mut s = schedule(Overrun::Skip);
+        s.finish(0, 3 * MS);
+        s.finish(10 * MS, 12 * MS);
+        assert_eq!(s.next, 20 * MS);
+        let t = s.timing.lock().unwrap();
+        assert_eq!((t.steps, t.overruns, t.skipped), (2, 0, 0));
+    }
+
+    #[test]
+    fn schedule_skip() {
+        let mut s = schedule(Overrun::Skip);
+        // ran past the deadlines at 10 and 20 ms, so the next step is at 30 ms
+        s.finish(0, 25 * MS);
+        assert_eq!(s.next, 30 * MS);
+        let t = s.timing.lock().unwrap();
+        assert_eq!((t.steps, t.overruns, t.skipped), (1, 1, 2));
+    }
+
+    #[test]
+    fn schedule_catch_up() {
+        let mut s = schedule(Overrun::CatchUp(2));
+        // two deadlines missed: run them back to back
+        s.finish(0, 25 * MS);
+        assert_eq!(s.next, 10 * MS);
+        s.finish(25 * MS, 26 * MS);
+        s.finish(26 * MS, 27 * MS);
+        assert_eq!(s.next, 30 * MS);
+        {
+            let t = s.timing.lock().unwrap();
+            assert_eq!((t.steps, t.overruns, t.skipped), (3, 2, 0));
+        }
+
+        // too far behind, so skip
+        s.finish(30 * MS, 65 * MS);
+        assert_eq!(s.next, 70 * MS);
+        let t = s.timing.lock().unwrap();
+        assert_eq!((t.steps, t.overruns, t.skipped), (4, 3, 3));
+    }
+
+    struct Slow;
+
+    guilty! {
+        impl Controllable for Slow {
+            const NAME: &'static str = "slow",
+            const BLOCK: Block = Block::Period(10_000_000_000, Overrun::Skip),
+
+            fn setup(_: Sender<CmdFrom>, _: Option<String>) -> Slow {
+                Slow
+            }
+
+            fn step(&mut self, _: Option<String>) {
+            }
+
+            fn teardown(&mut self) {
+            }
+        }
+    }
+
+    #[test]
+    fn period_stops_promptly() {
+        let (cmd_tx, cmd_rx) = service_channel();
+        let (tx, rx) = mpsc::channel();
+        let service = thread::spawn(move || go::<Slow>(cmd_rx, tx));
+
+        cmd_tx.send(CmdTo::Start).unwrap();
+        thread::sleep(Duration::from_millis(100)); // the first step is right away, then 10 s to go
+        let start = time::precise_time_ns();
+        cmd_tx.send(CmdTo::Quit).unwrap();
+        service.join().unwrap();
+        assert!(time::precise_time_ns() - start < 1_000 * MS);
+        drop(rx);
+    }
+
+    /// Read end of the pipe that Piped watches
+    static PIPE: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+    struct Piped {
+        tx: Sender<CmdFrom>,
+    }
+
+    guilty! {
+        impl Controllable for Piped {
+            const NAME: &'static str = "piped",
+            const BLOCK: Block = Block::Fd,
+
+            fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> Piped {
+                watch(Some(PIPE.load(Ordering::SeqCst) as RawFd));
+                Piped { tx: tx }
+            }
+
+            fn step(&mut self, data: Option<String>) {
+                let ready = readable();
+                if ready {
+                    let mut byte = 0u8;
+                    unsafe { libc::read(PIPE.load(Ordering::SeqCst) as RawFd, &mut byte as *mut u8 as *mut c_void, 1); }
+                }
+                self.tx.send(CmdFrom::Data(format!("{:?} {}", data, ready))).unwrap();
+            }
+
+            fn teardown(&mut self) {
+            }
+        }
+    }
+
+    fn next_data(rx: &Receiver<CmdFrom>) -> String {
+        loop {
+            if let CmdFrom::Data(s) = rx.recv().unwrap() {
+                return s;
+            }
+        }
+    }
+
+    #[test]
+    fn fd_readable() {
+        let mut fds = [0 as c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        PIPE.store(fds[0] as isize, Ordering::SeqCst);
+
+        let (cmd_tx, cmd_rx) = service_channel();
+        let (tx, rx) = mpsc::channel();
+        let service = thread::spawn(move || go::<Piped>(cmd_rx, tx));
+        cmd_tx.send(CmdTo::Start).unwrap();
+
+        // a command alone must not let step() read
+        cmd_tx.send(CmdTo::Data("hello".to_owned())).unwrap();
+        assert_eq!(next_data(&rx), "Some(\"hello\") false");
+
+        let byte = 1u8;
+        unsafe { libc::write(fds[1], &byte as *const u8 as *const c_void, 1); }
+        assert_eq!(next_data(&rx), "None true");
+
+        cmd_tx.send(CmdTo::Quit).unwrap();
+        service.join().unwrap();
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
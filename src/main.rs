@@ -172,7 +172,10 @@ macro_rules! prof {
 }
 
 #[macro_use] mod comms;
+mod pool;
 mod scribe;
+mod preview;
+mod calibration;
 mod cli;
 mod web;
 mod teensy;
@@ -188,7 +191,7 @@ use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender};
 use std::collections::HashMap;
 use std::time::Duration;
-use comms::{Controllable, CmdTo, CmdFrom, Power};
+use comms::{Controllable, CmdTo, CmdFrom, CmdSender, Power};
 use cli::CLI;
 use web::Web;
 use teensy::Teensy;
@@ -252,7 +255,7 @@ struct Service {
     thread: Option<thread::JoinHandle<()>>,
     /// synchronized Sender for commands from the master thread
     /// (should always be Some after Service::start runs)
-    tx: Arc<Mutex<Option<Sender<CmdTo>>>>,
+    tx: Arc<Mutex<Option<CmdSender>>>,
 }
 
 impl Service {
@@ -287,7 +290,7 @@ impl Service {
                                    // create master => service channel
                                    //   (give receiving end to service thread, swap sending end
                                    //   into self.tx)
-                                   let (thread_tx, thread_rx) = channel::<CmdTo>();
+                                   let (thread_tx, thread_rx) = comms::service_channel();
                                    // clone sending end of service => master channel
                                    let cloned_master_tx = master_tx.clone();
                                    // perform the self.tx swap
@@ -362,6 +365,7 @@ fn main() {
 
         let mut services = rxspawn!(reply_tx; CLI, Web, Teensy, Optoforce, Structure, Bluefox, Optoforce, Biotac);
         let mut timers = HashMap::new();
+        let mut snapshots = 0; // snapshots taken, so each set of camera stills gets its own number
 
         thread::sleep(Duration::from_millis(500)); // wait for threads to start
 
@@ -381,7 +385,7 @@ fn main() {
                     },
                     CmdFrom::Quit         => {
                         println!("STOPPING ALL");
-                        stop_all(&mut services[1..]);
+                        stop_all(&mut services);
                         println!("EXITING");
                         break;
                     },
@@ -394,7 +398,7 @@ fn main() {
                         fs::remove_file("keepalive").unwrap();
 
                         // step 2. stop all services
-                        stop_all(&mut services[1..]);
+                        stop_all(&mut services);
 
                         // step 3. reboot system through DBUS
                         process::Command::new("dbus-send")
@@ -433,6 +437,12 @@ fn main() {
                             "send" => { send_to(&services, "web".to_owned(), CmdTo::Data(d[5..].to_owned())); },
                             "kick" => { send_to(&services, words.next().unwrap().to_owned(), CmdTo::Data("kick".to_owned())); },
                             "to"   => { send_to(&services, words.next().unwrap().to_owned(), CmdTo::Data(words.collect::<Vec<_>>().join(" "))); },
+                            "snapshot" => {
+                                snapshots += 1;
+                                for &cam in &["structure", "bluefox"] {
+                                    send_to(&services, cam.to_owned(), CmdTo::Data(format!("snapshot {}", snapshots)));
+                                }
+                            },
                             _      => { errorln!("Strange message {} received from a service", d); }
                         }
                     },
@@ -441,13 +451,10 @@ fn main() {
                         send_to(&services, "web".to_owned(), CmdTo::Data(format!("panic {} {}", who, why)));
                     },
                 },
-                Err(_) => { stop_all(&mut services[1..]); break; }
+                Err(_) => { stop_all(&mut services); break; }
             }
         }
 
-        // we can't really join or kill the CLI thread, because it is waiting on stdin
-        // so just exit, and it will be killed
-
     });
 
     println!("\n\n");
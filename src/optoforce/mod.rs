@@ -4,63 +4,90 @@
 //!
 //! The driver SDK shipped by Optoforce is crap. The example barely compiles, depends on Qt (for
 //! _serial port access_ of all things), and core dumps the first time it is run (after that it
-//! freezes instead). The precompiled GUI application actually runs, which is a major step up, but
-//! it was compiled with an absolute path to the fonts directory in someone's home directory, so it
-//! requires a symlink under /home or an LD_PRELOAD library to display any characters. With that
-//! minor annoyance fixed, it shows numbers and plots that confirm the sensor works! It can even
-//! log values to a file, which may be useful for sanity checks later. But this isn't really good
-//! enough for our use case, unless we did some terrible hack such as starting the GUI to write to
-//! a file and having the NRI supervisor tail the file.
+//! freezes instead). The precompiled GUI application actually runs, but it isn't really good
+//! enough for our use case.
 //!
-//! Luckily, third-party drivers exist. A quick search found [liboptoforce][liboptoforce], out of
-//! ETH Zurich, freely available on Github. I installed the software from their PPA. (The source is
-//! in a git submodule.  Unfortunately building it requires ETH Zurich's own build system, called
-//! ReMake, which I also checked out into a submodule, but I can't get that to build, so I can't
-//! build liboptoforce either. Therefore, PPA it is.) I was able to compile the example program and
-//! it shows numbers from the sensor!
+//! For a while we used [liboptoforce][liboptoforce], out of ETH Zurich, through a little C++
+//! adapter library. That could only be installed from their PPA (building it requires ETH Zurich's
+//! own build system, which I couldn't get to build), and it had the annoying property that when
+//! the sensor was set to 1 kHz we only got readings at 500 Hz, since we polled the latest reading
+//! every 1 ms instead of receiving each sample.
 //!
-//! The ETH Zurich package includes a configuration program, which can set the sensor sample speed
-//! (among other things), and a statistics program which can measure it (among other things).
-//! Unfortunately, when to set to 1 kHz (the maximum) the sensor sends readings at only 500 Hz.
-//! This is probably still enough for us. A calibration program is also included, which will be
-//! useful for zeroing the sensor.
+//! So now we speak the sensor's serial protocol directly (see the wrapper module). There is no C++
+//! dependency, every sample is delivered exactly once (the sample counter is recorded, so gaps are
+//! visible in the data), and the driver can be exercised against `examples/optoforceemu.rs`, which
+//! emulates the sensor on a pseudo-terminal.
 //!
-//! - Commands to install liboptoforce:
-//! <code>
-//! $ sudo apt-add-repository ppa:ethz-asl/drivers
-//! $ sudo apt-get update
-//! $ sudo apt-get install liboptoforce*
-//! </code>
-//! - To compile and run the sample program:
-//! <code>
-//! $ sudo apt-get install libboost-{system,thread,signals,chrono,program-options}-dev
-//! $ cd liboptoforce/src/bin
-//! $ make
-//! $ ./configure -d /dev/ttyACM0 -s 1000
-//! $ ./statistics -d /dev/ttyACM0 -s
-//! $ ./dump_readings -d /dev/ttyACM0 -s
-//! $ ./calibrate -d /dev/ttyACM0
-//! </code>
+//! # Commands
+//!
+//! While running, the service accepts these commands (e.g. `: optoforce tare` in a flow):
+//!
+//! - `speed 30|100|333|1000`, `filter none|15|50|150`, `mode raw|force`: change the sensor
+//!   settings on the fly
+//! - `tare`: average the next few samples and subtract them from all subsequent readings (the
+//!   offset is recorded next to every reading, so the raw values can be recovered)
+//!
+//! The sensor state reported in every sample is monitored, and overloads or failures are raised
+//! as alarms in the web interface and in the active flow's record.
+//!
+//! The serial port defaults to `/dev/ttyOPTO` (see 99-usb-serial.rules). It can be overridden with
+//! the `NRI_OPTOFORCE_PORT` environment variable, or at runtime with `set_port`.
 //!
 //! [liboptoforce]: https://github.com/ethz-asl/liboptoforce
 
+use std::env;
+use std::sync::RwLock;
+
+/// Default path of the OptoForce's serial port
+pub const DEFAULT_PORT: &'static str = "/dev/ttyOPTO";
+
+lazy_static! {
+    /// Path of the serial port that will be opened the next time the service starts
+    static ref PORT: RwLock<String> = RwLock::new(env::var("NRI_OPTOFORCE_PORT").unwrap_or(DEFAULT_PORT.to_owned()));
+}
+
+/// Change the serial port used by the OptoForce service
+///
+/// Takes effect the next time the service is started.
+pub fn set_port<S: Into<String>>(path: S) {
+    *PORT.write().unwrap() = path.into();
+}
+
+/// Get the serial port path currently in use
+pub fn port() -> String {
+    PORT.read().unwrap().clone()
+}
+
 group_attr!{
     #[cfg(target_os = "linux")]
 
     extern crate time;
 
     use std::thread;
-    use std::io::Write;
     use std::default::Default;
     use std::sync::mpsc::Sender;
     use std::time::Duration;
-    use ::comms::{Controllable, CmdFrom, Block};
+    use ::comms::{self, Controllable, CmdFrom, Block};
     use ::scribe::{Writer, Writable};
 
     mod wrapper;
 
+    use self::wrapper::settings::{Speed, Filter, Mode, State};
+
+    /// Number of samples averaged by the tare command
+    const TARE_SAMPLES: usize = 100;
+
     pub struct Optoforce {
+        tx: Sender<CmdFrom>,
         device: wrapper::Device,
+        /// Settings most recently sent to the sensor
+        settings: wrapper::Settings,
+        /// Sensor state reported in the most recent sample
+        state: Option<State>,
+        /// Offset subtracted from every reading
+        offset: wrapper::XYZ,
+        /// Tare in progress (samples remaining, running sum)
+        taring: Option<(usize, [f64; 3])>,
         i: usize,
         file: Writer<Packet>,
         start: time::Tm
@@ -69,8 +96,100 @@ group_attr!{
     #[repr(packed)]
     #[allow(dead_code)]
     struct Packet {
-        stamp: time::Timespec,
-        xyz  : wrapper::XYZ,
+        stamp  : time::Timespec,
+        seq    : u16,
+        config : u8,
+        /// Tared reading (raw reading = xyz + offset)
+        xyz    : wrapper::XYZ,
+        offset : wrapper::XYZ,
+    }
+
+    impl Optoforce {
+        /// Handle a command from on high
+        fn command(&mut self, cmd: &str) {
+            let mut words = cmd.split(' ');
+            let new_settings = match (words.next(), words.next()) {
+                (Some("speed"), Some(hz)) => hz.parse().ok().and_then(Speed::from_hz)
+                                               .map(|speed| self.settings.set_speed(speed)),
+                (Some("filter"), Some("none")) => Some(self.settings.set_filter(Filter::None)),
+                (Some("filter"), Some(hz)) => hz.parse().ok().and_then(Filter::from_hz)
+                                                .map(|filter| self.settings.set_filter(filter)),
+                (Some("mode"), Some("raw")) => Some(self.settings.set_mode(Mode::Raw)),
+                (Some("mode"), Some("force")) => Some(self.settings.set_mode(Mode::Force)),
+                (Some("tare"), None) => {
+                    println!("OptoForce: taring over the next {} samples", TARE_SAMPLES);
+                    self.taring = Some((TARE_SAMPLES, [0.0; 3]));
+                    return;
+                },
+                _ => {
+                    errorln!("OptoForce: bad command {:?}", cmd);
+                    return;
+                },
+            };
+
+            match new_settings {
+                Some(settings) => match self.device.set(settings) {
+                    Ok(()) => {
+                        println!("OptoForce settings changed to {:?}", settings);
+                        self.settings = settings;
+                    },
+                    Err(e) => errorln!("OptoForce: could not change settings: {}", e),
+                },
+                None => errorln!("OptoForce: unsupported setting {:?}", cmd),
+            }
+        }
+
+        /// Keep track of the sensor state and raise alarms when it changes
+        fn monitor(&mut self, state: Option<State>) {
+            if state == self.state {
+                return;
+            }
+            self.state = state;
+
+            let msg = match state {
+                Some(State::SensorOk) => "clear".to_owned(),
+                Some(s)               => format!("{:?}", s),
+                None                  => "InvalidState".to_owned(),
+            };
+            if state != Some(State::SensorOk) {
+                errorln!("OptoForce alarm: {}", msg);
+            }
+            self.tx.send(CmdFrom::Data(format!("send alarm optoforce {}", msg))).unwrap();
+        }
+
+        /// Record one sample
+        fn record(&mut self, sample: wrapper::Sample) {
+            if sample.dropped > 0 {
+                errorln!("OptoForce: {} samples dropped before #{}", sample.dropped, sample.seq);
+            }
+            self.monitor(sample.settings().map(|s| s.state));
+            self.tare(&sample.xyz);
+            self.file.write(Packet {
+                stamp  : time::get_time(),
+                seq    : sample.seq,
+                config : sample.config,
+                xyz    : sample.xyz.sub(&self.offset),
+                offset : self.offset,
+            });
+            self.i += 1;
+        }
+
+        /// Accumulate a sample for a tare in progress
+        fn tare(&mut self, raw: &wrapper::XYZ) {
+            if let Some((left, mut sum)) = self.taring.take() {
+                let xyz = raw.to_array();
+                for i in 0..3 {
+                    sum[i] += xyz[i];
+                }
+                if left > 1 {
+                    self.taring = Some((left - 1, sum));
+                } else {
+                    let n = TARE_SAMPLES as f64;
+                    self.offset = wrapper::XYZ::new(sum[0] / n, sum[1] / n, sum[2] / n);
+                    println!("OptoForce: tared, offset = {:?}", self.offset);
+                }
+            }
+        }
     }
 
     unsafe impl Writable for Packet {}
@@ -78,31 +197,66 @@ group_attr!{
     guilty!{
         impl Controllable for Optoforce {
             const NAME: &'static str = "optoforce",
-            const BLOCK: Block = Block::Period(1_000_000),
+            const BLOCK: Block = Block::Fd, // step() runs when the port has data
 
-            fn setup(_: Sender<CmdFrom>, _: Option<String>) -> Optoforce {
-                let dev = wrapper::Device::new(Default::default());
-                dev.connect(wrapper::ConnectOptions { path: "/dev/ttyOPTO", ..Default::default() }).unwrap();
+            fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> Optoforce {
+                let path = super::port();
+                let mut dev = wrapper::Device::new(Default::default());
+                dev.connect(wrapper::ConnectOptions { path: &path, ..Default::default() })
+                    .unwrap_or_else(|e| panic!("Could not connect to OptoForce on {}: {}", path, e));
                 thread::sleep(Duration::from_millis(100));
-                dev.set(wrapper::Settings::new()
-                        .set_speed(wrapper::settings::Speed::Hz1000)
-                       );
-                println!("Optoforce settings: {:?}", dev.get().unwrap());
-                Optoforce { device: dev, i: 0, file: Writer::with_file("optoforce.dat"), start: time::now() }
+                let settings = wrapper::Settings::new().set_speed(Speed::Hz1000);
+                dev.set(settings).unwrap();
+                match dev.read() {
+                    Ok(sample) => println!("Optoforce settings: {:?}", sample.settings()),
+                    Err(e)     => panic!("OptoForce is not sending data: {}", e),
+                }
+                comms::watch(dev.fd());
+                Optoforce {
+                    tx: tx,
+                    device: dev,
+                    settings: settings,
+                    state: Some(State::SensorOk),
+                    offset: wrapper::XYZ::zero(),
+                    taring: None,
+                    i: 0,
+                    file: Writer::with_file("optoforce.dat"),
+                    start: time::now()
+                }
             }
 
-            fn step(&mut self, _: Option<String>) {
-                self.file.write(Packet {
-                    stamp: time::get_time(),
-                    xyz: self.device.read()
-                });
-                self.i += 1;
+            fn step(&mut self, cmd: Option<String>) {
+                if let Some(cmd) = cmd {
+                    self.command(&cmd);
+                }
+                if !comms::readable() {
+                    return; // only woken for the command
+                }
+
+                match self.device.read() {
+                    Ok(sample) => {
+                        self.record(sample);
+                        // the rest of what came in with it (the port will not be readable again
+                        // until more arrives)
+                        while let Some(sample) = self.device.buffered() {
+                            self.record(sample);
+                        }
+                    },
+                    Err(wrapper::Error::TimedOut) => {},
+                    Err(e) => errorln!("OptoForce read error: {}", e),
+                }
             }
 
             fn teardown(&mut self) {
+                if self.state != Some(State::SensorOk) {
+                    self.monitor(Some(State::SensorOk)); // don't leave a stale alarm up
+                }
+                self.device.disconnect();
                 let end = time::now();
                 let millis = (end - self.start).num_milliseconds() as f64;
-                println!("{} optoforce frames grabbed in {} s ({} FPS)!", self.i, millis/1000.0, 1000.0*(self.i as f64)/millis);
+                println!("{} optoforce frames grabbed in {} s ({} FPS, {} dropped, {} corrupted, {} resyncs)!",
+                         self.i, millis/1000.0, 1000.0*(self.i as f64)/millis,
+                         self.device.dropped, self.device.corrupted, self.device.resyncs);
             }
         }
     }
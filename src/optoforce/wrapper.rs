@@ -1,21 +1,30 @@
-#![allow(dead_code)] // these are full bindings to the adapter lib
+//! Native driver for the OptoForce 3-axis sensor's serial protocol
+//!
+//! This replaces the liboptoforce C++ adapter. The sensor shows up as a USB CDC serial port and
+//! speaks the following framed protocol (all multi-byte fields are big-endian):
+//!
+//! - Sensor to host, one frame per sample (16 bytes):
+//!   <pre>AA 07 08 0A | counter: u16 | status: u8 | config: u8 | Fx: i16 | Fy: i16 | Fz: i16 | checksum: u16</pre>
+//!   The config byte is the same settings byte that the host sends (see Settings::encode), except
+//!   that the top three bits report the sensor state (overloads, failures). The counter
+//!   increments by one for every sample, so gaps reveal dropped samples. A counter that repeats
+//!   or jumps backwards (e.g. the sensor restarted) is not a gap; counting starts over from it.
+//! - Host to sensor, to change the settings (7 bytes):
+//!   <pre>AA 00 32 01 | config: u8 | checksum: u16</pre>
+//!
+//! The checksum is the 16-bit sum of all preceding bytes in the frame.
+
+#![allow(dead_code)] // these are full bindings to the sensor protocol
+
+extern crate serial;
 
-extern crate libc;
-use self::libc::{c_void, c_int, c_char, c_uchar, c_float, c_double};
 use std::default::Default;
-use std::f32;
-use std::ffi::CString;
-use std::fmt;
+use std::{fmt, io};
+use std::io::{Read, Write};
 use std::ops::Deref;
-
-// FIXME this is totally undefined behavior all the time!
-// for literals: b"str\0".as_ptr()
-// for strings: keep the CString alive until the C function returns
-macro_rules! c_str {
-    ($s:expr) => {
-        CString::new($s).unwrap().as_ptr()
-    }
-}
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+use self::serial::prelude::*;
 
 macro_rules! try_opt {
     ($e:expr) => {
@@ -26,28 +35,51 @@ macro_rules! try_opt {
     }
 }
 
+/// Header of a sample frame (sensor to host)
+const SAMPLE_HEADER: [u8; 4] = [0xAA, 0x07, 0x08, 0x0A];
+/// Header of a configuration frame (host to sensor)
+const CONFIG_HEADER: [u8; 4] = [0xAA, 0x00, 0x32, 0x01];
+/// Length of a sample frame
+const SAMPLE_LEN: usize = 16;
+/// Default counts-to-Newtons conversion
+const DEFAULT_FACTOR: f32 = 0.001;
+/// Default baud rate (the sensor is USB CDC, so this is mostly ceremonial)
+const DEFAULT_BAUD: i32 = 115200;
+
+/// 16-bit sum of the bytes
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |acc, &b| acc.wrapping_add(b as u16))
+}
 
-#[link(name = "optoforce_adapter")]
-extern "C" {
-    fn lofa_get_version() -> Version;
-    fn lofa_create_sensor(buffer: c_int, factor: c_float) -> Handle;
-    fn lofa_sensor_connect(that: Handle, device: *const c_char, baudrate: c_int) -> bool;
-    fn lofa_sensor_disconnect(that: Handle, should_block: bool);
-    fn lofa_free_sensor(that: Handle);
-    fn lofa_sensor_read(that: Handle) -> XYZ;
-    fn lofa_sensor_get(that: Handle) -> c_uchar;
-    fn lofa_sensor_set(that: Handle, byte: c_uchar);
+fn be_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
 }
 
-#[repr(C)]
-pub struct Version {
-    major    : c_int,
-    minor    : c_int,
-    revision : c_int,
+/// Remove the first `n` bytes of a buffer (keeping its allocation)
+fn discard(buf: &mut Vec<u8>, n: usize) {
+    let len = buf.len();
+    for i in n..len {
+        buf[i - n] = buf[i];
+    }
+    buf.truncate(len - n);
+}
+
+/// Build the frame that sets the sensor configuration
+pub fn config_frame(conf: Settings) -> [u8; 7] {
+    let mut frame = [0u8; 7];
+    for (f, &h) in frame.iter_mut().zip(CONFIG_HEADER.iter()) {
+        *f = h;
+    }
+    frame[4] = conf.encode();
+    let sum = checksum(&frame[..5]);
+    frame[5] = (sum >> 8) as u8;
+    frame[6] = (sum & 0xFF) as u8;
+    frame
 }
 
 #[repr(C)]
-pub struct Double(c_double);
+#[derive(Copy, Clone)]
+pub struct Double(f64);
 
 impl fmt::Debug for Double {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
@@ -63,7 +95,7 @@ impl Deref for Double {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 #[repr(C)]
 pub struct XYZ {
     x : Double,
@@ -71,46 +103,237 @@ pub struct XYZ {
     z : Double,
 }
 
-type Handle = *mut c_void;
+impl XYZ {
+    pub fn new(x: f64, y: f64, z: f64) -> XYZ {
+        XYZ { x: Double(x), y: Double(y), z: Double(z) }
+    }
+
+    pub fn zero() -> XYZ {
+        XYZ::new(0.0, 0.0, 0.0)
+    }
+
+    pub fn to_array(&self) -> [f64; 3] {
+        [*self.x, *self.y, *self.z]
+    }
+
+    pub fn sub(&self, other: &XYZ) -> XYZ {
+        XYZ::new(*self.x - *other.x, *self.y - *other.y, *self.z - *other.z)
+    }
+}
+
+/// One sample from the sensor
+#[derive(Debug, Copy, Clone)]
+pub struct Sample {
+    /// Sample counter (wraps around)
+    pub seq      : u16,
+    /// Number of samples missing between the previous one and this one
+    pub dropped  : u16,
+    /// Raw status byte
+    pub status   : u8,
+    /// Raw config byte (decode with Settings::decode)
+    pub config   : u8,
+    /// Raw force readings (counts)
+    pub raw      : [i16; 3],
+    /// Force readings (N)
+    pub xyz      : XYZ,
+}
+
+impl Sample {
+    pub fn settings(&self) -> Option<Settings> {
+        Settings::decode(self.config)
+    }
+}
+
+/// Things that can go wrong while talking to the sensor
+#[derive(Debug)]
+pub enum Error {
+    /// Could not open or configure the serial port
+    Serial(serial::Error),
+    /// Read or write failed
+    Io(io::Error),
+    /// connect() has not been called (or disconnect() has)
+    NotConnected,
+    /// No complete frame arrived before the port timed out
+    TimedOut,
+}
+
+impl From<serial::Error> for Error {
+    fn from(e: serial::Error) -> Error {
+        Error::Serial(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::TimedOut => Error::TimedOut,
+            _                       => Error::Io(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Serial(ref e) => write!(f, "could not open serial port: {}", e),
+            Error::Io(ref e)     => write!(f, "serial I/O error: {}", e),
+            Error::NotConnected  => write!(f, "not connected"),
+            Error::TimedOut      => write!(f, "timed out waiting for a sample"),
+        }
+    }
+}
 
 pub struct Device {
-    pdev: Handle,
+    opt      : DeviceOptions,
+    port     : Option<serial::SystemPort>,
+    buf      : Vec<u8>,
+    last_seq : Option<u16>,
+    config   : Option<u8>,
+
+    /// Total number of samples missed (according to the counter)
+    pub dropped   : usize,
+    /// Total number of frames thrown away because of bad checksums
+    pub corrupted : usize,
+    /// Number of times the counter repeated or went backwards
+    pub resyncs   : usize,
 }
 
 impl Device {
     pub fn new(opt: DeviceOptions) -> Device {
-        Device { pdev: unsafe { lofa_create_sensor(opt.buffer as c_int, opt.factor as c_float) } }
+        Device {
+            opt       : opt,
+            port      : None,
+            buf       : Vec::with_capacity(4 * SAMPLE_LEN),
+            last_seq  : None,
+            config    : None,
+            dropped   : 0,
+            corrupted : 0,
+            resyncs   : 0,
+        }
     }
 
-    pub fn connect(&self, opt: ConnectOptions) -> Result<(), ()> {
-        if unsafe { lofa_sensor_connect(self.pdev, c_str!(opt.path), opt.baud) } {
+    pub fn connect(&mut self, opt: ConnectOptions) -> Result<(), Error> {
+        let mut port = try!(serial::open(opt.path));
+        let baud = if opt.baud == -1 { DEFAULT_BAUD } else { opt.baud };
+        try!(port.reconfigure(&|settings| {
+            try!(settings.set_baud_rate(serial::BaudRate::from_speed(baud as usize)));
             Ok(())
-        } else {
-            Err(())
-        }
+        }));
+        try!(port.set_timeout(Duration::from_millis(100)));
+        self.port = Some(port);
+        self.buf.clear();
+        self.last_seq = None;
+        Ok(())
     }
 
-    pub fn disconnect(&self, block: bool) {
-        unsafe {
-            lofa_sensor_disconnect(self.pdev, block);
-        }
+    pub fn disconnect(&mut self) {
+        self.port = None;
+    }
+
+    /// File descriptor of the serial port (to wait for data on), if connected
+    pub fn fd(&self) -> Option<RawFd> {
+        self.port.as_ref().map(|p| p.as_raw_fd())
     }
 
-    pub fn set(&self, conf: Settings) {
-        unsafe { lofa_sensor_set(self.pdev, conf.encode()) };
+    pub fn set(&mut self, conf: Settings) -> Result<(), Error> {
+        let port = try!(self.port.as_mut().ok_or(Error::NotConnected));
+        try!(port.write_all(&config_frame(conf)));
+        try!(port.flush());
+        Ok(())
     }
 
+    /// Settings reported in the most recent sample
     pub fn get(&self) -> Option<Settings> {
-        Settings::decode(unsafe { lofa_sensor_get(self.pdev) })
+        self.config.and_then(Settings::decode)
     }
 
-    pub fn read(&self) -> XYZ {
-        unsafe { lofa_sensor_read(self.pdev) }
+    /// Block until the next sample arrives
+    ///
+    /// Every sample is returned exactly once. Samples that the sensor sent but never arrived are
+    /// counted in Sample::dropped.
+    pub fn read(&mut self) -> Result<Sample, Error> {
+        loop {
+            if let Some(sample) = self.parse() {
+                return Ok(sample);
+            }
+
+            let port = try!(self.port.as_mut().ok_or(Error::NotConnected));
+            let mut chunk = [0u8; 256];
+            let n = try!(port.read(&mut chunk));
+            if n == 0 {
+                return Err(Error::TimedOut);
+            }
+            self.buf.extend(&chunk[..n]);
+        }
+    }
+
+    /// Take the next sample that has already been read from the port, if there is a whole one
+    ///
+    /// One read() from the port can bring in several samples; this gets the rest without
+    /// blocking.
+    pub fn buffered(&mut self) -> Option<Sample> {
+        self.parse()
+    }
+
+    /// Try to take one sample frame out of the buffer
+    fn parse(&mut self) -> Option<Sample> {
+        loop {
+            // resynchronize on the header
+            match self.buf.windows(SAMPLE_HEADER.len()).position(|w| w == SAMPLE_HEADER) {
+                Some(0) => {},
+                Some(i) => discard(&mut self.buf, i),
+                None    => {
+                    // keep a possible partial header at the end
+                    let keep = if self.buf.len() < SAMPLE_HEADER.len() { self.buf.len() } else { SAMPLE_HEADER.len() - 1 };
+                    let garbage = self.buf.len() - keep;
+                    discard(&mut self.buf, garbage);
+                    return None;
+                },
+            }
+
+            if self.buf.len() < SAMPLE_LEN {
+                return None;
+            }
+
+            if checksum(&self.buf[..SAMPLE_LEN-2]) != be_u16(&self.buf[SAMPLE_LEN-2..SAMPLE_LEN]) {
+                // not a real frame (or a corrupted one): skip the header and look again
+                self.corrupted += 1;
+                discard(&mut self.buf, 1);
+                continue;
+            }
+
+            let frame = self.buf[..SAMPLE_LEN].to_vec();
+            discard(&mut self.buf, SAMPLE_LEN);
+            let seq = be_u16(&frame[4..6]);
+            let raw = [be_u16(&frame[8..10]) as i16, be_u16(&frame[10..12]) as i16, be_u16(&frame[12..14]) as i16];
+            let dropped = match self.last_seq.map(|last| seq.wrapping_sub(last)) {
+                // forwards (allowing for wraparound)
+                Some(gap) if gap > 0 && gap < 0x8000 => gap - 1,
+                // repeated or backwards: start counting again from here
+                Some(_) => {
+                    self.resyncs += 1;
+                    0
+                },
+                None => 0,
+            };
+            self.last_seq = Some(seq);
+            self.dropped += dropped as usize;
+            self.config = Some(frame[7]);
+
+            let factor = self.opt.factor as f64;
+            return Some(Sample {
+                seq     : seq,
+                dropped : dropped,
+                status  : frame[6],
+                config  : frame[7],
+                raw     : raw,
+                xyz     : XYZ::new(raw[0] as f64 * factor, raw[1] as f64 * factor, raw[2] as f64 * factor),
+            });
+        }
     }
 }
 
 pub struct DeviceOptions {
-    pub buffer : isize,
     pub factor : f32,
 }
 
@@ -140,7 +363,7 @@ pub mod settings {
 
         (OUT $s:ident: $t:ty => $d:ty, [$(($hz_variant:ident, $hz_hz:expr, $hz_dev:expr)),*],
                                        [$(($nohz_variant:ident, $nohz_dev:expr)),*]) => {
-            #[derive(Debug, Copy, Clone)]
+            #[derive(Debug, Copy, Clone, PartialEq)]
             pub enum $s {
                 $($hz_variant,)*
                 $($nohz_variant),*
@@ -247,14 +470,14 @@ impl Settings {
         self
     }
 
-    fn encode(self) -> u8 {
+    pub fn encode(self) -> u8 {
         (self.mode.to_device())
             | (self.filter.to_device() << 1)
             | (self.speed.to_device()  << 3)
             | (self.state.to_device()  << 5)
     }
 
-    fn decode(byte: u8) -> Option<Settings> {
+    pub fn decode(byte: u8) -> Option<Settings> {
         Some(Settings {
                 mode   : try_opt!(settings::Mode::  from_device( byte       & 0b0000_0001)),
                 filter : try_opt!(settings::Filter::from_device((byte >> 1) & 0b0000_0011)),
@@ -266,7 +489,7 @@ impl Settings {
 
 impl Default for DeviceOptions {
     fn default() -> DeviceOptions {
-        DeviceOptions { buffer: -1, factor: f32::NAN }
+        DeviceOptions { factor: DEFAULT_FACTOR }
     }
 }
 
@@ -287,11 +510,87 @@ impl Default for Settings {
     }
 }
 
-impl Drop for Device {
-    fn drop(&mut self) {
-        unsafe {
-            lofa_sensor_disconnect(self.pdev, false);
-            lofa_free_sensor(self.pdev);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(seq: u16, force: [i16; 3]) -> Vec<u8> {
+        let mut frame = SAMPLE_HEADER.to_vec();
+        frame.push((seq >> 8) as u8);
+        frame.push(seq as u8);
+        frame.push(0); // status
+        frame.push(0); // config
+        for &f in &force {
+            frame.push((f as u16 >> 8) as u8);
+            frame.push(f as u8);
+        }
+        let sum = checksum(&frame);
+        frame.push((sum >> 8) as u8);
+        frame.push(sum as u8);
+        assert_eq!(frame.len(), SAMPLE_LEN);
+        frame
+    }
+
+    fn with_bytes(bytes: &[u8]) -> Device {
+        let mut device = Device::new(DeviceOptions { factor: 0.5 });
+        device.buf.extend(bytes);
+        device
+    }
+
+    #[test]
+    fn parse_frame() {
+        let mut device = with_bytes(&frame(7, [2, -4, 1000]));
+        let sample = device.buffered().unwrap();
+        assert_eq!(sample.seq, 7);
+        assert_eq!(sample.raw, [2, -4, 1000]);
+        assert_eq!(sample.xyz.to_array(), [1.0, -2.0, 500.0]);
+        assert!(device.buffered().is_none());
+        assert_eq!((device.dropped, device.corrupted, device.resyncs), (0, 0, 0));
+    }
+
+    #[test]
+    fn checksum_failure() {
+        let mut bytes = frame(1, [1, 2, 3]);
+        bytes[9] ^= 0x40;
+        bytes.extend(frame(2, [4, 5, 6]));
+        let mut device = with_bytes(&bytes);
+        let sample = device.buffered().unwrap();
+        assert_eq!((sample.seq, sample.raw), (2, [4, 5, 6]));
+        assert_eq!(device.corrupted, 1);
+        assert!(device.buffered().is_none());
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let mut bytes = vec![0x00, 0xAA, 0x07, 0xAA, 0x13];
+        bytes.extend(frame(1, [1, 1, 1]));
+        // a frame cut short, and then the next one
+        bytes.extend(&frame(2, [2, 2, 2])[..9]);
+        bytes.extend(frame(3, [3, 3, 3]));
+        let mut device = with_bytes(&bytes);
+        assert_eq!(device.buffered().unwrap().seq, 1);
+        assert_eq!(device.buffered().unwrap().seq, 3);
+        assert!(device.buffered().is_none());
+        assert_eq!(device.dropped, 1);
+
+        // a partial header at the end is kept for the next read
+        let mut device = with_bytes(&[0x01, 0x02, 0xAA, 0x07]);
+        assert!(device.buffered().is_none());
+        assert_eq!(device.buf, vec![0x02, 0xAA, 0x07]);
+        device.buf.extend(&frame(9, [0, 0, 0])[2..]);
+        assert_eq!(device.buffered().unwrap().seq, 9);
+    }
+
+    #[test]
+    fn counter() {
+        let mut bytes = vec![];
+        for &seq in &[65534, 65535, 0, 3, 3, 1, 2] {
+            bytes.extend(frame(seq, [0, 0, 0]));
         }
+        let mut device = with_bytes(&bytes);
+        let dropped = (0..7).map(|_| device.buffered().unwrap().dropped).collect::<Vec<_>>();
+        // wraps around without a gap, then 2 missing, a repeat and a jump backwards
+        assert_eq!(dropped, vec![0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!((device.dropped, device.resyncs), (2, 2));
     }
 }
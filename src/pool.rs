@@ -0,0 +1,261 @@
+//! Reusable frame buffers
+//!
+//! Camera services copy each frame out of the driver's buffer once, into a Buffer taken from their
+//! Pool, and then move that buffer around (to scribe for writing, or to a preview encoder) instead
+//! of copying it again. When the last owner drops a buffer, it goes back to its pool rather than
+//! being freed, so once every pool has warmed up the frame path does not allocate at all.
+//!
+//! Every allocation a pool makes runs under `prof!("pool alloc")`, so the profiler's call counts
+//! show how many there were per run, and pool_stats() (the `pools` CLI command) has the totals and
+//! rates for each pool.
+
+extern crate time;
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Weak, Mutex};
+
+/// What a Pool has been up to (see pool_stats)
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub name: &'static str,
+    /// Buffers allocated (or grown) because no free one was big enough
+    pub allocated: u64,
+    /// Buffers taken from the free list
+    pub reused: u64,
+    /// Buffers freed because the free list was full
+    pub discarded: u64,
+    /// Buffers waiting in the free list right now
+    pub free: usize,
+    /// Bytes held by the free list right now
+    pub bytes: usize,
+    /// When the pool was created (ns, from time::precise_time_ns)
+    pub since: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = (time::precise_time_ns() - self.since) as f64 / 1e9;
+        write!(f, "{}: {} allocated ({:.2}/s), {} reused ({:.1}/s), {} discarded, {} free ({:.1} MB)",
+               self.name,
+               self.allocated, self.allocated as f64 / secs,
+               self.reused, self.reused as f64 / secs,
+               self.discarded, self.free, self.bytes as f64 / 1e6)
+    }
+}
+
+lazy_static! {
+    /// Every Pool that is still around
+    static ref POOLS: Mutex<Vec<Weak<Inner>>> = Mutex::new(Vec::new());
+}
+
+/// Get the stats of all the pools (e.g. for the `pools` CLI command)
+pub fn pool_stats() -> Vec<Stats> {
+    let mut all = POOLS.lock().unwrap();
+    all.retain(|p| p.upgrade().is_some());
+    all.iter().filter_map(|p| p.upgrade()).map(|p| p.stats.lock().unwrap().clone()).collect()
+}
+
+struct Inner {
+    /// Most buffers to keep in the free list
+    keep: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+    stats: Mutex<Stats>,
+}
+
+/// A source of reusable buffers
+///
+/// Clones share the same free list.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+}
+
+impl Pool {
+    /// Create a pool which keeps up to `keep` returned buffers for reuse
+    ///
+    /// Make `keep` about the number of buffers that can be in flight at once (e.g. frames waiting
+    /// for scribe), so that the pool does not have to allocate in the steady state.
+    pub fn new(name: &'static str, keep: usize) -> Pool {
+        let inner = Arc::new(Inner {
+            keep: keep,
+            free: Mutex::new(Vec::with_capacity(keep)),
+            stats: Mutex::new(Stats {
+                name      : name,
+                allocated : 0,
+                reused    : 0,
+                discarded : 0,
+                free      : 0,
+                bytes     : 0,
+                since     : time::precise_time_ns(),
+            }),
+        });
+        POOLS.lock().unwrap().push(Arc::downgrade(&inner));
+        Pool { inner: inner }
+    }
+
+    /// Take an empty buffer with room for at least `capacity` bytes
+    ///
+    /// This is the smallest free buffer that is big enough, or if none is, one of the others
+    /// (which has to grow).
+    pub fn take(&self, capacity: usize) -> Buffer {
+        let recycled = {
+            let mut free = self.inner.free.lock().unwrap();
+            let mut fit: Option<usize> = None;
+            for (i, v) in free.iter().enumerate() {
+                if v.capacity() >= capacity && fit.map_or(true, |f| v.capacity() < free[f].capacity()) {
+                    fit = Some(i);
+                }
+            }
+            let recycled = match fit {
+                Some(i) => Some(free.swap_remove(i)),
+                None    => free.pop(),
+            };
+            let mut stats = self.inner.stats.lock().unwrap();
+            stats.free = free.len();
+            if let Some(ref v) = recycled {
+                stats.bytes -= v.capacity();
+            }
+            recycled
+        };
+
+        let data = match recycled {
+            Some(mut v) => {
+                v.clear();
+                if v.capacity() < capacity {
+                    prof!("pool alloc", v.reserve_exact(capacity));
+                    self.inner.stats.lock().unwrap().allocated += 1;
+                } else {
+                    self.inner.stats.lock().unwrap().reused += 1;
+                }
+                v
+            },
+            None => {
+                self.inner.stats.lock().unwrap().allocated += 1;
+                prof!("pool alloc", Vec::with_capacity(capacity))
+            },
+        };
+        Buffer { data: data, home: Some(Arc::downgrade(&self.inner)) }
+    }
+
+    /// Copy some bytes into a buffer
+    pub fn copy(&self, bytes: &[u8]) -> Buffer {
+        let mut buf = self.take(bytes.len());
+        buf.data.extend(bytes);
+        buf
+    }
+
+    /// Get the stats of this pool
+    pub fn stats(&self) -> Stats {
+        self.inner.stats.lock().unwrap().clone()
+    }
+}
+
+/// A byte buffer that goes back to its pool when dropped
+///
+/// The bytes are not necessarily 2-byte aligned, so decode 16-bit samples bytewise rather than
+/// viewing a buffer as a `&[u16]`.
+pub struct Buffer {
+    data: Vec<u8>,
+
+    /// Pool to return to (None if the buffer did not come from a pool, dangling if the pool is gone)
+    home: Option<Weak<Inner>>,
+}
+
+impl Buffer {
+    /// The underlying vector (for filling a buffer from Pool::take)
+    pub fn as_mut_vec(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+
+    /// Convert 16-bit samples from native to big-endian byte order in place
+    pub fn to_be_16(&mut self) {
+        if cfg!(target_endian = "little") {
+            for pair in self.data.chunks_mut(2) {
+                if pair.len() == 2 {
+                    pair.swap(0, 1);
+                }
+            }
+        }
+    }
+}
+
+/// Wrap a vector that was not allocated by a pool (it is freed as usual when dropped)
+impl From<Vec<u8>> for Buffer {
+    fn from(v: Vec<u8>) -> Buffer {
+        Buffer { data: v, home: None }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Buffer({} bytes)", self.data.len())
+    }
+}
+
+impl Drop for Buffer {
+    /// Return the buffer to its pool, if there is room in the free list
+    fn drop(&mut self) {
+        if let Some(pool) = self.home.as_ref().and_then(|h| h.upgrade()) {
+            let mut free = pool.free.lock().unwrap();
+            let mut stats = pool.stats.lock().unwrap();
+            if free.len() < pool.keep {
+                let data = ::std::mem::replace(&mut self.data, Vec::new());
+                stats.bytes += data.capacity();
+                free.push(data);
+                stats.free = free.len();
+            } else {
+                stats.discarded += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_fits() {
+        let pool = Pool::new("test", 4);
+        drop((pool.take(100), pool.take(10), pool.take(1000)));
+        let stats = pool.stats();
+        assert_eq!((stats.allocated, stats.free), (3, 3));
+
+        // the smallest free buffer that is big enough, without growing anything
+        let mut buf = pool.take(50);
+        assert!(buf.is_empty());
+        let capacity = buf.as_mut_vec().capacity();
+        assert!(capacity >= 100 && capacity < 1000, "took a buffer of {} bytes", capacity);
+        let stats = pool.stats();
+        assert_eq!((stats.allocated, stats.reused, stats.free), (3, 1, 2));
+        drop(buf);
+
+        // nothing fits, so one grows
+        let _big = pool.take(5000);
+        assert_eq!(pool.stats().allocated, 4);
+    }
+
+    #[test]
+    fn unpooled() {
+        let pool = Pool::new("test", 4);
+        drop(Buffer::from(vec![1, 2, 3]));
+        drop(pool.copy(&[1, 2, 3]));
+        let stats = pool.stats();
+        assert_eq!((stats.allocated, stats.free), (1, 1));
+    }
+}
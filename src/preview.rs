@@ -0,0 +1,242 @@
+//! Live camera previews for the web interface
+//!
+//! Each camera stream publishes preview frames under its own name (`bluefox`, `structure`,
+//! `structure_ir`), and the web server streams them to browsers as MJPEG at `/stream/<name>`. Frames
+//! are only encoded while someone is watching, at most `fps` times per second, and shrunk to (at
+//! most) `width` pixels across. The defaults are 5 fps at 320 pixels; they can be changed with the
+//! `NRI_PREVIEW` environment variable (comma-separated `name=fps@width` pairs, with `off` for no
+//! preview) or at runtime with the `preview` CLI command.
+//!
+//! Every client ties up one of the web server's threads, so each stream only has room for
+//! `MAX_WATCHERS` of them at a time.
+
+extern crate image;
+extern crate time;
+
+use std::{cmp, fmt};
+use std::env;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak, Mutex, RwLock, Condvar};
+use std::time::Duration;
+use self::image::{imageops, ImageBuffer, ColorType, FilterType};
+use self::image::jpeg::JPEGEncoder;
+use ::comms::{RestartableThread, Mailbox};
+use ::pool::Buffer;
+
+/// Frame rate and size of a preview stream
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Config {
+    /// Frames per second (0 for no preview)
+    pub fps: f64,
+    /// Largest width (pixels)
+    pub width: u32,
+}
+
+const DEFAULT: Config = Config { fps: 5.0, width: 320 };
+
+/// Names of all the preview streams
+pub const STREAMS: &'static [&'static str] = &["bluefox", "structure", "structure_ir"];
+
+/// Most clients watching one stream at a time
+pub const MAX_WATCHERS: usize = 2;
+
+impl Config {
+    pub fn parse(s: &str) -> Result<Config, String> {
+        if s == "off" {
+            return Ok(Config { fps: 0.0, width: DEFAULT.width });
+        }
+        let mut parts = s.splitn(2, '@');
+        match (parts.next().map(str::parse::<f64>), parts.next().map(str::parse::<u32>)) {
+            (Some(Ok(fps)), Some(Ok(width))) if fps >= 0.0 && width > 0 => Ok(Config { fps: fps, width: width }),
+            _ => Err(format!("bad preview config {:?} (expected FPS@WIDTH or off)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.fps == 0.0 {
+            write!(f, "off")
+        } else {
+            write!(f, "{}@{}", self.fps, self.width)
+        }
+    }
+}
+
+lazy_static! {
+    /// Preview settings that differ from the default
+    static ref CONFIG: RwLock<HashMap<String, Config>> = RwLock::new({
+        let mut config = HashMap::new();
+        if let Ok(s) = env::var("NRI_PREVIEW") {
+            for pair in s.split(',').filter(|p| !p.trim().is_empty()) {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) => { config.insert(k.trim().to_owned(), Config::parse(v.trim()).expect("bad NRI_PREVIEW")); },
+                    _                  => panic!("bad NRI_PREVIEW: expected name=FPS@WIDTH, found {:?}", pair),
+                }
+            }
+        }
+        config
+    });
+
+    /// Slots of the clients watching each stream
+    static ref WATCHERS: Mutex<HashMap<String, Vec<Weak<Slot>>>> = Mutex::new(HashMap::new());
+}
+
+/// Change the frame rate and size of a preview stream (takes effect immediately)
+pub fn set(name: &str, value: &str) -> Result<(), String> {
+    let config = try!(Config::parse(value));
+    CONFIG.write().unwrap().insert(name.to_owned(), config);
+    Ok(())
+}
+
+/// Get the settings of a preview stream
+pub fn config(name: &str) -> Config {
+    CONFIG.read().unwrap().get(name).cloned().unwrap_or(DEFAULT)
+}
+
+/// Get the settings of all the streams that have been configured
+pub fn configs() -> Vec<(String, Config)> {
+    let mut all = CONFIG.read().unwrap().iter().map(|(k, v)| (k.clone(), *v)).collect::<Vec<_>>();
+    all.sort_by(|a, b| a.0.cmp(&b.0));
+    all
+}
+
+/// Newest frame that a client has not picked up yet
+type Slot = (Mutex<Option<Arc<Vec<u8>>>>, Condvar);
+
+/// A client's end of a preview stream (stops watching when dropped)
+pub struct Watcher {
+    slot: Arc<Slot>,
+}
+
+impl Watcher {
+    /// Wait for the next JPEG frame (None if there was none within the timeout)
+    ///
+    /// Frames are dropped (not queued) for clients that fall behind.
+    pub fn next(&self, timeout: Duration) -> Option<Arc<Vec<u8>>> {
+        let (ref frame, ref arrived) = *self.slot;
+        let mut frame = frame.lock().unwrap();
+        if frame.is_none() {
+            frame = arrived.wait_timeout(frame, timeout).unwrap().0;
+        }
+        frame.take()
+    }
+}
+
+/// Start watching a preview stream (None if it already has MAX_WATCHERS clients)
+pub fn watch(name: &str) -> Option<Watcher> {
+    let mut all = WATCHERS.lock().unwrap();
+    let watchers = all.entry(name.to_owned()).or_insert_with(Vec::new);
+    watchers.retain(|w| w.upgrade().is_some());
+    if watchers.len() >= MAX_WATCHERS {
+        return None;
+    }
+    let slot = Arc::new((Mutex::new(None), Condvar::new()));
+    watchers.push(Arc::downgrade(&slot));
+    Some(Watcher { slot: slot })
+}
+
+/// Whether anyone is watching a preview stream
+pub fn watched(name: &str) -> bool {
+    WATCHERS.lock().unwrap().get(name).map_or(false, |w| w.iter().any(|w| w.upgrade().is_some()))
+}
+
+/// Send a frame to everyone watching (and forget the ones who left)
+fn publish(name: &str, jpeg: Vec<u8>) {
+    let jpeg = Arc::new(jpeg);
+    if let Some(watchers) = WATCHERS.lock().unwrap().get_mut(name) {
+        watchers.retain(|w| match w.upgrade() {
+            Some(slot) => {
+                let (ref frame, ref arrived) = *slot;
+                *frame.lock().unwrap() = Some(jpeg.clone());
+                arrived.notify_one();
+                true
+            },
+            None => false,
+        });
+    }
+}
+
+/// Shrink a frame and encode it as JPEG
+///
+/// Supports RGB(8), Gray(8) and Gray(16) (host byte order, shown as the top 8 bits).
+fn encode(name: &str, data: Buffer, (h, w): (usize, usize), color: ColorType) -> Option<Vec<u8>> {
+    let width = cmp::min(config(name).width, w as u32);
+    let (ww, hh) = (width, cmp::max(h as u32 * width / w as u32, 1));
+    let mut encoded = Vec::with_capacity((ww * hh) as usize);
+    match color {
+        ColorType::RGB(8) => {
+            let full = ImageBuffer::<image::Rgb<u8>, _>::from_raw(w as u32, h as u32, data).unwrap();
+            let small = prof!("resize", imageops::resize(&full, ww, hh, FilterType::Nearest));
+            prof!("encode", JPEGEncoder::new(&mut encoded).encode(&small, ww, hh, ColorType::RGB(8)).unwrap());
+        },
+        ColorType::Gray(8) | ColorType::Gray(16) => {
+            let data = if color == ColorType::Gray(16) {
+                let high = if cfg!(target_endian = "little") { 1 } else { 0 };
+                Buffer::from(data.chunks(2).map(|b| b[high]).collect::<Vec<_>>())
+            } else {
+                data
+            };
+            let full = ImageBuffer::<image::Luma<u8>, _>::from_raw(w as u32, h as u32, data).unwrap();
+            let small = prof!("resize", imageops::resize(&full, ww, hh, FilterType::Nearest));
+            prof!("encode", JPEGEncoder::new(&mut encoded).encode(&small, ww, hh, ColorType::Gray(8)).unwrap());
+        },
+        _ => {
+            println!("{} preview: cannot show {:?} frames", name, color);
+            return None;
+        },
+    }
+    Some(encoded)
+}
+
+/// A camera's end of a preview stream
+pub struct Preview {
+    name: &'static str,
+
+    /// When the last frame was sent (ns, from time::precise_time_ns)
+    last: u64,
+
+    /// Encoder thread, so the camera does not wait for JPEG encoding (only the newest frame waits,
+    /// so a slow encoder skips frames instead of piling them up)
+    encoder: RestartableThread<(Buffer, (usize, usize), ColorType)>,
+}
+
+impl Preview {
+    pub fn new(name: &'static str) -> Preview {
+        Preview {
+            name: name,
+            last: 0,
+            encoder: RestartableThread::with_mailbox("preview encoder", Mailbox::Latest, 1, move |(data, size, color)| {
+                if let Some(jpeg) = encode(name, data, size, color) {
+                    publish(name, jpeg);
+                }
+            }),
+        }
+    }
+
+    /// Whether the camera should send the current frame
+    ///
+    /// True if someone is watching and the last frame was long enough ago. Check this before
+    /// copying or converting a frame for the preview.
+    pub fn due(&mut self) -> bool {
+        let fps = config(self.name).fps;
+        if fps <= 0.0 || !watched(self.name) {
+            return false;
+        }
+        let now = time::precise_time_ns();
+        if now - self.last >= (1e9 / fps) as u64 {
+            self.last = now;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Send a frame (RGB(8), Gray(8) or Gray(16)) to be encoded and published
+    ///
+    /// The buffer goes back to its pool once the frame is encoded (or replaced by a newer one).
+    pub fn send(&self, data: Buffer, size: (usize, usize), color: ColorType) {
+        self.encoder.send((data, size, color)).unwrap();
+    }
+}
@@ -1,8 +1,12 @@
 //! Utilities for writing stuff to files
+//!
+//! Writes go to a single worker thread as pool buffers (see the pool module). Callers that already
+//! have their data in a buffer (camera frames) hand it over with Writer::write_buffer, so it is not
+//! copied again; everything else is copied into a buffer from scribe's own pool.
 
 extern crate libc;
 
-use std::{mem, ptr};
+use std::{mem, slice};
 use std::fs::File;
 use std::io::Write;
 use std::collections::HashMap;
@@ -11,7 +15,7 @@ use std::sync::mpsc;
 use std::marker::PhantomData;
 use std::thread;
 use std::convert::Into;
-use std::ops::DerefMut;
+use ::pool::{Pool, Buffer};
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Handle(usize);
@@ -31,6 +35,9 @@ struct Worker {
 }
 
 lazy_static! {
+    /// Buffers for writes that do not bring their own
+    static ref POOL: Pool = Pool::new("scribe", 256);
+
     static ref WORKER: Mutex<Worker> = {
         let (tx, rx) = mpsc::channel();
 
@@ -98,11 +105,11 @@ enum Destination {
 pub enum Message {
     Open(String, mpsc::Sender<Handle>),
     Close(Handle),
-    Write(Handle, Box<[u8]>),
+    Write(Handle, Buffer),
 
     Register(String, mpsc::Sender<Handle>),
     Unregister(Handle),
-    Packet(Handle, Box<[u8]>),
+    Packet(Handle, Buffer),
     Decoy(Handle),
 }
 
@@ -144,35 +151,34 @@ impl<T: ?Sized> Writer<T> {
     }
 }
 
-impl<T: Writable + Send + 'static> Writer<T> {
-    pub fn write(&mut self, data: T) {
-        let mut raw_data = vec![0u8; mem::size_of::<T>()].into_boxed_slice();
-        unsafe {
-            ptr::copy::<T>(&data as *const T,
-                           raw_data.deref_mut() as *mut [u8] as *mut T,
-                           1);
-        }
-
+impl<T: ?Sized> Writer<T> {
+    fn send(&mut self, data: Buffer) {
         send(match self.dst {
-                Destination::Name    => Message::Write(self.handle, raw_data),
-                Destination::Pattern => Message::Packet(self.handle, raw_data),
+                Destination::Name    => Message::Write(self.handle, data),
+                Destination::Pattern => Message::Packet(self.handle, data),
             });
     }
 }
 
+impl<T: Writable + Send + 'static> Writer<T> {
+    pub fn write(&mut self, data: T) {
+        let raw_data = unsafe {
+            slice::from_raw_parts(&data as *const T as *const u8, mem::size_of::<T>())
+        };
+        let buf = POOL.copy(raw_data);
+        self.send(buf);
+    }
+}
+
 impl Writer<[u8]> {
     pub fn write(&mut self, data: &[u8]) {
-        let mut raw_data = vec![0u8; data.len()].into_boxed_slice();
-        unsafe {
-            ptr::copy::<u8>(data as *const[u8] as *const u8,
-                            raw_data.deref_mut() as *mut [u8] as *mut u8,
-                            data.len());
-        }
+        let buf = POOL.copy(data);
+        self.send(buf);
+    }
 
-        send(match self.dst {
-                Destination::Name    => Message::Write(self.handle, raw_data),
-                Destination::Pattern => Message::Packet(self.handle, raw_data),
-            });
+    /// Write a buffer without copying it (it goes back to its pool once it is on disk)
+    pub fn write_buffer(&mut self, data: Buffer) {
+        self.send(data);
     }
 }
 
@@ -0,0 +1,193 @@
+//! Depth frames as false-colour previews
+//!
+//! Depths between the near and far limits are spread over the colour map, with near at the hot
+//! (or bright) end, so the surface being aimed at stands out. Depths outside the range are clipped
+//! to black, and pixels with no reading (zero) are shown in magenta. Like geometry.rs, this only
+//! depends on std.
+
+use std::{cmp, fmt};
+
+/// Colour of pixels with no depth reading
+pub const INVALID: [u8; 3] = [255, 0, 255];
+
+/// Colour of pixels outside the near/far range
+pub const CLIPPED: [u8; 3] = [0, 0, 0];
+
+/// Depth100um counts per millimetre
+const PER_MM: u32 = 10;
+
+/// Names of the colour maps
+pub const NAMES: [&'static str; 3] = ["gray", "jet", "viridis"];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Colormap {
+    Gray,
+    Jet,
+    Viridis,
+}
+
+impl Colormap {
+    pub fn parse(s: &str) -> Result<Colormap, String> {
+        match s {
+            "gray"    => Ok(Colormap::Gray),
+            "jet"     => Ok(Colormap::Jet),
+            "viridis" => Ok(Colormap::Viridis),
+            _         => Err(format!("unknown colour map {:?} (expected one of {})", s, NAMES.join(", "))),
+        }
+    }
+
+    /// Colour at `t` (0 = far, 1 = near)
+    fn color(self, t: f64) -> [u8; 3] {
+        let byte = |x: f64| (255.0 * x.max(0.0).min(1.0)).round() as u8;
+        match self {
+            Colormap::Gray => [byte(t); 3],
+            Colormap::Jet  => [byte(1.5 - (4.0*t - 3.0).abs()),
+                               byte(1.5 - (4.0*t - 2.0).abs()),
+                               byte(1.5 - (4.0*t - 1.0).abs())],
+            Colormap::Viridis => {
+                // a few samples of matplotlib's viridis, linearly interpolated
+                const STOPS: [[f64; 3]; 5] = [[ 68.0,   1.0,  84.0],
+                                              [ 59.0,  82.0, 139.0],
+                                              [ 33.0, 145.0, 140.0],
+                                              [ 94.0, 201.0,  98.0],
+                                              [253.0, 231.0,  37.0]];
+                let x = t.max(0.0).min(1.0) * (STOPS.len() - 1) as f64;
+                let k = cmp::min(x.floor() as usize, STOPS.len() - 2);
+                let f = x - k as f64;
+                let mut rgb = [0; 3];
+                for c in 0..3 {
+                    rgb[c] = byte((STOPS[k][c] * (1.0 - f) + STOPS[k + 1][c] * f) / 255.0);
+                }
+                rgb
+            },
+        }
+    }
+
+    /// 256-entry lookup table, from far to near
+    fn table(self) -> Vec<[u8; 3]> {
+        (0..256).map(|i| self.color(i as f64 / 255.0)).collect()
+    }
+}
+
+impl fmt::Display for Colormap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Colormap::Gray    => "gray",
+            Colormap::Jet     => "jet",
+            Colormap::Viridis => "viridis",
+        })
+    }
+}
+
+/// How depth previews are drawn
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct View {
+    pub colormap: Colormap,
+    /// Nearest depth shown (mm)
+    pub near: u32,
+    /// Farthest depth shown (mm)
+    pub far: u32,
+}
+
+impl Default for View {
+    fn default() -> View {
+        View { colormap: Colormap::Jet, near: 400, far: 2000 }
+    }
+}
+
+impl View {
+    /// Check and set the range (mm)
+    pub fn set_range(&mut self, near: u32, far: u32) -> Result<(), String> {
+        if near >= far {
+            return Err(format!("bad depth range {}-{} mm (near must be less than far)", near, far));
+        }
+        self.near = near;
+        self.far = far;
+        Ok(())
+    }
+
+    /// Change a setting: `colormap <name>` or `range <near>-<far>` (mm)
+    pub fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "colormap" => self.colormap = try!(Colormap::parse(value)),
+            "range"    => {
+                let mut ends = value.splitn(2, '-').map(str::parse::<u32>);
+                match (ends.next(), ends.next()) {
+                    (Some(Ok(near)), Some(Ok(far))) => try!(self.set_range(near, far)),
+                    _ => return Err(format!("bad depth range {:?} (expected NEAR-FAR in mm)", value)),
+                }
+            },
+            _ => return Err(format!("unknown depth view setting {:?}", key)),
+        }
+        Ok(())
+    }
+
+    /// Render a Depth100um frame as RGB
+    pub fn render(&self, depth: &[u16]) -> Vec<u8> {
+        let table = self.colormap.table();
+        let (near, far) = (self.near * PER_MM, self.far * PER_MM);
+        let mut rgb = Vec::with_capacity(depth.len() * 3);
+        for &d in depth {
+            let d = d as u32;
+            rgb.extend(&if d == 0 {
+                INVALID
+            } else if d < near || d > far {
+                CLIPPED
+            } else {
+                table[(255 * (far - d) / (far - near)) as usize]
+            });
+        }
+        rgb
+    }
+
+    /// Count the depths in `bins` equal slices of the range
+    pub fn histogram(&self, depth: &[u16], bins: usize) -> Histogram {
+        let (near, far) = (self.near * PER_MM, self.far * PER_MM);
+        let mut hist = Histogram { near: self.near, far: self.far, counts: vec![0; bins], invalid: 0, below: 0, above: 0 };
+        for &d in depth {
+            let d = d as u32;
+            if d == 0 {
+                hist.invalid += 1;
+            } else if d < near {
+                hist.below += 1;
+            } else if d > far {
+                hist.above += 1;
+            } else {
+                let bin = cmp::min((d - near) as usize * bins / (far - near) as usize, bins - 1);
+                hist.counts[bin] += 1;
+            }
+        }
+        hist
+    }
+}
+
+impl fmt::Display for View {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}-{} mm", self.colormap, self.near, self.far)
+    }
+}
+
+/// Distribution of the depths in a frame
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    /// Range covered by the bins (mm)
+    pub near: u32,
+    pub far: u32,
+    /// Pixels in each bin, from near to far
+    pub counts: Vec<usize>,
+    /// Pixels with no reading
+    pub invalid: usize,
+    /// Pixels nearer than the range
+    pub below: usize,
+    /// Pixels farther than the range
+    pub above: usize,
+}
+
+impl fmt::Display for Histogram {
+    /// Space-separated: near, far, invalid, below, above, then the comma-separated bin counts
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {} {} {}",
+               self.near, self.far, self.invalid, self.below, self.above,
+               self.counts.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(","))
+    }
+}
@@ -0,0 +1,204 @@
+//! Depth frames as 3D point clouds
+//!
+//! The Structure Sensor only reports the field of view of each stream, so the intrinsics are those
+//! of an ideal pinhole camera with that FOV and the principal point in the middle of the image.
+//! They are written to `structure_intrinsics.csv` in the session when the service starts, one line
+//! per stream, so recorded depth frames can be turned into point clouds later (see
+//! examples/structurecloud.rs, which includes this module as-is: it only depends on std).
+//!
+//! Points are in metres in the camera frame: x to the right, y down and z forward along the
+//! optical axis. Depth100um frames hold the z coordinate in units of 100 µm, and zero means there
+//! was no reading, so those pixels are skipped.
+
+use std::f64;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+
+/// Metres per Depth100um count
+pub const DEPTH_SCALE: f64 = 0.0001;
+
+/// Column names of the intrinsics file
+pub const INTRINSICS_HEADER: &'static str = "stream,width,height,hfov,vfov,fx,fy,cx,cy";
+
+/// Pinhole camera model for one stream
+#[derive(Debug, Copy, Clone)]
+pub struct Intrinsics {
+    pub width: usize,
+    pub height: usize,
+    /// Horizontal field of view (radians)
+    pub hfov: f64,
+    /// Vertical field of view (radians)
+    pub vfov: f64,
+    /// Focal length (pixels)
+    pub fx: f64,
+    pub fy: f64,
+    /// Principal point (pixels)
+    pub cx: f64,
+    pub cy: f64,
+}
+
+impl Intrinsics {
+    pub fn from_fov(width: usize, height: usize, hfov: f64, vfov: f64) -> Intrinsics {
+        Intrinsics {
+            width  : width,
+            height : height,
+            hfov   : hfov,
+            vfov   : vfov,
+            fx     : width as f64 / 2.0 / (hfov / 2.0).tan(),
+            fy     : height as f64 / 2.0 / (vfov / 2.0).tan(),
+            cx     : (width as f64 - 1.0) / 2.0,
+            cy     : (height as f64 - 1.0) / 2.0,
+        }
+    }
+
+    /// One line of the intrinsics file (without the newline)
+    pub fn to_csv(&self, stream: &str) -> String {
+        format!("{},{},{},{},{},{},{},{},{}",
+                stream, self.width, self.height, self.hfov, self.vfov, self.fx, self.fy, self.cx, self.cy)
+    }
+
+    /// Look up a stream in an intrinsics file
+    pub fn load(path: &str, stream: &str) -> Result<Intrinsics, String> {
+        let file = try!(File::open(path).map_err(|e| format!("could not open {}: {}", path, e)));
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = try!(line.map_err(|e| format!("could not read {}: {}", path, e)));
+            let fields = line.trim().split(',').map(str::trim).collect::<Vec<_>>();
+            if fields[0] != stream {
+                continue;
+            }
+            if fields.len() != 9 {
+                return Err(format!("{}:{}: expected 9 fields, found {}", path, n + 1, fields.len()));
+            }
+            let mut numbers = [0.0; 8];
+            for i in 0..8 {
+                numbers[i] = try!(fields[i + 1].parse()
+                                               .map_err(|_| format!("{}:{}: bad number {:?}", path, n + 1, fields[i + 1])));
+            }
+            return Ok(Intrinsics {
+                width  : numbers[0] as usize,
+                height : numbers[1] as usize,
+                hfov   : numbers[2],
+                vfov   : numbers[3],
+                fx     : numbers[4],
+                fy     : numbers[5],
+                cx     : numbers[6],
+                cy     : numbers[7],
+            });
+        }
+        Err(format!("{}: no intrinsics for the {} stream", path, stream))
+    }
+
+    /// Back-project one pixel (`z` in metres)
+    pub fn point(&self, u: usize, v: usize, z: f64) -> Point {
+        Point {
+            x: ((u as f64 - self.cx) * z / self.fx) as f32,
+            y: ((v as f64 - self.cy) * z / self.fy) as f32,
+            z: z as f32,
+        }
+    }
+
+    /// Turn a Depth100um frame into a point cloud
+    ///
+    /// Only every `step`th pixel in each direction is used (1 for the full cloud). Pixels with no
+    /// reading are skipped.
+    pub fn cloud(&self, depth: &[u16], step: usize) -> Vec<Point> {
+        assert_eq!(depth.len(), self.width * self.height);
+        let step = if step == 0 { 1 } else { step };
+
+        let mut points = Vec::with_capacity(depth.len() / (step * step));
+        for v in (0..self.height).filter(|v| v % step == 0) {
+            for u in (0..self.width).filter(|u| u % step == 0) {
+                let d = depth[v * self.width + u];
+                if d != 0 {
+                    points.push(self.point(u, v, d as f64 * DEPTH_SCALE));
+                }
+            }
+        }
+        points
+    }
+}
+
+impl fmt::Display for Intrinsics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}, FOV {:.1}°x{:.1}°, f = ({:.1}, {:.1}), c = ({:.1}, {:.1})",
+               self.width, self.height,
+               self.hfov * 180.0 / f64::consts::PI, self.vfov * 180.0 / f64::consts::PI,
+               self.fx, self.fy, self.cx, self.cy)
+    }
+}
+
+/// A point in the camera frame (metres)
+#[derive(Debug, Copy, Clone)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+fn put_f32<W: Write>(w: &mut W, x: f32) -> io::Result<()> {
+    let bits: u32 = unsafe { ::std::mem::transmute(x) };
+    w.write_all(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8])
+}
+
+/// Write the points as little-endian f32 triples with no header
+pub fn write_points<W: Write>(w: &mut W, points: &[Point]) -> io::Result<()> {
+    for p in points {
+        try!(put_f32(w, p.x));
+        try!(put_f32(w, p.y));
+        try!(put_f32(w, p.z));
+    }
+    Ok(())
+}
+
+/// Write a binary (little-endian) PLY file
+pub fn write_ply<W: Write>(w: &mut W, points: &[Point]) -> io::Result<()> {
+    try!(write!(w, "ply\n\
+                    format binary_little_endian 1.0\n\
+                    comment Structure Sensor depth, metres\n\
+                    element vertex {}\n\
+                    property float x\n\
+                    property float y\n\
+                    property float z\n\
+                    end_header\n", points.len()));
+    write_points(w, points)
+}
+
+/// Write a binary (little-endian) PLY file with a colour for each point
+pub fn write_color_ply<W: Write>(w: &mut W, points: &[Point], colors: &[[u8; 3]]) -> io::Result<()> {
+    assert_eq!(points.len(), colors.len());
+    try!(write!(w, "ply\n\
+                    format binary_little_endian 1.0\n\
+                    comment Structure Sensor depth, metres, coloured from the Bluefox\n\
+                    element vertex {}\n\
+                    property float x\n\
+                    property float y\n\
+                    property float z\n\
+                    property uchar red\n\
+                    property uchar green\n\
+                    property uchar blue\n\
+                    end_header\n", points.len()));
+    for (p, c) in points.iter().zip(colors) {
+        try!(put_f32(w, p.x));
+        try!(put_f32(w, p.y));
+        try!(put_f32(w, p.z));
+        try!(w.write_all(c));
+    }
+    Ok(())
+}
+
+/// Write a binary PCD file (as read by PCL)
+pub fn write_pcd<W: Write>(w: &mut W, points: &[Point]) -> io::Result<()> {
+    try!(write!(w, "# .PCD v0.7 - Point Cloud Data file format\n\
+                    VERSION 0.7\n\
+                    FIELDS x y z\n\
+                    SIZE 4 4 4\n\
+                    TYPE F F F\n\
+                    COUNT 1 1 1\n\
+                    WIDTH {0}\n\
+                    HEIGHT 1\n\
+                    VIEWPOINT 0 0 0 1 0 0 0\n\
+                    POINTS {0}\n\
+                    DATA binary\n", points.len()));
+    write_points(w, points)
+}
@@ -1,4 +1,239 @@
 //! Service to capture frames from the Structure Sensor
+//!
+//! # Commands
+//!
+//! - `disk start`/`disk stop`: start/stop recording
+//! - `kick`: send the next depth and IR frames to the web interface
+//! - `cloud`: send a decimated point cloud of the next depth frame to the web interface (see the
+//!   geometry module)
+//! - `set <control> <value>`: change a camera control or the depth preview (see below)
+//! - `snapshot <N>`: save the next matched depth and IR frames losslessly, as
+//!   `snapshot<N>_structure.png` (16-bit depth in units of 100 µm) and `snapshot<N>_structure_ir.png`
+//!   (or `.dat`, for IR formats that PNG cannot hold), listed in `structure_snapshots.csv` with
+//!   their timestamps and the camera settings. The supervisor sends this to all the cameras at once
+//!   (see the `snapshot` CLI and flow commands).
+//!
+//! The depth and IR streams are also previewed live in the web interface, as `structure` and
+//! `structure_ir` (see the preview module). The depth preview is colour-mapped (see the colormap
+//! module), and a histogram of the depths in each preview frame is sent along with it.
+//!
+//! # Configuration
+//!
+//! The device and video modes are chosen when the service starts, according to the settings in
+//! the `NRI_STRUCTURE` environment variable (comma-separated `key=value` pairs), which can be
+//! changed at runtime with `set` (e.g. the `structure` CLI command):
+//!
+//! - `device`: URI or serial number of the device to open (default `any`: the first one found)
+//! - `depth`, `ir`: video mode of each stream, as `WxH@FPS/format` (e.g. `1280x1024@30/rgb888`).
+//!   Any part can be left out, and the service picks the largest resolution and highest frame rate
+//!   that the device supports and that match the rest. The depth stream is always recorded as
+//!   `depth100um`, since that is what the converters expect.
+//! - `exposure`, `gain` (integers), `mirroring`, `autoexposure` (`on` or `off`): camera controls,
+//!   applied at startup and also changeable while running with the `set` command. Exposure, gain
+//!   and auto-exposure apply to the IR stream; mirroring applies to both.
+//! - `colormap` (`gray`, `jet` or `viridis`) and `range` (`NEAR-FAR` in mm, default `400-2000`):
+//!   how the depth preview is drawn, also changeable while running with `set`.
+//!
+//! The device, chosen modes and controls are recorded in `structure_settings.csv`.
+
+use std::env;
+use std::fmt;
+use std::sync::RwLock;
+
+#[allow(dead_code)] mod colormap;
+
+/// Names of the pixel formats (as in wrapper::OniPixelFormat::name)
+const FORMATS: [&'static str; 10] = ["depth1mm", "depth100um", "shift92", "shift93",
+                                     "rgb888", "yuv422", "gray8", "gray16", "jpeg", "yuyv"];
+
+/// Requested video mode (None means the service picks the best available)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModeRequest {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub fps: Option<i32>,
+    pub format: Option<String>,
+}
+
+impl ModeRequest {
+    /// Parse `[WxH][@FPS][/format]`
+    pub fn parse(s: &str) -> Result<ModeRequest, String> {
+        let bad = || format!("bad video mode {:?} (expected e.g. 640x480@30/depth100um)", s);
+        let mut req = ModeRequest::default();
+
+        let (rest, format) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i+1..])),
+            None    => (s, None),
+        };
+        if let Some(format) = format {
+            let format = format.to_lowercase();
+            if !FORMATS.contains(&&*format) {
+                return Err(format!("unknown pixel format {:?} (expected one of {})", format, FORMATS.join(", ")));
+            }
+            req.format = Some(format);
+        }
+        let (resolution, fps) = match rest.find('@') {
+            Some(i) => (&rest[..i], Some(&rest[i+1..])),
+            None    => (rest, None),
+        };
+        if let Some(fps) = fps {
+            req.fps = Some(try!(fps.parse().map_err(|_| bad())));
+        }
+        if !resolution.is_empty() {
+            let mut dims = resolution.split('x');
+            match (dims.next(), dims.next(), dims.next()) {
+                (Some(w), Some(h), None) => {
+                    req.width = Some(try!(w.parse().map_err(|_| bad())));
+                    req.height = Some(try!(h.parse().map_err(|_| bad())));
+                },
+                _ => return Err(bad()),
+            }
+        }
+        Ok(req)
+    }
+}
+
+impl fmt::Display for ModeRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn or_any<T: fmt::Display>(x: &Option<T>) -> String {
+            x.as_ref().map_or("*".to_owned(), |x| x.to_string())
+        }
+        write!(f, "{}x{}@{}/{}", or_any(&self.width), or_any(&self.height), or_any(&self.fps), or_any(&self.format))
+    }
+}
+
+/// A camera control setting
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Control {
+    Exposure(i32),
+    Gain(i32),
+    Mirroring(bool),
+    AutoExposure(bool),
+}
+
+impl Control {
+    pub fn parse(key: &str, value: &str) -> Result<Control, String> {
+        let int = || value.parse().map_err(|_| format!("bad {} {:?} (expected an integer)", key, value));
+        let onoff = || match value {
+            "on"  => Ok(true),
+            "off" => Ok(false),
+            _     => Err(format!("bad {} {:?} (expected on or off)", key, value)),
+        };
+        Ok(match key {
+            "exposure"     => Control::Exposure(try!(int())),
+            "gain"         => Control::Gain(try!(int())),
+            "mirroring"    => Control::Mirroring(try!(onoff())),
+            "autoexposure" => Control::AutoExposure(try!(onoff())),
+            _              => return Err(format!("unknown Structure setting {:?}", key)),
+        })
+    }
+
+    pub fn key(&self) -> &'static str {
+        match *self {
+            Control::Exposure(_)     => "exposure",
+            Control::Gain(_)         => "gain",
+            Control::Mirroring(_)    => "mirroring",
+            Control::AutoExposure(_) => "autoexposure",
+        }
+    }
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let onoff = |b| if b { "on" } else { "off" };
+        match *self {
+            Control::Exposure(x) | Control::Gain(x)            => write!(f, "{} {}", self.key(), x),
+            Control::Mirroring(b) | Control::AutoExposure(b)   => write!(f, "{} {}", self.key(), onoff(b)),
+        }
+    }
+}
+
+/// Settings used the next time the service starts
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// URI or serial number (None means the first device found)
+    pub device: Option<String>,
+    pub depth: ModeRequest,
+    pub ir: ModeRequest,
+    pub controls: Vec<Control>,
+    /// How the depth preview is drawn
+    pub view: colormap::View,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            device   : None,
+            depth    : ModeRequest::parse("640x480@30/depth100um").unwrap(),
+            ir       : ModeRequest::parse("1280x1024@30/rgb888").unwrap(),
+            controls : vec![],
+            view     : colormap::View::default(),
+        }
+    }
+}
+
+impl Settings {
+    fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "device" => self.device = if value == "any" { None } else { Some(value.to_owned()) },
+            "depth"  => {
+                let mut req = try!(ModeRequest::parse(value));
+                match req.format {
+                    None                             => req.format = Some("depth100um".to_owned()),
+                    Some(ref f) if f == "depth100um" => {},
+                    Some(ref f)                      => return Err(format!("depth format must be depth100um, not {}", f)),
+                }
+                self.depth = req;
+            },
+            "ir" => self.ir = try!(ModeRequest::parse(value)),
+            "colormap" | "range" => try!(self.view.apply(key, value)),
+            _    => {
+                let control = try!(Control::parse(key, value));
+                self.controls.retain(|c| c.key() != control.key());
+                self.controls.push(control);
+            },
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "device {}, depth {}, ir {}, preview {}", self.device.as_ref().map_or("any", |s| s as &str), self.depth, self.ir, self.view));
+        for c in &self.controls {
+            try!(write!(f, ", {}", c));
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref SETTINGS: RwLock<Settings> = RwLock::new({
+        let mut settings = Settings::default();
+        if let Ok(s) = env::var("NRI_STRUCTURE") {
+            for pair in s.split(',').filter(|p| !p.trim().is_empty()) {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) => settings.apply(k.trim(), v.trim()).expect("bad NRI_STRUCTURE"),
+                    _                  => panic!("bad NRI_STRUCTURE: expected key=value, found {:?}", pair),
+                }
+            }
+        }
+        settings
+    });
+}
+
+/// Change a setting of the Structure service (see the module docs for the keys)
+///
+/// Takes effect the next time the service is started.
+pub fn set(key: &str, value: &str) -> Result<(), String> {
+    SETTINGS.write().unwrap().apply(key, value)
+}
+
+/// Get the settings currently in use
+pub fn settings() -> Settings {
+    SETTINGS.read().unwrap().clone()
+}
 
 group_attr!{
     #[cfg(target_os = "linux")]
@@ -6,7 +241,6 @@ group_attr!{
     extern crate time;
     extern crate image;
     extern crate rustc_serialize as serialize;
-    use std::{mem, slice};
     use std::io::Write;
     use std::sync::Mutex;
     use self::image::{imageops, ImageBuffer, ColorType, FilterType};
@@ -14,38 +248,291 @@ group_attr!{
     use self::serialize::base64;
     use self::serialize::base64::ToBase64;
     use std::sync::mpsc::Sender;
-    use ::comms::{Controllable, CmdFrom, Block, RestartableThread};
+    use ::comms::{Controllable, CmdFrom, Block, RestartableThread, Mailbox};
     use ::scribe::Writer;
+    use ::pool::{Pool, Buffer};
+    use ::preview::Preview;
+    use super::{ModeRequest, Control};
+    use super::colormap;
 
-    type PngStuff = (usize, Vec<u8>, bool, (i32, i32), ColorType);
+    type PngStuff = (&'static str, usize, Buffer, bool, (i32, i32), ColorType);
 
     mod wrapper;
+    mod sync;
+    #[allow(dead_code)] mod geometry;
+
+    /// Decimation of the preview point clouds (every Nth pixel in each direction)
+    const CLOUD_STEP: usize = 8;
+
+    /// Number of bins in the depth preview histograms
+    const HISTOGRAM_BINS: usize = 32;
+
+    /// Frame buffers kept for reuse by each stream (enough for scribe to fall a second behind)
+    const POOL_FRAMES: usize = 32;
+
+    /// A frame kept for a snapshot
+    struct Shot {
+        frame: sync::Frame,
+        /// Host time of arrival
+        stamp: time::Timespec,
+        /// Frame data (16-bit samples in host byte order, for png() to swap)
+        data: Buffer,
+        /// (height, width)
+        size: (i32, i32),
+    }
+
+    /// Decode 16-bit samples in host byte order
+    ///
+    /// Done bytewise, because pool buffers are not necessarily aligned well enough to be viewed as
+    /// a `&[u16]`.
+    fn samples(data: &[u8]) -> Vec<u16> {
+        data.chunks(2)
+            .map(|b| if cfg!(target_endian = "little") {
+                (b[1] as u16) << 8 | b[0] as u16
+            } else {
+                (b[0] as u16) << 8 | b[1] as u16
+            })
+            .collect()
+    }
+
+    /// Encode a frame as PNG (16-bit samples are expected in host byte order)
+    fn png(data: &[u8], (h, w): (i32, i32), bd: ColorType) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(data.len());
+        if let ColorType::Gray(16) = bd {
+            // PNG wants big-endian samples
+            let swapped = data.chunks(2).fold(Vec::with_capacity(data.len()), |mut v, b| { v.push(b[1]); v.push(b[0]); v });
+            PNGEncoder::new(&mut encoded).encode(&swapped, w as u32, h as u32, bd).unwrap();
+        } else {
+            PNGEncoder::new(&mut encoded).encode(data, w as u32, h as u32, bd).unwrap();
+        }
+        encoded
+    }
+
+    /// One of the image streams, with its own output files and frame counter
+    struct Stream {
+        /// Private handle to the OpenNI stream
+        handle: wrapper::VideoStream,
+
+        /// Name used for the output files and previews
+        name: &'static str,
+
+        /// Number of frames captured since setup() was last called
+        i: usize,
+
+        /// Send the next frame to the web interface
+        kick: bool,
+
+        /// Live preview stream (with the same name)
+        preview: Preview,
+
+        /// Buffers for this stream's frames (with the same name)
+        frames: Pool,
+
+        /// Timestamp file handle
+        stampfile: Writer<[u8]>,
+
+        writer: Writer<[u8]>
+    }
+
+    impl Stream {
+        fn new(handle: wrapper::VideoStream, name: &'static str) -> Stream {
+            Stream {
+                handle: handle,
+                name: name,
+                i: 0,
+                kick: false,
+                preview: Preview::new(name),
+                frames: Pool::new(name, POOL_FRAMES),
+                stampfile: Writer::with_file(format!("{}_times.csv", name)),
+                writer: Writer::with_files(format!("{}{{}}.dat", name)),
+            }
+        }
+
+        /// Count a new frame, returning its host time of arrival
+        fn next(&mut self) -> time::Timespec {
+            self.i += 1;
+            time::get_time()
+        }
+
+        /// Write (or, given None, skip) the current frame
+        ///
+        /// The times file has the frame number, file name, host time and device timestamp (µs).
+        fn write(&mut self, data: Option<Buffer>, stamp: time::Timespec, device_stamp: u64) {
+            if let Some(data) = data {
+                self.writer.write_buffer(data);
+                self.stampfile.write(format!("{},{}{}.dat,{:.9},{}\n", self.i, self.name, self.i, stamp.sec as f64 + stamp.nsec as f64 / 1_000_000_000f64, device_stamp).as_bytes());
+            } else {
+                self.writer.decoy();
+            }
+        }
+    }
 
     /// Controllable struct for the camera
+    ///
+    /// Depth frames go to `structure<N>.dat` and `structure_times.csv` (as before), and IR frames to
+    /// `structure_ir<N>.dat` and `structure_ir_times.csv`. Matching depth and IR frame numbers are
+    /// listed in `structure_pairs.csv` (see the sync module).
     pub struct Structure {
         /// Private handle to the device
         device: wrapper::Device,
 
-        /// Private handle to the depth data stream
-        depth: wrapper::VideoStream,
+        /// Depth data stream
+        depth: Stream,
 
-        /// Private handle to the raw IR data stream
-        ir: wrapper::VideoStream,
+        /// Raw IR data stream
+        ir: Stream,
 
         /// Time that setup() was last called (used for calculating frame rates)
         start: time::Tm,
 
-        /// Number of frames captured since setup() was last called (used for calculating frame rates)
-        i: usize,
         writing: bool,
 
+        /// Matches up depth and IR frames
+        pairer: sync::Pairer,
+
+        /// Camera model of the depth stream
+        intrinsics: geometry::Intrinsics,
+
+        /// IR pixel format (determines how previews are encoded)
+        ir_format: wrapper::OniPixelFormat,
+
+        /// Send a point cloud of the next depth frame to the web interface
+        cloud: bool,
+
+        /// How the depth preview is drawn
+        view: colormap::View,
+
+        /// Number of the snapshot being taken
+        snapshot: Option<usize>,
+        /// Latest depth and IR frames while a snapshot is being taken
+        shots: (Option<Shot>, Option<Shot>),
+        /// Snapshot list handle
+        snapfile: Writer<[u8]>,
+        /// Video modes and controls, for the snapshot list
+        modes: String,
+        controls: Vec<Control>,
+
+        tx: Sender<CmdFrom>,
+
+        /// Pairs file handle
+        pairfile: Writer<[u8]>,
+
         /// PNG writer/sender
-        png: RestartableThread<PngStuff>,
+        png: RestartableThread<PngStuff, Result<(), String>>,
+    }
 
-        /// Timestamp file handle
-        stampfile: Writer<[u8]>,
+    /// Camera model of a stream, from its video mode and field of view
+    fn intrinsics(stream: &wrapper::VideoStream) -> geometry::Intrinsics {
+        let mode = stream.get::<wrapper::prop::VideoMode>().unwrap();
+        geometry::Intrinsics::from_fov(mode.resolution_x as usize,
+                                       mode.resolution_y as usize,
+                                       stream.get::<wrapper::prop::HorizontalFOV>().unwrap() as f64,
+                                       stream.get::<wrapper::prop::VerticalFOV>().unwrap() as f64)
+    }
 
-        writer: Writer<[u8]>
+    /// Open the device selected by URI or serial number (or the first one, if None)
+    fn open_device(wanted: Option<&str>) -> Result<wrapper::Device, String> {
+        let devices = try!(wrapper::devices().map_err(|e| format!("could not list devices: {:?}", e)));
+        if devices.is_empty() {
+            return Err("no Structure Sensor connected".to_owned());
+        }
+
+        let mut found = vec![];
+        for info in &devices {
+            let device = try!(wrapper::Device::new(Some(&info.uri)).map_err(|e| format!("could not open {}: {:?}", info.uri, e)));
+            let serial = device.serial().unwrap_or("unknown".to_owned());
+            println!("Structure device: {} {} at {} (serial {})", info.vendor, info.name, info.uri, serial);
+            match wanted {
+                None => return Ok(device),
+                Some(w) if w == info.uri || w == serial => return Ok(device),
+                _ => {
+                    let mut device = device;
+                    device.close();
+                    found.push(format!("{} (serial {})", info.uri, serial));
+                },
+            }
+        }
+        Err(format!("Structure Sensor {} not found (connected: {})", wanted.unwrap(), found.join(", ")))
+    }
+
+    /// Pick the supported video mode that best matches the request
+    ///
+    /// Every part of the request that is given has to match exactly. Among the modes that match,
+    /// the one with the largest resolution (and then the highest frame rate) wins.
+    fn choose_mode(which: &str, stream: &wrapper::VideoStream, req: &ModeRequest) -> Result<wrapper::OniVideoMode, String> {
+        let info = try!(stream.info().map_err(|e| format!("could not get {} sensor info: {:?}", which, e)));
+        let modes = info.video_modes();
+        let size = |m: &wrapper::OniVideoMode| (m.resolution_x * m.resolution_y, m.fps);
+        modes.iter()
+             .filter(|m| req.format.as_ref().map_or(true, |f| m.pixel_format.name() == f)
+                         && req.width.map_or(true, |w| m.resolution_x == w)
+                         && req.height.map_or(true, |h| m.resolution_y == h)
+                         && req.fps.map_or(true, |fps| m.fps == fps))
+             .fold(None, |best: Option<&wrapper::OniVideoMode>, m| match best {
+                 Some(b) if size(b) > size(m) => best,
+                 _ => Some(m),
+             })
+             .cloned()
+             .ok_or_else(|| format!("no {} video mode matches {} (supported: {})",
+                                    which, req, modes.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(", ")))
+    }
+
+    /// Apply a camera control (to the IR stream, or both streams for mirroring)
+    fn apply_control(depth: &wrapper::VideoStream, ir: &wrapper::VideoStream, control: Control) -> Result<(), String> {
+        let result = match control {
+            Control::Exposure(x)     => ir.set::<wrapper::prop::Exposure>(x),
+            Control::Gain(x)         => ir.set::<wrapper::prop::Gain>(x),
+            Control::AutoExposure(b) => ir.set::<wrapper::prop::AutoExposure>(b),
+            Control::Mirroring(b)    => depth.set::<wrapper::prop::Mirroring>(b).and_then(|_| ir.set::<wrapper::prop::Mirroring>(b)),
+        };
+        result.map_err(|e| format!("could not set {}: {:?}", control, e))
+    }
+
+    impl Structure {
+        /// Write out a snapshot (if the stored frames are the pair that was just matched)
+        fn finish_snapshot(&mut self, depth: sync::Frame, ir: sync::Frame) {
+            let n = match self.snapshot { Some(n) => n, None => return };
+            let matched = match self.shots {
+                (Some(ref d), Some(ref i)) => d.frame.i == depth.i && i.frame.i == ir.i,
+                _ => false,
+            };
+            if !matched {
+                return;
+            }
+
+            let settings = format!("{}; {}", self.modes, self.controls.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("; "));
+            let (d, i) = (self.shots.0.take().unwrap(), self.shots.1.take().unwrap());
+            let ir_color = match self.ir_format {
+                wrapper::OniPixelFormat::RGB888 => Some(ColorType::RGB(8)),
+                wrapper::OniPixelFormat::Gray8  => Some(ColorType::Gray(8)),
+                wrapper::OniPixelFormat::Gray16 => Some(ColorType::Gray(16)),
+                _                               => None,
+            };
+            for &(name, ref shot, color) in &[(self.depth.name, &d, Some(ColorType::Gray(16))), (self.ir.name, &i, ir_color)] {
+                let (file, bytes) = match color {
+                    Some(bd) => (format!("snapshot{}_{}.png", n, name), prof!("encode", png(&shot.data, shot.size, bd))),
+                    None     => (format!("snapshot{}_{}.dat", n, name), shot.data.to_vec()),
+                };
+                Writer::<[u8]>::with_file(file.clone()).write(&bytes);
+                self.snapfile.write(format!("{},{},{},{:.9},{},{},{},{}\n",
+                                            n, name, file,
+                                            shot.stamp.sec as f64 + shot.stamp.nsec as f64 / 1_000_000_000f64,
+                                            shot.frame.timestamp, shot.size.1, shot.size.0, settings).as_bytes());
+            }
+            println!("Structure: saved snapshot {} (depth frame {}, IR frame {})", n, depth.i, ir.i);
+            self.tx.send(CmdFrom::Data(format!("send status structure snapshot {} saved", n))).unwrap();
+            self.snapshot = None;
+        }
+
+        /// Send a decimated point cloud to the web interface
+        ///
+        /// The points go as base64-encoded little-endian f32 triples (x, y, z in metres).
+        fn send_cloud(&self, depth: &[u16]) {
+            let points = self.intrinsics.cloud(depth, CLOUD_STEP);
+            let mut encoded = Vec::with_capacity(points.len() * 12);
+            geometry::write_points(&mut encoded, &points).unwrap();
+            self.tx.send(CmdFrom::Data(format!("send cloud structure {} {} {}", self.depth.i, points.len(), encoded.to_base64(base64::STANDARD)))).unwrap();
+        }
     }
 
     guilty!{
@@ -54,67 +541,97 @@ group_attr!{
             const BLOCK: Block = Block::Immediate,
 
             fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> Structure {
+                let settings = super::settings();
+                println!("Structure settings: {}", settings);
+
                 wrapper::initialize().unwrap();
-                let device = wrapper::Device::new(None).unwrap();
+                let device = open_device(settings.device.as_ref().map(|s| s as &str)).unwrap_or_else(|e| panic!("{}", e));
 
                 let depth = wrapper::VideoStream::new(&device, wrapper::OniSensorType::Depth).unwrap();
                 let ir = wrapper::VideoStream::new(&device, wrapper::OniSensorType::IR).unwrap();
-                println!("device = {:?}", device);
-                println!("depth = {:?}", depth);
-                println!("ir = {:?}", ir);
-                println!("{:?}", *depth.info().unwrap());
-                println!("{:?}", depth.get::<wrapper::prop::VideoMode>());
-                for mode in depth.info().unwrap().video_modes() { println!("{:?}", mode); }
-                depth.set::<wrapper::prop::VideoMode>(
-                        wrapper::OniVideoMode {
-                            pixel_format: wrapper::OniPixelFormat::Depth100um,
-                            resolution_x: 640,
-                            resolution_y: 480,
-                            fps: 30
-                        }).unwrap();
-                ir.set::<wrapper::prop::VideoMode>(
-                        wrapper::OniVideoMode {
-                            pixel_format: wrapper::OniPixelFormat::RGB888,
-                            resolution_x: 1280,
-                            resolution_y: 1024,
-                            fps: 30
-                        }).unwrap();
+                let depth_mode = choose_mode("depth", &depth, &settings.depth).unwrap_or_else(|e| panic!("{}", e));
+                let ir_mode = choose_mode("IR", &ir, &settings.ir).unwrap_or_else(|e| panic!("{}", e));
+                println!("Structure video modes: depth {}, IR {}", depth_mode, ir_mode);
+                depth.set::<wrapper::prop::VideoMode>(depth_mode).unwrap();
+                ir.set::<wrapper::prop::VideoMode>(ir_mode).unwrap();
+                for &control in &settings.controls {
+                    apply_control(&depth, &ir, control).unwrap_or_else(|e| panic!("{}", e));
+                }
+
+                let mut snapfile = Writer::<[u8]>::with_file("structure_snapshots.csv");
+                snapfile.write(b"snapshot,stream,file,host_stamp,device_stamp,width,height,settings\n");
+                let mut settings_file = Writer::<[u8]>::with_file("structure_settings.csv");
+                settings_file.write(format!("uri,serial,depth,ir,controls\n{},{},{},{},{}\n",
+                                            settings.device.as_ref().map_or("any", |s| s as &str),
+                                            device.serial().unwrap_or("unknown".to_owned()),
+                                            depth_mode, ir_mode,
+                                            settings.controls.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(";")).as_bytes());
+
+                // the frames are paired by timestamp regardless, but with sync they line up better
+                match device.set_frame_sync(true) {
+                    Ok(()) => println!("Structure frame sync enabled: {}", device.frame_sync()),
+                    Err(e) => println!("Structure frame sync not available ({:?}), pairing frames by timestamp only", e),
+                }
+
+                // record the camera models in the session
+                let depth_intrinsics = intrinsics(&depth);
+                let ir_intrinsics = intrinsics(&ir);
+                println!("depth intrinsics: {}", depth_intrinsics);
+                println!("IR intrinsics: {}", ir_intrinsics);
+                let mut intrinsics_file = Writer::<[u8]>::with_file("structure_intrinsics.csv");
+                intrinsics_file.write(format!("{}\n{}\n{}\n",
+                                              geometry::INTRINSICS_HEADER,
+                                              depth_intrinsics.to_csv("depth"),
+                                              ir_intrinsics.to_csv("ir")).as_bytes());
+
                 depth.start().unwrap();
-                //ir.start().unwrap();
+                ir.start().unwrap();
 
-                let mtx = Mutex::new(tx);
+                let mtx = Mutex::new(tx.clone());
                 Structure {
                     device: device,
-                    depth: depth,
-                    ir: ir,
+                    depth: Stream::new(depth, "structure"),
+                    ir: Stream::new(ir, "structure_ir"),
                     start: time::now(),
-                    i: 0,
                     writing: false,
+                    pairer: sync::Pairer::new(depth_mode.fps),
+                    pairfile: Writer::with_file("structure_pairs.csv"),
+                    intrinsics: depth_intrinsics,
+                    ir_format: ir_mode.pixel_format,
+                    cloud: false,
+                    view: settings.view,
+                    snapshot: None,
+                    shots: (None, None),
+                    snapfile: snapfile,
+                    modes: format!("depth {}; ir {}", depth_mode, ir_mode),
+                    controls: settings.controls.clone(),
+                    tx: tx,
 
-                    png: RestartableThread::new("Structure PNG thread", move |(i, unencoded, do_resize, (h, w), bd)| {
+                    // depth and IR previews are kicked together, so keep one of each in flight
+                    png: RestartableThread::with_mailbox("Structure PNG thread", Mailbox::Bounded(2), 2,
+                                                         move |(name, i, unencoded, do_resize, (h, w), bd)| {
                         let mut encoded = Vec::with_capacity((w*h) as usize);
 
                         if do_resize {
-                            let to_resize = prof!("imagebuffer", ImageBuffer::<image::Rgb<u8>, _>::from_raw(w as u32, h as u32, unencoded).unwrap());
+                            let to_resize = try!(prof!("imagebuffer", ImageBuffer::<image::Rgb<u8>, _>::from_raw(w as u32, h as u32, unencoded))
+                                                 .ok_or(format!("{} frame {} is not {}x{} RGB", name, i, w, h)));
                             let (ww, hh) = ((w as u32)/4, (h as u32)/4);
                             let resized = prof!("resize", imageops::resize(&to_resize, ww, hh, FilterType::Nearest));
-                            prof!("encode", PNGEncoder::new(&mut encoded).encode(&resized, ww, hh, bd).unwrap());
+                            try!(prof!("encode", PNGEncoder::new(&mut encoded).encode(&resized, ww, hh, bd))
+                                 .map_err(|e| format!("could not encode {} frame {}: {}", name, i, e)));
                         } else {
                             let (ww, hh) = (w as u32, h as u32);
-                            prof!("encode", PNGEncoder::new(&mut encoded).encode(&unencoded as &[u8], ww, hh, bd).unwrap());
+                            try!(prof!("encode", PNGEncoder::new(&mut encoded).encode(&unencoded[..], ww, hh, bd))
+                                 .map_err(|e| format!("could not encode {} frame {}: {}", name, i, e)));
                         }
 
-                        prof!("send", mtx.lock().unwrap().send(CmdFrom::Data(format!("send kick structure {} data:image/png;base64,{}", i, encoded.to_base64(base64::STANDARD)))).unwrap());
+                        prof!("send", mtx.lock().unwrap().send(CmdFrom::Data(format!("send kick {} {} data:image/png;base64,{}", name, i, encoded.to_base64(base64::STANDARD)))))
+                            .map_err(|e| format!("could not send {} frame {}: {}", name, i, e))
                     }),
-
-                    stampfile: Writer::with_file("structure_times.csv"),
-                    writer: Writer::with_files("structure{}.dat"),
                 }
             }
 
             fn step(&mut self, cmd: Option<String>) {
-                self.i += 1;
-
                 match cmd.as_ref().map(|s| s as &str) {
                     Some("disk start") => {
                         println!("Started Structure recording.");
@@ -124,73 +641,161 @@ group_attr!{
                         println!("Stopped Structure recording.");
                         self.writing = false;
                     },
+                    Some("kick") => {
+                        self.depth.kick = true;
+                        self.ir.kick = true;
+                    },
+                    Some("cloud") => self.cloud = true,
+                    Some(s) if s.starts_with("snapshot") => match s[8..].trim().parse() {
+                        Ok(n) => {
+                            self.snapshot = Some(n);
+                            self.shots = (None, None);
+                        },
+                        Err(_) => println!("Structure: bad command {:?} (expected snapshot <N>)", s),
+                    },
+                    Some(s) if s.starts_with("set colormap ") || s.starts_with("set range ") => {
+                        let words = s.split_whitespace().collect::<Vec<_>>();
+                        let result = if words.len() == 3 {
+                            self.view.apply(words[1], words[2])
+                        } else {
+                            Err(format!("bad command {:?} (expected set <setting> <value>)", s))
+                        };
+                        match result {
+                            Ok(()) => println!("Structure: depth preview {}", self.view),
+                            Err(e) => println!("Structure: {}", e),
+                        }
+                    },
+                    Some(s) if s.starts_with("set ") => {
+                        let words = s.split_whitespace().collect::<Vec<_>>();
+                        let result = if words.len() == 3 {
+                            Control::parse(words[1], words[2]).and_then(|c| apply_control(&self.depth.handle, &self.ir.handle, c).map(|_| c))
+                        } else {
+                            Err(format!("bad command {:?} (expected set <control> <value>)", s))
+                        };
+                        match result {
+                            Ok(c)  => {
+                                println!("Structure: {}", c);
+                                self.controls.retain(|x| x.key() != c.key());
+                                self.controls.push(c);
+                            },
+                            Err(e) => println!("Structure: {}", e),
+                        }
+                    },
                     _ => {},
                 }
+                for result in self.png.results() {
+                    if let Err(e) = result {
+                        errorln!("Structure PNG thread: {}", e);
+                    }
+                }
 
-                if self.depth.is_running() {
+                // wait for whichever stream has a frame (with a timeout, so commands still get through)
+                let ready = match wrapper::wait_for_any(&[&self.depth.handle, &self.ir.handle], 100) {
+                    Ok(i) => i,
+                    Err(wrapper::OniError::TimeOut) => return,
+                    Err(e) => panic!("Structure: waiting for frames failed: {:?}", e),
+                };
+
+                let (kind, frame) = if ready == 0 {
                     prof!("depth", {
-                        let frame = prof!("readFrame", self.depth.read_frame().unwrap());
-                        let narrow_data: &[u8] = prof!(frame.data());
-                        let data: Vec<u8> = prof!("endianness", {
-                            unsafe { // flip bytes
-                                let wide_data: &[u16] = slice::from_raw_parts(narrow_data as *const _ as *const u16, narrow_data.len()/2);
-                                let mut wide_data_flipped: Vec<u16> = wide_data.into_iter().map(|&word| u16::from_be(word)).collect();
-                                let (ptr, len, cap): (*mut u16, usize, usize) = (wide_data_flipped.as_mut_ptr(),
-                                                                                 wide_data_flipped.len()       ,
-                                                                                 wide_data_flipped.capacity()  );
-                                mem::forget(wide_data_flipped);
-                                Vec::<u8>::from_raw_parts(ptr as *mut u8, len*2, cap*2)
-                            }
-                        });
+                        let frame = prof!("readFrame", self.depth.handle.read_frame().unwrap());
+                        let stamp = self.depth.next();
+                        let raw: &[u8] = prof!(frame.data());
 
-                        if self.writing {
-                            let stamp = time::get_time();
-                            self.writer.write(&data);
-                            self.stampfile.write(format!("{},structure{}.dat,{:.9}\n", self.i, self.i, stamp.sec as f64 + stamp.nsec as f64 / 1_000_000_000f64).as_bytes());
+                        let due = self.depth.preview.due();
+                        let depth = if self.cloud || due || self.depth.kick {
+                            prof!("decode", samples(raw))
                         } else {
-                            self.writer.decoy();
+                            Vec::new()
+                        };
+                        if self.cloud {
+                            self.cloud = false;
+                            prof!("cloud", self.send_cloud(&depth));
                         }
-                        match cmd.as_ref().map(|s| s as &str) {
-                            Some("kick") => {
-                                prof!("send to thread", self.png.send((self.i, data, false, (frame.height, frame.width), ColorType::Gray(16))).unwrap());
-                            },
-                            Some(_) | None => ()
+                        if due || self.depth.kick {
+                            let rgb = prof!("colormap", self.view.render(&depth));
+                            let hist = prof!("histogram", self.view.histogram(&depth, HISTOGRAM_BINS));
+                            self.tx.send(CmdFrom::Data(format!("send hist structure {} {}", self.depth.i, hist))).unwrap();
+                            if self.depth.kick {
+                                self.depth.kick = false;
+                                prof!("send to thread", self.png.send((self.depth.name, self.depth.i, Buffer::from(rgb.clone()), false, (frame.height, frame.width), ColorType::RGB(8))).unwrap());
+                            }
+                            if due {
+                                self.depth.preview.send(Buffer::from(rgb), (frame.height as usize, frame.width as usize), ColorType::RGB(8));
+                            }
                         }
-                    });
-                }
 
-                if self.ir.is_running() {
+                        let info = sync::Frame { i: self.depth.i, timestamp: frame.timestamp };
+                        // the frame is only copied out of the driver's buffer for a snapshot or the disk
+                        if self.snapshot.is_some() {
+                            self.shots.0 = Some(Shot { frame: info, stamp: stamp, data: self.depth.frames.copy(raw), size: (frame.height, frame.width) });
+                        }
+                        // depth frames are written big-endian (as they always have been)
+                        if self.writing {
+                            let mut data = prof!("copy", self.depth.frames.copy(raw));
+                            prof!("endianness", data.to_be_16());
+                            self.depth.write(Some(data), stamp, frame.timestamp);
+                        } else {
+                            self.depth.write(None, stamp, frame.timestamp);
+                        }
+                        (sync::Kind::Depth, info)
+                    })
+                } else {
                     prof!("ir", {
-                        let frame = prof!("readFrame", self.ir.read_frame().unwrap());
+                        let frame = prof!("readFrame", self.ir.handle.read_frame().unwrap());
                         let data: &[u8] = prof!(frame.data());
 
-                        if self.writing {
-                            let stamp = time::get_time();
-                            self.writer.write(data);
-                            self.stampfile.write(format!("{},structure{}.dat,{:.9}\n", self.i, self.i, stamp.sec as f64 + stamp.nsec as f64 / 1_000_000_000f64).as_bytes());
-                        } else {
-                            self.writer.decoy();
+                        let stamp = self.ir.next();
+                        let copy = if self.writing { Some(prof!("copy", self.ir.frames.copy(data))) } else { None };
+                        self.ir.write(copy, stamp, frame.timestamp);
+                        // only RGB frames are shrunk for the PNG (the gray modes are small already)
+                        let preview = match self.ir_format {
+                            wrapper::OniPixelFormat::RGB888 => Some((true, ColorType::RGB(8))),
+                            wrapper::OniPixelFormat::Gray8  => Some((false, ColorType::Gray(8))),
+                            wrapper::OniPixelFormat::Gray16 => Some((false, ColorType::Gray(16))),
+                            _                               => None,
+                        };
+                        if let Some((_, bd)) = preview {
+                            if self.ir.preview.due() {
+                                self.ir.preview.send(self.ir.frames.copy(data), (frame.height as usize, frame.width as usize), bd);
+                            }
                         }
-                        match cmd.as_ref().map(|s| s as &str) {
-                            Some("kick") => {
-                                prof!("send to thread", self.png.send((self.i, data.into(), true, (frame.height, frame.width), ColorType::RGB(8))).unwrap());
-                            },
-                            Some(_) | None => ()
+                        if self.ir.kick {
+                            self.ir.kick = false;
+                            if let Some((do_resize, bd)) = preview {
+                                prof!("send to thread", self.png.send((self.ir.name, self.ir.i, self.ir.frames.copy(data), do_resize, (frame.height, frame.width), bd)).unwrap());
+                            }
                         }
-                    });
+
+                        let info = sync::Frame { i: self.ir.i, timestamp: frame.timestamp };
+                        if self.snapshot.is_some() {
+                            self.shots.1 = Some(Shot { frame: info, stamp: stamp, data: self.ir.frames.copy(data), size: (frame.height, frame.width) });
+                        }
+                        (sync::Kind::Ir, info)
+                    })
+                };
+
+                if let Some((depth, ir)) = self.pairer.push(kind, frame) {
+                    if self.writing {
+                        self.pairfile.write(format!("{},{},{},{}\n", depth.i, ir.i, depth.timestamp, ir.timestamp).as_bytes());
+                    }
+                    self.finish_snapshot(depth, ir);
                 }
             }
 
             fn teardown(&mut self) {
                 let end = time::now();
-                if self.ir.is_running() { self.ir.stop(); }
-                self.ir.destroy();
-                if self.depth.is_running() { self.depth.stop(); }
-                self.depth.destroy();
+                for stream in &[&self.ir, &self.depth] {
+                    if stream.handle.is_running() { stream.handle.stop(); }
+                    stream.handle.destroy();
+                }
                 self.device.close();
                 wrapper::shutdown();
                 let millis = (end - self.start).num_milliseconds() as f64;
-                println!("{} structure frames grabbed in {} s ({} FPS)!", self.i, millis/1000.0, 1000.0*(self.i as f64)/millis);
+                for stream in &[&self.depth, &self.ir] {
+                    println!("{} {} frames grabbed in {} s ({} FPS)!", stream.i, stream.name, millis/1000.0, 1000.0*(stream.i as f64)/millis);
+                }
+                println!("{} depth/IR pairs ({} unpaired frames)", self.pairer.pairs, self.pairer.orphans);
             }
         }
     }
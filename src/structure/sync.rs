@@ -0,0 +1,92 @@
+//! Pairing of depth and IR frames by device timestamp
+//!
+//! With frame sync enabled, the device delivers depth and IR frames in step, but they still arrive
+//! (and are written) one at a time, each with its own frame number. So every frame is fed to a
+//! Pairer, which matches it with the frame from the other stream that has the nearest device
+//! timestamp. The pairs are written to `structure_pairs.csv`, so offline tools can find the IR
+//! frame that goes with a depth frame (or vice versa) without guessing.
+
+use std::collections::VecDeque;
+
+/// How many unmatched frames to remember per stream
+const BACKLOG: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Kind {
+    Depth,
+    Ir,
+}
+
+/// A frame as far as pairing is concerned
+#[derive(Debug, Copy, Clone)]
+pub struct Frame {
+    /// Frame number in its own output stream
+    pub i: usize,
+    /// Device timestamp (µs)
+    pub timestamp: u64,
+}
+
+/// Matches up depth and IR frames
+pub struct Pairer {
+    /// Frames waiting for a partner (depth, IR)
+    pending: (VecDeque<Frame>, VecDeque<Frame>),
+    /// Largest timestamp difference allowed within a pair (µs)
+    tolerance: u64,
+    /// Number of pairs made
+    pub pairs: usize,
+    /// Number of frames that never found a partner
+    pub orphans: usize,
+}
+
+impl Pairer {
+    /// The tolerance is half a frame period, so a frame can only pair with its own counterpart
+    pub fn new(fps: i32) -> Pairer {
+        Pairer {
+            pending: (VecDeque::with_capacity(BACKLOG + 1), VecDeque::with_capacity(BACKLOG + 1)),
+            tolerance: 500_000 / fps as u64,
+            pairs: 0,
+            orphans: 0,
+        }
+    }
+
+    /// Add a frame, and return (depth, IR) if it completes a pair
+    pub fn push(&mut self, kind: Kind, frame: Frame) -> Option<(Frame, Frame)> {
+        let (mine, theirs) = match kind {
+            Kind::Depth => (&mut self.pending.0, &mut self.pending.1),
+            Kind::Ir    => (&mut self.pending.1, &mut self.pending.0),
+        };
+
+        let nearest = theirs.iter().enumerate()
+                            .map(|(k, f)| (k, distance(f.timestamp, frame.timestamp)))
+                            .fold(None, |best, (k, d)| match best {
+                                Some((_, closest)) if closest <= d => best,
+                                _ => Some((k, d)),
+                            });
+        if let Some((k, d)) = nearest {
+            if d <= self.tolerance {
+                // anything older than the partner will never be matched now
+                self.orphans += k;
+                for _ in 0..k {
+                    theirs.pop_front();
+                }
+                let partner = theirs.pop_front().unwrap();
+                self.pairs += 1;
+                return Some(match kind {
+                    Kind::Depth => (frame, partner),
+                    Kind::Ir    => (partner, frame),
+                });
+            }
+        }
+
+        mine.push_back(frame);
+        if mine.len() > BACKLOG {
+            mine.pop_front();
+            self.orphans += 1;
+        }
+        None
+    }
+}
+
+fn distance(a: u64, b: u64) -> u64 {
+    if a > b { a - b } else { b - a }
+}
@@ -6,10 +6,11 @@ use self::libc::{c_void, c_char, c_int};
 use self::conv::TryFrom;
 use std::ptr;
 use std::mem;
-use std::ffi::CString;
+use std::ffi::{CString, CStr};
 use std::cell::Cell;
 use std::ops::Deref;
 use std::slice;
+use std::fmt;
 //use std::time::Duration;
 
 custom_derive! {
@@ -72,6 +73,25 @@ pub enum OniPixelFormat {
     YUYV   = 205,
 }
 
+impl OniPixelFormat {
+    /// Short lowercase name used in configuration
+    pub fn name(self) -> &'static str {
+        use self::OniPixelFormat::*;
+        match self {
+            Depth1mm   => "depth1mm",
+            Depth100um => "depth100um",
+            Shift92    => "shift92",
+            Shift93    => "shift93",
+            RGB888     => "rgb888",
+            YUV422     => "yuv422",
+            Gray8      => "gray8",
+            Gray16     => "gray16",
+            JPEG       => "jpeg",
+            YUYV       => "yuyv",
+        }
+    }
+}
+
 pub mod prop {
 
     guilty! {
@@ -179,8 +199,10 @@ pub struct OniFrame {
     data             : *mut c_void,
 
     sensor_type      : OniSensorType,
-    timestamp        : u64,
-    frame_index      : i32,
+    /// Device timestamp (µs)
+    pub timestamp    : u64,
+    /// Frame number assigned by the device
+    pub frame_index  : i32,
 
     pub width        : i32,
     pub height       : i32,
@@ -202,6 +224,50 @@ pub struct OniVideoMode {
     pub fps          : i32,
 }
 
+impl fmt::Display for OniVideoMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}@{}/{}", self.resolution_x, self.resolution_y, self.fps, self.pixel_format.name())
+    }
+}
+
+/// Length of the strings in OniDeviceInfo (ONI_MAX_STR)
+const ONI_MAX_STR: usize = 256;
+
+#[repr(C)]
+pub struct OniDeviceInfo {
+    uri            : [c_char; ONI_MAX_STR],
+    vendor         : [c_char; ONI_MAX_STR],
+    name           : [c_char; ONI_MAX_STR],
+    usb_vendor_id  : u16,
+    usb_product_id : u16,
+}
+
+/// A connected device, as listed by devices()
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub uri            : String,
+    pub vendor         : String,
+    pub name           : String,
+    pub usb_vendor_id  : u16,
+    pub usb_product_id : u16,
+}
+
+fn c_string(chars: &[c_char]) -> String {
+    unsafe { CStr::from_ptr(chars.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+impl<'a> From<&'a OniDeviceInfo> for DeviceInfo {
+    fn from(info: &OniDeviceInfo) -> DeviceInfo {
+        DeviceInfo {
+            uri            : c_string(&info.uri),
+            vendor         : c_string(&info.vendor),
+            name           : c_string(&info.name),
+            usb_vendor_id  : info.usb_vendor_id,
+            usb_product_id : info.usb_product_id,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 #[allow(raw_pointer_derive)]
@@ -222,10 +288,18 @@ impl OniSensorInfo {
 extern "C" {
     fn oniInitialize(apiVersion: i32) -> OniStatus;
     fn oniShutdown();
+    fn oniGetDeviceList(pDevices: *mut *mut OniDeviceInfo, pNumDevices: *mut c_int) -> OniStatus;
+    fn oniReleaseDeviceList(pDevices: *mut OniDeviceInfo) -> OniStatus;
 
     fn oniDeviceOpen(uri: *const c_char, device: *mut *mut c_void) -> OniStatus;
     fn oniDeviceClose(device: *mut c_void) -> OniStatus;
     fn oniDeviceCreateStream(device: *mut c_void, sensorType: OniSensorType, pStream: *mut *mut c_void) -> OniStatus;
+    fn oniDeviceGetProperty(device: *mut c_void, property_id: c_int, data: *mut c_void, data_size: *mut c_int) -> OniStatus;
+    fn oniDeviceEnableDepthColorSync(device: *mut c_void) -> OniStatus;
+    fn oniDeviceDisableDepthColorSync(device: *mut c_void);
+    fn oniDeviceGetDepthColorSyncEnabled(device: *mut c_void) -> c_int;
+
+    fn oniWaitForAnyStream(pStreams: *mut *mut c_void, numStreams: c_int, pStreamIndex: *mut c_int, timeout: c_int) -> OniStatus;
 
     // TODO typedefs for OniStreamHandle etc
     fn oniStreamStart(stream: *mut c_void) -> OniStatus;
@@ -267,6 +341,34 @@ pub fn shutdown() {
     unsafe { oniShutdown() }
 }
 
+/// List the connected devices
+pub fn devices() -> Result<Vec<DeviceInfo>, OniError> {
+    let mut pdevices: *mut OniDeviceInfo = ptr::null_mut();
+    let mut count: c_int = 0;
+    try!(status2result!(unsafe { oniGetDeviceList(&mut pdevices, &mut count) }));
+    // (the list pointer may be null when there are no devices)
+    let list = if count > 0 && !pdevices.is_null() {
+        unsafe { slice::from_raw_parts(pdevices, count as usize) }.iter().map(DeviceInfo::from).collect()
+    } else {
+        vec![]
+    };
+    if !pdevices.is_null() {
+        unsafe { oniReleaseDeviceList(pdevices) };
+    }
+    Ok(list)
+}
+
+/// Wait until one of the streams has a new frame, and return its index in the slice
+///
+/// The timeout is in milliseconds (-1 to wait forever). Returns OniError::TimeOut if no frame
+/// arrived in time.
+pub fn wait_for_any(streams: &[&VideoStream], timeout: i32) -> Result<usize, OniError> {
+    let mut handles = streams.iter().map(|s| s.pvs).collect::<Vec<_>>();
+    let mut index: c_int = -1;
+    try!(status2result!(unsafe { oniWaitForAnyStream(handles.as_mut_ptr(), handles.len() as c_int, &mut index, timeout as c_int) }));
+    Ok(index as usize)
+}
+
 #[allow(raw_pointer_derive)]
 #[derive(Debug)]
 pub struct Device {
@@ -313,6 +415,29 @@ impl Device {
             oniDeviceClose(self.pdev);
         } // TODO this returns an error which I am ignoring
     }
+
+    /// Ask the device to deliver frames from its streams in step (depth-color sync, which also
+    /// applies to the IR stream)
+    pub fn set_frame_sync(&self, enabled: bool) -> Result<(), OniError> {
+        if enabled {
+            status2result!(unsafe { oniDeviceEnableDepthColorSync(self.pdev) })
+        } else {
+            unsafe { oniDeviceDisableDepthColorSync(self.pdev) };
+            Ok(())
+        }
+    }
+
+    pub fn frame_sync(&self) -> bool {
+        unsafe { oniDeviceGetDepthColorSyncEnabled(self.pdev) != 0 }
+    }
+
+    /// Serial number (ONI_DEVICE_PROPERTY_SERIAL_NUMBER, a string of unspecified length)
+    pub fn serial(&self) -> Result<String, OniError> {
+        let mut buf = [0 as c_char; ONI_MAX_STR];
+        let mut size = (ONI_MAX_STR - 1) as c_int;
+        try!(status2result!(unsafe { oniDeviceGetProperty(self.pdev, 3, buf.as_mut_ptr() as *mut c_void, &mut size) }));
+        Ok(c_string(&buf))
+    }
 }
 
 impl VideoStream {
@@ -1,4 +1,31 @@
 //! Service to read data from the Teensy and attached sensors
+//!
+//! The serial port defaults to `/dev/ttyTEENSY` (see 99-usb-serial.rules). It can be overridden
+//! with the `NRI_TEENSY_PORT` environment variable, or at runtime with `set_port` (e.g. to point
+//! the service at the pseudo-terminal opened by `examples/teensyemu.rs`).
+
+use std::env;
+use std::sync::RwLock;
+
+/// Default path of the Teensy's serial port
+pub const DEFAULT_PORT: &'static str = "/dev/ttyTEENSY";
+
+lazy_static! {
+    /// Path of the serial port that will be opened the next time the Teensy is accessed
+    static ref PORT: RwLock<String> = RwLock::new(env::var("NRI_TEENSY_PORT").unwrap_or(DEFAULT_PORT.to_owned()));
+}
+
+/// Change the serial port used by the Teensy service and ParkState::metermaid
+///
+/// Takes effect the next time the port is opened (i.e. the next setup() or metermaid() call).
+pub fn set_port<S: Into<String>>(path: S) {
+    *PORT.write().unwrap() = path.into();
+}
+
+/// Get the serial port path currently in use
+pub fn port() -> String {
+    PORT.read().unwrap().clone()
+}
 
 custom_derive! {
     /// Which end effector is in use (i.e. not parked)
@@ -30,17 +57,20 @@ group_attr!{
     extern crate serial;
     extern crate time;
     extern crate conv;
-    use ::comms::{Controllable, CmdFrom, Block};
+    use ::comms::{self, Controllable, CmdFrom, Block};
     use ::scribe::{Writer, Writable};
     use std::io::{self, Read, Write};
-    use std::fs::File;
     use std::sync::mpsc::Sender;
     use std::{u8, ptr, mem, ops};
     use std::fmt::{self, Display, Debug, Formatter};
     use std::time::Duration;
+    use std::os::unix::io::{AsRawFd, RawFd};
     use self::serial::prelude::*;
     use self::conv::TryFrom;
 
+    mod protocol;
+    pub use self::protocol::ImuRates;
+
     trait RFC980: Read {
         fn read_exact_shim(&mut self, buf: &mut [u8]) -> io::Result<()> {
             self.read_exact_real(buf)
@@ -76,26 +106,62 @@ group_attr!{
 
     impl<T: Read> RFC980 for T {}
 
+    /// Magic bytes at the beginning of a raw serial capture file
+    ///
+    /// The rest of the file is a sequence of records, one per read() from the port:
+    /// `sec: i64, nsec: i32, len: u32` (all little-endian) followed by `len` bytes off the wire.
+    /// See examples/readstbdump.rs for the decoder.
+    const CAPTURE_MAGIC: &'static [u8; 8] = b"NRITRAW1";
+
+    /// Tee adaptor that copies everything read from the port into a raw capture file (when one is
+    /// open), so that framing problems can be diagnosed offline
     trait Coffee: Read + Write {
-        fn coffee<W: Write>(self, w: W) -> CoffeeImpl<Self, W> where Self: Sized {
-            CoffeeImpl { parent: self, writer: w }
+        fn coffee(self) -> CoffeeImpl<Self> where Self: Sized {
+            CoffeeImpl { parent: self, writer: None }
         }
     }
 
-    struct CoffeeImpl<RW: Read + Write, W: Write> {
+    struct CoffeeImpl<RW: Read + Write> {
         parent: RW,
-        writer: W,
+        writer: Option<Writer<[u8]>>,
+    }
+
+    impl<RW: Read + Write> CoffeeImpl<RW> {
+        /// Start capturing into a new file (in the current directory, i.e. the session directory
+        /// if a flow is running)
+        fn start_capture(&mut self) -> String {
+            let name = format!("teensy_raw.{}.dat", time::get_time().sec);
+            let mut writer = Writer::with_file(&*name);
+            writer.write(CAPTURE_MAGIC);
+            self.writer = Some(writer);
+            name
+        }
+
+        /// Stop capturing (returns false if no capture was running)
+        fn stop_capture(&mut self) -> bool {
+            self.writer.take().is_some()
+        }
     }
 
-    impl<RW: Read + Write, W: Write> Read for CoffeeImpl<RW, W> {
+    impl<RW: Read + Write> Read for CoffeeImpl<RW> {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             let n = try!(self.parent.read(buf));
-            try!(self.writer.write_all(&buf[..n]));
+            if n > 0 {
+                if let Some(ref mut writer) = self.writer {
+                    let stamp = time::get_time();
+                    let mut record = Vec::with_capacity(16 + n);
+                    for i in 0..8 { record.push((stamp.sec  as u64 >> (8*i)) as u8); }
+                    for i in 0..4 { record.push((stamp.nsec as u32 >> (8*i)) as u8); }
+                    for i in 0..4 { record.push((n          as u32 >> (8*i)) as u8); }
+                    record.extend(&buf[..n]);
+                    writer.write(&record);
+                }
+            }
             Ok(n)
         }
     }
 
-    impl<RW: Read + Write, W: Write> Write for CoffeeImpl<RW, W> {
+    impl<RW: Read + Write> Write for CoffeeImpl<RW> {
         fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.parent.write(buf) }
         fn flush(&mut self)             -> io::Result<()>    { self.parent.flush()    }
     }
@@ -109,35 +175,32 @@ group_attr!{
         to.write(from).unwrap()
     }
 
-    fn serialport() -> Box<StaticReadWrite> {
-        let mut port = serial::open("/dev/ttyTEENSY").unwrap();
+    /// Open the Teensy's serial port (also returning its file descriptor, to wait on)
+    fn serialport() -> (Box<StaticReadWrite>, RawFd) {
+        let path = super::port();
+        let mut port = serial::open(&path).unwrap_or_else(|e| panic!("Could not open Teensy port {}: {}", path, e));
         port.reconfigure(&|settings| {
             try!(settings.set_baud_rate(serial::Baud115200));
             Ok(())
         }).unwrap();
         port.set_timeout(Duration::from_millis(100)).unwrap();
-        if false {
-            Box::new(port.coffee(File::create("teensydump.dat").unwrap()))
-        } else {
-            Box::new(port)
-        }
+        let fd = port.as_raw_fd();
+        (Box::new(port), fd)
     }
 
     impl super::ParkState {
         pub fn metermaid() -> Option<super::ParkState> {
-            let mut port = serialport();
+            let (mut port, _) = serialport();
 
-            port.write_all(&['4' as u8]).unwrap();
-
-            let mut buf = [0u8; 1];
-            match port.read_exact_shim(&mut buf) {
-                Ok(())         => {
-                    match super::ParkState::try_from(!(buf[0] | 0b1111_1000)) {
+            match protocol::query_park(&mut port) {
+                Ok(bits)        => {
+                    match super::ParkState::try_from(bits) {
                         Ok(ps) => Some(ps),
                         Err(_) => Some(super::ParkState::Multiple)
                     }
                 },
-                Err(..)         => {
+                Err(e)          => {
+                    errorln!("Could not query Teensy park state: {}", e);
                     None
                 }
             }
@@ -145,7 +208,9 @@ group_attr!{
     }
 
     pub struct Teensy {
-        port: Box<StaticReadWrite>,
+        port: CoffeeImpl<Box<StaticReadWrite>>,
+        firmware: protocol::FirmwareInfo,
+        rates: ImuRates,
         file: Writer<Packet>,
         i: usize,
         start: time::Tm,
@@ -205,9 +270,10 @@ group_attr!{
                 p
             }
 
+            // the handshake only accepts firmware that checksums its packets, so anything without
+            // a checksum byte is corrupt
             match buf.len() {
-                x if x < 31 => Err(format!("Implausibly small packet ({}) from Teensy!", x)),
-                31 => Ok(only_analog(buf)),
+                x if x < 32 => Err(format!("Implausibly small packet ({}) from Teensy!", x)),
                 32 => {
                     try!(checksum(buf));
                     Ok(only_analog(buf))
@@ -216,7 +282,6 @@ group_attr!{
                     let a = buf[0] as usize;
                     let g = buf[1] as usize;
                     match x {
-                        t if t == 31 + 2 + 6*(a + g + 1) => Ok(imu_and_analog(buf, a, g)),
                         t if t == 31 + 2 + 6*(a + g + 1) + 1 => {
                             try!(checksum(buf));
                             Ok(imu_and_analog(buf, a, g))
@@ -247,18 +312,69 @@ group_attr!{
     guilty! {
         impl Controllable for Teensy {
             const NAME: &'static str = "teensy",
-            const BLOCK: Block = Block::Period(333_333),
+            const BLOCK: Block = Block::Fd, // step() reads one packet whenever the port has data
 
             fn setup(_: Sender<CmdFrom>, _: Option<String>) -> Teensy {
                 assert_eq!(mem::size_of::<Packet>(), u8::MAX as usize + mem::size_of::<time::Timespec>());
 
-                let mut port = serialport();
-                port.write_all(&['1' as u8]).unwrap();
+                let (port, fd) = serialport();
+                let mut port = port.coffee();
+                let firmware = match protocol::handshake(&mut port) {
+                    Ok(info) => info,
+                    Err(e)   => panic!("Teensy handshake failed: {}", e),
+                };
+                println!("Teensy firmware speaks protocol v{}.{} (capabilities {:#010b})",
+                         firmware.major, firmware.minor, firmware.caps);
+
+                let rates = ImuRates::default();
+                if firmware.has(protocol::caps::IMU_RATES) {
+                    protocol::set_imu_rates(&mut port, &firmware, rates)
+                        .unwrap_or_else(|e| panic!("Could not set Teensy IMU rates: {}", e));
+                }
+                protocol::send(&mut port, protocol::Command::StartStream)
+                    .unwrap_or_else(|e| panic!("Could not start Teensy stream: {}", e));
+                comms::watch(Some(fd));
 
-                Teensy { port: port, file: Writer::with_file("teensy.dat"), i: 0, start: time::now() }
+                Teensy { port: port, firmware: firmware, rates: rates, file: Writer::with_file("teensy.dat"), i: 0, start: time::now() }
             }
 
-            fn step(&mut self, _: Option<String>) {
+            fn step(&mut self, cmd: Option<String>) {
+                if let Some(cmd) = cmd {
+                    let mut words = cmd.split(' ');
+                    match words.next() {
+                        Some("imu") => match ImuRates::parse(words) {
+                            Some(rates) => {
+                                let result = protocol::send(&mut self.port, protocol::Command::StopStream)
+                                    .and_then(|()| protocol::set_imu_rates(&mut self.port, &self.firmware, rates))
+                                    .and_then(|()| protocol::send(&mut self.port, protocol::Command::StartStream));
+                                match result {
+                                    Ok(()) => {
+                                        println!("Teensy IMU rates changed from {:?} to {:?}", self.rates, rates);
+                                        self.rates = rates;
+                                    },
+                                    Err(e) => errorln!("Could not change Teensy IMU rates: {}", e),
+                                }
+                            },
+                            None => errorln!("Usage: imu <acc Hz> <gyro Hz> <mag Hz>"),
+                        },
+                        Some("capture") => match words.next() {
+                            Some("start") => {
+                                let name = self.port.start_capture();
+                                println!("Started raw Teensy capture into {}", name);
+                            },
+                            Some("stop") => {
+                                if self.port.stop_capture() {
+                                    println!("Stopped raw Teensy capture.");
+                                }
+                            },
+                            _ => errorln!("Usage: capture start|stop"),
+                        },
+                        _ => errorln!("Unknown command {:?} sent to Teensy", cmd),
+                    }
+                }
+                if !comms::readable() {
+                    return; // only woken for the command
+                }
                 self.i += 1;
 
                 /*
@@ -308,7 +424,9 @@ group_attr!{
             }
 
             fn teardown(&mut self) {
-                self.port.write_all(&['2' as u8]).unwrap();
+                self.port.stop_capture();
+                protocol::send(&mut self.port, protocol::Command::StopStream)
+                    .unwrap_or_else(|e| errorln!("Could not stop Teensy stream: {}", e));
                 let end = time::now();
                 let millis = (end - self.start).num_milliseconds() as f64;
                 println!("{} Teensy packets grabbed in {} s ({} FPS)!", self.i, millis/1000.0, 1000.0*(self.i as f64)/millis);
@@ -0,0 +1,404 @@
+//! Typed command protocol for talking to the Teensy firmware
+//!
+//! Every command is a single ASCII byte, optionally followed by a fixed-size payload. Commands that
+//! change the firmware's state are acknowledged with `ACK <cmd>` (or refused with `NAK <cmd>`).
+//! Since the acknowledgement may arrive in the middle of a stream of data packets, the reader
+//! scans for it instead of expecting it to be the very next thing on the wire.
+//!
+//! The handshake reply is the magic string "NRI", followed by the protocol version (major, minor)
+//! and a capability bitmask. Firmware that predates the handshake does not answer at all, and
+//! setup() refuses to talk to it (as it does to firmware that does not checksum its packets).
+
+use std::io::{self, Read, Write};
+use std::{fmt, thread};
+use std::time::Duration;
+use super::RFC980;
+
+/// Protocol version spoken by this driver
+///
+/// The firmware must have the same major version. Minor versions only add capabilities.
+pub const PROTOCOL_VERSION: (u8, u8) = (1, 0);
+
+/// Acknowledgement byte (ASCII ACK)
+const ACK: u8 = 0x06;
+/// Negative acknowledgement byte (ASCII NAK)
+const NAK: u8 = 0x15;
+/// Magic string at the beginning of the handshake reply
+const MAGIC: &'static [u8; 3] = b"NRI";
+/// How many bytes to wade through (packets that were already in flight) while waiting for an ACK
+const ACK_SCAN_LIMIT: usize = 4096;
+
+/// Capability bits reported by the firmware in the handshake
+pub mod caps {
+    /// Packets end with a checksum byte
+    pub const CHECKSUM  : u8 = 0b0000_0001;
+    /// IMU sample rates can be set with Command::SetImuRates
+    pub const IMU_RATES : u8 = 0b0000_0010;
+}
+
+/// IMU sample rates (in Hz) requested from the firmware
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ImuRates {
+    pub acc  : u16,
+    pub gyro : u16,
+    pub mag  : u16,
+}
+
+impl Default for ImuRates {
+    fn default() -> ImuRates {
+        ImuRates { acc: 1600, gyro: 760, mag: 100 }
+    }
+}
+
+impl ImuRates {
+    /// Parse "acc gyro mag" (as typed on the CLI or sent from a flow)
+    pub fn parse<'a, I: Iterator<Item=&'a str>>(mut words: I) -> Option<ImuRates> {
+        let mut next = || words.next().and_then(|w| w.parse().ok());
+        match (next(), next(), next()) {
+            (Some(acc), Some(gyro), Some(mag)) => Some(ImuRates { acc: acc, gyro: gyro, mag: mag }),
+            _ => None,
+        }
+    }
+}
+
+/// Commands understood by the firmware
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Ask for the protocol version and capabilities
+    Hello,
+    /// Start streaming data packets
+    StartStream,
+    /// Stop streaming data packets
+    StopStream,
+    /// Ask which end effector is unparked
+    QueryPark,
+    /// Change the IMU sample rates
+    SetImuRates(ImuRates),
+}
+
+impl Command {
+    /// The command byte on the wire
+    fn byte(&self) -> u8 {
+        match *self {
+            Command::Hello          => b'v',
+            Command::StartStream    => b'1',
+            Command::StopStream     => b'2',
+            Command::QueryPark      => b'4',
+            Command::SetImuRates(_) => b'r',
+        }
+    }
+
+    /// Serialize the command byte and payload (multi-byte integers are little-endian)
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![self.byte()];
+        if let Command::SetImuRates(rates) = *self {
+            for &r in &[rates.acc, rates.gyro, rates.mag] {
+                buf.push((r & 0xFF) as u8);
+                buf.push((r >> 8) as u8);
+            }
+        }
+        buf
+    }
+
+    /// Does the firmware acknowledge this command?
+    fn acked(&self) -> bool {
+        match *self {
+            Command::Hello | Command::QueryPark => false,
+            Command::StartStream | Command::StopStream | Command::SetImuRates(_) => true,
+        }
+    }
+}
+
+/// Version and capabilities reported by the firmware
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    pub major : u8,
+    pub minor : u8,
+    pub caps  : u8,
+}
+
+impl FirmwareInfo {
+    pub fn has(&self, cap: u8) -> bool {
+        self.caps & cap == cap
+    }
+}
+
+/// Things that can go wrong while talking to the Teensy
+#[derive(Debug)]
+pub enum Error {
+    /// The serial port itself failed
+    Io(io::Error),
+    /// The firmware did not answer the handshake (it is probably too old)
+    NoHandshake,
+    /// The firmware answered the handshake with something other than the magic string
+    BadHandshake([u8; 6]),
+    /// The firmware speaks a different major protocol version
+    Incompatible(FirmwareInfo),
+    /// The firmware does not end its packets with a checksum
+    NoChecksum(FirmwareInfo),
+    /// The firmware lacks a capability required for a command
+    Unsupported(Command),
+    /// The firmware refused a command
+    Nak(Command),
+    /// No acknowledgement arrived for a command
+    NoAck(Command),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e)           => write!(f, "serial I/O error: {}", e),
+            Error::NoHandshake         => write!(f, "no reply to handshake (firmware predates protocol v{}.{}?)",
+                                                 PROTOCOL_VERSION.0, PROTOCOL_VERSION.1),
+            Error::BadHandshake(ref b) => write!(f, "garbled handshake reply {:?}", b),
+            Error::Incompatible(info)  => write!(f, "firmware speaks protocol v{}.{}, but this driver needs v{}.x",
+                                                 info.major, info.minor, PROTOCOL_VERSION.0),
+            Error::NoChecksum(info)    => write!(f, "firmware v{}.{} does not checksum its packets", info.major, info.minor),
+            Error::Unsupported(cmd)    => write!(f, "firmware does not support {:?}", cmd),
+            Error::Nak(cmd)            => write!(f, "firmware refused {:?}", cmd),
+            Error::NoAck(cmd)          => write!(f, "firmware did not acknowledge {:?}", cmd),
+        }
+    }
+}
+
+/// Discard everything waiting on the port (i.e. read until the port times out)
+pub fn drain<P: Read>(port: &mut P) {
+    let mut buf = [0u8; 256];
+    loop {
+        match port.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_)          => {},
+        }
+    }
+}
+
+/// Send a command, and wait for the acknowledgement if it needs one
+pub fn send<P: Read + Write>(port: &mut P, cmd: Command) -> Result<(), Error> {
+    try!(port.write_all(&cmd.encode()));
+    try!(port.flush());
+    if cmd.acked() {
+        try!(expect_ack(port, cmd));
+    }
+    Ok(())
+}
+
+/// Scan the incoming bytes for `ACK <cmd>` or `NAK <cmd>`
+fn expect_ack<P: Read>(port: &mut P, cmd: Command) -> Result<(), Error> {
+    let mut prev = 0u8;
+    let mut byte = [0u8; 1];
+    for _ in 0..ACK_SCAN_LIMIT {
+        match port.read_exact_shim(&mut byte) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => return Err(Error::NoAck(cmd)),
+            Err(e) => return Err(Error::Io(e)),
+        }
+        if byte[0] == cmd.byte() {
+            match prev {
+                ACK => return Ok(()),
+                NAK => return Err(Error::Nak(cmd)),
+                _   => {},
+            }
+        }
+        prev = byte[0];
+    }
+    Err(Error::NoAck(cmd))
+}
+
+/// Perform the version/capability handshake
+///
+/// Streaming is stopped first (without waiting for an ACK, since old firmware does not send one)
+/// so that the reply is not buried in data packets.
+pub fn handshake<P: Read + Write>(port: &mut P) -> Result<FirmwareInfo, Error> {
+    try!(port.write_all(&Command::StopStream.encode()));
+    thread::sleep(Duration::from_millis(50));
+    drain(port);
+
+    try!(port.write_all(&Command::Hello.encode()));
+    try!(port.flush());
+
+    let mut reply = [0u8; 6];
+    match port.read_exact_shim(&mut reply) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => return Err(Error::NoHandshake),
+        Err(ref e) if e.kind() == io::ErrorKind::Other    => return Err(Error::NoHandshake), // short read
+        Err(e) => return Err(Error::Io(e)),
+    }
+
+    if &reply[..3] != MAGIC {
+        return Err(Error::BadHandshake(reply));
+    }
+
+    let info = FirmwareInfo { major: reply[3], minor: reply[4], caps: reply[5] };
+    if info.major != PROTOCOL_VERSION.0 {
+        return Err(Error::Incompatible(info));
+    }
+    if !info.has(caps::CHECKSUM) {
+        return Err(Error::NoChecksum(info));
+    }
+    Ok(info)
+}
+
+/// Change the IMU sample rates (the stream must be stopped)
+pub fn set_imu_rates<P: Read + Write>(port: &mut P, info: &FirmwareInfo, rates: ImuRates) -> Result<(), Error> {
+    let cmd = Command::SetImuRates(rates);
+    if !info.has(caps::IMU_RATES) {
+        return Err(Error::Unsupported(cmd));
+    }
+    send(port, cmd)
+}
+
+/// Ask the parking lot which end effectors are out
+///
+/// Returns the raw bitmask of unparked end effectors (the sensors are active-low on the wire).
+pub fn query_park<P: Read + Write>(port: &mut P) -> Result<u8, Error> {
+    try!(send(port, Command::QueryPark));
+
+    let mut buf = [0u8; 1];
+    try!(port.read_exact_shim(&mut buf));
+    Ok(!(buf[0] | 0b1111_1000))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Read, Write};
+
+    /// A fake serial port: bytes in `input` can be read right away, and writing a command byte
+    /// queues up the scripted reply to it (so that it survives the drain before the handshake)
+    struct Port {
+        input: Vec<u8>,
+        replies: Vec<(u8, Vec<u8>)>,
+        output: Vec<u8>,
+    }
+
+    impl Port {
+        fn new(input: Vec<u8>) -> Port {
+            Port { input: input, replies: vec![], output: vec![] }
+        }
+
+        fn reply(mut self, cmd: u8, reply: &[u8]) -> Port {
+            self.replies.push((cmd, reply.to_vec()));
+            self
+        }
+    }
+
+    impl Read for Port {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = if buf.len() < self.input.len() { buf.len() } else { self.input.len() };
+            for (b, &i) in buf.iter_mut().zip(&self.input) {
+                *b = i;
+            }
+            self.input = self.input[n..].to_vec();
+            Ok(n)
+        }
+    }
+
+    impl Write for Port {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &b in buf {
+                self.output.push(b);
+                for &(cmd, ref reply) in &self.replies {
+                    if b == cmd {
+                        self.input.extend(reply.iter().cloned());
+                    }
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn hello(reply: &[u8]) -> Result<FirmwareInfo, Error> {
+        // leftover stream data is drained before the handshake
+        let mut port = Port::new(vec![b'a'; 100]).reply(b'v', reply);
+        handshake(&mut port)
+    }
+
+    #[test]
+    fn handshake_ok() {
+        let info = hello(b"NRI\x01\x02\x03").unwrap();
+        assert_eq!(info, FirmwareInfo { major: 1, minor: 2, caps: caps::CHECKSUM | caps::IMU_RATES });
+    }
+
+    #[test]
+    fn handshake_bad_magic() {
+        match hello(b"NRJ\x01\x00\x01") {
+            Err(Error::BadHandshake(reply)) => assert_eq!(&reply, b"NRJ\x01\x00\x01"),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn handshake_missing_caps() {
+        match hello(b"NRI\x01\x00\x02") {
+            Err(Error::NoChecksum(info)) => assert_eq!(info.caps, caps::IMU_RATES),
+            r => panic!("unexpected {:?}", r),
+        }
+        match hello(b"NRI\x02\x00\x01") {
+            Err(Error::Incompatible(info)) => assert_eq!(info.major, 2),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn handshake_no_reply() {
+        match hello(b"NR") {
+            Err(Error::NoHandshake) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    fn start(before: &[u8], reply: &[u8]) -> Result<(), Error> {
+        let mut input = before.to_vec();
+        input.extend(reply.iter().cloned());
+        send(&mut Port::new(input), Command::StartStream)
+    }
+
+    #[test]
+    fn ack_scanning() {
+        // the acknowledgement can arrive after packets that were already on the wire, and a lone
+        // command byte (or an ACK for something else) is just data
+        let data = [b'a', b'a', b'a', 4, b'1', ACK, b'2', 0, 1, 2];
+        start(&data, &[ACK, b'1']).unwrap();
+        match start(&data, &[NAK, b'1']) {
+            Err(Error::Nak(Command::StartStream)) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn ack_scan_limit() {
+        let data = vec![0; ACK_SCAN_LIMIT - 2];
+        start(&data, &[ACK, b'1']).unwrap();
+
+        let data = vec![0; ACK_SCAN_LIMIT - 1];
+        match start(&data, &[ACK, b'1']) {
+            Err(Error::NoAck(Command::StartStream)) => {},
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn imu_rates() {
+        let info = FirmwareInfo { major: 1, minor: 0, caps: caps::CHECKSUM };
+        let rates = ImuRates { acc: 1600, gyro: 760, mag: 100 };
+        match set_imu_rates(&mut Port::new(vec![]), &info, rates) {
+            Err(Error::Unsupported(Command::SetImuRates(r))) => assert_eq!(r, rates),
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let info = FirmwareInfo { caps: caps::CHECKSUM | caps::IMU_RATES, .. info };
+        let mut port = Port::new(vec![ACK, b'r']);
+        set_imu_rates(&mut port, &info, rates).unwrap();
+        assert_eq!(port.output, vec![b'r', 0x40, 0x06, 0xF8, 0x02, 100, 0]);
+    }
+}
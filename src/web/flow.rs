@@ -2,12 +2,12 @@ extern crate time;
 extern crate uuid;
 extern crate rustc_serialize as serialize;
 
-use std::sync::mpsc;
+use std::sync::{mpsc, Mutex};
 use std::collections::BTreeMap;
 use std::io::{Write, BufRead};
 use std::fs::{self, File};
 use std::path::PathBuf;
-use std::{fmt, env, thread};
+use std::{fmt, env, mem, thread};
 use std::time::Duration;
 use ::teensy::ParkState;
 use ::comms::CmdFrom;
@@ -23,6 +23,25 @@ enum EventContour {
     In,
 }
 
+/// Maximum number of alarms remembered between flows
+const MAX_ALARMS: usize = 1000;
+
+lazy_static! {
+    /// Alarms raised by services (e.g. sensor overloads), to be recorded by the active flow
+    static ref ALARMS: Mutex<Vec<(time::Timespec, String)>> = Mutex::new(Vec::new());
+}
+
+/// Record an alarm raised by a service
+///
+/// If a flow is running, the alarm will show up in its .flow file.
+pub fn alarm(msg: String) {
+    let mut alarms = ALARMS.lock().unwrap();
+    if alarms.len() >= MAX_ALARMS {
+        alarms.remove(0);
+    }
+    alarms.push((time::get_time(), msg));
+}
+
 struct StampPrinter(time::Timespec);
 
 impl fmt::Display for StampPrinter {
@@ -77,6 +96,8 @@ pub enum FlowCmd {
     Stop(String),
     Send(String),
     StopSensors,
+    /// Take a still from every running camera
+    Snapshot,
 }
 
 impl Flow {
@@ -96,7 +117,11 @@ impl Flow {
             self.id = Some(uuid::Uuid::new_v4());
             self.active = true;
 
+            // alarms from before the flow started are irrelevant
+            ALARMS.lock().unwrap().clear();
+
             fs::create_dir(format!("data/{}.{}", self.shortname, self.stamp.unwrap().sec)).unwrap();
+            ::calibration::attach(format!("data/{}.{}", self.shortname, self.stamp.unwrap().sec));
             self.dir = Some(env::current_dir().unwrap());
             env::set_current_dir(format!("data/{}.{}", self.shortname, self.stamp.unwrap().sec)).unwrap();
 
@@ -107,6 +132,9 @@ impl Flow {
         if let Some(state) = self.states.iter_mut().skip_while(|s| s.done).next() {
             if state.park.map_or(true, |p| p == park) {
                 ret = EventContour::Continuing;
+                if state.park.is_some() {
+                    park_hooks(park, tx);
+                }
                 println!("Executing state {}", state.name);
                 state.run(tx, wsid);
                 println!("Finished executing state {}", state.name);
@@ -132,6 +160,13 @@ impl Flow {
                     writeln!(file, "").unwrap();
                 }
 
+                let stamp = self.stamp.unwrap();
+                for (when, msg) in mem::replace(&mut *ALARMS.lock().unwrap(), Vec::new()) {
+                    if when >= stamp {
+                        writeln!(file, "! {} [{}]", msg, StampPrinter(when)).unwrap();
+                    }
+                }
+
                 env::set_current_dir(self.dir.take().unwrap()).unwrap();
 
                 ret = EventContour::Finishing;
@@ -235,6 +270,7 @@ impl Flow {
                                 script.push((FlowCmd::Start(word.to_owned()), None));
                             }
                         },
+                        Some("snapshot") => script.push((FlowCmd::Snapshot, None)),
                         Some(_) => return Err((i, "invalid command")),
                     }
                 }
@@ -248,6 +284,18 @@ impl Flow {
 
 }
 
+/// Things to do whenever the park state triggers a flow state
+fn park_hooks(park: ParkState, tx: &mpsc::Sender<CmdFrom>) {
+    match park {
+        ParkState::None | ParkState::OptoForce | ParkState::Stick => {
+            // the BioTac is in its park, so it is unloaded (the service ignores this if it is not
+            // running)
+            tx.send(CmdFrom::Data("to biotac baseline".to_owned())).unwrap();
+        },
+        ParkState::BioTac | ParkState::Multiple => {},
+    }
+}
+
 impl FlowState {
     pub fn new(name: String, park: Option<ParkState>, script: Vec<(FlowCmd, Option<time::Timespec>)>) -> FlowState {
         FlowState { name: name, park: park, script: script, stamp: None, done: false }
@@ -337,6 +385,9 @@ impl FlowCmd {
                     assert!(rpc!(tx, CmdFrom::Stop, svc.to_owned()).unwrap());
                 }
             }
+            FlowCmd::Snapshot => {
+                tx.send(CmdFrom::Data("snapshot".to_owned())).unwrap();
+            }
         }
     }
     
@@ -355,6 +406,7 @@ impl FlowCmd {
             FlowCmd::Stop(ref service) => write!(file, "stop {}", service).unwrap(),
             FlowCmd::Send(ref string) => write!(file, ": {}", string).unwrap(),
             FlowCmd::StopSensors => write!(file, "stop").unwrap(),
+            FlowCmd::Snapshot => write!(file, "snapshot").unwrap(),
         }
     }
 }
@@ -0,0 +1,85 @@
+extern crate iron;
+extern crate router;
+
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use self::iron::prelude::*;
+use self::iron::status;
+use self::iron::middleware::Handler;
+use self::iron::response::{WriteBody, ResponseBody};
+use self::router::Router;
+use ::preview;
+
+/// Separator between the frames of a stream
+const BOUNDARY: &'static str = "nriframe";
+
+/// How long to go without a frame before writing something anyway (seconds)
+///
+/// A client that has gone away is only noticed when a write fails, so this is how quickly the
+/// server thread (and the client's place on the stream) is freed when nothing is being published
+/// (e.g. the camera is stopped).
+const KEEPALIVE: u64 = 2;
+
+/// Response body that keeps writing frames until the client goes away
+struct Stream {
+    frames: preview::Watcher,
+}
+
+impl Stream {
+    fn write_frame(res: &mut ResponseBody, jpeg: &[u8]) -> io::Result<()> {
+        try!(write!(res, "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", BOUNDARY, jpeg.len()));
+        try!(res.write_all(jpeg));
+        try!(res.write_all(b"\r\n"));
+        res.flush()
+    }
+}
+
+impl WriteBody for Stream {
+    fn write_body(&mut self, res: &mut ResponseBody) -> io::Result<()> {
+        let mut last: Option<Arc<Vec<u8>>> = None;
+        loop {
+            match self.frames.next(Duration::from_secs(KEEPALIVE)) {
+                Some(jpeg) => {
+                    try!(Stream::write_frame(res, &jpeg));
+                    last = Some(jpeg);
+                },
+                None => {
+                    // repeat the last frame (or send a blank line, which goes in the preamble, if
+                    // there has not been one yet)
+                    match last {
+                        Some(ref jpeg) => try!(Stream::write_frame(res, jpeg)),
+                        None           => { try!(res.write_all(b"\r\n")); try!(res.flush()); },
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Handler for live previews (multipart/x-mixed-replace, which browsers show in an img tag)
+///
+/// Each client holds on to one of the server's threads for as long as it watches (and up to
+/// KEEPALIVE seconds after it leaves), so there is a limit of preview::MAX_WATCHERS clients per
+/// stream to leave enough threads for the rest of the web interface.
+pub fn handler() -> Box<Handler> {
+    Box::new(move |req: &mut Request| -> IronResult<Response> {
+                      let camera = req.extensions.get::<Router>().unwrap().find("camera").unwrap_or("").to_owned();
+                      if !preview::STREAMS.contains(&&camera[..]) {
+                          return Ok(Response::with((status::NotFound, format!("no preview stream called {:?}", camera))));
+                      }
+
+                      let frames = match preview::watch(&camera) {
+                          Some(frames) => frames,
+                          None => return Ok(Response::with((status::ServiceUnavailable,
+                                                            format!("too many clients watching {:?}", camera)))),
+                      };
+
+                      let mut resp = Response::new();
+                      resp.headers.set_raw("Content-Type", vec![format!("multipart/x-mixed-replace; boundary={}", BOUNDARY).into_bytes()]);
+                      resp.headers.set_raw("Cache-Control", vec![b"no-cache".to_vec()]);
+                      resp.status = Some(status::Ok);
+                      resp.body = Some(Box::new(Stream { frames: frames }));
+                      Ok(resp)
+                  })
+}
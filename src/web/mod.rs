@@ -56,6 +56,8 @@ mod config;
 mod middleware;
 /// websocket server and utilities
 mod ws;
+/// MJPEG streams of the camera previews
+mod mjpeg;
 
 use self::flow::Flow;
 
@@ -168,11 +170,11 @@ fn index() -> Box<Handler> {
     Box::new(move |req: &mut Request| -> IronResult<Response> {
                       let mut data = BTreeMap::<String, Json>::new();
                       data.insert("services".to_owned(), vec![
-                                  Service::new("Structure Sensor", "structure" , "<img class=\"structure latest\" /><div class=\"structure framenum\"></div>"),
-                                  Service::new("mvBlueFOX3"      , "bluefox"   , "<img class=\"bluefox latest\" /><div class=\"bluefox framenum\"></div>"),
-                                  Service::new("OptoForce"       , "optoforce" , ""),
-                                  Service::new("SynTouch BioTac" , "biotac"    , ""),
-                                  Service::new("Teensy"          , "teensy"    , ""),
+                                  Service::new("Structure Sensor", "structure" , "<img class=\"structure live\" src=\"/stream/structure\" /><img class=\"structure_ir live\" src=\"/stream/structure_ir\" /><img class=\"structure latest\" /><div class=\"structure framenum\"></div><img class=\"structure_ir latest\" /><div class=\"structure_ir framenum\"></div><canvas class=\"structure cloud\" width=\"320\" height=\"240\"></canvas><div class=\"structure cloudinfo\"></div><button type=\"button\" class=\"btn btn-default\" onclick=\"send('to structure cloud')\">Point cloud</button> <button type=\"button\" class=\"btn btn-default\" onclick=\"send('snapshot')\">Snapshot (all cameras)</button><div class=\"structure status\"></div><div><canvas class=\"structure hist\" width=\"320\" height=\"80\"></canvas><div class=\"structure histinfo\"></div><select onchange=\"send('to structure set colormap ' + this.value)\"><option>jet</option><option>viridis</option><option>gray</option></select> <input type=\"text\" size=\"10\" placeholder=\"400-2000\" onchange=\"send('to structure set range ' + this.value)\" /> mm</div>"),
+                                  Service::new("mvBlueFOX3"      , "bluefox"   , "<img class=\"bluefox live\" src=\"/stream/bluefox\" /><img class=\"bluefox latest\" /><div class=\"bluefox framenum\"></div><button type=\"button\" class=\"btn btn-default\" onclick=\"send('to bluefox preset preview')\">Preview settings</button> <button type=\"button\" class=\"btn btn-default\" onclick=\"send('to bluefox preset capture')\">Capture settings</button> <button type=\"button\" class=\"btn btn-default\" onclick=\"send('to bluefox preset raw')\">Raw Bayer</button> <button type=\"button\" class=\"btn btn-default\" onclick=\"send('to bluefox preset color')\">RGB</button> <button type=\"button\" class=\"btn btn-default\" onclick=\"send('snapshot')\">Snapshot (all cameras)</button><div class=\"bluefox status\"></div>"),
+                                  Service::new("OptoForce"       , "optoforce" , "<div class=\"optoforce alarm text-danger\"></div>"),
+                                  Service::new("SynTouch BioTac" , "biotac"    , "<div class=\"biotac status\"></div><div class=\"biotac timing text-muted\"></div><div class=\"biotac alarm text-danger\"></div><button type=\"button\" class=\"btn btn-default\" onclick=\"send('to biotac capture start')\">Start SPI capture</button> <button type=\"button\" class=\"btn btn-default\" onclick=\"send('to biotac capture stop')\">Stop SPI capture</button>"),
+                                  Service::new("Teensy"          , "teensy"    , "<button type=\"button\" class=\"btn btn-default\" onclick=\"send('to teensy capture start')\">Start raw capture</button> <button type=\"button\" class=\"btn btn-default\" onclick=\"send('to teensy capture stop')\">Stop raw capture</button>"),
                       ].to_json());
                       data.insert("flows".to_owned(), FLOWS.read().unwrap().to_json());
                       data.insert("server".to_owned(), format!("{}:{}", req.url.host, config::WS_PORT).to_json());
@@ -318,6 +320,7 @@ guilty!{
             router.post("/nuc/:action", nuc(tx.clone()));
             router.post("/control/:service/:action", control(tx.clone()));
             router.post("/flow/:flow/:action", flow(tx.clone()));
+            router.get("/stream/:camera", mjpeg::handler());
 
             let mut mount = Mount::new();
             for p in &["css", "fonts", "js"] {
@@ -337,6 +340,9 @@ guilty!{
 
         fn step(&mut self, data: Option<String>) {
             if let Some(d) = data {
+                if d.starts_with("alarm ") {
+                    flow::alarm(d[6..].to_owned());
+                }
                 self.wstx.as_ref().unwrap().send(ws::Message::text(d)).unwrap();
             }
         }